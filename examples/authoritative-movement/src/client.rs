@@ -0,0 +1,169 @@
+use common::chunks::rotation::Rotation;
+use network::{
+    client::{ClientConfig, IClientNetwork},
+    interpolation::{
+        adaptive_delay::AdaptiveDelay,
+        clock_sync::ClockSync,
+        history_buffer::{HistoryBuffer, SampleResult},
+        traits::Interpolatable,
+        visual_correction::VisualCorrection,
+    },
+    messages::{ClientMessages, NetworkMessageType, ServerMessages},
+    NetworkClient,
+};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::shared::{Position, TICK_RATE};
+
+/// Bots walk back and forth along the x axis this far from their spawn
+const WALK_AMPLITUDE: f32 = 40.0;
+
+/// Legal walking speed of a bot, blocks per second
+pub const BOT_SPEED: f32 = 6.0;
+
+/// Another player as seen by this client
+struct RemotePlayer {
+    history: HistoryBuffer<Position>,
+    delay: AdaptiveDelay,
+}
+
+#[derive(Debug, Default)]
+pub struct ClientReport {
+    pub corrections: u32,
+    pub max_streamed: usize,
+    pub interpolated_samples: u64,
+    pub extrapolated_samples: u64,
+    pub max_visual_error: f32,
+}
+
+/// Run a bot walking with `speed_multiplier` times the legal speed
+pub async fn run_client(
+    ip: String,
+    duration: Duration,
+    speed_multiplier: f32,
+    phase: f32,
+    network_preset: Option<String>,
+) -> Result<ClientReport, String> {
+    let mut config = ClientConfig::default();
+    if let Some(preset) = network_preset.as_ref() {
+        config = config.with_network_preset(preset);
+    }
+    let client = NetworkClient::new_with_config(ip, config).await?;
+
+    let started = Instant::now();
+    let mut report = ClientReport::default();
+
+    // Predicted local position; `None` until the server spawns the player
+    let mut predicted: Option<Position> = None;
+    let mut walk_time = 0.0_f32;
+    let mut correction = VisualCorrection::<Position>::new(10.0, 0.01);
+
+    let mut clock = ClockSync::new(32);
+    let mut remotes: HashMap<u32, RemotePlayer> = HashMap::new();
+
+    client
+        .run_fixed(TICK_RATE, |client, delta| {
+            for error in client.iter_errors() {
+                if error.is_fatal() {
+                    log::error!("Client error: {}", error);
+                    return false;
+                }
+                log::warn!("Client error: {}", error);
+            }
+
+            let local_time = started.elapsed().as_secs_f64();
+            for message in client.iter_server_messages() {
+                match message {
+                    ServerMessages::PlayerSpawn { position, .. } => {
+                        // The first spawn places the player, the next ones are
+                        // server corrections of a rejected prediction
+                        let server_position = Position(position);
+                        if let Some(visual) = predicted.map(|p| correction.apply(&p)) {
+                            report.corrections += 1;
+                            correction.record_correction(&visual, &server_position);
+                        }
+                        predicted = Some(server_position);
+                    }
+                    ServerMessages::StartStreamingEntity { id, position, .. } => {
+                        let mut remote = RemotePlayer {
+                            history: HistoryBuffer::new(64),
+                            delay: AdaptiveDelay::new(TICK_RATE as f64),
+                        };
+                        remote.history.push(Position(position), local_time);
+                        remotes.insert(id, remote);
+                        report.max_streamed = report.max_streamed.max(remotes.len());
+                    }
+                    ServerMessages::StopStreamingEntities { ids, .. } => {
+                        for id in ids {
+                            remotes.remove(&id);
+                        }
+                    }
+                    ServerMessages::EntityMove {
+                        id,
+                        position,
+                        timestamp,
+                        ..
+                    } => {
+                        clock.record_sample(local_time, timestamp);
+                        let Some(remote) = remotes.get_mut(&id) else {
+                            continue;
+                        };
+                        remote
+                            .history
+                            .push(Position(position), clock.server_to_local(timestamp));
+                        remote.delay.record_arrival(local_time);
+                    }
+                    ServerMessages::Disconnect { message } => {
+                        log::info!("Disconnected: {:?}", message);
+                    }
+                    _ => {}
+                }
+            }
+
+            let Some(position) = predicted.as_mut() else {
+                return started.elapsed() < duration;
+            };
+
+            // Prediction: apply the input locally and send the result to the server
+            let dt = delta.as_secs_f32();
+            walk_time += dt;
+            let speed = BOT_SPEED * speed_multiplier;
+            let velocity = speed * (walk_time * speed / WALK_AMPLITUDE + phase).cos();
+            position.0.x += velocity * dt;
+            client.send_message(
+                NetworkMessageType::UnreliableSequenced,
+                &ClientMessages::PlayerMove {
+                    position: position.0,
+                    rotation: Rotation::new(0.0, 0.0),
+                    animation_state: Default::default(),
+                },
+            );
+
+            // Render: own player with the decaying correction, others interpolated
+            correction.update(dt);
+            let visual = correction.apply(position);
+            report.max_visual_error = report.max_visual_error.max(visual.distance(position));
+
+            for remote in remotes.values_mut() {
+                let render_time = local_time - remote.delay.delay();
+                match remote.history.sample(render_time) {
+                    SampleResult::Interpolate { before, after, t } => {
+                        let _rendered = before.value.lerp(&after.value, t);
+                        report.interpolated_samples += 1;
+                    }
+                    SampleResult::Extrapolate { .. } => {
+                        report.extrapolated_samples += 1;
+                    }
+                    _ => {}
+                }
+                remote.history.cleanup_before(render_time - 1.0, 2);
+            }
+
+            started.elapsed() < duration
+        })
+        .await;
+
+    client.disconnect();
+    Ok(report)
+}
@@ -0,0 +1,155 @@
+use clap::Parser;
+use log::LevelFilter;
+use std::f32::consts::TAU;
+use std::process::ExitCode;
+use std::time::Duration;
+
+pub mod client;
+pub mod server;
+pub mod shared;
+
+/// Minimal authoritative movement server.
+///
+/// The server validates client moves and corrects predictions that break
+/// the speed limit, streams players to each other by distance and
+/// unloads chunks around them; the clients predict their own movement and
+/// interpolate the other players.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    #[arg(short, long, default_value_t = String::from("127.0.0.1:25571"))]
+    ip: String,
+
+    /// local (server and bots in one process, checks the results), server or client
+    #[arg(short = 't', long, default_value_t = String::from("local"))]
+    run_type: String,
+
+    /// Test duration in seconds
+    #[arg(short, long, default_value_t = 10)]
+    duration: u64,
+
+    /// Number of bots in local mode
+    #[arg(short, long, default_value_t = 4)]
+    bots: usize,
+
+    /// Speed multiplier of the first bot in local mode (or of the client);
+    /// above 1.0 the bot walks faster than allowed and must be corrected
+    #[arg(short, long, default_value_t = 3.0)]
+    speed_hack: f32,
+
+    /// Network simulation: fiber, wifi, mobile-3g
+    #[arg(short = 'n', long)]
+    network_preset: Option<String>,
+}
+
+struct SimpleLogger;
+impl log::Log for SimpleLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+    fn log(&self, record: &log::Record) {
+        eprintln!("{} {}", record.level(), record.args());
+    }
+    fn flush(&self) {}
+}
+static LOGGER: SimpleLogger = SimpleLogger;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Info);
+
+    let args = Args::parse();
+    let duration = Duration::from_secs(args.duration);
+
+    match args.run_type.as_str() {
+        "server" => {
+            let report = server::run_server(args.ip, duration, args.network_preset).await;
+            println!("{:#?}", report);
+            ExitCode::SUCCESS
+        }
+        "client" => match client::run_client(args.ip, duration, args.speed_hack, 0.0, args.network_preset).await {
+            Ok(report) => {
+                println!("{:#?}", report);
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                log::error!("Client error: {}", e);
+                ExitCode::FAILURE
+            }
+        },
+        _ => run_local(args, duration).await,
+    }
+}
+
+/// Run the server and the bots together and check that every subsystem did its job
+async fn run_local(args: Args, duration: Duration) -> ExitCode {
+    // The server outlives the bots so they all finish their run
+    let server = tokio::spawn(server::run_server(
+        args.ip.clone(),
+        duration + Duration::from_secs(2),
+        args.network_preset.clone(),
+    ));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut bots = Vec::new();
+    for i in 0..args.bots {
+        let speed_multiplier = if i == 0 { args.speed_hack } else { 1.0 };
+        let phase = TAU * i as f32 / args.bots as f32;
+        bots.push(tokio::spawn(client::run_client(
+            args.ip.clone(),
+            duration,
+            speed_multiplier,
+            phase,
+            args.network_preset.clone(),
+        )));
+    }
+
+    let mut failures = Vec::new();
+    let mut reports = Vec::new();
+    for (i, bot) in bots.into_iter().enumerate() {
+        match bot.await.unwrap() {
+            Ok(report) => reports.push(report),
+            Err(e) => failures.push(format!("bot {} failed to connect: {}", i, e)),
+        }
+    }
+    let server_report = server.await.unwrap();
+
+    println!("\n========== AUTHORITATIVE MOVEMENT ==========");
+    println!("{:#?}", server_report);
+    for (i, report) in reports.iter().enumerate() {
+        println!("bot {}: {:?}", i, report);
+    }
+
+    for (i, report) in reports.iter().enumerate() {
+        let cheating = i == 0 && args.speed_hack > 1.0;
+        if cheating && report.corrections == 0 {
+            failures.push(format!("bot {} moved too fast but was never corrected", i));
+        }
+        // Lost moves look like too long steps, so only a clean network has no false corrections
+        if !cheating && args.network_preset.is_none() && report.corrections > 0 {
+            failures.push(format!(
+                "bot {} moved legally but was corrected {} times",
+                i, report.corrections
+            ));
+        }
+        if args.bots > 1 && report.max_streamed == 0 {
+            failures.push(format!("bot {} never saw another player", i));
+        }
+        if report.max_streamed > 0 && report.interpolated_samples == 0 {
+            failures.push(format!("bot {} never interpolated another player", i));
+        }
+    }
+    if args.bots > 1 && server_report.streaming_stopped == 0 {
+        failures.push("no player ever left the view distance of another".to_string());
+    }
+
+    if failures.is_empty() {
+        println!("All checks passed");
+        return ExitCode::SUCCESS;
+    }
+    for failure in failures.iter() {
+        println!("FAILED: {}", failure);
+    }
+    ExitCode::FAILURE
+}
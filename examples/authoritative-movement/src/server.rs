@@ -0,0 +1,242 @@
+use common::chunks::chunk_position::ChunkPosition;
+use common::chunks::rotation::Rotation;
+use network::{
+    client_id::ClientId,
+    messages::{ClientMessages, NetworkMessageType, ServerMessages},
+    server::{ConnectionMessages, IServerConnection, IServerNetwork, ServerConfig},
+    NetworkServer, NetworkServerConnection,
+};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::shared::{
+    Position, CHUNK_UNLOAD_RADIUS, MAX_STEP, TICK_RATE, VIEW_DISTANCE, VIEW_DISTANCE_HYSTERESIS, WORLD_SLUG,
+};
+
+/// Corrections are resent at most this often; moves already in flight
+/// when the client is corrected would otherwise trigger another one each
+const CORRECTION_INTERVAL: Duration = Duration::from_millis(250);
+
+struct Player {
+    connection: NetworkServerConnection,
+    position: Position,
+    rotation: Rotation,
+    chunk: Option<ChunkPosition>,
+    /// Entity ids of the players streamed to this one
+    streamed: HashSet<u32>,
+    last_correction: Option<Instant>,
+}
+
+impl Player {
+    fn get_entity_id(&self) -> u32 {
+        self.connection.get_client_id().get() as u32
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ServerReport {
+    pub moves: u64,
+    pub corrections: HashMap<ClientId, u32>,
+    pub streaming_started: u32,
+    pub streaming_stopped: u32,
+}
+
+pub async fn run_server(ip: String, duration: Duration, network_preset: Option<String>) -> ServerReport {
+    let mut config = ServerConfig::default().with_chunk_unload_radius(CHUNK_UNLOAD_RADIUS);
+    if let Some(preset) = network_preset.as_ref() {
+        config = config.with_network_preset(preset);
+    }
+    let server = NetworkServer::new_with_config(ip.clone(), config).await;
+    log::info!("Server listening on {}", ip);
+
+    let started = Instant::now();
+    let mut players: HashMap<ClientId, Player> = HashMap::new();
+    let mut report = ServerReport::default();
+
+    server
+        .run_fixed(TICK_RATE, |server, _delta| {
+            for error in server.drain_errors() {
+                log::warn!("Server error: {}", error);
+            }
+
+            for message in server.drain_connections() {
+                match message {
+                    ConnectionMessages::Connect { connection } => {
+                        on_connect(&mut players, connection);
+                    }
+                    ConnectionMessages::Disconnect { client_id, reason } => {
+                        log::info!("Client {} disconnected: {}", client_id, reason);
+                        on_disconnect(&mut players, client_id);
+                    }
+                }
+            }
+
+            for player in players.values_mut() {
+                apply_moves(player, &mut report);
+            }
+
+            update_interest(&mut players, started.elapsed().as_secs_f64(), &mut report);
+
+            started.elapsed() < duration
+        })
+        .await;
+
+    server.disconnect_all(Some("Test finished".to_string()));
+    for _ in 0..TICK_RATE {
+        server.step(Duration::from_secs_f64(1.0 / TICK_RATE as f64)).await;
+    }
+    report
+}
+
+fn on_connect(players: &mut HashMap<ClientId, Player>, connection: NetworkServerConnection) {
+    let client_id = connection.get_client_id();
+    log::info!("Client {} connected from {}", client_id, connection.get_ip());
+
+    // Players spawn in a row along the z axis
+    let position = Position::new(0.0, 64.0, client_id.get() as f32 * 4.0);
+    let rotation = Rotation::new(0.0, 0.0);
+    connection.send_message(NetworkMessageType::ReliableOrdered, &ServerMessages::AllowConnection);
+    connection.send_message(
+        NetworkMessageType::ReliableOrdered,
+        &ServerMessages::PlayerSpawn {
+            world_slug: WORLD_SLUG.to_string(),
+            position: position.0,
+            rotation,
+            components: Vec::new(),
+        },
+    );
+
+    players.insert(
+        client_id,
+        Player {
+            connection,
+            position,
+            rotation,
+            chunk: None,
+            streamed: Default::default(),
+            last_correction: None,
+        },
+    );
+}
+
+fn on_disconnect(players: &mut HashMap<ClientId, Player>, client_id: ClientId) {
+    let Some(player) = players.remove(&client_id) else {
+        return;
+    };
+    let id = player.get_entity_id();
+    for other in players.values_mut() {
+        if other.streamed.remove(&id) {
+            other.connection.send_message(
+                NetworkMessageType::ReliableOrdered,
+                &ServerMessages::StopStreamingEntities {
+                    world_slug: WORLD_SLUG.to_string(),
+                    ids: vec![id],
+                },
+            );
+        }
+    }
+}
+
+/// Validate the client moves; the server position is the authoritative one
+fn apply_moves(player: &mut Player, report: &mut ServerReport) {
+    let mut rejected = false;
+    for message in player.connection.drain_client_messages() {
+        let ClientMessages::PlayerMove { position, rotation, .. } = message else {
+            continue;
+        };
+        report.moves += 1;
+        let requested = Position(position);
+        let allowed = player.position.step_towards(&requested, MAX_STEP);
+        if allowed.distance(&requested) > f32::EPSILON {
+            rejected = true;
+        }
+        player.position = allowed;
+        player.rotation = rotation;
+    }
+
+    let now = Instant::now();
+    let can_correct = match player.last_correction {
+        Some(at) => now - at >= CORRECTION_INTERVAL,
+        None => true,
+    };
+    if rejected && can_correct {
+        player.last_correction = Some(now);
+        *report.corrections.entry(player.connection.get_client_id()).or_default() += 1;
+        player.connection.send_message(
+            NetworkMessageType::ReliableOrdered,
+            &ServerMessages::PlayerSpawn {
+                world_slug: WORLD_SLUG.to_string(),
+                position: player.position.0,
+                rotation: player.rotation,
+                components: Vec::new(),
+            },
+        );
+    }
+
+    // Chunks outside the radius are unloaded on the client
+    let chunk = player.position.get_chunk_position();
+    if player.chunk != Some(chunk) {
+        player.chunk = Some(chunk);
+        player.connection.set_chunk_center(WORLD_SLUG, chunk);
+    }
+}
+
+/// Stream players to each other by distance and send the positions of the streamed ones
+fn update_interest(players: &mut HashMap<ClientId, Player>, server_time: f64, report: &mut ServerReport) {
+    let snapshot: Vec<(u32, Position, Rotation)> = players
+        .values()
+        .map(|p| (p.get_entity_id(), p.position, p.rotation))
+        .collect();
+
+    for observer in players.values_mut() {
+        let observer_id = observer.get_entity_id();
+        let mut stopped = Vec::new();
+        for (id, position, rotation) in snapshot.iter() {
+            if *id == observer_id {
+                continue;
+            }
+            let distance = observer.position.distance(position);
+            let streamed = observer.streamed.contains(id);
+
+            if !streamed && distance <= VIEW_DISTANCE {
+                observer.streamed.insert(*id);
+                report.streaming_started += 1;
+                observer.connection.send_message(
+                    NetworkMessageType::ReliableOrdered,
+                    &ServerMessages::StartStreamingEntity {
+                        world_slug: WORLD_SLUG.to_string(),
+                        id: *id,
+                        position: position.0,
+                        rotation: *rotation,
+                        components: Vec::new(),
+                    },
+                );
+            } else if streamed && distance > VIEW_DISTANCE + VIEW_DISTANCE_HYSTERESIS {
+                observer.streamed.remove(id);
+                report.streaming_stopped += 1;
+                stopped.push(*id);
+            } else if streamed {
+                observer.connection.send_message(
+                    NetworkMessageType::UnreliableSequenced,
+                    &ServerMessages::EntityMove {
+                        world_slug: WORLD_SLUG.to_string(),
+                        id: *id,
+                        position: position.0,
+                        rotation: *rotation,
+                        animation_state: Default::default(),
+                        timestamp: server_time,
+                    },
+                );
+            }
+        }
+        if !stopped.is_empty() {
+            observer.connection.send_message(
+                NetworkMessageType::ReliableOrdered,
+                &ServerMessages::StopStreamingEntities {
+                    world_slug: WORLD_SLUG.to_string(),
+                    ids: stopped,
+                },
+            );
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use common::chunks::chunk_position::ChunkPosition;
+use common::chunks::position::Vector3;
+use network::interpolation::traits::{Diffable, Interpolatable};
+
+pub const WORLD_SLUG: &str = "default";
+
+/// Server and client tick rate
+pub const TICK_RATE: u32 = 32;
+
+/// Fastest legal movement, blocks per second
+pub const MAX_SPEED: f32 = 8.0;
+
+/// Legal distance of a single `PlayerMove`, with slack for float error
+pub const MAX_STEP: f32 = MAX_SPEED / TICK_RATE as f32 * 1.25;
+
+/// Players closer than this are streamed to each other
+pub const VIEW_DISTANCE: f32 = 32.0;
+
+/// Streamed players are dropped only past this distance, so a player
+/// walking along the edge is not started and stopped every tick
+pub const VIEW_DISTANCE_HYSTERESIS: f32 = 4.0;
+
+/// Chunks kept loaded around the player, see `ServerConfig::with_chunk_unload_radius`
+pub const CHUNK_UNLOAD_RADIUS: u32 = 4;
+
+const CHUNK_SIZE: f32 = 16.0;
+
+/// Position wrapper implementing the interpolation traits
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Position(pub Vector3);
+
+impl Position {
+    pub fn new(x: f32, y: f32, z: f32) -> Self {
+        Self(Vector3 { x, y, z })
+    }
+
+    pub fn distance(&self, other: &Position) -> f32 {
+        let (dx, dy, dz) = (self.0.x - other.0.x, self.0.y - other.0.y, self.0.z - other.0.z);
+        (dx * dx + dy * dy + dz * dz).sqrt()
+    }
+
+    /// Move towards `target` by at most `max_step`
+    pub fn step_towards(&self, target: &Position, max_step: f32) -> Position {
+        let distance = self.distance(target);
+        if distance <= max_step {
+            return *target;
+        }
+        self.lerp(target, max_step / distance)
+    }
+
+    pub fn get_chunk_position(&self) -> ChunkPosition {
+        ChunkPosition::new(
+            (self.0.x / CHUNK_SIZE).floor() as i64,
+            (self.0.z / CHUNK_SIZE).floor() as i64,
+        )
+    }
+}
+
+impl Interpolatable for Position {
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        Position::new(
+            self.0.x + (other.0.x - self.0.x) * t,
+            self.0.y + (other.0.y - self.0.y) * t,
+            self.0.z + (other.0.z - self.0.z) * t,
+        )
+    }
+}
+
+impl Diffable for Position {
+    type Delta = Position;
+
+    fn diff(&self, other: &Self) -> Self::Delta {
+        Position::new(self.0.x - other.0.x, self.0.y - other.0.y, self.0.z - other.0.z)
+    }
+
+    fn apply_delta(&self, delta: &Self::Delta) -> Self {
+        Position::new(self.0.x + delta.0.x, self.0.y + delta.0.y, self.0.z + delta.0.z)
+    }
+
+    fn scale_delta(delta: &Self::Delta, factor: f32) -> Self::Delta {
+        Position::new(delta.0.x * factor, delta.0.y * factor, delta.0.z * factor)
+    }
+
+    fn delta_is_negligible(delta: &Self::Delta, threshold: f32) -> bool {
+        delta.distance(&Position::default()) < threshold
+    }
+}
@@ -28,7 +28,9 @@ impl Client {
             // Recieve errors from network thread
             for error in self.client.iter_errors() {
                 log::info!("Network error: {}", error);
-                return;
+                if error.is_fatal() {
+                    return;
+                }
             }
 
             for message in self.client.iter_server_messages() {
@@ -1,6 +1,7 @@
 use std::{collections::HashMap, time::Duration};
 
 use network::{
+    client_id::ClientId,
     messages::{ClientMessages, NetworkMessageType, ServerMessages},
     server::{ConnectionMessages, IServerConnection, IServerNetwork},
     NetworkServer, NetworkServerConnection,
@@ -28,7 +29,7 @@ impl ClientNetwork {
 
 pub struct Server {
     server: NetworkServer,
-    connections: HashMap<u64, ClientNetwork>,
+    connections: HashMap<ClientId, ClientNetwork>,
 }
 
 impl Server {
@@ -46,8 +47,11 @@ impl Server {
             let delta = Duration::from_millis(10);
             self.server.step(delta.clone()).await;
 
-            for message in self.server.drain_errors() {
-                panic!("Network error: {}", message);
+            for error in self.server.drain_errors() {
+                if error.is_fatal() {
+                    panic!("Network error: {}", error);
+                }
+                log::warn!("Network error: {}", error);
             }
 
             for (client_id, decoded) in self.server.drain_client_messages() {
@@ -0,0 +1,171 @@
+use network::{
+    client::IClientNetwork,
+    messages::NetworkMessageType,
+    server::{ConnectionMessages, IServerConnection, IServerNetwork},
+    NetworkClient, NetworkServer,
+};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::fixtures::{
+    client_messages, read_frame, write_frame, Fixture, RecordedFrame, FRAME_HANDSHAKE, FRAME_PING, FRAME_PONG,
+};
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A frame encoded by the current version is readable by the old peer if
+/// it starts with the recorded bytes: fields appended at the end of a
+/// message are ignored by the old decoder, anything else is not.
+fn check_encoding(recorded: &RecordedFrame, current: &[u8]) -> Result<(), String> {
+    if current.starts_with(&recorded.get_data()) {
+        return Ok(());
+    }
+    Err(format!(
+        "{} is encoded differently than in the fixture: {}",
+        recorded.label,
+        RecordedFrame::new(&recorded.label, current).hex
+    ))
+}
+
+fn message_labels(frames: &[RecordedFrame]) -> Vec<String> {
+    frames
+        .iter()
+        .filter(|f| !f.is_handshake())
+        .map(|f| f.label.clone())
+        .collect()
+}
+
+/// Old client against the current server: replay the recorded client frames
+pub async fn check_old_client(fixture: &Fixture, server_ip: &str) -> Result<(), String> {
+    let server = NetworkServer::new(server_ip.to_string()).await;
+    let mut stream = TcpStream::connect(server_ip).await.map_err(|e| e.to_string())?;
+
+    let server_hello = read_frame(&mut stream).await.map_err(|e| e.to_string())?;
+    if server_hello.first() != Some(&FRAME_HANDSHAKE) {
+        return Err("Server did not start with the handshake".to_string());
+    }
+    for frame in fixture.client_frames.iter() {
+        write_frame(&mut stream, &frame.get_data())
+            .await
+            .map_err(|e| e.to_string())?;
+        if !frame.is_handshake() {
+            continue;
+        }
+        // `HandshakeResult::Accepted` is variant 0; a rejection carries its reason
+        let result = read_frame(&mut stream).await.map_err(|e| e.to_string())?;
+        if result.get(..5) != Some(&[FRAME_HANDSHAKE, 0, 0, 0, 0]) {
+            let reason = String::from_utf8_lossy(result.get(13..).unwrap_or_default());
+            return Err(format!(
+                "Server rejected the {} client handshake: {}",
+                fixture.version, reason
+            ));
+        }
+    }
+
+    let expected = message_labels(&fixture.client_frames);
+    let mut received = Vec::new();
+    let started = Instant::now();
+    while received.len() < expected.len() && started.elapsed() < CHECK_TIMEOUT {
+        server.step(Duration::from_millis(10)).await;
+        for message in server.drain_connections() {
+            if let ConnectionMessages::Disconnect { reason, .. } = message {
+                return Err(format!("Server dropped the {} client: {}", fixture.version, reason));
+            }
+        }
+        if let Some(error) = server.drain_errors().next() {
+            return Err(format!(
+                "Server failed on a {} client frame: {}",
+                fixture.version, error
+            ));
+        }
+        for connection in server.connections_snapshot() {
+            received.extend(connection.drain_client_messages().map(|m| m.as_ref().to_string()));
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    if received != expected {
+        return Err(format!("Server decoded {:?}, expected {:?}", received, expected));
+    }
+    Ok(())
+}
+
+/// Current client against an old server: replay the recorded server frames
+/// and check the client frames against the recorded ones
+pub async fn check_old_server(fixture: &Fixture, server_ip: &str) -> Result<(), String> {
+    let listener = TcpListener::bind(server_ip).await.map_err(|e| e.to_string())?;
+    let server_fixture = fixture.clone();
+    let fake_server = tokio::spawn(async move {
+        let fixture = server_fixture;
+        let (mut stream, _) = listener.accept().await.map_err(|e| e.to_string())?;
+        let mut client_frames = fixture.client_frames.iter();
+        for frame in fixture.server_frames.iter() {
+            write_frame(&mut stream, &frame.get_data())
+                .await
+                .map_err(|e| e.to_string())?;
+            if frame.label != "server-hello" {
+                continue;
+            }
+            let client_hello = read_frame(&mut stream).await.map_err(|e| e.to_string())?;
+            check_encoding(client_frames.next().unwrap(), &client_hello)?;
+        }
+        for recorded in client_frames {
+            let frame = loop {
+                let frame = read_frame(&mut stream).await.map_err(|e| e.to_string())?;
+                if !matches!(frame.first(), Some(&FRAME_PING) | Some(&FRAME_PONG)) {
+                    break frame;
+                }
+            };
+            check_encoding(recorded, &frame)?;
+        }
+        // Keep the connection open until the client is done
+        while read_frame(&mut stream).await.is_ok() {}
+        Ok::<(), String>(())
+    });
+
+    // The recorded `HandshakeResult::Accepted` of the old server, without a protocol version
+    let client = match NetworkClient::new(server_ip.to_string()).await {
+        Ok(client) => client,
+        Err(e) => {
+            fake_server.abort();
+            return Err(format!(
+                "Client rejected the {} server handshake: {}",
+                fixture.version, e
+            ));
+        }
+    };
+    for message in client_messages().iter() {
+        client.send_message(NetworkMessageType::ReliableOrdered, message);
+    }
+
+    let expected = message_labels(&fixture.server_frames);
+    let mut received = Vec::new();
+    let mut client_error = None;
+    let started = Instant::now();
+    while received.len() < expected.len() && started.elapsed() < CHECK_TIMEOUT {
+        client.step(Duration::from_millis(10)).await;
+        if let Some(error) = client.iter_errors().next() {
+            client_error = Some(format!(
+                "Client failed on a {} server frame: {}",
+                fixture.version, error
+            ));
+            break;
+        }
+        received.extend(client.iter_server_messages().map(|m| m.as_ref().to_string()));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    client.disconnect();
+    if client_error.is_none() && received != expected {
+        client_error = Some(format!("Client decoded {:?}, expected {:?}", received, expected));
+    }
+
+    // A frame the old server could not read is the root cause of a client failure
+    let server_result = match tokio::time::timeout(CHECK_TIMEOUT, fake_server).await {
+        Ok(result) => result.unwrap(),
+        Err(_) => Err("Client did not send every recorded message".to_string()),
+    };
+    server_result?;
+    match client_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,139 @@
+use network::messages::{ClientMessages, ServerMessages};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Wire format of the tokio backend: first byte of every frame
+pub const FRAME_MESSAGE: u8 = 0x00;
+pub const FRAME_PING: u8 = 0x01;
+pub const FRAME_PONG: u8 = 0x02;
+pub const FRAME_HANDSHAKE: u8 = 0x03;
+
+/// Recorded frame payload (without the length prefix)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    /// Handshake step ("server-hello", ...) or message variant
+    pub label: String,
+    pub hex: String,
+}
+
+impl RecordedFrame {
+    pub fn new(label: &str, data: &[u8]) -> Self {
+        Self {
+            label: label.to_string(),
+            hex: data.iter().map(|b| format!("{:02x}", b)).collect(),
+        }
+    }
+
+    pub fn get_data(&self) -> Vec<u8> {
+        (0..self.hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&self.hex[i..i + 2], 16).expect("invalid fixture hex"))
+            .collect()
+    }
+
+    pub fn is_handshake(&self) -> bool {
+        self.get_data().first() == Some(&FRAME_HANDSHAKE)
+    }
+}
+
+/// Frames of one connection recorded with a released version
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fixture {
+    pub version: String,
+    /// Client to server, in order
+    pub client_frames: Vec<RecordedFrame>,
+    /// Server to client, in order
+    pub server_frames: Vec<RecordedFrame>,
+}
+
+impl Fixture {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        serde_json::from_str(&json).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self).unwrap();
+        std::fs::write(path, json + "\n").map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Fixture of the newest recorded version, the N-1 of the code under test
+    pub fn find_latest(dir: &Path) -> Result<PathBuf, String> {
+        let entries = std::fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        let mut fixtures: Vec<(Vec<u64>, PathBuf)> = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().map(|e| e == "json").unwrap_or(false))
+            .map(|p| {
+                let version = p.file_stem().unwrap().to_string_lossy().to_string();
+                let parts = version.split('.').map(|v| v.parse().unwrap_or(0)).collect();
+                (parts, p)
+            })
+            .collect();
+        fixtures.sort();
+        fixtures
+            .pop()
+            .map(|(_, path)| path)
+            .ok_or_else(|| format!("No fixtures in {}", dir.display()))
+    }
+}
+
+/// Messages exchanged by the recorded connection.
+///
+/// Only types owned by this crate are used, so the encoding does
+/// not depend on the version of the common crate.
+pub fn client_messages() -> Vec<ClientMessages> {
+    vec![
+        ClientMessages::ConnectionInfo {
+            login: "compat".to_string(),
+            version: "compat".to_string(),
+            architecture: "x86_64".to_string(),
+            rendering_device: "none".to_string(),
+        },
+        ClientMessages::ConsoleInput {
+            command: "say compat".to_string(),
+        },
+        ClientMessages::ClientScriptEvent {
+            script_slug: "default".to_string(),
+            slug: "compat".to_string(),
+            json: "{}".to_string(),
+        },
+        ClientMessages::ResourcesHasCache { exists: true },
+        ClientMessages::ResourcesLoaded { last_index: 3 },
+        ClientMessages::SettingsLoaded,
+    ]
+}
+
+pub fn server_messages() -> Vec<ServerMessages> {
+    vec![
+        ServerMessages::AllowConnection,
+        ServerMessages::ConsoleOutput {
+            message: "compat".to_string(),
+        },
+        ServerMessages::ResourcesPart {
+            index: 0,
+            total: 1,
+            data: vec![1, 2, 3],
+        },
+        ServerMessages::ServerStatus { tps: 20.0 },
+        ServerMessages::Disconnect {
+            message: Some("compat".to_string()),
+        },
+    ]
+}
+
+/// Read a length-prefixed frame
+pub async fn read_frame(reader: &mut (impl AsyncReadExt + Unpin)) -> std::io::Result<Vec<u8>> {
+    let len = reader.read_u32_le().await?;
+    let mut buf = vec![0u8; len as usize];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Write a length-prefixed frame
+pub async fn write_frame(writer: &mut (impl AsyncWriteExt + Unpin), data: &[u8]) -> std::io::Result<()> {
+    writer.write_u32_le(data.len() as u32).await?;
+    writer.write_all(data).await?;
+    writer.flush().await
+}
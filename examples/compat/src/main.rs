@@ -0,0 +1,105 @@
+use clap::Parser;
+use fixtures::Fixture;
+use log::LevelFilter;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+pub mod check;
+pub mod fixtures;
+pub mod record;
+
+/// Cross-version compatibility check.
+///
+/// Every release records the frames of a connection into `fixtures/<version>.json`.
+/// The check replays the newest fixture against the current code in both
+/// directions: an old client against the current server and the current
+/// client against an old server. A release may only break the wire format
+/// of versions older than the previous one (N-1 policy).
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// check or record
+    #[arg(short = 't', long, default_value_t = String::from("check"))]
+    run_type: String,
+
+    /// Fixture to check against; the newest one in the fixtures directory by default
+    #[arg(short, long)]
+    fixture: Option<PathBuf>,
+
+    /// Version to record the fixture for
+    #[arg(short = 'r', long)]
+    release: Option<String>,
+
+    /// Address of the server under test
+    #[arg(long, default_value_t = String::from("127.0.0.1:25575"))]
+    server_ip: String,
+
+    /// Address of the recording proxy and of the replayed old server
+    #[arg(long, default_value_t = String::from("127.0.0.1:25576"))]
+    peer_ip: String,
+}
+
+struct SimpleLogger;
+impl log::Log for SimpleLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+    fn log(&self, record: &log::Record) {
+        eprintln!("{} {}", record.level(), record.args());
+    }
+    fn flush(&self) {}
+}
+static LOGGER: SimpleLogger = SimpleLogger;
+
+fn fixtures_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures")
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Warn);
+
+    let args = Args::parse();
+    let result = match args.run_type.as_str() {
+        "record" => run_record(&args).await,
+        _ => run_check(&args).await,
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            println!("FAILED: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run_record(args: &Args) -> Result<(), String> {
+    let Some(release) = args.release.as_ref() else {
+        return Err("--release is required to record a fixture".to_string());
+    };
+    let fixture = record::record(release, &args.server_ip, &args.peer_ip).await?;
+    let path = fixtures_dir().join(format!("{}.json", release));
+    fixture.save(&path)?;
+    println!("Recorded {}", path.display());
+    Ok(())
+}
+
+async fn run_check(args: &Args) -> Result<(), String> {
+    let path = match args.fixture.as_ref() {
+        Some(path) => path.clone(),
+        None => Fixture::find_latest(&fixtures_dir())?,
+    };
+    let fixture = Fixture::load(&path)?;
+    println!("Checking against the {} fixture", fixture.version);
+
+    let old_client = check::check_old_client(&fixture, &args.server_ip).await;
+    println!("{} client -> current server: {:?}", fixture.version, old_client);
+    let old_server = check::check_old_server(&fixture, &args.peer_ip).await;
+    println!("current client -> {} server: {:?}", fixture.version, old_server);
+
+    old_client?;
+    old_server?;
+    println!("Compatible with {}", fixture.version);
+    Ok(())
+}
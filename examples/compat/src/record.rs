@@ -0,0 +1,114 @@
+use network::{
+    client::IClientNetwork,
+    messages::NetworkMessageType,
+    server::{ConnectionMessages, IServerConnection, IServerNetwork},
+    NetworkClient, NetworkServer,
+};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+use crate::fixtures::{
+    client_messages, read_frame, server_messages, write_frame, Fixture, RecordedFrame, FRAME_PING, FRAME_PONG,
+};
+
+const RECORD_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Recording = Arc<Mutex<Vec<Vec<u8>>>>;
+
+/// Forward frames from one side to the other, keeping all but pings
+fn spawn_pump(mut from: tokio::net::tcp::OwnedReadHalf, mut to: tokio::net::tcp::OwnedWriteHalf, recording: Recording) {
+    tokio::spawn(async move {
+        while let Ok(frame) = read_frame(&mut from).await {
+            if write_frame(&mut to, &frame).await.is_err() {
+                break;
+            }
+            if !matches!(frame.first(), Some(&FRAME_PING) | Some(&FRAME_PONG)) {
+                recording.lock().await.push(frame);
+            }
+        }
+    });
+}
+
+/// Record the frames of a connection between the current client and server
+pub async fn record(version: &str, server_ip: &str, proxy_ip: &str) -> Result<Fixture, String> {
+    let server = NetworkServer::new(server_ip.to_string()).await;
+
+    // Recording proxy between the client and the server
+    let client_frames: Recording = Default::default();
+    let server_frames: Recording = Default::default();
+    let listener = TcpListener::bind(proxy_ip).await.map_err(|e| e.to_string())?;
+    {
+        let server_ip = server_ip.to_string();
+        let client_frames = client_frames.clone();
+        let server_frames = server_frames.clone();
+        tokio::spawn(async move {
+            let (client_stream, _) = listener.accept().await.unwrap();
+            let server_stream = TcpStream::connect(server_ip).await.unwrap();
+            let (client_read, client_write) = client_stream.into_split();
+            let (server_read, server_write) = server_stream.into_split();
+            spawn_pump(client_read, server_write, client_frames);
+            spawn_pump(server_read, client_write, server_frames);
+        });
+    }
+
+    let client = NetworkClient::new(proxy_ip.to_string()).await?;
+    for message in client_messages().iter() {
+        client.send_message(NetworkMessageType::ReliableOrdered, message);
+    }
+
+    let started = Instant::now();
+    let mut received = 0;
+    let mut delivered = 0;
+    while received < client_messages().len() || delivered < server_messages().len() {
+        if started.elapsed() > RECORD_TIMEOUT {
+            return Err("Recorded connection timed out".to_string());
+        }
+        server.step(Duration::from_millis(10)).await;
+        client.step(Duration::from_millis(10)).await;
+        for message in server.drain_connections() {
+            if let ConnectionMessages::Connect { connection } = message {
+                for message in server_messages().iter() {
+                    connection.send_message(NetworkMessageType::ReliableOrdered, message);
+                }
+            }
+        }
+        for connection in server.connections_snapshot() {
+            received += connection.drain_client_messages().count();
+        }
+        delivered += client.iter_server_messages().count();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    client.disconnect();
+    // Let the proxy store the last frames it forwarded
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut client_labels = vec!["client-hello".to_string()];
+    client_labels.extend(client_messages().iter().map(|m| m.as_ref().to_string()));
+    let mut server_labels = vec!["server-hello".to_string(), "handshake-result".to_string()];
+    server_labels.extend(server_messages().iter().map(|m| m.as_ref().to_string()));
+
+    let client_frames = client_frames.lock().await;
+    let server_frames = server_frames.lock().await;
+    if client_frames.len() != client_labels.len() || server_frames.len() != server_labels.len() {
+        return Err(format!(
+            "Unexpected frames recorded: {} from the client, {} from the server",
+            client_frames.len(),
+            server_frames.len()
+        ));
+    }
+    Ok(Fixture {
+        version: version.to_string(),
+        client_frames: client_labels
+            .iter()
+            .zip(client_frames.iter())
+            .map(|(label, data)| RecordedFrame::new(label, data))
+            .collect(),
+        server_frames: server_labels
+            .iter()
+            .zip(server_frames.iter())
+            .map(|(label, data)| RecordedFrame::new(label, data))
+            .collect(),
+    })
+}
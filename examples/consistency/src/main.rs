@@ -1,9 +1,10 @@
 use clap::Parser;
 use log::LevelFilter;
 use network::{
-    client::IClientNetwork,
+    client::{ClientConfig, IClientNetwork},
+    errors::ErrorSeverity,
     messages::{ClientMessages, NetworkMessageType, ServerMessages},
-    server::{ConnectionMessages, IServerConnection, IServerNetwork},
+    server::{ConnectionMessages, IServerConnection, IServerNetwork, ServerConfig},
     NetworkClient, NetworkServer, NetworkServerConnection,
 };
 use std::time::{Duration, Instant};
@@ -25,6 +26,10 @@ struct Args {
     /// Длительность теста в секундах
     #[arg(short, long, default_value_t = 10)]
     duration: u64,
+
+    /// Симуляция сети: fiber, wifi, mobile-3g
+    #[arg(short = 'n', long)]
+    network_preset: Option<String>,
 }
 
 struct SimpleLogger;
@@ -59,7 +64,11 @@ async fn main() {
 
 async fn run_server(args: Args) {
     log::info!("Server starting on {}", args.ip);
-    let server = NetworkServer::new(args.ip.clone()).await;
+    let mut config = ServerConfig::default();
+    if let Some(preset) = args.network_preset.as_ref() {
+        config = config.with_network_preset(preset);
+    }
+    let server = NetworkServer::new_with_config(args.ip.clone(), config).await;
 
     let tick_duration = Duration::from_secs_f64(1.0 / 64.0);
     let test_end = Instant::now() + Duration::from_secs(args.duration);
@@ -81,7 +90,10 @@ async fn run_server(args: Args) {
         server.step(tick_duration).await;
 
         for error in server.drain_errors() {
-            log::error!("Server error: {}", error);
+            match error.get_severity() {
+                ErrorSeverity::Warning => log::warn!("Server error: {}", error),
+                _ => log::error!("Server error: {}", error),
+            }
         }
 
         // Обработка подключений
@@ -240,10 +252,13 @@ async fn run_server(args: Args) {
 
 async fn run_client(args: Args) {
     log::info!("Client connecting to {}", args.ip);
-    let client = NetworkClient::new(args.ip.clone()).await.unwrap();
+    let mut config = ClientConfig::default();
+    if let Some(preset) = args.network_preset.as_ref() {
+        config = config.with_network_preset(preset);
+    }
+    let client = NetworkClient::new_with_config(args.ip.clone(), config).await.unwrap();
 
     let send_interval = Duration::from_secs_f64(1.0 / 64.0);
-    let step_interval = Duration::from_secs_f64(1.0 / 64.0);
     let test_end = Instant::now() + Duration::from_secs(args.duration);
 
     let mut connected = false;
@@ -257,71 +272,67 @@ async fn run_client(args: Args) {
 
     let mut last_send = Instant::now();
 
-    loop {
-        let tick_start = Instant::now();
-
-        client.step(step_interval).await;
-
-        for error in client.iter_errors() {
-            log::error!("Client error: {}", error);
-            return;
-        }
+    client
+        .run_fixed(64, |client, _delta| {
+            let tick_start = Instant::now();
 
-        let mut recv_this_tick = 0usize;
-        for msg in client.iter_server_messages() {
-            match msg {
-                ServerMessages::AllowConnection => {
-                    log::info!("Connection allowed, starting test...");
-                    client.send_message(
-                        NetworkMessageType::ReliableOrdered,
-                        &ClientMessages::ConnectionInfo {
-                            login: "consistency-test".to_string(),
-                            version: "test".to_string(),
-                            architecture: "test".to_string(),
-                            rendering_device: "test".to_string(),
-                        },
-                    );
-                    connected = true;
-                    last_send = Instant::now();
-                }
-                ServerMessages::EntityMove { .. } => {
-                    recv_this_tick += 1;
-                    recv_total += 1;
+            for error in client.iter_errors() {
+                if error.is_fatal() {
+                    log::error!("Client error: {}", error);
+                    return false;
                 }
-                _ => {}
+                log::warn!("Client error: {}", error);
             }
-        }
 
-        if connected {
-            recv_counts.push(recv_this_tick);
-            total_client_ticks += 1;
-
-            // Отправка PlayerMove с фиксированным интервалом
-            if tick_start.duration_since(last_send) >= send_interval {
-                sequence += 1;
-                let msg = ClientMessages::PlayerMove {
-                    position: common::chunks::position::Vector3 {
-                        x: sequence as f32,
-                        y: 0.0,
-                        z: 0.0,
-                    },
-                    rotation: common::chunks::rotation::Rotation::new(0.0, 0.0),
-                };
-                client.send_message(NetworkMessageType::Unreliable, &msg);
-                total_sent += 1;
-                last_send = tick_start;
+            let mut recv_this_tick = 0usize;
+            for msg in client.iter_server_messages() {
+                match msg {
+                    ServerMessages::AllowConnection => {
+                        log::info!("Connection allowed, starting test...");
+                        client.send_message(
+                            NetworkMessageType::ReliableOrdered,
+                            &ClientMessages::ConnectionInfo {
+                                login: "consistency-test".to_string(),
+                                version: "test".to_string(),
+                                architecture: "test".to_string(),
+                                rendering_device: "test".to_string(),
+                            },
+                        );
+                        connected = true;
+                        last_send = Instant::now();
+                    }
+                    ServerMessages::EntityMove { .. } => {
+                        recv_this_tick += 1;
+                        recv_total += 1;
+                    }
+                    _ => {}
+                }
             }
-        }
 
-        if Instant::now() >= test_end && connected {
-            break;
-        }
+            if connected {
+                recv_counts.push(recv_this_tick);
+                total_client_ticks += 1;
+
+                // Отправка PlayerMove с фиксированным интервалом
+                if tick_start.duration_since(last_send) >= send_interval {
+                    sequence += 1;
+                    let msg = ClientMessages::PlayerMove {
+                        position: common::chunks::position::Vector3 {
+                            x: sequence as f32,
+                            y: 0.0,
+                            z: 0.0,
+                        },
+                        rotation: common::chunks::rotation::Rotation::new(0.0, 0.0),
+                    };
+                    client.send_message(NetworkMessageType::Unreliable, &msg);
+                    total_sent += 1;
+                    last_send = tick_start;
+                }
+            }
 
-        let elapsed = tick_start.elapsed();
-        if elapsed < step_interval {
-            tokio::time::sleep(step_interval - elapsed).await;
-        }
-    }
+            Instant::now() < test_end || !connected
+        })
+        .await;
 
     // ============ СТАТИСТИКА ============
     println!("\n========== CLIENT STATS ==========");
@@ -0,0 +1,137 @@
+use common::chunks::position::Vector3;
+use common::chunks::rotation::Rotation;
+use network::{
+    client::IClientNetwork,
+    messages::{ClientMessages, NetworkMessageType, ServerMessages},
+    NetworkClient,
+};
+use std::time::Duration;
+
+/// Interval between chat messages of a chatter
+const CHAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Walking speed of movers, blocks per second
+const MOVER_SPEED: f32 = 4.0;
+
+/// Chunk requesters run straight ahead to enter a new chunk every second or so
+const CHUNK_REQUESTER_SPEED: f32 = 16.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum BotBehavior {
+    /// Connects and stays
+    Idle,
+    /// Walks in a circle, sending `PlayerMove` every tick
+    Mover,
+    /// Sends a console message every second
+    Chatter,
+    /// Runs straight ahead and acknowledges every chunk it receives
+    ChunkRequester,
+}
+
+impl BotBehavior {
+    pub fn as_name(&self) -> &'static str {
+        match self {
+            Self::Idle => "idle",
+            Self::Mover => "mover",
+            Self::Chatter => "chatter",
+            Self::ChunkRequester => "chunk-requester",
+        }
+    }
+}
+
+/// Per-bot state of a behavior
+pub struct BotState {
+    behavior: BotBehavior,
+    index: usize,
+    time: Duration,
+    last_chat: Duration,
+    position: Vector3,
+}
+
+impl BotState {
+    pub fn new(behavior: BotBehavior, index: usize) -> Self {
+        Self {
+            behavior,
+            index,
+            time: Duration::ZERO,
+            last_chat: Duration::ZERO,
+            position: Vector3 {
+                x: 0.0,
+                y: 64.0,
+                z: 0.0,
+            },
+        }
+    }
+
+    /// React to a server message; returns the number of messages sent
+    pub fn on_message(&mut self, client: &NetworkClient, message: &ServerMessages) -> u64 {
+        match message {
+            ServerMessages::AllowConnection => {
+                let info = ClientMessages::ConnectionInfo {
+                    login: format!("{}-{}", self.behavior.as_name(), self.index),
+                    version: String::from("-"),
+                    architecture: String::from("-"),
+                    rendering_device: String::from("swarm"),
+                };
+                client.send_message(NetworkMessageType::ReliableOrdered, &info);
+                1
+            }
+            ServerMessages::PlayerSpawn { position, .. } => {
+                self.position = *position;
+                0
+            }
+            ServerMessages::ChunkSectionInfo { chunk_position, .. }
+            | ServerMessages::ChunkSectionInfoEncoded { chunk_position, .. }
+                if self.behavior == BotBehavior::ChunkRequester =>
+            {
+                let ack = ClientMessages::ChunkRecieved {
+                    chunk_positions: vec![*chunk_position],
+                };
+                client.send_message(NetworkMessageType::ReliableOrdered, &ack);
+                1
+            }
+            _ => 0,
+        }
+    }
+
+    /// Run one tick of the behavior; returns the number of messages sent
+    pub fn tick(&mut self, client: &NetworkClient, delta: Duration) -> u64 {
+        self.time += delta;
+        let dt = delta.as_secs_f32();
+        match self.behavior {
+            BotBehavior::Idle => 0,
+            BotBehavior::Mover => {
+                let angle = self.time.as_secs_f32() * 0.5 + self.index as f32;
+                self.position.x += angle.cos() * MOVER_SPEED * dt;
+                self.position.z += angle.sin() * MOVER_SPEED * dt;
+                self.send_move(client);
+                1
+            }
+            BotBehavior::Chatter => {
+                if self.time - self.last_chat < CHAT_INTERVAL {
+                    return 0;
+                }
+                self.last_chat = self.time;
+                let chat = ClientMessages::ConsoleInput {
+                    command: format!("say hello from chatter-{}", self.index),
+                };
+                client.send_message(NetworkMessageType::ReliableOrdered, &chat);
+                1
+            }
+            BotBehavior::ChunkRequester => {
+                self.position.x += CHUNK_REQUESTER_SPEED * dt;
+                self.send_move(client);
+                1
+            }
+        }
+    }
+
+    fn send_move(&self, client: &NetworkClient) {
+        let message = ClientMessages::PlayerMove {
+            position: self.position,
+            rotation: Rotation::new(0.0, 0.0),
+            animation_state: Default::default(),
+        };
+        client.send_message(NetworkMessageType::Unreliable, &message);
+    }
+}
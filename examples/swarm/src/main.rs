@@ -0,0 +1,132 @@
+use behaviors::BotBehavior;
+use clap::Parser;
+use log::LevelFilter;
+use network::{client::ClientConfig, server::ServerConfig};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use swarm::BotSwarm;
+
+pub mod behaviors;
+pub mod server;
+pub mod swarm;
+
+/// Bot swarm for capacity tests.
+///
+/// Connects the requested number of bots of every behavior, runs them
+/// for the given duration and prints a JSON report.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    #[arg(short, long, default_value_t = String::from("127.0.0.1:25572"))]
+    ip: String,
+
+    /// Bots that connect and stay idle
+    #[arg(long, default_value_t = 0)]
+    idle: usize,
+
+    /// Bots walking in circles
+    #[arg(long, default_value_t = 0)]
+    mover: usize,
+
+    /// Bots sending a chat message every second
+    #[arg(long, default_value_t = 0)]
+    chatter: usize,
+
+    /// Bots running straight ahead and acknowledging chunks
+    #[arg(long, default_value_t = 0)]
+    chunk_requester: usize,
+
+    /// Test duration in seconds, after the ramp up
+    #[arg(short, long, default_value_t = 30)]
+    duration: u64,
+
+    /// Seconds over which the bot connections are spread
+    #[arg(short, long, default_value_t = 0)]
+    ramp_up: u64,
+
+    /// Bot tick rate
+    #[arg(long, default_value_t = 20)]
+    tick_rate: u32,
+
+    /// Write the JSON report to this file instead of stdout
+    #[arg(long)]
+    report: Option<String>,
+
+    /// Start a minimal server on --ip instead of connecting to a running one
+    #[arg(long, default_value_t = false)]
+    local_server: bool,
+
+    /// Network simulation: fiber, wifi, mobile-3g
+    #[arg(short = 'n', long)]
+    network_preset: Option<String>,
+}
+
+struct SimpleLogger;
+impl log::Log for SimpleLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+    fn log(&self, record: &log::Record) {
+        eprintln!("{} {}", record.level(), record.args());
+    }
+    fn flush(&self) {}
+}
+static LOGGER: SimpleLogger = SimpleLogger;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Warn);
+
+    let args = Args::parse();
+
+    let mut client_config = ClientConfig::default();
+    if let Some(preset) = args.network_preset.as_ref() {
+        client_config = client_config.with_network_preset(preset);
+    }
+    let swarm = BotSwarm::new(args.ip.clone(), client_config)
+        .with_bots(BotBehavior::Idle, args.idle)
+        .with_bots(BotBehavior::Mover, args.mover)
+        .with_bots(BotBehavior::Chatter, args.chatter)
+        .with_bots(BotBehavior::ChunkRequester, args.chunk_requester)
+        .with_tick_rate(args.tick_rate)
+        .with_ramp_up(Duration::from_secs(args.ramp_up));
+    if swarm.get_bots_count() == 0 {
+        eprintln!("No bots requested; use --idle, --mover, --chatter or --chunk-requester");
+        return ExitCode::FAILURE;
+    }
+
+    let server_running = Arc::new(AtomicBool::new(true));
+    let server = match args.local_server {
+        true => {
+            let mut config = ServerConfig::default().with_chunk_unload_radius(8);
+            if let Some(preset) = args.network_preset.as_ref() {
+                config = config.with_network_preset(preset);
+            }
+            let task = tokio::spawn(server::run_server(args.ip.clone(), config, server_running.clone()));
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Some(task)
+        }
+        false => None,
+    };
+
+    let report = swarm.run(Duration::from_secs(args.duration)).await;
+
+    server_running.store(false, Ordering::SeqCst);
+    if let Some(server) = server {
+        server.await.unwrap();
+    }
+
+    let json = serde_json::to_string_pretty(&report).unwrap();
+    match args.report.as_ref() {
+        Some(path) => std::fs::write(path, json).unwrap(),
+        None => println!("{}", json),
+    }
+
+    match report.is_healthy() {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    }
+}
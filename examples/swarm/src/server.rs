@@ -0,0 +1,52 @@
+use common::chunks::chunk_position::ChunkPosition;
+use network::{
+    messages::{ClientMessages, NetworkMessageType, ServerMessages},
+    server::{ConnectionMessages, IServerConnection, IServerNetwork, ServerConfig},
+    NetworkServer, NetworkServerConnection,
+};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Minimal server for running the swarm without a game server:
+/// spawns the bots, echoes chat and follows the movers with the chunk center
+pub async fn run_server(ip: String, config: ServerConfig, running: Arc<AtomicBool>) {
+    let server = NetworkServer::new_with_config(ip.clone(), config).await;
+    log::info!("Local server listening on {}", ip);
+
+    let mut connections: HashMap<u64, NetworkServerConnection> = HashMap::new();
+    server
+        .run_fixed(20, |server, _delta| {
+            for error in server.drain_errors() {
+                log::warn!("Server error: {}", error);
+            }
+            for message in server.drain_connections() {
+                match message {
+                    ConnectionMessages::Connect { connection } => {
+                        connection.send_message(NetworkMessageType::ReliableOrdered, &ServerMessages::AllowConnection);
+                        connections.insert(connection.get_client_id(), connection);
+                    }
+                    ConnectionMessages::Disconnect { client_id, .. } => {
+                        connections.remove(&client_id);
+                    }
+                }
+            }
+            for connection in connections.values() {
+                for message in connection.drain_client_messages() {
+                    match message {
+                        ClientMessages::ConsoleInput { command } => {
+                            let output = ServerMessages::ConsoleOutput { message: command };
+                            connection.send_message(NetworkMessageType::ReliableOrdered, &output);
+                        }
+                        ClientMessages::PlayerMove { position, .. } => {
+                            let chunk = ChunkPosition::new((position.x / 16.0) as i64, (position.z / 16.0) as i64);
+                            connection.set_chunk_center("default", chunk);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            running.load(Ordering::SeqCst)
+        })
+        .await;
+}
@@ -0,0 +1,245 @@
+use network::{
+    client::{ClientConfig, IClientNetwork},
+    NetworkClient,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::behaviors::{BotBehavior, BotState};
+
+/// Distinct connection errors kept in the report
+const MAX_REPORTED_FAILURES: usize = 10;
+
+/// Outcome of a single bot
+struct BotResult {
+    behavior: BotBehavior,
+    connect_time: Option<Duration>,
+    connect_error: Option<String>,
+    disconnected_early: bool,
+    messages_sent: u64,
+    messages_received: u64,
+    errors: u64,
+}
+
+/// Swarm of bots connecting to one server
+pub struct BotSwarm {
+    ip: String,
+    client_config: ClientConfig,
+    tick_rate: u32,
+    ramp_up: Duration,
+    bots: Vec<BotBehavior>,
+}
+
+impl BotSwarm {
+    pub fn new(ip: String, client_config: ClientConfig) -> Self {
+        Self {
+            ip,
+            client_config,
+            tick_rate: 20,
+            ramp_up: Duration::ZERO,
+            bots: Vec::new(),
+        }
+    }
+
+    pub fn with_bots(mut self, behavior: BotBehavior, count: usize) -> Self {
+        self.bots.extend(std::iter::repeat_n(behavior, count));
+        self
+    }
+
+    pub fn with_tick_rate(mut self, tick_rate: u32) -> Self {
+        self.tick_rate = tick_rate;
+        self
+    }
+
+    /// Spread the bot connections evenly over this time
+    pub fn with_ramp_up(mut self, ramp_up: Duration) -> Self {
+        self.ramp_up = ramp_up;
+        self
+    }
+
+    pub fn get_bots_count(&self) -> usize {
+        self.bots.len()
+    }
+
+    /// Run all bots until the ramp up plus `duration` is over
+    pub async fn run(&self, duration: Duration) -> SwarmReport {
+        let started = Instant::now();
+        let deadline = started + self.ramp_up + duration;
+        let spacing = match self.bots.len() {
+            0 => Duration::ZERO,
+            n => self.ramp_up / n as u32,
+        };
+
+        let mut tasks = Vec::with_capacity(self.bots.len());
+        for (index, behavior) in self.bots.iter().enumerate() {
+            tokio::time::sleep_until((started + spacing * index as u32).into()).await;
+            tasks.push(tokio::spawn(run_bot(
+                self.ip.clone(),
+                self.client_config.clone(),
+                *behavior,
+                index,
+                self.tick_rate,
+                deadline,
+            )));
+        }
+
+        let mut results = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            results.push(task.await.unwrap());
+        }
+        SwarmReport::new(&results, started.elapsed())
+    }
+}
+
+async fn run_bot(
+    ip: String,
+    config: ClientConfig,
+    behavior: BotBehavior,
+    index: usize,
+    tick_rate: u32,
+    deadline: Instant,
+) -> BotResult {
+    let mut result = BotResult {
+        behavior,
+        connect_time: None,
+        connect_error: None,
+        disconnected_early: false,
+        messages_sent: 0,
+        messages_received: 0,
+        errors: 0,
+    };
+
+    let connect_started = Instant::now();
+    let client = match NetworkClient::new_with_config(ip, config).await {
+        Ok(client) => client,
+        Err(e) => {
+            result.connect_error = Some(e);
+            return result;
+        }
+    };
+    result.connect_time = Some(connect_started.elapsed());
+
+    let mut state = BotState::new(behavior, index);
+    client
+        .run_fixed(tick_rate, |client, delta| {
+            for error in client.iter_errors() {
+                result.errors += 1;
+                if error.is_fatal() {
+                    result.disconnected_early = true;
+                    return false;
+                }
+            }
+            for message in client.iter_server_messages() {
+                result.messages_received += 1;
+                result.messages_sent += state.on_message(client, &message);
+            }
+            result.messages_sent += state.tick(client, delta);
+            Instant::now() < deadline
+        })
+        .await;
+
+    if !client.is_connected() && Instant::now() < deadline {
+        result.disconnected_early = true;
+    }
+    client.disconnect();
+    result
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p95: f64,
+    pub max: f64,
+}
+
+impl Percentiles {
+    fn from_millis(mut values: Vec<f64>) -> Self {
+        if values.is_empty() {
+            return Self::default();
+        }
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let at = |q: f64| values[((values.len() - 1) as f64 * q).round() as usize];
+        Self {
+            p50: at(0.5),
+            p95: at(0.95),
+            max: at(1.0),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct BehaviorReport {
+    pub bots: usize,
+    pub connected: usize,
+    pub disconnected_early: usize,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub errors: u64,
+}
+
+/// Machine-readable result of a swarm run
+#[derive(Debug, Serialize)]
+pub struct SwarmReport {
+    pub duration_secs: f64,
+    pub bots: usize,
+    pub connected: usize,
+    pub connect_failures: usize,
+    pub disconnected_early: usize,
+    pub connect_time_ms: Percentiles,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub errors: u64,
+    pub behaviors: BTreeMap<String, BehaviorReport>,
+    /// Distinct connection errors, at most `MAX_REPORTED_FAILURES`
+    pub failures: Vec<String>,
+}
+
+impl SwarmReport {
+    fn new(results: &[BotResult], elapsed: Duration) -> Self {
+        let mut behaviors: BTreeMap<String, BehaviorReport> = BTreeMap::new();
+        let mut failures: Vec<String> = Vec::new();
+        for result in results.iter() {
+            let behavior = behaviors.entry(result.behavior.as_name().to_string()).or_default();
+            behavior.bots += 1;
+            behavior.messages_sent += result.messages_sent;
+            behavior.messages_received += result.messages_received;
+            behavior.errors += result.errors;
+            if result.connect_time.is_some() {
+                behavior.connected += 1;
+            }
+            if result.disconnected_early {
+                behavior.disconnected_early += 1;
+            }
+            if let Some(e) = result.connect_error.as_ref() {
+                if failures.len() < MAX_REPORTED_FAILURES && !failures.contains(e) {
+                    failures.push(e.clone());
+                }
+            }
+        }
+
+        let connect_times = results
+            .iter()
+            .filter_map(|r| r.connect_time)
+            .map(|t| t.as_secs_f64() * 1000.0)
+            .collect();
+        Self {
+            duration_secs: elapsed.as_secs_f64(),
+            bots: results.len(),
+            connected: behaviors.values().map(|b| b.connected).sum(),
+            connect_failures: results.iter().filter(|r| r.connect_error.is_some()).count(),
+            disconnected_early: behaviors.values().map(|b| b.disconnected_early).sum(),
+            connect_time_ms: Percentiles::from_millis(connect_times),
+            messages_sent: behaviors.values().map(|b| b.messages_sent).sum(),
+            messages_received: behaviors.values().map(|b| b.messages_received).sum(),
+            errors: behaviors.values().map(|b| b.errors).sum(),
+            behaviors,
+            failures,
+        }
+    }
+
+    /// Every bot connected and stayed connected until the end
+    pub fn is_healthy(&self) -> bool {
+        self.connect_failures == 0 && self.disconnected_early == 0
+    }
+}
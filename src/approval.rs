@@ -0,0 +1,213 @@
+//! Connection approval.
+//!
+//! With `ServerConfig::with_approval` the server sends `ServerMessages::AllowConnection`
+//! itself and reports a connection as `ConnectionMessages::Connect` only once the
+//! approval accepts its `ClientMessages::ConnectionInfo`. Messages sent before that
+//! are dropped. A rejected client receives `ServerMessages::ConnectionRejected`
+//! with the reason and is disconnected; it is never reported to the game.
+
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fmt, sync::Arc};
+
+use crate::client_id::ClientId;
+use crate::messages::ClientMessages;
+
+/// Client details from its `ClientMessages::ConnectionInfo`
+#[derive(Debug, Clone)]
+pub struct ConnectionRequest {
+    pub client_id: ClientId,
+    pub ip: String,
+    pub login: String,
+    pub version: String,
+    pub architecture: String,
+    pub rendering_device: String,
+}
+
+/// Why a connection was not accepted, sent to the client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RejectionReason {
+    /// Login is not on the allowlist
+    NotAllowed,
+    Banned {
+        reason: Option<String>,
+    },
+    UnsupportedVersion {
+        required: String,
+    },
+    ServerFull,
+    Other(String),
+    /// Server is draining before a restart
+    ShuttingDown,
+    /// Address is at its `ServerConfig::ip_limit`
+    TooManyConnections,
+}
+
+impl fmt::Display for RejectionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAllowed => write!(f, "Not on the allowlist"),
+            Self::Banned { reason: Some(reason) } => write!(f, "Banned: {}", reason),
+            Self::Banned { reason: None } => write!(f, "Banned"),
+            Self::UnsupportedVersion { required } => write!(f, "Unsupported version; {} required", required),
+            Self::ServerFull => write!(f, "Server is full"),
+            Self::Other(reason) => write!(f, "{}", reason),
+            Self::ShuttingDown => write!(f, "Server is shutting down"),
+            Self::TooManyConnections => write!(f, "Too many connections from this address"),
+        }
+    }
+}
+
+/// Decides whether a client may join; called from the network tasks,
+/// so it must not block for long
+pub trait ConnectionApproval: Send + Sync {
+    fn approve(&self, request: &ConnectionRequest) -> Result<(), RejectionReason>;
+}
+
+impl<F> ConnectionApproval for F
+where
+    F: Fn(&ConnectionRequest) -> Result<(), RejectionReason> + Send + Sync,
+{
+    fn approve(&self, request: &ConnectionRequest) -> Result<(), RejectionReason> {
+        self(request)
+    }
+}
+
+/// Approval accepting only the listed logins.
+///
+/// Clones share the list, so it can be edited while the server runs.
+#[derive(Debug, Clone, Default)]
+pub struct Allowlist {
+    logins: Arc<RwLock<HashSet<String>>>,
+}
+
+impl Allowlist {
+    pub fn new(logins: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        let logins = logins.into_iter().map(|l| l.into()).collect();
+        Self {
+            logins: Arc::new(RwLock::new(logins)),
+        }
+    }
+
+    pub fn allow(&self, login: &str) {
+        self.logins.write().insert(login.to_string());
+    }
+
+    pub fn revoke(&self, login: &str) {
+        self.logins.write().remove(login);
+    }
+
+    pub fn is_allowed(&self, login: &str) -> bool {
+        self.logins.read().contains(login)
+    }
+}
+
+impl ConnectionApproval for Allowlist {
+    fn approve(&self, request: &ConnectionRequest) -> Result<(), RejectionReason> {
+        match self.is_allowed(&request.login) {
+            true => Ok(()),
+            false => Err(RejectionReason::NotAllowed),
+        }
+    }
+}
+
+/// Approval set in `ServerConfig`
+#[derive(Clone)]
+pub struct Approval(Arc<dyn ConnectionApproval>);
+
+impl Approval {
+    pub fn new(approval: impl ConnectionApproval + 'static) -> Self {
+        Self(Arc::new(approval))
+    }
+}
+
+impl fmt::Debug for Approval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Approval")
+    }
+}
+
+#[derive(Debug)]
+enum ApprovalState {
+    Awaiting,
+    /// Not yet taken by `step()`
+    Decided(Result<(), RejectionReason>),
+    Approved,
+    Rejected,
+}
+
+/// Approval state of one connection
+#[derive(Debug)]
+pub(crate) struct ApprovalGate {
+    state: Mutex<ApprovalState>,
+}
+
+impl ApprovalGate {
+    pub fn new(approval: Option<&Approval>) -> Self {
+        let state = match approval {
+            Some(_) => ApprovalState::Awaiting,
+            None => ApprovalState::Approved,
+        };
+        Self {
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Connection was reported as `ConnectionMessages::Connect`
+    pub fn is_approved(&self) -> bool {
+        matches!(*self.state.lock(), ApprovalState::Approved)
+    }
+
+    /// Whether the message is passed on; the first `ConnectionInfo` is checked by the approval
+    pub fn check(&self, approval: Option<&Approval>, client_id: ClientId, ip: &str, message: &ClientMessages) -> bool {
+        let mut state = self.state.lock();
+        match &*state {
+            ApprovalState::Approved | ApprovalState::Decided(Ok(())) => return true,
+            ApprovalState::Rejected | ApprovalState::Decided(Err(_)) => return false,
+            ApprovalState::Awaiting => {}
+        }
+        let (
+            Some(approval),
+            ClientMessages::ConnectionInfo {
+                login,
+                version,
+                architecture,
+                rendering_device,
+            },
+        ) = (approval, message)
+        else {
+            return false;
+        };
+        let request = ConnectionRequest {
+            client_id,
+            ip: ip.to_string(),
+            login: login.clone(),
+            version: version.clone(),
+            architecture: architecture.clone(),
+            rendering_device: rendering_device.clone(),
+        };
+        let decision = approval.0.approve(&request);
+        let accepted = decision.is_ok();
+        *state = ApprovalState::Decided(decision);
+        accepted
+    }
+
+    /// Refused by the crate before any decision, e.g. by `ServerConfig::ip_limit`
+    pub fn reject(&self) {
+        *self.state.lock() = ApprovalState::Rejected;
+    }
+
+    /// The decision once, for `step()` to report or reject the connection
+    pub fn take_decision(&self) -> Option<Result<(), RejectionReason>> {
+        let mut state = self.state.lock();
+        let ApprovalState::Decided(decision) = &*state else {
+            return None;
+        };
+        let decision = decision.clone();
+        *state = match decision {
+            Ok(()) => ApprovalState::Approved,
+            Err(_) => ApprovalState::Rejected,
+        };
+        Some(decision)
+    }
+}
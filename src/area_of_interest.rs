@@ -0,0 +1,148 @@
+//! Server side area of interest: where each connection is and which
+//! chunks it watches, for `IServerNetwork::send_to_radius` and
+//! `IServerNetwork::send_to_chunk`.
+//!
+//! Positions are fed from `ClientMessages::PlayerMove` when
+//! `ServerConfig::position_tracking` is set, or with `set_position`.
+//! Subscriptions are managed by the game, per world, and kept as a
+//! `ChunkInterest` per client like the chunks a connection holds; a
+//! disconnected client is removed from both.
+
+use common::chunks::chunk_position::ChunkPosition;
+use common::chunks::position::Vector3;
+use parking_lot::RwLock;
+use std::collections::{HashMap, HashSet};
+
+use crate::client_id::ClientId;
+use crate::interest::ChunkInterest;
+use crate::messages::ClientMessages;
+
+/// World slug and chunk
+type ChunkKey = (String, ChunkPosition);
+
+#[derive(Default)]
+struct Areas {
+    positions: HashMap<ClientId, Vector3>,
+    subscriptions: HashMap<ClientId, ChunkInterest>,
+    subscribers: HashMap<ChunkKey, HashSet<ClientId>>,
+}
+
+impl Areas {
+    fn add_subscriber(&mut self, client_id: ClientId, world_slug: &str, chunk_position: ChunkPosition) {
+        let key = (world_slug.to_string(), chunk_position);
+        self.subscribers.entry(key).or_default().insert(client_id);
+    }
+
+    fn remove_subscriber(&mut self, client_id: ClientId, world_slug: &str, chunk_position: &ChunkPosition) {
+        let key = (world_slug.to_string(), *chunk_position);
+        if let Some(subscribers) = self.subscribers.get_mut(&key) {
+            subscribers.remove(&client_id);
+            if subscribers.is_empty() {
+                self.subscribers.remove(&key);
+            }
+        }
+    }
+
+    fn clear_subscriptions(&mut self, client_id: ClientId) {
+        let Some(previous) = self.subscriptions.remove(&client_id) else {
+            return;
+        };
+        for (world_slug, chunk_position) in previous.iter() {
+            self.remove_subscriber(client_id, world_slug, chunk_position);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct AreaOfInterest {
+    areas: RwLock<Areas>,
+}
+
+impl AreaOfInterest {
+    pub fn set_position(&self, client_id: ClientId, position: Vector3) {
+        self.areas.write().positions.insert(client_id, position);
+    }
+
+    pub fn get_position(&self, client_id: ClientId) -> Option<Vector3> {
+        self.areas.read().positions.get(&client_id).cloned()
+    }
+
+    /// Clients whose last known position is within `radius` meters
+    pub fn get_in_radius(&self, center: &Vector3, radius: f32) -> Vec<ClientId> {
+        self.areas
+            .read()
+            .positions
+            .iter()
+            .filter(|(_, position)| {
+                let x = position.x - center.x;
+                let y = position.y - center.y;
+                let z = position.z - center.z;
+                x * x + y * y + z * z <= radius * radius
+            })
+            .map(|(client_id, _)| *client_id)
+            .collect()
+    }
+
+    pub fn subscribe(&self, client_id: ClientId, world_slug: &str, chunk_position: ChunkPosition) {
+        let mut areas = self.areas.write();
+        let interest = areas.subscriptions.entry(client_id).or_default();
+        interest.insert(world_slug, chunk_position);
+        areas.add_subscriber(client_id, world_slug, chunk_position);
+    }
+
+    pub fn unsubscribe(&self, client_id: ClientId, world_slug: &str, chunk_position: &ChunkPosition) {
+        let mut areas = self.areas.write();
+        if let Some(interest) = areas.subscriptions.get_mut(&client_id) {
+            interest.remove(world_slug, chunk_position);
+        }
+        areas.remove_subscriber(client_id, world_slug, chunk_position);
+    }
+
+    /// Replace all subscriptions of the client with chunks of `world_slug`,
+    /// e.g. the chunks around its new center
+    pub fn set_subscriptions(
+        &self,
+        client_id: ClientId,
+        world_slug: &str,
+        chunks: impl IntoIterator<Item = ChunkPosition>,
+    ) {
+        let mut areas = self.areas.write();
+        areas.clear_subscriptions(client_id);
+        let mut interest = ChunkInterest::default();
+        for chunk_position in chunks {
+            interest.insert(world_slug, chunk_position);
+            areas.add_subscriber(client_id, world_slug, chunk_position);
+        }
+        areas.subscriptions.insert(client_id, interest);
+    }
+
+    pub fn is_subscribed(&self, client_id: ClientId, world_slug: &str, chunk_position: &ChunkPosition) -> bool {
+        self.areas
+            .read()
+            .subscriptions
+            .get(&client_id)
+            .map(|interest| interest.is_loaded(world_slug, chunk_position))
+            .unwrap_or(false)
+    }
+
+    pub fn get_subscribers(&self, world_slug: &str, chunk_position: &ChunkPosition) -> Vec<ClientId> {
+        let key = (world_slug.to_string(), *chunk_position);
+        match self.areas.read().subscribers.get(&key) {
+            Some(subscribers) => subscribers.iter().copied().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Track the position reported by the client
+    pub(crate) fn observe(&self, client_id: ClientId, message: &ClientMessages) {
+        if let ClientMessages::PlayerMove { position, .. } = message {
+            self.set_position(client_id, position.clone());
+        }
+    }
+
+    pub(crate) fn remove_client(&self, client_id: ClientId) {
+        let mut areas = self.areas.write();
+        areas.positions.remove(&client_id);
+        areas.clear_subscriptions(client_id);
+    }
+}
@@ -0,0 +1,180 @@
+//! Hash-chained log of security events.
+//!
+//! The server records the events it decides itself; the game records its
+//! own, e.g. `AuditEvent::AdminCommand` or a ban applied outside the
+//! approval, with `IServerNetwork::get_audit_log().record(...)`. Every record
+//! is chained to the previous one with an HMAC keyed by
+//! `ServerConfig::audit_key`, so whoever edits the log without the key can't
+//! recompute the chain. Without a key the chain is a plain checksum.
+
+use hmac::{Hmac, Mac};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{sync::Arc, time::SystemTime};
+
+use crate::approval::RejectionReason;
+use crate::client_id::ClientId;
+
+pub type AuditHash = [u8; 32];
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Security-relevant event recorded in the audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AuditEvent {
+    AuthFailed {
+        ip: String,
+        reason: String,
+    },
+    /// Recorded by the server when the approval rejects a client as
+    /// `RejectionReason::Banned`, and by the game for its own bans
+    BanApplied {
+        client_id: ClientId,
+        ip: String,
+        reason: String,
+    },
+    RateLimitKick {
+        client_id: ClientId,
+        ip: String,
+    },
+    /// Recorded by the game, the server has no admin commands
+    AdminCommand {
+        client_id: ClientId,
+        command: String,
+    },
+    PermissionKick {
+        client_id: ClientId,
+        ip: String,
+    },
+    ConnectionRejected {
+        client_id: ClientId,
+        ip: String,
+        reason: String,
+    },
+}
+
+impl AuditEvent {
+    /// `BanApplied` for a ban, `ConnectionRejected` otherwise
+    pub(crate) fn rejection(client_id: ClientId, ip: &str, reason: &RejectionReason) -> Self {
+        match reason {
+            RejectionReason::Banned { .. } => Self::BanApplied {
+                client_id,
+                ip: ip.to_string(),
+                reason: reason.to_string(),
+            },
+            _ => Self::ConnectionRejected {
+                client_id,
+                ip: ip.to_string(),
+                reason: reason.to_string(),
+            },
+        }
+    }
+}
+
+/// A single entry of the hash chain.
+///
+/// `hash` is the HMAC of the record and the previous entry hash, so removing
+/// or editing any record breaks every hash after it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub sequence: u64,
+    /// Milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub event: AuditEvent,
+    pub prev_hash: AuditHash,
+    pub hash: AuditHash,
+}
+
+impl AuditRecord {
+    fn compute_hash(key: &[u8], sequence: u64, timestamp: u64, event: &AuditEvent, prev_hash: &AuditHash) -> AuditHash {
+        let mut mac = HmacSha256::new_from_slice(key).expect("hmac accepts any key size");
+        mac.update(prev_hash);
+        mac.update(&sequence.to_le_bytes());
+        mac.update(&timestamp.to_le_bytes());
+        mac.update(&bincode::serialize(event).expect("audit event is always serializable"));
+        mac.finalize().into_bytes().into()
+    }
+
+    /// `key` is the `ServerConfig::audit_key` the record was chained with
+    pub fn is_valid(&self, key: &[u8]) -> bool {
+        self.hash == Self::compute_hash(key, self.sequence, self.timestamp, &self.event, &self.prev_hash)
+    }
+}
+
+/// Receiver of audit records (file writer, log shipper, ...).
+pub trait AuditSink: Send + Sync {
+    fn record(&self, record: &AuditRecord);
+}
+
+impl AuditSink for flume::Sender<AuditRecord> {
+    fn record(&self, record: &AuditRecord) {
+        self.send(record.clone()).ok();
+    }
+}
+
+#[derive(Default)]
+pub struct AuditLog {
+    sinks: RwLock<Vec<Arc<dyn AuditSink>>>,
+    // Sequence and hash of the last record
+    head: Mutex<(u64, AuditHash)>,
+    key: Vec<u8>,
+}
+
+impl AuditLog {
+    /// Log chaining its records with `key`, see `ServerConfig::audit_key`
+    pub fn new(key: Vec<u8>) -> Self {
+        Self {
+            key,
+            ..Default::default()
+        }
+    }
+
+    pub fn register_sink(&self, sink: Arc<dyn AuditSink>) {
+        self.sinks.write().push(sink);
+    }
+
+    /// Append an event to the chain and pass it to every sink; the game
+    /// records its own events, e.g. `AuditEvent::AdminCommand`, with it too
+    pub fn record(&self, event: AuditEvent) {
+        let record = {
+            let mut head = self.head.lock();
+            let sequence = head.0;
+            let timestamp = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            let prev_hash = head.1;
+            let hash = AuditRecord::compute_hash(&self.key, sequence, timestamp, &event, &prev_hash);
+            *head = (sequence + 1, hash);
+            AuditRecord {
+                sequence,
+                timestamp,
+                event,
+                prev_hash,
+                hash,
+            }
+        };
+        log::info!(target: "audit", "#{} {:?}", record.sequence, record.event);
+        for sink in self.sinks.read().iter() {
+            sink.record(&record);
+        }
+    }
+
+    /// Check that the records form an unbroken chain keyed with `key`
+    pub fn verify_chain(key: &[u8], records: &[AuditRecord]) -> bool {
+        let mut prev: Option<&AuditRecord> = None;
+        for record in records {
+            if !record.is_valid(key) {
+                return false;
+            }
+            if let Some(prev) = prev {
+                if record.prev_hash != prev.hash || record.sequence != prev.sequence + 1 {
+                    return false;
+                }
+            }
+            prev = Some(record);
+        }
+        true
+    }
+}
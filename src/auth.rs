@@ -0,0 +1,284 @@
+//! Pluggable authentication of the clients.
+//!
+//! With `ServerConfig::with_auth` the tokio server hands the credential the
+//! client set with `ClientConfig::with_credential` to an `AuthProvider`
+//! during the handshake, after the passphrase and connect token checks. A
+//! client it refuses is rejected before it is reported as connected and the
+//! failure is audited; an accepted one carries its `AuthIdentity`
+//! (`IServerConnection::get_identity`).
+//!
+//! Verification is async, so a provider can call its auth service without
+//! holding up the other handshakes; it must finish within the handshake
+//! timeout. An accepted credential is trusted for `AuthProvider::get_cache_ttl`
+//! without verifying it again, e.g. for a client reconnecting.
+//!
+//! Two providers come with the crate: `BearerTokenProvider` for OAuth access
+//! tokens, and `SessionServerProvider` for Mojang-style sessions, where the
+//! client joins a session server with the `server_id` of the handshake
+//! before answering it. Any other backend implements `AuthProvider`, or is a
+//! closure. Renet connects without the handshake: with a provider set, the
+//! renet server rejects every client.
+
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Time an accepted credential is trusted, unless the provider says otherwise
+pub const DEFAULT_AUTH_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Accepted credentials kept in the cache
+pub const MAX_CACHED_CREDENTIALS: usize = 10_000;
+
+/// Account of an authenticated client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthIdentity {
+    /// Stable id of the account at the provider
+    pub subject: String,
+    /// Name of the player
+    pub name: String,
+}
+
+/// Credential of a client to verify
+#[derive(Debug, Clone)]
+pub struct AuthRequest {
+    pub credential: Vec<u8>,
+    /// `server_id` of this handshake
+    pub server_id: String,
+    pub ip: String,
+}
+
+pub type AuthFuture = Pin<Box<dyn Future<Output = Result<AuthIdentity, String>> + Send>>;
+
+/// Verifies the credential of a connecting client; the error is sent to it as the rejection reason
+pub trait AuthProvider: Send + Sync {
+    fn verify(&self, request: AuthRequest) -> AuthFuture;
+
+    /// Time an accepted credential is trusted without `verify`; zero verifies every handshake
+    fn get_cache_ttl(&self) -> Duration {
+        DEFAULT_AUTH_CACHE_TTL
+    }
+}
+
+impl<F, Fut> AuthProvider for F
+where
+    F: Fn(AuthRequest) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<AuthIdentity, String>> + Send + 'static,
+{
+    fn verify(&self, request: AuthRequest) -> AuthFuture {
+        Box::pin(self(request))
+    }
+}
+
+/// Id of the server for one handshake, derived from its challenge; the
+/// client joins the session server with it (see `SessionServerProvider`)
+pub fn server_id(challenge: &[u8]) -> String {
+    let digest = Sha256::digest(challenge);
+    let mut id = String::with_capacity(40);
+    for byte in &digest[..20] {
+        write!(id, "{:02x}", byte).unwrap();
+    }
+    id
+}
+
+/// Provider set in `ServerConfig`, with the cache of the accepted credentials
+#[derive(Clone)]
+pub struct Auth {
+    provider: Arc<dyn AuthProvider>,
+    /// Digest of the credential, identity and when it was verified
+    cache: Arc<Mutex<HashMap<[u8; 32], (AuthIdentity, Instant)>>>,
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Auth")
+    }
+}
+
+impl Auth {
+    pub fn new(provider: impl AuthProvider + 'static) -> Self {
+        Self {
+            provider: Arc::new(provider),
+            cache: Default::default(),
+        }
+    }
+
+    /// Forget the accepted credentials, e.g. after banning an account
+    pub fn clear_cache(&self) {
+        self.cache.lock().clear();
+    }
+
+    pub(crate) async fn verify(&self, credential: Vec<u8>, challenge: &[u8], ip: &str) -> Result<AuthIdentity, String> {
+        let ttl = self.provider.get_cache_ttl();
+        let key: [u8; 32] = Sha256::digest(&credential).into();
+        if let Some((identity, verified)) = self.cache.lock().get(&key) {
+            if verified.elapsed() < ttl {
+                return Ok(identity.clone());
+            }
+        }
+
+        let request = AuthRequest {
+            credential,
+            server_id: server_id(challenge),
+            ip: ip.to_string(),
+        };
+        let identity = self.provider.verify(request).await?;
+        if !ttl.is_zero() {
+            let mut cache = self.cache.lock();
+            if cache.len() >= MAX_CACHED_CREDENTIALS {
+                cache.retain(|_, (_, verified)| verified.elapsed() < ttl);
+            }
+            if cache.len() < MAX_CACHED_CREDENTIALS {
+                cache.insert(key, (identity.clone(), Instant::now()));
+            }
+        }
+        Ok(identity)
+    }
+}
+
+/// Credential sent by the client in the handshake, see `ClientConfig::with_credential`
+#[derive(Clone)]
+pub struct Credential(Arc<dyn Fn(&str) -> Vec<u8> + Send + Sync>);
+
+impl fmt::Debug for Credential {
+    // Never printed, it may be a token
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Credential")
+    }
+}
+
+impl Credential {
+    /// The same credential for every server, e.g. an access token
+    pub fn fixed(credential: Vec<u8>) -> Self {
+        Self(Arc::new(move |_| credential.clone()))
+    }
+
+    /// Credential made for the `server_id` of each handshake, e.g. after joining
+    /// a session server with it; called on the network task, so it must not block for long
+    pub fn per_server(credential: impl Fn(&str) -> Vec<u8> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(credential))
+    }
+
+    pub(crate) fn get(&self, server_id: &str) -> Vec<u8> {
+        (self.0)(server_id)
+    }
+}
+
+/// Answer of the OAuth token introspection (RFC 7662)
+#[derive(Debug, Clone, Default)]
+pub struct TokenInfo {
+    pub active: bool,
+    pub subject: String,
+    pub name: String,
+    pub scopes: Vec<String>,
+    /// Unix time in seconds the token expires at
+    pub expires_at: Option<u64>,
+}
+
+/// Provider of OAuth access tokens, sent by the client as their UTF-8 bytes.
+///
+/// `introspect` asks the authorization server about the token; the provider
+/// rejects inactive and expired tokens and those lacking the required scope.
+pub struct BearerTokenProvider<F> {
+    introspect: F,
+    required_scope: Option<String>,
+}
+
+impl<F, Fut> BearerTokenProvider<F>
+where
+    F: Fn(String) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<TokenInfo, String>> + Send + 'static,
+{
+    pub fn new(introspect: F) -> Self {
+        Self {
+            introspect,
+            required_scope: None,
+        }
+    }
+
+    pub fn with_required_scope(mut self, scope: &str) -> Self {
+        self.required_scope = Some(scope.to_string());
+        self
+    }
+}
+
+impl<F, Fut> AuthProvider for BearerTokenProvider<F>
+where
+    F: Fn(String) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<TokenInfo, String>> + Send + 'static,
+{
+    fn verify(&self, request: AuthRequest) -> AuthFuture {
+        let token = match String::from_utf8(request.credential) {
+            Ok(token) if !token.is_empty() => token,
+            _ => return Box::pin(async { Err("Invalid access token".to_string()) }),
+        };
+        let info = (self.introspect)(token);
+        let required_scope = self.required_scope.clone();
+        Box::pin(async move {
+            let info = info.await?;
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            if !info.active || info.expires_at.is_some_and(|expires_at| expires_at <= now) {
+                return Err("Access token expired".to_string());
+            }
+            if let Some(scope) = required_scope.filter(|scope| !info.scopes.contains(scope)) {
+                return Err(format!("Access token lacks the {} scope", scope));
+            }
+            Ok(AuthIdentity {
+                subject: info.subject,
+                name: info.name,
+            })
+        })
+    }
+}
+
+/// Mojang-style session provider.
+///
+/// The client joins the session server with the `server_id` of the handshake
+/// and sends its player name as the credential (`Credential::per_server`);
+/// `has_joined` asks the session server whether that player joined with that
+/// id. The id changes with every handshake, so nothing is cached.
+pub struct SessionServerProvider<F> {
+    has_joined: F,
+}
+
+impl<F, Fut> SessionServerProvider<F>
+where
+    F: Fn(String, String) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Option<AuthIdentity>, String>> + Send + 'static,
+{
+    /// `has_joined` is called with the player name and the server id
+    pub fn new(has_joined: F) -> Self {
+        Self { has_joined }
+    }
+}
+
+impl<F, Fut> AuthProvider for SessionServerProvider<F>
+where
+    F: Fn(String, String) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Option<AuthIdentity>, String>> + Send + 'static,
+{
+    fn verify(&self, request: AuthRequest) -> AuthFuture {
+        let name = match String::from_utf8(request.credential) {
+            Ok(name) if !name.is_empty() => name,
+            _ => return Box::pin(async { Err("Invalid player name".to_string()) }),
+        };
+        let joined = (self.has_joined)(name, request.server_id);
+        Box::pin(async move {
+            match joined.await? {
+                Some(identity) => Ok(identity),
+                None => Err("Not joined to the session server".to_string()),
+            }
+        })
+    }
+
+    fn get_cache_ttl(&self) -> Duration {
+        Duration::ZERO
+    }
+}
@@ -0,0 +1,94 @@
+//! Send-side batching of the messages of a tick.
+//!
+//! With `ServerConfig::send_batching` the messages sent to a connection are
+//! held until the end of `step()`, an explicit `IServerConnection::flush`,
+//! or until they add up to `SendBatching::max_batch_size`, and are then
+//! written together, so a tick of small messages leaves in a few packets
+//! rather than one per message. Channels set with `with_immediate` skip the
+//! batch and go out as soon as they are sent, for latency-critical traffic.
+//!
+//! Messages queued with `send_latest` are coalesced rather than batched.
+//! The renet backend already packs the messages of a tick into packets
+//! when it steps; the setting has no effect there.
+
+use parking_lot::Mutex;
+
+use crate::messages::NetworkMessageType;
+
+/// Batch size flushed without waiting for the end of the tick
+pub const DEFAULT_MAX_BATCH_SIZE: usize = 16 * 1024;
+
+/// Channels batched until the end of the tick, see `ServerConfig::with_send_batching`
+#[derive(Debug, Clone)]
+pub struct SendBatching {
+    max_batch_size: usize,
+    immediate: Vec<NetworkMessageType>,
+}
+
+impl Default for SendBatching {
+    fn default() -> Self {
+        Self {
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            immediate: Vec::new(),
+        }
+    }
+}
+
+impl SendBatching {
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = max_batch_size;
+        self
+    }
+
+    /// Send the messages of the channel as they are queued
+    pub fn with_immediate(mut self, channel: NetworkMessageType) -> Self {
+        self.immediate.push(channel);
+        self
+    }
+
+    pub fn get_max_batch_size(&self) -> usize {
+        self.max_batch_size
+    }
+
+    pub fn is_batched(&self, channel: NetworkMessageType) -> bool {
+        !self.immediate.contains(&channel)
+    }
+}
+
+/// Frames held for one connection, in the order they were sent
+pub(crate) struct SendBatch<T> {
+    frames: Mutex<(Vec<T>, usize)>,
+}
+
+impl<T> Default for SendBatch<T> {
+    fn default() -> Self {
+        Self {
+            frames: Mutex::new((Vec::new(), 0)),
+        }
+    }
+}
+
+impl<T> SendBatch<T> {
+    /// Hold the frame; the batch is passed to `send` once it reaches `max_batch_size`
+    pub fn push(&self, frame: T, size: usize, max_batch_size: usize, send: impl FnMut(T)) {
+        let mut frames = self.frames.lock();
+        frames.0.push(frame);
+        frames.1 += size;
+        if frames.1 >= max_batch_size {
+            frames.1 = 0;
+            frames.0.drain(..).for_each(send);
+        }
+    }
+
+    /// Pass every held frame to `send`; under the lock, so no frame sent
+    /// meanwhile overtakes them
+    pub fn flush(&self, send: impl FnMut(T)) {
+        let mut frames = self.frames.lock();
+        frames.1 = 0;
+        frames.0.drain(..).for_each(send);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.lock().0.is_empty()
+    }
+}
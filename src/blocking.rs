@@ -0,0 +1,63 @@
+//! Synchronous facade for game loops that do not run an async runtime.
+//!
+//! With `IBlockingServer` or `IBlockingClient` in scope, the network is
+//! created with `new_blocking` and stepped with `step_blocking` from a
+//! plain loop, e.g. an engine's frame callback; the other methods of
+//! `IServerNetwork` and `IClientNetwork` do not block and are called as
+//! usual. The background tasks of the backend run on a runtime owned by the
+//! crate, started on first use and shared by every network of the process.
+//!
+//! The blocking calls panic when made from within an async runtime; async
+//! code keeps using `new` and `step`.
+
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+use crate::client::{ClientConfig, IClientNetwork};
+use crate::server::{IServerConnection, IServerNetwork, ServerConfig};
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+fn runtime() -> &'static Runtime {
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("network")
+            .build()
+            .expect("Network runtime could not be started")
+    })
+}
+
+pub trait IBlockingServer<C: IServerConnection>: IServerNetwork<C> {
+    fn new_blocking(ip_port: String) -> Self {
+        runtime().block_on(Self::new(ip_port))
+    }
+
+    fn new_blocking_with_config(ip_port: String, config: ServerConfig) -> Self {
+        runtime().block_on(Self::new_with_config(ip_port, config))
+    }
+
+    fn step_blocking(&self, delta: Duration) {
+        runtime().block_on(self.step(delta))
+    }
+}
+
+impl<C: IServerConnection, S: IServerNetwork<C>> IBlockingServer<C> for S {}
+
+pub trait IBlockingClient: IClientNetwork {
+    fn new_blocking(ip_port: String) -> Result<Self, String> {
+        runtime().block_on(Self::new(ip_port))
+    }
+
+    fn new_blocking_with_config(ip_port: String, config: ClientConfig) -> Result<Self, String> {
+        runtime().block_on(Self::new_with_config(ip_port, config))
+    }
+
+    /// False once the connection is closed, like `IClientNetwork::step`
+    fn step_blocking(&self, delta: Duration) -> bool {
+        runtime().block_on(self.step(delta))
+    }
+}
+
+impl<C: IClientNetwork> IBlockingClient for C {}
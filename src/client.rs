@@ -1,29 +1,474 @@
 #![allow(opaque_hidden_inferred_bound)]
 
 use super::messages::{ClientMessages, NetworkMessageType, ServerMessages};
+use crate::auth::Credential;
+use crate::errors::NetworkError;
+use crate::network_info::{BandwidthUsage, NetworkInfo};
+use crate::priorities::ChannelPriorities;
+use crate::proxy::Socks5Proxy;
+use crate::rpc::{IncomingRequest, Request, RpcEndpoint, RpcError, DEFAULT_RPC_TIMEOUT};
+use crate::security::ConnectToken;
+use crate::size_limits::{MessageSizeLimits, SizeCheck};
+use crate::conditions::NetworkConditions;
+use crate::datagram::DEFAULT_DATAGRAM_RATE;
+use crate::decode_budget::DecodeQueue;
+use crate::diagnostics::ClientDiagnostics;
+use crate::echo::{EchoProbe, EchoReply};
+use crate::keyed_state::{StateChange, StateReplica};
+use crate::scoreboard::Scoreboard;
+use crate::fragmentation::DEFAULT_MAX_MESSAGE_SIZE;
+use crate::journal::MessageJournal;
+use crate::socket_options::SocketOptions;
+use crate::snapshots::Snapshot;
+use crate::system::SystemMessage;
+use crate::streams::StreamReader;
+use crate::tick::TickSchedule;
+use crate::trace_context::TraceContext;
+use crate::validation::MessageValidation;
+use crate::timeouts::{DEFAULT_CONNECTION_TIMEOUT, DEFAULT_KEEP_ALIVE};
 use common::utils::debug::info::DebugInfo;
 use flume::Drain;
 use parking_lot::RwLockReadGuard;
-use std::{future::Future, net::SocketAddr, time::Duration};
+use socket2::SockRef;
+use std::{future::Future, net::SocketAddr, path::Path, time::Duration};
 use trust_dns_resolver::{
     config::{ResolverConfig, ResolverOpts},
     TokioAsyncResolver,
 };
 
 pub trait IClientNetwork: Sized {
-    fn new(ip_port: String) -> impl Future<Output = Result<Self, String>>;
+    fn new(ip_port: String) -> impl Future<Output = Result<Self, String>> {
+        Self::new_with_config(ip_port, ClientConfig::default())
+    }
+    fn new_with_config(ip_port: String, config: ClientConfig) -> impl Future<Output = Result<Self, String>>;
     fn step(&self, delta: Duration) -> impl Future<Output = bool> + Send;
 
     fn iter_server_messages(&self) -> Drain<'_, ServerMessages>;
-    fn iter_errors(&self) -> Drain<'_, String>;
+    fn iter_errors(&self) -> Drain<'_, NetworkError>;
+
+    /// Byte streams opened by the server with `IServerConnection::open_stream`
+    fn iter_streams(&self) -> Drain<'_, StreamReader>;
+
+    /// Entity snapshots sent with `IServerConnection::send_snapshot`, rebuilt
+    /// from their deltas; acknowledged on the next `step()`
+    fn iter_snapshots(&self) -> Drain<'_, Snapshot>;
+
+    /// Messages of the crate sent with `IServerConnection::send_system`, see `crate::system`
+    fn iter_system_messages(&self) -> Drain<'_, SystemMessage>;
 
     fn is_connected(&self) -> bool;
 
     fn disconnect(&self);
 
+    /// Send the reason to the server after the messages already sent, then disconnect.
+    ///
+    /// The server reports it in `ConnectionMessages::Disconnect`.
+    fn disconnect_with_reason(&self, reason: Option<String>);
+
     fn send_message(&self, message_type: NetworkMessageType, message: &ClientMessages);
 
+    /// Calls of and to the server, see `crate::rpc`
+    fn get_rpc(&self) -> &RpcEndpoint;
+
+    /// Call the server over the reliable channel and wait for its response,
+    /// at most `ClientConfig::rpc_timeout`
+    fn request<R: Request>(&self, request: &R) -> impl Future<Output = Result<R::Response, RpcError>> + Send {
+        self.get_rpc().call(request, |frame| {
+            self.send_message(NetworkMessageType::ReliableOrdered, &ClientMessages::Rpc(frame));
+        })
+    }
+
+    /// Answer a call of the server taken from `RpcEndpoint::drain_requests`
+    fn respond<R: Request>(&self, request: &IncomingRequest, response: Result<&R::Response, String>) {
+        let frame = request.response_frame::<R>(response);
+        self.send_message(NetworkMessageType::ReliableOrdered, &ClientMessages::Rpc(frame));
+    }
+
+    /// The server reads trace contexts, see `crate::trace_context`
+    fn is_trace_supported(&self) -> bool;
+
+    /// Send the message with the trace context of the action it belongs to;
+    /// a server that predates tracing is sent the bare message
+    fn send_traced(&self, message_type: NetworkMessageType, message: &ClientMessages, context: &TraceContext) {
+        if !self.is_trace_supported() {
+            self.send_message(message_type, message);
+            return;
+        }
+        self.send_message(message_type, &message.clone().traced(context.clone()));
+    }
+
+    /// Send an out-of-band datagram: unordered, unreliable, outside the message channels.
+    ///
+    /// Fails if the payload exceeds `MAX_DATAGRAM_SIZE` or the rate limit is reached.
+    fn send_datagram(&self, data: &[u8]) -> Result<(), String>;
+    fn iter_datagrams(&self) -> impl Iterator<Item = Vec<u8>>;
+
     fn get_debug_info(&self) -> RwLockReadGuard<'_, DebugInfo>;
+
+    /// Round-trip time, loss, bandwidth and send queue of the connection
+    fn get_network_info(&self) -> NetworkInfo;
+
+    /// Current `IServerNetwork::server_time` estimated by `crate::time_sync`,
+    /// e.g. to place `ServerMessages::EntityMove::timestamp` on the local timeline;
+    /// None until the first answer of the server
+    fn server_time(&self) -> Option<f64>;
+
+    /// Round-trip time smoothed by `crate::time_sync`; None until the first answer
+    fn rtt(&self) -> Option<Duration>;
+
+    /// Message bytes sent and received per channel, cumulative and over the last second
+    fn get_bandwidth_usage(&self) -> BandwidthUsage;
+
+    /// Change the simulated conditions of the connection (testing only).
+    ///
+    /// Only takes effect if `ClientConfig::network_conditions` was set, e.g. to the "none" preset.
+    fn set_network_conditions(&self, conditions: NetworkConditions);
+
+    /// Random seed chosen by the server during the handshake, signed with the
+    /// passphrase when one is set; None if the server sent none
+    fn get_session_seed(&self) -> Option<u64>;
+
+    /// Sent message journal enabled with `ClientConfig::with_message_journal`;
+    /// keep a clone for the crash handler
+    fn get_message_journal(&self) -> Option<MessageJournal>;
+
+    /// Echoes sent and returned, see `crate::echo`
+    fn get_echo_probe(&self) -> &EchoProbe;
+
+    /// Send `payload` for the server to return at once on the same channel;
+    /// returns the id of its `EchoReply`. Fails over `MAX_ECHO_PAYLOAD` bytes.
+    fn send_echo(&self, message_type: NetworkMessageType, payload: Vec<u8>) -> Result<u32, String> {
+        let (id, message) = self.get_echo_probe().request(payload)?;
+        self.send_message(message_type, &message);
+        Ok(id)
+    }
+
+    /// Echoes returned since the last call, with their round-trip time
+    fn drain_echoes(&self) -> Vec<EchoReply> {
+        self.get_echo_probe().take_replies()
+    }
+
+    /// Stores synced by the server, see `crate::keyed_state`
+    fn get_state_replica(&self) -> &StateReplica;
+
+    /// Keys changed by the state syncs received since the last call
+    fn drain_state_changes(&self) -> Vec<StateChange> {
+        self.get_state_replica().take_changes()
+    }
+
+    /// Scoreboard built from the scoreboard messages received, see `crate::scoreboard`
+    fn get_scoreboard(&self) -> &Scoreboard;
+
+    /// Connection history for support reports, see `crate::diagnostics`
+    fn get_diagnostics(&self) -> &ClientDiagnostics;
+
+    /// Write a redacted connection report to `path`, for players to attach to support tickets
+    fn export_diagnostics(&self, path: &Path) -> Result<(), String> {
+        let info = self.get_network_info();
+        self.get_diagnostics().export(path, &info, &self.get_bandwidth_usage())
+    }
+
+    /// Step the client `rate` times per second and call `callback` after every step.
+    ///
+    /// Ticks are scheduled against absolute deadlines (see `crate::tick`).
+    /// Returns when the callback returns false or the connection is lost.
+    fn run_fixed<F>(&self, rate: u32, mut callback: F) -> impl Future<Output = ()>
+    where
+        F: FnMut(&Self, Duration) -> bool,
+    {
+        async move {
+            let mut schedule = TickSchedule::new(rate, false);
+            loop {
+                if !self.step(schedule.get_interval()).await {
+                    return;
+                }
+                if !callback(self, schedule.get_interval()) {
+                    return;
+                }
+                schedule.wait().await;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ClientConfig {
+    pub socket: SocketOptions,
+
+    /// Simulated latency, jitter, loss and duplication of outgoing traffic (testing only);
+    /// changed at runtime with `set_network_conditions`
+    pub network_conditions: Option<NetworkConditions>,
+
+    /// Local address to bind before connecting (e.g. the Wi-Fi adapter address)
+    pub local_address: Option<SocketAddr>,
+    /// Network interface name to bind to (SO_BINDTODEVICE, Linux only)
+    pub interface: Option<String>,
+
+    /// Reach the server through a TCP-only SOCKS5 proxy, see `crate::proxy`
+    pub proxy: Option<Socks5Proxy>,
+
+    /// Private server password
+    pub passphrase: Option<String>,
+
+    /// Token from the auth service; the connection is encrypted with its
+    /// keys and fails if the server does not accept it
+    pub connect_token: Option<ConnectToken>,
+
+    /// Size limits for sent messages
+    pub message_size_limits: MessageSizeLimits,
+
+    /// Largest message reassembled from fragments, `DEFAULT_MAX_MESSAGE_SIZE` if unset
+    pub max_message_size: Option<usize>,
+
+    /// Datagrams per second allowed in each direction,
+    /// `DEFAULT_DATAGRAM_RATE` if unset
+    pub datagram_rate: Option<u32>,
+
+    /// Data saver: while the message bytes sent and received per second
+    /// exceed this cap, messages on optional channels (`NetworkMessageType::is_optional`)
+    /// are not sent. Incoming traffic is up to the server
+    pub bandwidth_cap: Option<u32>,
+
+    /// Largest texture size (pixels) the client renders; the server
+    /// sends skin variants within it, see `EntitySkinData::Variants`
+    pub max_texture_size: Option<u32>,
+
+    /// Keep the metadata of the messages sent during this long,
+    /// see `crate::journal`
+    pub message_journal: Option<Duration>,
+
+    /// Tenant of a server hosting several, see `crate::tenants`
+    pub tenant: Option<String>,
+
+    /// Time without a frame from the server before the connection is lost,
+    /// `DEFAULT_CONNECTION_TIMEOUT` if unset (see `crate::timeouts`)
+    pub connection_timeout: Option<Duration>,
+
+    /// Ping interval, `DEFAULT_KEEP_ALIVE` if unset
+    pub keep_alive: Option<Duration>,
+
+    /// Time each `step()` may spend decoding chunks; unset decodes them
+    /// as they arrive (see `crate::decode_budget`)
+    pub decode_budget: Option<Duration>,
+
+    /// Send order of the channels when the send budget is tight, renet only
+    /// (see `crate::priorities`)
+    pub channel_priorities: ChannelPriorities,
+
+    /// Time `request` waits for the response, `DEFAULT_RPC_TIMEOUT` if unset
+    pub rpc_timeout: Option<Duration>,
+
+    /// Checks every message passed to `send_message` before it is encoded;
+    /// a failing one is reported and not sent (see `crate::validation`)
+    pub validation: Option<MessageValidation>,
+
+    /// Sent in the handshake for the server `AuthProvider`, see `crate::auth`
+    pub credential: Option<Credential>,
+
+    /// Channels the server may send unencrypted on an encrypted connection
+    /// (`ServerConfig::clear_channels`); the connection fails if it sends
+    /// any other in the clear
+    pub clear_channels: Vec<NetworkMessageType>,
+}
+
+impl ClientConfig {
+    pub fn with_socket_options(mut self, socket: SocketOptions) -> Self {
+        self.socket = socket;
+        self
+    }
+
+    pub fn with_network_conditions(mut self, conditions: NetworkConditions) -> Self {
+        self.network_conditions = Some(conditions);
+        self
+    }
+
+    /// Select simulated conditions by preset name: "none", "fiber", "wifi" or "mobile-3g"
+    pub fn with_network_preset(self, preset: &str) -> Self {
+        let conditions = NetworkConditions::from_name(preset).unwrap_or_else(|| {
+            panic!(
+                "unknown network preset {}; expected one of {:?}",
+                preset,
+                NetworkConditions::preset_names()
+            )
+        });
+        self.with_network_conditions(conditions)
+    }
+
+    pub fn with_local_address(mut self, local_address: SocketAddr) -> Self {
+        self.local_address = Some(local_address);
+        self
+    }
+
+    pub fn with_interface(mut self, interface: String) -> Self {
+        self.interface = Some(interface);
+        self
+    }
+
+    pub fn with_proxy(mut self, proxy: Socks5Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    pub fn with_passphrase(mut self, passphrase: String) -> Self {
+        self.passphrase = Some(passphrase);
+        self
+    }
+
+    pub fn with_connect_token(mut self, token: ConnectToken) -> Self {
+        self.connect_token = Some(token);
+        self
+    }
+
+    pub fn with_message_size_limits(mut self, limits: MessageSizeLimits) -> Self {
+        self.message_size_limits = limits;
+        self
+    }
+
+    pub fn with_max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = Some(size);
+        self
+    }
+
+    pub(crate) fn get_max_message_size(&self) -> usize {
+        self.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+
+    pub fn with_datagram_rate(mut self, rate: u32) -> Self {
+        self.datagram_rate = Some(rate);
+        self
+    }
+
+    pub fn with_bandwidth_cap(mut self, bytes_per_sec: u32) -> Self {
+        self.bandwidth_cap = Some(bytes_per_sec);
+        self
+    }
+
+    pub fn with_message_journal(mut self, retention: Duration) -> Self {
+        self.message_journal = Some(retention);
+        self
+    }
+
+    pub(crate) fn create_message_journal(&self) -> Option<MessageJournal> {
+        self.message_journal.map(MessageJournal::new)
+    }
+
+    pub fn with_max_texture_size(mut self, size: u32) -> Self {
+        self.max_texture_size = Some(size);
+        self
+    }
+
+    pub fn with_tenant(mut self, tenant: &str) -> Self {
+        self.tenant = Some(tenant.to_string());
+        self
+    }
+
+    pub fn with_connection_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_timeout = Some(timeout);
+        self
+    }
+
+    pub(crate) fn get_connection_timeout(&self) -> Duration {
+        self.connection_timeout.unwrap_or(DEFAULT_CONNECTION_TIMEOUT)
+    }
+
+    pub fn with_keep_alive(mut self, interval: Duration) -> Self {
+        self.keep_alive = Some(interval);
+        self
+    }
+
+    pub(crate) fn get_keep_alive(&self) -> Duration {
+        self.keep_alive.unwrap_or(DEFAULT_KEEP_ALIVE)
+    }
+
+    pub fn with_decode_budget(mut self, budget: Duration) -> Self {
+        self.decode_budget = Some(budget);
+        self
+    }
+
+    pub fn with_channel_priorities(mut self, priorities: ChannelPriorities) -> Self {
+        self.channel_priorities = priorities;
+        self
+    }
+
+    pub fn with_rpc_timeout(mut self, timeout: Duration) -> Self {
+        self.rpc_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_validation(mut self, validation: MessageValidation) -> Self {
+        self.validation = Some(validation);
+        self
+    }
+
+    pub fn with_credential(mut self, credential: Credential) -> Self {
+        self.credential = Some(credential);
+        self
+    }
+
+    pub fn with_clear_channel(mut self, message_type: NetworkMessageType) -> Self {
+        self.clear_channels.push(message_type);
+        self
+    }
+
+    pub(crate) fn create_rpc_endpoint(&self) -> RpcEndpoint {
+        RpcEndpoint::new(self.rpc_timeout.unwrap_or(DEFAULT_RPC_TIMEOUT))
+    }
+
+    pub(crate) fn create_decode_queue(&self) -> Option<DecodeQueue> {
+        self.decode_budget.map(DecodeQueue::new)
+    }
+
+    pub(crate) fn get_datagram_rate(&self) -> u32 {
+        self.datagram_rate.unwrap_or(DEFAULT_DATAGRAM_RATE)
+    }
+
+    /// Warn about size outliers; returns an error if the message must not be sent
+    pub(crate) fn check_message_size(&self, variant: &str, size: usize) -> Result<(), NetworkError> {
+        match self.message_size_limits.check(variant, size) {
+            SizeCheck::Ok => Ok(()),
+            SizeCheck::Warning => {
+                log::warn!(target: "network", "Message {} of {} bytes is larger than expected", variant, size);
+                Ok(())
+            }
+            SizeCheck::Rejected => Err(NetworkError::MessageTooLarge {
+                client_id: None,
+                variant: variant.to_string(),
+                size,
+            }),
+        }
+    }
+
+    pub(crate) fn check_message(&self, message: &ClientMessages) -> Result<(), NetworkError> {
+        let Some(validation) = self.validation.as_ref() else {
+            return Ok(());
+        };
+        validation.validate(message).map_err(|reason| NetworkError::InvalidMessage {
+            client_id: None,
+            variant: message.as_ref().to_string(),
+            reason,
+        })
+    }
+
+    /// Bind the socket to the configured interface and local address
+    pub(crate) fn bind_local<'s>(&self, socket: impl Into<SockRef<'s>>) -> Result<(), String> {
+        let socket = socket.into();
+        if let Some(interface) = self.interface.as_ref() {
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            socket
+                .bind_device(Some(interface.as_bytes()))
+                .map_err(|e| format!("Bind to interface {} error: {e}", interface))?;
+
+            #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+            return Err(format!("Binding to interface {} is not supported on this platform", interface));
+        }
+        let local_address = self
+            .local_address
+            .unwrap_or_else(|| "0.0.0.0:0".parse::<SocketAddr>().unwrap());
+        socket
+            .bind(&local_address.into())
+            .map_err(|e| format!("Bind error: {e}"))?;
+        Ok(())
+    }
 }
 
 pub async fn resolve_connect_domain(input: &String, default_port: u16) -> Result<SocketAddr, String> {
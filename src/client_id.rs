@@ -0,0 +1,44 @@
+//! Ids of the clients connected to a server.
+//!
+//! Every connection, event, error and report of the server names its client
+//! with a `ClientId`, never with a bare integer, so that it can't be mixed up
+//! with an entity id or another counter. It is serialized as its u64, so the
+//! recordings and write-ahead logs written before keep loading; code that
+//! doesn't reach for `get` keeps working if the id is ever widened.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::num::NonZeroU64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ClientId(NonZeroU64);
+
+impl ClientId {
+    /// None for 0, never a client id
+    pub fn new(id: u64) -> Option<Self> {
+        NonZeroU64::new(id).map(Self)
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+impl From<NonZeroU64> for ClientId {
+    fn from(id: NonZeroU64) -> Self {
+        Self(id)
+    }
+}
+
+impl From<ClientId> for u64 {
+    fn from(id: ClientId) -> Self {
+        id.get()
+    }
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
@@ -0,0 +1,63 @@
+//! Send-side coalescing of state messages.
+//!
+//! `IServerConnection::send_latest` sends a message that supersedes the
+//! previous one of the same key, like `ServerMessages::EntityMove` keyed by
+//! the entity id. While a message of the key is still queued it is replaced
+//! instead of appended, so a congested connection holds only the most recent
+//! state of each key rather than every step of it. Keys are scoped to the
+//! message variant.
+//!
+//! The queued message keeps the place of the first one it replaced. Only
+//! the unreliable channels coalesce; on the others `send_latest` is a plain
+//! `send_message`.
+
+use parking_lot::Mutex;
+use std::collections::HashMap;
+
+type SlotKey = (String, u64);
+
+struct Slots<T> {
+    order: Vec<SlotKey>,
+    values: HashMap<SlotKey, T>,
+}
+
+/// Latest queued value of each key, in the order the keys were first queued
+pub(crate) struct Coalescer<T> {
+    slots: Mutex<Slots<T>>,
+}
+
+impl<T> Default for Coalescer<T> {
+    fn default() -> Self {
+        Self {
+            slots: Mutex::new(Slots {
+                order: Vec::new(),
+                values: HashMap::new(),
+            }),
+        }
+    }
+}
+
+impl<T> Coalescer<T> {
+    /// Queue the value, replacing the queued one of the key;
+    /// true if the key had none queued
+    pub fn put(&self, variant: &str, key: u64, value: T) -> bool {
+        let mut slots = self.slots.lock();
+        let slot_key = (variant.to_string(), key);
+        if slots.values.insert(slot_key.clone(), value).is_some() {
+            return false;
+        }
+        slots.order.push(slot_key);
+        true
+    }
+
+    /// Take every queued value
+    pub fn drain(&self) -> Vec<T> {
+        let mut slots = self.slots.lock();
+        let Slots { order, values } = &mut *slots;
+        order.drain(..).filter_map(|key| values.remove(&key)).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.lock().values.len()
+    }
+}
@@ -0,0 +1,162 @@
+//! Compression of large message payloads.
+//!
+//! Enabled by `ServerConfig::compression_threshold` and negotiated during
+//! the handshake. The flag is carried per message on the channel byte, so
+//! payloads under the threshold (entity moves, inputs, ...) are sent as is.
+//!
+//! The tokio server adapts the deflate level of each connection every step:
+//! the fastest level for LAN clients, where bandwidth is cheap, and while the
+//! server spends more than `ServerConfig::compression_cpu_budget` compressing;
+//! the highest one for connections whose frames queue up; the default level
+//! otherwise. `IServerConnection::get_compression` overrides it.
+
+use parking_lot::Mutex;
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::time::{Duration, Instant};
+
+/// Set on the channel byte of a message frame whose payload is compressed
+pub(crate) const COMPRESSED_FLAG: u8 = 0x80;
+
+/// Deflate level; chunk data compresses well even at fast levels
+pub const DEFAULT_COMPRESSION_LEVEL: u8 = 3;
+
+/// Deflate level of LAN clients and of a server over its CPU budget
+pub const FAST_COMPRESSION_LEVEL: u8 = 1;
+
+/// Highest deflate level, used for constrained connections
+pub const MAX_COMPRESSION_LEVEL: u8 = 9;
+
+/// Round-trip time under which a client is taken for a LAN client
+pub const LAN_RTT: Duration = Duration::from_millis(5);
+
+/// Frames waiting to be written over which a connection is taken for a constrained one
+const CONSTRAINED_QUEUE: usize = 32;
+
+/// Window the compression CPU time is measured over
+const CPU_WINDOW: Duration = Duration::from_secs(1);
+
+/// Marks an `AdaptiveCompression` without override
+const NO_OVERRIDE: u8 = u8::MAX;
+
+/// Upper bound of a decompressed payload, against decompression bombs
+const MAX_DECOMPRESSED_SIZE: usize = 16 * 1024 * 1024;
+
+/// Compress the payload if it is over the session threshold and compression pays off.
+///
+/// Returns the channel byte, flagged if the returned payload is compressed.
+pub(crate) fn compress_payload(threshold: Option<u32>, channel: u8, payload: Vec<u8>) -> (u8, Vec<u8>) {
+    compress_payload_at(threshold, DEFAULT_COMPRESSION_LEVEL, channel, payload)
+}
+
+/// `compress_payload` at the given deflate level
+pub(crate) fn compress_payload_at(threshold: Option<u32>, level: u8, channel: u8, payload: Vec<u8>) -> (u8, Vec<u8>) {
+    let Some(threshold) = threshold else {
+        return (channel, payload);
+    };
+    if payload.len() <= threshold as usize {
+        return (channel, payload);
+    }
+    let compressed = miniz_oxide::deflate::compress_to_vec(&payload, level);
+    if compressed.len() >= payload.len() {
+        return (channel, payload);
+    }
+    (channel | COMPRESSED_FLAG, compressed)
+}
+
+/// Split a received channel byte into the channel id and the payload, decompressed if flagged
+pub(crate) fn decompress_payload(channel: u8, payload: &[u8]) -> Result<(u8, Cow<'_, [u8]>), String> {
+    if channel & COMPRESSED_FLAG == 0 {
+        return Ok((channel, Cow::Borrowed(payload)));
+    }
+    let data = miniz_oxide::inflate::decompress_to_vec_with_limit(payload, MAX_DECOMPRESSED_SIZE)
+        .map_err(|e| format!("Payload decompression error: {:?}", e.status))?;
+    Ok((channel & !COMPRESSED_FLAG, Cow::Owned(data)))
+}
+
+/// Deflate level of one connection
+#[derive(Debug)]
+pub struct AdaptiveCompression {
+    level: AtomicU8,
+    forced: AtomicU8,
+}
+
+impl Default for AdaptiveCompression {
+    fn default() -> Self {
+        Self {
+            level: AtomicU8::new(DEFAULT_COMPRESSION_LEVEL),
+            forced: AtomicU8::new(NO_OVERRIDE),
+        }
+    }
+}
+
+impl AdaptiveCompression {
+    /// Level the next messages are compressed at
+    pub fn get_level(&self) -> u8 {
+        match self.forced.load(Ordering::Relaxed) {
+            NO_OVERRIDE => self.level.load(Ordering::Relaxed),
+            level => level,
+        }
+    }
+
+    /// Compress at `level`, up to `MAX_COMPRESSION_LEVEL`, rather than adapt; None adapts again
+    pub fn set_level(&self, level: Option<u8>) {
+        let forced = level.map_or(NO_OVERRIDE, |level| level.min(MAX_COMPRESSION_LEVEL));
+        self.forced.store(forced, Ordering::Relaxed);
+    }
+
+    /// `rtt` is zero if unknown; `queued` counts the frames waiting to be written
+    pub(crate) fn adapt(&self, rtt: Duration, queued: usize, over_budget: bool) {
+        let level = if over_budget || (!rtt.is_zero() && rtt <= LAN_RTT) {
+            FAST_COMPRESSION_LEVEL
+        } else if queued >= CONSTRAINED_QUEUE {
+            MAX_COMPRESSION_LEVEL
+        } else {
+            DEFAULT_COMPRESSION_LEVEL
+        };
+        self.level.store(level, Ordering::Relaxed);
+    }
+}
+
+/// CPU time the server spends compressing, against `ServerConfig::compression_cpu_budget`
+#[derive(Debug)]
+pub(crate) struct CompressionBudget {
+    /// Per second
+    budget: Option<Duration>,
+    spent_nanos: AtomicU64,
+    window_start: Mutex<Instant>,
+    exhausted: AtomicBool,
+}
+
+impl CompressionBudget {
+    pub fn new(budget: Option<Duration>) -> Self {
+        Self {
+            budget,
+            spent_nanos: Default::default(),
+            window_start: Mutex::new(Instant::now()),
+            exhausted: Default::default(),
+        }
+    }
+
+    pub fn record(&self, spent: Duration) {
+        if self.budget.is_some() {
+            self.spent_nanos.fetch_add(spent.as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// True if the last complete window went over the budget
+    pub fn is_exhausted(&self) -> bool {
+        let Some(budget) = self.budget else {
+            return false;
+        };
+        let mut window_start = self.window_start.lock();
+        let elapsed = window_start.elapsed();
+        if elapsed >= CPU_WINDOW {
+            let spent = Duration::from_nanos(self.spent_nanos.swap(0, Ordering::Relaxed));
+            let allowed = budget.mul_f64(elapsed.as_secs_f64());
+            self.exhausted.store(spent > allowed, Ordering::Relaxed);
+            *window_start = Instant::now();
+        }
+        self.exhausted.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,184 @@
+use parking_lot::{Mutex, RwLock};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{sync::Arc, time::Duration};
+
+/// Simulated network conditions applied to outgoing traffic.
+///
+/// Used for testing only: every frame is held back by `latency` plus a
+/// random `jitter`. The stream transport cannot drop or duplicate frames,
+/// so a lost frame is delayed by a retransmission timeout instead, like
+/// TCP does, and `duplicate` is ignored. Datagrams are really dropped,
+/// duplicated and reordered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NetworkConditions {
+    pub name: &'static str,
+    /// One-way delay
+    pub latency: Duration,
+    /// Maximum random delay added to `latency`
+    pub jitter: Duration,
+    /// Loss probability, 0.0 - 1.0
+    pub loss: f32,
+    /// Datagram duplication probability, 0.0 - 1.0
+    pub duplicate: f32,
+    /// Seed of the random draws, for reproducible runs; every connection
+    /// then draws the same sequence
+    pub seed: Option<u64>,
+}
+
+/// No added delay or loss; configure it to change conditions at runtime
+pub const PRESET_NONE: NetworkConditions = NetworkConditions {
+    name: "none",
+    latency: Duration::ZERO,
+    jitter: Duration::ZERO,
+    loss: 0.0,
+    duplicate: 0.0,
+    seed: None,
+};
+
+pub const PRESET_FIBER: NetworkConditions = NetworkConditions {
+    name: "fiber",
+    latency: Duration::from_millis(5),
+    jitter: Duration::from_millis(1),
+    loss: 0.0,
+    duplicate: 0.0,
+    seed: None,
+};
+
+pub const PRESET_WIFI: NetworkConditions = NetworkConditions {
+    name: "wifi",
+    latency: Duration::from_millis(20),
+    jitter: Duration::from_millis(10),
+    loss: 0.005,
+    duplicate: 0.0,
+    seed: None,
+};
+
+pub const PRESET_MOBILE_3G: NetworkConditions = NetworkConditions {
+    name: "mobile-3g",
+    latency: Duration::from_millis(150),
+    jitter: Duration::from_millis(50),
+    loss: 0.02,
+    duplicate: 0.001,
+    seed: None,
+};
+
+pub const PRESETS: [NetworkConditions; 4] = [PRESET_NONE, PRESET_FIBER, PRESET_WIFI, PRESET_MOBILE_3G];
+
+/// Minimum retransmission timeout of a lost frame
+#[cfg_attr(not(feature = "network-tokio"), allow(dead_code))]
+const MIN_RETRANSMIT: Duration = Duration::from_millis(200);
+
+impl NetworkConditions {
+    pub fn new(latency: Duration, jitter: Duration, loss: f32) -> Self {
+        Self {
+            name: "custom",
+            latency,
+            jitter,
+            loss,
+            ..PRESET_NONE
+        }
+    }
+
+    pub fn with_duplicate(mut self, duplicate: f32) -> Self {
+        self.duplicate = duplicate;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        PRESETS.iter().find(|p| p.name == name).copied()
+    }
+
+    pub fn preset_names() -> Vec<&'static str> {
+        PRESETS.iter().map(|p| p.name).collect()
+    }
+}
+
+/// Conditions of a running server or client, changed by `set_network_conditions`
+pub(crate) type SharedConditions = Arc<RwLock<NetworkConditions>>;
+
+/// Random draws of one connection under the shared conditions
+#[cfg_attr(not(feature = "network-tokio"), allow(dead_code))]
+pub(crate) struct Conditioner {
+    conditions: SharedConditions,
+    rng: Mutex<StdRng>,
+}
+
+#[cfg_attr(not(feature = "network-tokio"), allow(dead_code))]
+impl Conditioner {
+    pub fn new(conditions: &SharedConditions) -> Self {
+        let rng = match conditions.read().seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        Self {
+            conditions: conditions.clone(),
+            rng: Mutex::new(rng),
+        }
+    }
+
+    fn sample_latency(&self, conditions: &NetworkConditions, rng: &mut StdRng) -> Duration {
+        conditions.latency + conditions.jitter.mul_f32(rng.random::<f32>())
+    }
+
+    /// Delay of a stream frame; a lost frame waits for its retransmission
+    fn frame_delay(&self) -> Duration {
+        let conditions = *self.conditions.read();
+        let mut rng = self.rng.lock();
+        let mut delay = self.sample_latency(&conditions, &mut rng);
+        if conditions.loss > 0.0 && rng.random::<f32>() < conditions.loss {
+            delay += MIN_RETRANSMIT.max(conditions.latency * 2);
+        }
+        delay
+    }
+
+    /// Delay of every copy of a datagram that arrives: none if it is lost, two if duplicated
+    pub fn datagram_delays(&self) -> Vec<Duration> {
+        let conditions = *self.conditions.read();
+        let mut rng = self.rng.lock();
+        if conditions.loss > 0.0 && rng.random::<f32>() < conditions.loss {
+            return Vec::new();
+        }
+        let mut delays = vec![self.sample_latency(&conditions, &mut rng)];
+        if conditions.duplicate > 0.0 && rng.random::<f32>() < conditions.duplicate {
+            delays.push(self.sample_latency(&conditions, &mut rng));
+        }
+        delays
+    }
+}
+
+/// Put a delay stage in front of a writer task.
+///
+/// Delays count from the moment a frame is queued. Frames keep their
+/// order: a frame is never released before the previous one.
+#[cfg(feature = "network-tokio")]
+pub(crate) fn condition_channel<T: Send + 'static>(
+    rx: flume::Receiver<T>,
+    conditioner: Conditioner,
+) -> flume::Receiver<T> {
+    let (queued_tx, queued_rx) = flume::unbounded();
+    let (delayed_tx, delayed_rx) = flume::unbounded();
+    tokio::spawn(async move {
+        while let Ok(frame) = rx.recv_async().await {
+            let release_at = tokio::time::Instant::now() + conditioner.frame_delay();
+            if queued_tx.send((release_at, frame)).is_err() {
+                break;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        let mut last_release = tokio::time::Instant::now();
+        while let Ok((release_at, frame)) = queued_rx.recv_async().await {
+            last_release = last_release.max(release_at);
+            tokio::time::sleep_until(last_release).await;
+            if delayed_tx.send(frame).is_err() {
+                break;
+            }
+        }
+    });
+    delayed_rx
+}
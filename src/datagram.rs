@@ -0,0 +1,96 @@
+//! Out-of-band datagrams.
+//!
+//! Raw payloads sent outside the message channels (no ordering, no
+//! retransmission), for bandwidth probes or NAT keepalives. The crate caps
+//! their size and rate; excess datagrams are dropped.
+//!
+//! Every datagram starts with `DATAGRAM_MAGIC` and `DATAGRAM_VERSION`, so
+//! stray traffic on the port (port scanners, other games) and datagrams of
+//! an incompatible build are dropped before anything else is looked at.
+//! The header only covers this socket of the tokio backend: the renet
+//! packets are netcode's, which checks its own protocol id.
+
+use std::time::{Duration, Instant};
+
+/// Maximum payload of a datagram; fits a single packet on common paths
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+
+/// First bytes of every datagram
+pub const DATAGRAM_MAGIC: [u8; 3] = *b"BRN";
+
+/// Datagram layout version, bumped on incompatible changes
+pub const DATAGRAM_VERSION: u8 = 1;
+
+pub(crate) const DATAGRAM_HEADER_SIZE: usize = DATAGRAM_MAGIC.len() + 1;
+
+/// Default datagrams per second allowed in each direction of a connection
+pub const DEFAULT_DATAGRAM_RATE: u32 = 60;
+
+/// Token bucket refilled at `rate` per second, holding at most one second of burst.
+#[derive(Debug)]
+pub(crate) struct DatagramRateLimiter {
+    rate: u32,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl DatagramRateLimiter {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Retuned at runtime, see `crate::tuning`
+    pub fn set_rate(&mut self, rate: u32) {
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate as f64);
+    }
+
+    pub fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate as f64).min(self.rate as f64);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+pub(crate) fn write_header(packet: &mut Vec<u8>) {
+    packet.extend_from_slice(&DATAGRAM_MAGIC);
+    packet.push(DATAGRAM_VERSION);
+}
+
+/// The datagram without its header; None if it is foreign
+pub(crate) fn strip_header(packet: &[u8]) -> Option<&[u8]> {
+    let body = packet.strip_prefix(DATAGRAM_MAGIC.as_slice())?;
+    match body.split_first() {
+        Some((&DATAGRAM_VERSION, body)) => Some(body),
+        _ => None,
+    }
+}
+
+/// Check an outgoing payload against the size cap and the rate limit
+pub(crate) fn check_outgoing(limiter: &mut DatagramRateLimiter, data: &[u8]) -> Result<(), String> {
+    if data.len() > MAX_DATAGRAM_SIZE {
+        return Err(format!(
+            "Datagram of {} bytes exceeds the maximum of {}",
+            data.len(),
+            MAX_DATAGRAM_SIZE
+        ));
+    }
+    if !limiter.try_acquire() {
+        return Err("Datagram rate limit exceeded".to_string());
+    }
+    Ok(())
+}
+
+/// Interval of the keepalive datagram the client sends so the server
+/// learns (and the NAT keeps) its address
+pub const DATAGRAM_KEEPALIVE: Duration = Duration::from_secs(5);
@@ -0,0 +1,56 @@
+//! Time-sliced decoding of bulk messages on the client.
+//!
+//! With `ClientConfig::with_decode_budget` the messages of the `WorldInfo`
+//! channel (chunks) are queued undecoded as they arrive, and each `step()`
+//! decompresses and decodes them until its budget is spent; the rest waits
+//! for the next `step()`. A burst of chunks is then spread over several
+//! frames instead of stalling one. At least one message is decoded per
+//! `step()`, so the queue always drains.
+//!
+//! Messages of the other channels are decoded on receipt and may overtake
+//! the queued chunks.
+
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::messages::NetworkMessageType;
+
+/// Undecoded bulk messages and the decode time allowed per `step()`
+pub(crate) struct DecodeQueue {
+    budget: Duration,
+    queue: Mutex<VecDeque<Vec<u8>>>,
+}
+
+impl DecodeQueue {
+    pub fn new(budget: Duration) -> Self {
+        Self {
+            budget,
+            queue: Default::default(),
+        }
+    }
+
+    /// Messages of the channel are decoded by `step()`
+    pub fn is_deferred(channel_id: u8) -> bool {
+        channel_id == NetworkMessageType::WorldInfo.channel_id()
+    }
+
+    pub fn push(&self, data: Vec<u8>) {
+        self.queue.lock().push_back(data);
+    }
+
+    /// Decode queued messages in order until the budget is spent
+    pub fn run(&self, mut decode: impl FnMut(Vec<u8>)) {
+        let started = Instant::now();
+        loop {
+            // Not locked while decoding, so the receiver can keep queueing
+            let Some(data) = self.queue.lock().pop_front() else {
+                return;
+            };
+            decode(data);
+            if started.elapsed() >= self.budget {
+                return;
+            }
+        }
+    }
+}
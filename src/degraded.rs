@@ -0,0 +1,209 @@
+//! Emergency unreliable-only mode of a connection.
+//!
+//! A player on a terrible connection drowns in the reliable channels: every
+//! lost packet holds back everything behind it. A degraded connection is
+//! sent only the unreliable state the game marks essential
+//! (`DegradedMode::essential_variants`), the messages of the crate itself and
+//! the system channel; game messages sent on the reliable channels
+//! meanwhile are dropped, so the player stays in the world, degraded but
+//! playable. Open streams and bounded retries keep going, whatever their
+//! channel: a stream missing a chunk could not be read on.
+//!
+//! The server trips the mode with `IServerConnection::degrade`, or on its own
+//! with `ServerConfig::degraded_mode` once the loss or round-trip time stays
+//! over its thresholds for `DegradedMode::hold`; it restores the connection
+//! once they stay under them as long, unless it was tripped by hand
+//! (`IServerConnection::restore`). Both ends are told: the server app with
+//! `ServerEvents::DegradedModeChanged`, the client with
+//! `SystemMessage::DegradedMode`. The reliable messages dropped are lost,
+//! so the game resyncs the client when the mode ends.
+
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::client_id::ClientId;
+use crate::messages::{NetworkMessageType, ServerMessages};
+use crate::phases::{is_system_message, is_transport_message};
+use crate::server::ServerEvents;
+
+/// Time over or under the thresholds before the mode changes, if unset
+pub const DEFAULT_DEGRADED_HOLD: Duration = Duration::from_secs(5);
+
+/// Thresholds tripping the mode, see `crate::degraded`
+#[derive(Debug, Clone)]
+pub struct DegradedMode {
+    /// Packet loss, 0.0 - 100.0
+    pub trip_loss: f64,
+    /// Round-trip time; the only sign of loss over a stream transport
+    pub trip_rtt: Option<Duration>,
+    /// Time over the thresholds to trip the mode, and under them to restore
+    pub hold: Duration,
+    /// Variants still sent on the unreliable channels; all of them if empty
+    pub essential_variants: Vec<String>,
+}
+
+impl DegradedMode {
+    pub fn new(trip_loss: f64) -> Self {
+        Self {
+            trip_loss,
+            trip_rtt: None,
+            hold: DEFAULT_DEGRADED_HOLD,
+            essential_variants: Vec::new(),
+        }
+    }
+
+    pub fn with_trip_rtt(mut self, rtt: Duration) -> Self {
+        self.trip_rtt = Some(rtt);
+        self
+    }
+
+    pub fn with_hold(mut self, hold: Duration) -> Self {
+        self.hold = hold;
+        self
+    }
+
+    /// Keep sending the variant (kebab-case) while degraded
+    pub fn with_essential_variant(mut self, variant: &str) -> Self {
+        self.essential_variants.push(variant.to_string());
+        self
+    }
+
+    fn is_over(&self, rtt: Duration, packet_loss: f64) -> bool {
+        packet_loss >= self.trip_loss || self.trip_rtt.is_some_and(|trip_rtt| rtt >= trip_rtt)
+    }
+}
+
+#[derive(Debug, Default)]
+struct DegradationState {
+    degraded: bool,
+    /// Tripped by `IServerConnection::degrade`, not restored on its own
+    manual: bool,
+    /// Since when the connection is on the other side of the thresholds
+    crossed_since: Option<Instant>,
+    /// Reliable messages dropped since the mode was tripped
+    suspended: u64,
+    /// Changes for `step()` to report, with the messages suspended
+    changes: Vec<(bool, u64)>,
+}
+
+impl DegradationState {
+    fn change(&mut self, degraded: bool) -> bool {
+        if self.degraded == degraded {
+            return false;
+        }
+        self.degraded = degraded;
+        self.crossed_since = None;
+        let suspended = std::mem::take(&mut self.suspended);
+        self.changes.push((degraded, if degraded { 0 } else { suspended }));
+        true
+    }
+}
+
+/// Mode of one connection
+#[derive(Debug, Default)]
+pub struct Degradation {
+    state: Mutex<DegradationState>,
+}
+
+impl Degradation {
+    pub fn is_degraded(&self) -> bool {
+        self.state.lock().degraded
+    }
+
+    /// Trip the mode until `restore`; false if it already was
+    pub(crate) fn trip(&self) -> bool {
+        let mut state = self.state.lock();
+        state.manual = true;
+        state.change(true)
+    }
+
+    /// False if the connection wasn't degraded
+    pub(crate) fn restore(&self) -> bool {
+        let mut state = self.state.lock();
+        state.manual = false;
+        state.change(false)
+    }
+
+    /// Trip or restore on the measures of the connection; called by the server `step()`
+    pub(crate) fn update(&self, mode: &DegradedMode, rtt: Duration, packet_loss: f64) {
+        let mut state = self.state.lock();
+        if state.manual || mode.is_over(rtt, packet_loss) == state.degraded {
+            state.crossed_since = None;
+            return;
+        }
+        let crossed_since = *state.crossed_since.get_or_insert_with(Instant::now);
+        if crossed_since.elapsed() >= mode.hold {
+            let degraded = !state.degraded;
+            state.change(degraded);
+        }
+    }
+
+    /// False if the message must be dropped while degraded
+    pub(crate) fn check(
+        &self,
+        mode: Option<&DegradedMode>,
+        message_type: NetworkMessageType,
+        message: &ServerMessages,
+    ) -> bool {
+        let mut state = self.state.lock();
+        if !state.degraded || is_system_message(message) || is_transport_message(message) {
+            return true;
+        }
+        match message_type {
+            NetworkMessageType::Unreliable | NetworkMessageType::UnreliableSequenced => {
+                let essential = mode.map(|mode| mode.essential_variants.as_slice()).unwrap_or_default();
+                essential.is_empty() || essential.iter().any(|variant| variant == message.as_ref())
+            }
+            NetworkMessageType::ReliableOrdered
+            | NetworkMessageType::ReliableUnordered
+            | NetworkMessageType::WorldInfo => {
+                state.suspended += 1;
+                false
+            }
+        }
+    }
+
+    /// `ServerEvents::DegradedModeChanged` since the last call, with the
+    /// mode to send to the client; called by the server `step()`
+    pub(crate) fn take_changes(&self, client_id: ClientId) -> Vec<(bool, ServerEvents)> {
+        let changes = std::mem::take(&mut self.state.lock().changes);
+        changes
+            .into_iter()
+            .map(|(degraded, suspended)| {
+                let event = ServerEvents::DegradedModeChanged {
+                    client_id,
+                    degraded,
+                    suspended,
+                };
+                (degraded, event)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams_go_on_while_degraded() {
+        let degradation = Degradation::default();
+        degradation.trip();
+        let data = ServerMessages::StreamData {
+            stream_id: 1,
+            data: vec![1, 2, 3],
+        };
+        let status = ServerMessages::ServerStatus { tps: 20.0 };
+        assert!(degradation.check(None, NetworkMessageType::ReliableOrdered, &data));
+        assert!(!degradation.check(None, NetworkMessageType::ReliableOrdered, &status));
+        // Only the game message counts as suspended
+        let changes = degradation.take_changes(ClientId::new(1).unwrap());
+        assert_eq!(changes.len(), 1);
+        degradation.restore();
+        let changes = degradation.take_changes(ClientId::new(1).unwrap());
+        assert!(matches!(
+            changes[0].1,
+            ServerEvents::DegradedModeChanged { suspended: 1, .. }
+        ));
+    }
+}
@@ -0,0 +1,161 @@
+//! Connection report for players to attach to support tickets.
+//!
+//! The client samples its `NetworkInfo` every `SAMPLE_INTERVAL` from
+//! `step()`, and records the settings negotiated in the handshake and the
+//! connection events: connect, socket failures, session resumes and the
+//! disconnect with its cause. `IClientNetwork::export_diagnostics` writes
+//! them with the bandwidth usage as a text report.
+//!
+//! The report is redacted: it holds no server address, session seed, token
+//! or message content.
+
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::network_info::{BandwidthUsage, NetworkInfo};
+
+/// Interval between two samples of the connection
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Samples kept, the last five minutes
+pub const MAX_SAMPLES: usize = 300;
+
+/// Connection events kept
+pub const MAX_EVENTS: usize = 100;
+
+#[derive(Debug)]
+struct Sample {
+    /// Since the connection was opened
+    at: Duration,
+    info: NetworkInfo,
+}
+
+#[derive(Debug, Default)]
+struct DiagnosticsState {
+    settings: Vec<(String, String)>,
+    samples: VecDeque<Sample>,
+    events: VecDeque<(Duration, String)>,
+    last_sample: Option<Instant>,
+    disconnected: bool,
+}
+
+/// Connection history of a client, see `crate::diagnostics`
+#[derive(Debug)]
+pub struct ClientDiagnostics {
+    backend: &'static str,
+    started: Instant,
+    state: Mutex<DiagnosticsState>,
+}
+
+impl ClientDiagnostics {
+    pub(crate) fn new(backend: &'static str) -> Self {
+        Self {
+            backend,
+            started: Instant::now(),
+            state: Default::default(),
+        }
+    }
+
+    /// A setting of the session; never pass addresses, seeds or tokens
+    pub(crate) fn add_setting(&self, name: &str, value: impl ToString) {
+        self.state.lock().settings.push((name.to_string(), value.to_string()));
+    }
+
+    /// Take a sample with `info` once `SAMPLE_INTERVAL` passed since the last one
+    pub(crate) fn sample(&self, info: impl FnOnce() -> NetworkInfo) {
+        let now = Instant::now();
+        let mut state = self.state.lock();
+        if state
+            .last_sample
+            .is_some_and(|last| now.duration_since(last) < SAMPLE_INTERVAL)
+        {
+            return;
+        }
+        state.last_sample = Some(now);
+        let sample = Sample {
+            at: now.duration_since(self.started),
+            info: info(),
+        };
+        state.samples.push_back(sample);
+        if state.samples.len() > MAX_SAMPLES {
+            state.samples.pop_front();
+        }
+    }
+
+    pub(crate) fn record_event(&self, event: &str) {
+        let mut state = self.state.lock();
+        state.events.push_back((self.started.elapsed(), event.to_string()));
+        if state.events.len() > MAX_EVENTS {
+            state.events.pop_front();
+        }
+    }
+
+    /// Record the end of the connection; only the first cause is kept
+    pub(crate) fn record_disconnect(&self, cause: &str) {
+        let recorded = std::mem::replace(&mut self.state.lock().disconnected, true);
+        if !recorded {
+            self.record_event(&format!("Disconnected: {}", cause));
+        }
+    }
+
+    /// Text of the report, with the current network info and bandwidth usage
+    pub fn report(&self, info: &NetworkInfo, usage: &BandwidthUsage) -> String {
+        let state = self.state.lock();
+        let mut report = String::new();
+        writeln!(report, "backend: {}", self.backend).ok();
+        writeln!(report, "version: {}", env!("CARGO_PKG_VERSION")).ok();
+        writeln!(report, "uptime: {:.1?}", self.started.elapsed()).ok();
+        writeln!(report, "connected: {}", !state.disconnected).ok();
+
+        writeln!(report, "\n[settings]").ok();
+        for (name, value) in state.settings.iter() {
+            writeln!(report, "{}: {}", name, value).ok();
+        }
+
+        writeln!(report, "\n[network]").ok();
+        writeln!(report, "rtt: {:.1?}", info.rtt).ok();
+        writeln!(report, "packet_loss: {:.2}%", info.packet_loss).ok();
+        writeln!(report, "send_queue: {}", info.send_queue).ok();
+        writeln!(report, "bytes_sent: {}", usage.total.bytes_sent).ok();
+        writeln!(report, "bytes_received: {}", usage.total.bytes_received).ok();
+        let mut channels: Vec<_> = usage.channels.iter().collect();
+        channels.sort_by_key(|(message_type, _)| message_type.channel_id());
+        for (message_type, channel) in channels {
+            writeln!(
+                report,
+                "channel {:?}: sent {} received {}",
+                message_type, channel.bytes_sent, channel.bytes_received
+            )
+            .ok();
+        }
+
+        writeln!(report, "\n[events]").ok();
+        for (at, event) in state.events.iter() {
+            writeln!(report, "{:>9.1?} {}", at, event).ok();
+        }
+
+        writeln!(report, "\n[samples] at rtt loss sent/s received/s queue").ok();
+        for sample in state.samples.iter() {
+            writeln!(
+                report,
+                "{:>9.1?} {:.1?} {:.2}% {:.0} {:.0} {}",
+                sample.at,
+                sample.info.rtt,
+                sample.info.packet_loss,
+                sample.info.bytes_sent_per_sec,
+                sample.info.bytes_received_per_sec,
+                sample.info.send_queue
+            )
+            .ok();
+        }
+        report
+    }
+
+    pub fn export(&self, path: &Path, info: &NetworkInfo, usage: &BandwidthUsage) -> Result<(), String> {
+        std::fs::write(path, self.report(info, usage))
+            .map_err(|e| format!("Diagnostics export to {} failed: {}", path.display(), e))
+    }
+}
@@ -0,0 +1,193 @@
+//! Discovery of the servers on the local network, without a master server.
+//!
+//! A server with `ServerConfig::discovery` answers the queries broadcast on
+//! its discovery port (`DISCOVERY_PORT` by default) with its name, player
+//! count, protocol version and game port. `Discovery::scan` broadcasts a
+//! query and collects the answers for a while, timing each one, for a
+//! "local servers" list in the client.
+//!
+//! A query is `QUERY_MAGIC` and a u64 LE nonce; the answer is
+//! `ANSWER_MAGIC`, the same nonce and the bincode `ServerAdvertisement`.
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+use crate::handshake::check_protocol_version;
+use crate::health::ServerStats;
+use crate::messages::PROTOCOL_VERSION;
+
+/// Port the servers listen for discovery queries on by default
+pub const DISCOVERY_PORT: u16 = 25566;
+
+const QUERY_MAGIC: &[u8; 4] = b"BNDQ";
+const ANSWER_MAGIC: &[u8; 4] = b"BNDA";
+
+/// How a server advertises itself, see `ServerConfig::with_discovery`
+#[derive(Clone, Debug)]
+pub struct DiscoveryConfig {
+    pub name: String,
+    /// Port the discovery queries are received on
+    pub port: u16,
+}
+
+impl DiscoveryConfig {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            port: DISCOVERY_PORT,
+        }
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+}
+
+/// Answer of a server to a discovery query
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServerAdvertisement {
+    pub name: String,
+    pub players: u32,
+    /// `ServerConfig::max_connections`
+    pub max_players: Option<u32>,
+    pub protocol_version: u32,
+    /// Port the server accepts connections on
+    pub port: u16,
+}
+
+/// Server found by `Discovery::scan`
+#[derive(Clone, Debug)]
+pub struct DiscoveredServer {
+    /// Address to connect to: the address the answer came from, with the game port
+    pub address: SocketAddr,
+    pub advertisement: ServerAdvertisement,
+    /// Time between the query and the answer
+    pub latency: Duration,
+}
+
+impl DiscoveredServer {
+    /// False if the server speaks a protocol version no longer supported; connecting would fail
+    pub fn is_compatible(&self) -> bool {
+        check_protocol_version(self.advertisement.protocol_version).is_ok()
+    }
+}
+
+/// Scanner of the local network for servers with `ServerConfig::discovery`
+#[derive(Clone, Debug)]
+pub struct Discovery {
+    port: u16,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self { port: DISCOVERY_PORT }
+    }
+}
+
+impl Discovery {
+    /// Discovery port the servers listen on, `DISCOVERY_PORT` by default
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Broadcast a query and collect the answers received within `wait`,
+    /// the fastest first
+    pub async fn scan(&self, wait: Duration) -> io::Result<Vec<DiscoveredServer>> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.set_broadcast(true)?;
+        let nonce: u64 = rand::random();
+        let mut query = QUERY_MAGIC.to_vec();
+        query.extend(nonce.to_le_bytes());
+        let sent_at = Instant::now();
+        socket.send_to(&query, (Ipv4Addr::BROADCAST, self.port)).await?;
+
+        let mut servers: HashMap<SocketAddr, DiscoveredServer> = HashMap::new();
+        let mut buf = [0u8; 1024];
+        let deadline = tokio::time::Instant::now() + wait;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (size, from) = received?;
+            let Some(advertisement) = parse_answer(&buf[..size], nonce) else {
+                continue;
+            };
+            let address = SocketAddr::new(from.ip(), advertisement.port);
+            let server = DiscoveredServer {
+                address,
+                advertisement,
+                latency: sent_at.elapsed(),
+            };
+            servers.entry(address).or_insert(server);
+        }
+        let mut servers: Vec<_> = servers.into_values().collect();
+        servers.sort_by_key(|s| s.latency);
+        Ok(servers)
+    }
+}
+
+fn parse_answer(data: &[u8], nonce: u64) -> Option<ServerAdvertisement> {
+    if data.get(..4)? != ANSWER_MAGIC || data.get(4..12)? != nonce.to_le_bytes() {
+        return None;
+    }
+    bincode::deserialize(&data[12..]).ok()
+}
+
+/// Answer the discovery queries configured by `ServerConfig::discovery`
+pub(crate) fn start_discovery(
+    config: Option<&DiscoveryConfig>,
+    stats: &Arc<ServerStats>,
+    game_port: u16,
+    max_players: Option<usize>,
+) {
+    let Some(config) = config else {
+        return;
+    };
+    // Shared, so several servers of one host all answer
+    let bind = || -> io::Result<UdpSocket> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, config.port)).into())?;
+        UdpSocket::from_std(socket.into())
+    };
+    let socket = match bind() {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::error!(target: "network", "Discovery bind error on port {}: {}", config.port, e);
+            return;
+        }
+    };
+    log::info!(target: "network", "Discovery answering on port {}", config.port);
+
+    let name = config.name.clone();
+    let stats = stats.clone();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 64];
+        loop {
+            let Ok((size, from)) = socket.recv_from(&mut buf).await else {
+                continue;
+            };
+            let query = &buf[..size];
+            if size != 12 || &query[..4] != QUERY_MAGIC {
+                continue;
+            }
+            let advertisement = ServerAdvertisement {
+                name: name.clone(),
+                players: stats.get_players() as u32,
+                max_players: max_players.map(|max| max as u32),
+                protocol_version: PROTOCOL_VERSION,
+                port: game_port,
+            };
+            let mut answer = ANSWER_MAGIC.to_vec();
+            answer.extend(&query[4..12]);
+            answer.extend(bincode::serialize(&advertisement).unwrap_or_default());
+            socket.send_to(&answer, from).await.ok();
+        }
+    });
+}
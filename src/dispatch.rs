@@ -0,0 +1,146 @@
+//! Routing of drained messages to registered handlers.
+//!
+//! A server with dozens of message kinds otherwise keeps one long match
+//! where the order of the arms is easy to break. `MessageDispatcher` holds
+//! handlers, each for one variant (the kebab-case name, `AsRef<str>` of the
+//! message) or for every message, and passes each message to them by
+//! descending priority, handlers of the same priority in the order they
+//! were added. A handler consumes the message or gives it back to the next
+//! one; a message no handler consumed goes to the unhandled sink, or is
+//! logged and dropped without one.
+//!
+//! It works for `ClientMessages` and `ServerMessages` alike, with any
+//! context the handlers get along the message (game state, the connection):
+//!
+//! ```ignore
+//! let mut dispatcher = MessageDispatcher::new()
+//!     .with_variant("console-input", 10, |game: &mut Game, message| game.console(message))
+//!     .with_unhandled(|_game, message| log::debug!("Unhandled {}", message));
+//! connection.drain_client_messages().dispatch(&mut dispatcher, &mut game);
+//! ```
+
+use std::fmt;
+
+/// Handler of a message; returns it to pass it on to the next handler
+pub trait MessageHandler<M, C>: Send {
+    fn handle(&mut self, context: &mut C, message: M) -> Option<M>;
+}
+
+impl<M, C, F> MessageHandler<M, C> for F
+where
+    F: FnMut(&mut C, M) -> Option<M> + Send,
+{
+    fn handle(&mut self, context: &mut C, message: M) -> Option<M> {
+        self(context, message)
+    }
+}
+
+struct Route<M, C> {
+    priority: i32,
+    variant: Option<&'static str>,
+    handler: Box<dyn MessageHandler<M, C>>,
+}
+
+type UnhandledSink<M, C> = Box<dyn FnMut(&mut C, M) + Send>;
+
+/// Handlers of the messages by priority, see `crate::dispatch`
+pub struct MessageDispatcher<M, C> {
+    routes: Vec<Route<M, C>>,
+    unhandled: Option<UnhandledSink<M, C>>,
+}
+
+impl<M, C> Default for MessageDispatcher<M, C> {
+    fn default() -> Self {
+        Self {
+            routes: Vec::new(),
+            unhandled: None,
+        }
+    }
+}
+
+impl<M, C> fmt::Debug for MessageDispatcher<M, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let routes: Vec<(i32, Option<&str>)> = self.routes.iter().map(|r| (r.priority, r.variant)).collect();
+        f.debug_struct("MessageDispatcher")
+            .field("routes", &routes)
+            .field("unhandled", &self.unhandled.is_some())
+            .finish()
+    }
+}
+
+impl<M: AsRef<str>, C> MessageDispatcher<M, C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn add(
+        mut self,
+        priority: i32,
+        variant: Option<&'static str>,
+        handler: impl MessageHandler<M, C> + 'static,
+    ) -> Self {
+        // After the routes of the same or a higher priority
+        let index = self.routes.partition_point(|r| r.priority >= priority);
+        let route = Route {
+            priority,
+            variant,
+            handler: Box::new(handler),
+        };
+        self.routes.insert(index, route);
+        self
+    }
+
+    /// Handler of every message
+    pub fn with_handler(self, priority: i32, handler: impl MessageHandler<M, C> + 'static) -> Self {
+        self.add(priority, None, handler)
+    }
+
+    /// Handler of one variant, by its kebab-case name (e.g. "player-move")
+    pub fn with_variant(
+        self,
+        variant: &'static str,
+        priority: i32,
+        handler: impl MessageHandler<M, C> + 'static,
+    ) -> Self {
+        self.add(priority, Some(variant), handler)
+    }
+
+    /// Sink of the messages no handler consumed
+    pub fn with_unhandled(mut self, sink: impl FnMut(&mut C, M) + Send + 'static) -> Self {
+        self.unhandled = Some(Box::new(sink));
+        self
+    }
+
+    /// Pass the message to the handlers; true if one of them consumed it
+    pub fn dispatch(&mut self, context: &mut C, mut message: M) -> bool {
+        for route in self.routes.iter_mut() {
+            if route.variant.is_some_and(|variant| variant != message.as_ref()) {
+                continue;
+            }
+            match route.handler.handle(context, message) {
+                Some(passed) => message = passed,
+                None => return true,
+            }
+        }
+        match self.unhandled.as_mut() {
+            Some(sink) => sink(context, message),
+            None => log::debug!(target: "network", "Message {} has no handler", message.as_ref()),
+        }
+        false
+    }
+}
+
+/// `dispatch` on any iterator of messages, such as `drain_client_messages()`
+pub trait DispatchExt<M>: Iterator<Item = M> + Sized {
+    /// Dispatch every message; returns how many were consumed by a handler
+    fn dispatch<C>(self, dispatcher: &mut MessageDispatcher<M, C>, context: &mut C) -> usize
+    where
+        M: AsRef<str>,
+    {
+        self.map(|message| dispatcher.dispatch(context, message))
+            .filter(|handled| *handled)
+            .count()
+    }
+}
+
+impl<M, I: Iterator<Item = M>> DispatchExt<M> for I {}
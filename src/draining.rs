@@ -0,0 +1,84 @@
+//! Connection draining for rolling restarts.
+//!
+//! `IServerNetwork::start_draining` stops accepting connections and sends every
+//! client `ServerMessages::ShutdownNotice` with the time left. `step()` emits
+//! `ServerEvents::Drained` once the last connection closes or the deadline
+//! passes; connections still open then are left to the caller. Clients that
+//! connect in between are refused, or notified if they were already accepted.
+
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::messages::ServerMessages;
+use crate::server::ServerEvents;
+
+#[derive(Debug, Default)]
+enum DrainState {
+    #[default]
+    Accepting,
+    Draining {
+        deadline: Instant,
+        message: Option<String>,
+    },
+    /// `ServerEvents::Drained` was emitted
+    Drained,
+}
+
+/// Drain state of a server, shared with its accept tasks
+#[derive(Debug, Default)]
+pub struct Draining {
+    state: Mutex<DrainState>,
+}
+
+impl Draining {
+    /// Whether new connections are refused
+    pub fn is_draining(&self) -> bool {
+        !matches!(*self.state.lock(), DrainState::Accepting)
+    }
+
+    /// Time left before the deadline; None unless draining
+    pub fn get_time_left(&self) -> Option<Duration> {
+        match *self.state.lock() {
+            DrainState::Draining { deadline, .. } => Some(deadline.saturating_duration_since(Instant::now())),
+            _ => None,
+        }
+    }
+
+    /// Returns false if the server is already draining
+    pub(crate) fn start(&self, shutdown_in: Duration, message: Option<String>) -> bool {
+        let mut state = self.state.lock();
+        if !matches!(*state, DrainState::Accepting) {
+            return false;
+        }
+        *state = DrainState::Draining {
+            deadline: Instant::now() + shutdown_in,
+            message,
+        };
+        true
+    }
+
+    /// `ServerMessages::ShutdownNotice` with the time left; None unless draining
+    pub(crate) fn notice(&self) -> Option<ServerMessages> {
+        match &*self.state.lock() {
+            DrainState::Draining { deadline, message } => Some(ServerMessages::ShutdownNotice {
+                shutdown_in: deadline.saturating_duration_since(Instant::now()),
+                message: message.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// `ServerEvents::Drained` once, when no connection is left or the deadline passed
+    pub(crate) fn check(&self, connections: usize) -> Option<ServerEvents> {
+        let mut state = self.state.lock();
+        let DrainState::Draining { deadline, .. } = *state else {
+            return None;
+        };
+        if connections > 0 && Instant::now() < deadline {
+            return None;
+        }
+        *state = DrainState::Drained;
+        log::info!(target: "network", "Server drained; {} connections left", connections);
+        Some(ServerEvents::Drained { remaining: connections })
+    }
+}
@@ -0,0 +1,84 @@
+//! Echo of client payloads, to tell network delay from game delay.
+//!
+//! `IClientNetwork::send_echo` sends `ClientMessages::Echo` on the channel
+//! of the caller's choice; the server returns it as `ServerMessages::Echo`
+//! on the same channel as soon as it is decoded, from the connection task
+//! of the tokio backend and within `step()` on renet, without it ever
+//! reaching the game. The round-trip time of an echo is thus the transport
+//! and decoding delay alone: compared with the round trip of a game
+//! request, it tells how long the game logic kept the request queued.
+//!
+//! Payloads over `MAX_ECHO_PAYLOAD` bytes are refused. An echo lost on an
+//! unreliable channel is forgotten after `ECHO_TIMEOUT`.
+
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::messages::{ClientMessages, ServerMessages};
+
+/// Largest payload of an echo
+pub const MAX_ECHO_PAYLOAD: usize = 1024;
+
+/// Time after which an unanswered echo is forgotten
+pub const ECHO_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct EchoReply {
+    /// Id returned by `IClientNetwork::send_echo`
+    pub id: u32,
+    pub payload: Vec<u8>,
+    pub rtt: Duration,
+}
+
+/// Echoes sent by a client and their replies
+#[derive(Debug, Default)]
+pub struct EchoProbe {
+    next_id: AtomicU32,
+    pending: Mutex<HashMap<u32, Instant>>,
+    replies: Mutex<Vec<EchoReply>>,
+}
+
+impl EchoProbe {
+    /// `ClientMessages::Echo` to send, with its id
+    pub(crate) fn request(&self, payload: Vec<u8>) -> Result<(u32, ClientMessages), String> {
+        if payload.len() > MAX_ECHO_PAYLOAD {
+            return Err(format!(
+                "Echo payload of {} bytes exceeds {}",
+                payload.len(),
+                MAX_ECHO_PAYLOAD
+            ));
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let mut pending = self.pending.lock();
+        pending.retain(|_, sent| now.duration_since(*sent) < ECHO_TIMEOUT);
+        pending.insert(id, now);
+        Ok((id, ClientMessages::Echo { id, payload }))
+    }
+
+    /// Consume echo replies; any other message is returned back
+    pub(crate) fn route(&self, message: ServerMessages) -> Option<ServerMessages> {
+        let ServerMessages::Echo { id, payload } = message else {
+            return Some(message);
+        };
+        // Forgotten or never sent
+        let sent = self.pending.lock().remove(&id)?;
+        self.replies.lock().push(EchoReply {
+            id,
+            payload,
+            rtt: sent.elapsed(),
+        });
+        None
+    }
+
+    pub(crate) fn take_replies(&self) -> Vec<EchoReply> {
+        std::mem::take(&mut *self.replies.lock())
+    }
+}
+
+/// Server answer to `ClientMessages::Echo`; None if the payload is too large
+pub(crate) fn echo_reply(id: u32, payload: Vec<u8>) -> Option<ServerMessages> {
+    (payload.len() <= MAX_ECHO_PAYLOAD).then_some(ServerMessages::Echo { id, payload })
+}
@@ -0,0 +1,48 @@
+//! Game-specific entity components.
+//!
+//! A type implementing `NetworkComponent` is synced through the same
+//! messages as the built-in components: wrap it with
+//! `EntityNetworkComponent::custom` on the server and, on the client, match
+//! `EntityNetworkComponent::Custom` by `CustomComponent::get_name` and
+//! `CustomComponent::decode` it. Peers that do not know the name can skip
+//! the component.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+pub trait NetworkComponent: Serialize + DeserializeOwned {
+    /// Name the component is matched by, unique per component type
+    const NAME: &'static str;
+}
+
+/// Component encoded by its `NetworkComponent` type
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CustomComponent {
+    name: String,
+    payload: Vec<u8>,
+}
+
+impl CustomComponent {
+    pub fn new<C: NetworkComponent>(component: &C) -> Self {
+        Self {
+            name: C::NAME.to_string(),
+            payload: bincode::serialize(component).unwrap(),
+        }
+    }
+
+    pub fn get_name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn is<C: NetworkComponent>(&self) -> bool {
+        self.name == C::NAME
+    }
+
+    /// None if the component is of another type
+    pub fn decode<C: NetworkComponent>(&self) -> Option<Result<C, String>> {
+        if !self.is::<C>() {
+            return None;
+        }
+        Some(bincode::deserialize(&self.payload).map_err(|e| e.to_string()))
+    }
+}
@@ -1,23 +1,188 @@
-use serde::{Deserialize, Serialize};
+use common::chunks::position::Vector3;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Style flags of a rich text segment
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct TextStyle {
+    pub bold: bool,
+    pub italic: bool,
+    pub underlined: bool,
+    pub strikethrough: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TextSegment {
+    pub text: String,
+    /// RGB color; the client default color if unset
+    #[serde(default)]
+    pub color: Option<[u8; 3]>,
+    #[serde(default)]
+    pub style: TextStyle,
+}
+
+impl TextSegment {
+    pub fn new(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            color: None,
+            style: Default::default(),
+        }
+    }
+
+    pub fn with_color(mut self, r: u8, g: u8, b: u8) -> Self {
+        self.color = Some([r, g, b]);
+        self
+    }
+
+    pub fn with_style(mut self, style: TextStyle) -> Self {
+        self.style = style;
+        self
+    }
+}
+
+/// Text of an entity tag: plain, or segments with their own color and style.
+///
+/// Human-readable formats (JSON, scripts) accept the plain form as a bare
+/// string, as before rich text, and the rich form as a list of segments.
+/// Binary formats encode the variant explicitly.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagContent {
+    Plain(String),
+    Rich(Vec<TextSegment>),
+}
+
+impl TagContent {
+    /// Text without colors and styles, for clients that can't render rich text
+    pub fn to_plain_text(&self) -> String {
+        match self {
+            Self::Plain(text) => text.clone(),
+            Self::Rich(segments) => segments.iter().map(|s| s.text.as_str()).collect(),
+        }
+    }
+}
+
+impl From<String> for TagContent {
+    fn from(text: String) -> Self {
+        Self::Plain(text)
+    }
+}
+
+impl From<&str> for TagContent {
+    fn from(text: &str) -> Self {
+        Self::Plain(text.to_string())
+    }
+}
+
+impl From<Vec<TextSegment>> for TagContent {
+    fn from(segments: Vec<TextSegment>) -> Self {
+        Self::Rich(segments)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum TaggedContent {
+    Plain(String),
+    Rich(Vec<TextSegment>),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum UntaggedContent {
+    Plain(String),
+    Rich(Vec<TextSegment>),
+}
+
+impl Serialize for TagContent {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match (self.clone(), serializer.is_human_readable()) {
+            (Self::Plain(text), true) => UntaggedContent::Plain(text).serialize(serializer),
+            (Self::Rich(segments), true) => UntaggedContent::Rich(segments).serialize(serializer),
+            (Self::Plain(text), false) => TaggedContent::Plain(text).serialize(serializer),
+            (Self::Rich(segments), false) => TaggedContent::Rich(segments).serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TagContent {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            return Ok(match UntaggedContent::deserialize(deserializer)? {
+                UntaggedContent::Plain(text) => Self::Plain(text),
+                UntaggedContent::Rich(segments) => Self::Rich(segments),
+            });
+        }
+        Ok(match TaggedContent::deserialize(deserializer)? {
+            TaggedContent::Plain(text) => Self::Plain(text),
+            TaggedContent::Rich(segments) => Self::Rich(segments),
+        })
+    }
+}
+
+/// Which viewers receive an entity tag.
+///
+/// Evaluated on the server per connection; the rule itself is never sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum TagVisibility {
+    #[default]
+    Everyone,
+    /// Nobody, e.g. while the player is sneaking
+    Hidden,
+    /// Viewers within this distance of the entity (meters)
+    WithinDistance(f32),
+}
+
+impl TagVisibility {
+    pub fn is_visible(&self, entity_position: &Vector3, viewer_position: &Vector3) -> bool {
+        match self {
+            Self::Everyone => true,
+            Self::Hidden => false,
+            Self::WithinDistance(distance) => {
+                let x = entity_position.x - viewer_position.x;
+                let y = entity_position.y - viewer_position.y;
+                let z = entity_position.z - viewer_position.z;
+                x * x + y * y + z * z <= distance * distance
+            }
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct EntityTagData {
-    content: String,
+    content: TagContent,
     offset: Option<f32>,
     font_size: Option<i32>,
     outline_size: Option<i32>,
+    #[serde(skip)]
+    visibility: TagVisibility,
 }
 
 impl EntityTagData {
-    pub fn create(content: String, offset: Option<f32>, font_size: Option<i32>, outline_size: Option<i32>) -> Self {
+    /// `content` is a `String` for a plain tag or a `Vec<TextSegment>` for rich text
+    pub fn create(
+        content: impl Into<TagContent>,
+        offset: Option<f32>,
+        font_size: Option<i32>,
+        outline_size: Option<i32>,
+    ) -> Self {
         Self {
-            content,
+            content: content.into(),
             offset,
             font_size,
             outline_size,
+            visibility: Default::default(),
         }
     }
 
+    pub fn with_visibility(mut self, visibility: TagVisibility) -> Self {
+        self.visibility = visibility;
+        self
+    }
+
+    pub fn get_visibility(&self) -> &TagVisibility {
+        &self.visibility
+    }
+
     pub fn get_offset(&self) -> Option<&f32> {
         self.offset.as_ref()
     }
@@ -30,7 +195,7 @@ impl EntityTagData {
         self.font_size.as_ref()
     }
 
-    pub fn get_content(&self) -> &String {
+    pub fn get_content(&self) -> &TagContent {
         &self.content
     }
 }
@@ -0,0 +1,159 @@
+use std::collections::{HashMap, VecDeque};
+
+const INDEX_BITS: u32 = 24;
+const INDEX_MASK: u32 = (1 << INDEX_BITS) - 1;
+const GENERATION_MASK: u32 = u8::MAX as u32;
+
+/// Entity id with a generation counter.
+///
+/// Packed into the `u32` used by entity messages: the low 24 bits are
+/// the slot index, the high 8 bits the generation of that slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EntityId {
+    index: u32,
+    generation: u8,
+}
+
+impl EntityId {
+    pub fn from_network(id: u32) -> Self {
+        Self {
+            index: id & INDEX_MASK,
+            generation: ((id >> INDEX_BITS) & GENERATION_MASK) as u8,
+        }
+    }
+
+    pub fn to_network(&self) -> u32 {
+        ((self.generation as u32) << INDEX_BITS) | self.index
+    }
+
+    pub fn get_index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn get_generation(&self) -> u8 {
+        self.generation
+    }
+}
+
+/// Server-side entity id allocator.
+///
+/// Freed slots are reused in FIFO order and only once `reuse_delay`
+/// other slots are free, so a despawned id is not immediately handed
+/// out again while its messages may still be in flight.
+pub struct EntityIdAllocator {
+    generations: Vec<u8>,
+    alive: Vec<bool>,
+    free: VecDeque<u32>,
+    reuse_delay: usize,
+}
+
+impl Default for EntityIdAllocator {
+    fn default() -> Self {
+        Self::new(1024)
+    }
+}
+
+impl EntityIdAllocator {
+    pub fn new(reuse_delay: usize) -> Self {
+        Self {
+            generations: Default::default(),
+            alive: Default::default(),
+            free: Default::default(),
+            reuse_delay,
+        }
+    }
+
+    pub fn allocate(&mut self) -> EntityId {
+        if self.free.len() > self.reuse_delay {
+            let index = self.free.pop_front().unwrap();
+            self.alive[index as usize] = true;
+            return EntityId {
+                index,
+                generation: self.generations[index as usize],
+            };
+        }
+
+        let index = self.generations.len() as u32;
+        assert!(index <= INDEX_MASK, "entity id space exhausted");
+        self.generations.push(0);
+        self.alive.push(true);
+        EntityId { index, generation: 0 }
+    }
+
+    /// Release the id; returns false if it was already stale
+    pub fn free(&mut self, id: EntityId) -> bool {
+        if !self.is_alive(id) {
+            return false;
+        }
+        let index = id.index as usize;
+        self.alive[index] = false;
+        self.generations[index] = self.generations[index].wrapping_add(1);
+        self.free.push_back(id.index);
+        true
+    }
+
+    pub fn is_alive(&self, id: EntityId) -> bool {
+        let index = id.index as usize;
+        index < self.generations.len() && self.alive[index] && self.generations[index] == id.generation
+    }
+
+    pub fn len(&self) -> usize {
+        self.generations.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Client-side table mapping network entity ids to local handles.
+///
+/// Lookups with an outdated generation miss, and inserting a new
+/// generation into an occupied slot returns the stale handle so the
+/// caller can despawn it.
+pub struct EntityRemap<T> {
+    entries: HashMap<u32, (u8, T)>,
+}
+
+impl<T> Default for EntityRemap<T> {
+    fn default() -> Self {
+        Self {
+            entries: Default::default(),
+        }
+    }
+}
+
+impl<T> EntityRemap<T> {
+    pub fn insert(&mut self, network_id: u32, local: T) -> Option<T> {
+        let id = EntityId::from_network(network_id);
+        self.entries
+            .insert(id.index, (id.generation, local))
+            .map(|(_, stale)| stale)
+    }
+
+    pub fn get(&self, network_id: u32) -> Option<&T> {
+        let id = EntityId::from_network(network_id);
+        match self.entries.get(&id.index) {
+            Some((generation, local)) if *generation == id.generation => Some(local),
+            _ => None,
+        }
+    }
+
+    pub fn remove(&mut self, network_id: u32) -> Option<T> {
+        let id = EntityId::from_network(network_id);
+        match self.entries.get(&id.index) {
+            Some((generation, _)) if *generation == id.generation => {
+                self.entries.remove(&id.index).map(|(_, local)| local)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
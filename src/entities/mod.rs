@@ -1,7 +1,12 @@
+use common::chunks::position::Vector3;
+use custom_component::{CustomComponent, NetworkComponent};
 use entity_tag::EntityTagData;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
+pub mod custom_component;
 pub mod entity_tag;
+pub mod id_allocator;
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum AnimationState {
@@ -45,10 +50,61 @@ pub enum EntitySkinData {
     Generic,
     Fixed(String),
     None,
+    /// Skin per texture size (pixels); the server sends each client
+    /// the `Fixed` skin fitting its declared maximum texture size
+    Variants(BTreeMap<u32, String>),
+}
+
+impl EntitySkinData {
+    /// The skin as replicated to a client: variants are resolved to the largest
+    /// one within `max_texture_size`, or the smallest if none fits
+    pub fn for_texture_size(&self, max_texture_size: Option<u32>) -> Self {
+        let Self::Variants(variants) = self else {
+            return self.clone();
+        };
+        let fitting = match max_texture_size {
+            Some(max_texture_size) => variants.range(..=max_texture_size).next_back(),
+            None => variants.iter().next_back(),
+        };
+        match fitting.or_else(|| variants.iter().next()) {
+            Some((_, skin)) => Self::Fixed(skin.clone()),
+            None => Self::Generic,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum EntityNetworkComponent {
     Tag(Option<EntityTagData>),
     Skin(EntitySkinData),
+    /// Component defined outside this crate, see `custom_component`
+    Custom(CustomComponent),
+}
+
+impl EntityNetworkComponent {
+    pub fn custom<C: NetworkComponent>(component: &C) -> Self {
+        Self::Custom(CustomComponent::new(component))
+    }
+
+    /// The component as replicated to one viewer: a tag the viewer
+    /// may not see (see `TagVisibility`) is sent as `Tag(None)`
+    pub fn for_viewer(&self, entity_position: &Vector3, viewer_position: &Vector3) -> Self {
+        match self {
+            Self::Tag(Some(tag)) if !tag.get_visibility().is_visible(entity_position, viewer_position) => {
+                Self::Tag(None)
+            }
+            _ => self.clone(),
+        }
+    }
+
+    pub fn for_texture_size(&self, max_texture_size: Option<u32>) -> Self {
+        match self {
+            Self::Skin(skin) => Self::Skin(skin.for_texture_size(max_texture_size)),
+            _ => self.clone(),
+        }
+    }
+
+    pub(crate) fn has_skin_variants(&self) -> bool {
+        matches!(self, Self::Skin(EntitySkinData::Variants(_)))
+    }
 }
@@ -0,0 +1,147 @@
+//! Time of day and weather of a world, kept in sync without per-tick messages.
+//!
+//! The server polls an `EnvironmentSender` every tick; it returns a
+//! `ServerMessages::WorldEnvironment` once per `interval`, or at once when
+//! the weather changes. Between two messages the client `WorldClock` advances
+//! the time of day on its own at the known day length; when a message shows
+//! it drifted, the difference is made up over `DRIFT_CORRECTION_TIME` rather
+//! than with a visible jump, unless it exceeds `MAX_DRIFT`.
+
+use std::time::{Duration, Instant};
+
+use crate::messages::ServerMessages;
+
+/// Interval between two environment messages of an unchanged weather
+pub const DEFAULT_ENVIRONMENT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time over which a client makes up for a drift
+pub const DRIFT_CORRECTION_TIME: Duration = Duration::from_secs(2);
+
+/// Drift, as a fraction of the day, over which a client jumps to the server time
+pub const MAX_DRIFT: f32 = 0.05;
+
+/// Difference `a - b` of two times of day, the short way around the day
+fn wrapped_difference(a: f32, b: f32) -> f32 {
+    (a - b + 0.5).rem_euclid(1.0) - 0.5
+}
+
+/// Decides when the server sends the environment of a world
+#[derive(Debug, Clone)]
+pub struct EnvironmentSender {
+    interval: Duration,
+    last_sent: Option<Instant>,
+    weather: String,
+}
+
+impl Default for EnvironmentSender {
+    fn default() -> Self {
+        Self::new(DEFAULT_ENVIRONMENT_INTERVAL)
+    }
+}
+
+impl EnvironmentSender {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_sent: None,
+            weather: String::new(),
+        }
+    }
+
+    /// `ServerMessages::WorldEnvironment` to broadcast, if it is time or the weather changed.
+    ///
+    /// `time_of_day` is the fraction of the day passed, from 0 to 1.
+    pub fn poll(&mut self, time_of_day: f32, weather: &str, tick: u64, now: Instant) -> Option<ServerMessages> {
+        let due = self
+            .last_sent
+            .map_or(true, |sent| now.duration_since(sent) >= self.interval);
+        if !due && self.weather == weather {
+            return None;
+        }
+        self.last_sent = Some(now);
+        self.weather = weather.to_string();
+        Some(ServerMessages::WorldEnvironment {
+            time_of_day,
+            weather: weather.to_string(),
+            tick,
+        })
+    }
+}
+
+/// Client estimate of the time of day between environment messages
+#[derive(Debug, Clone)]
+pub struct WorldClock {
+    day_length: Duration,
+    /// Estimated time of day at the last message, when it was received
+    anchor: Option<(Instant, f32)>,
+    /// Drift made up over `DRIFT_CORRECTION_TIME` from the anchor
+    correction: f32,
+    tick: Option<u64>,
+    weather: String,
+}
+
+impl WorldClock {
+    /// `day_length` is the real time a day of the world lasts
+    pub fn new(day_length: Duration) -> Self {
+        Self {
+            day_length,
+            anchor: None,
+            correction: 0.0,
+            tick: None,
+            weather: String::new(),
+        }
+    }
+
+    /// Apply a `ServerMessages::WorldEnvironment`; any other message is ignored.
+    ///
+    /// Returns true if the weather changed. Messages older than the last one are ignored.
+    pub fn apply(&mut self, message: &ServerMessages, now: Instant) -> bool {
+        let ServerMessages::WorldEnvironment {
+            time_of_day,
+            weather,
+            tick,
+        } = message
+        else {
+            return false;
+        };
+        if self.tick.is_some_and(|last| *tick <= last) {
+            return false;
+        }
+        self.tick = Some(*tick);
+
+        match self.get_time_of_day(now) {
+            Some(estimate) if wrapped_difference(*time_of_day, estimate).abs() <= MAX_DRIFT => {
+                self.anchor = Some((now, estimate));
+                self.correction = wrapped_difference(*time_of_day, estimate);
+            }
+            _ => {
+                self.anchor = Some((now, *time_of_day));
+                self.correction = 0.0;
+            }
+        }
+
+        if self.weather == *weather {
+            return false;
+        }
+        self.weather = weather.clone();
+        true
+    }
+
+    /// Fraction of the day passed, from 0 to 1; None until the first message
+    pub fn get_time_of_day(&self, now: Instant) -> Option<f32> {
+        let (received, time_of_day) = self.anchor?;
+        let elapsed = now.saturating_duration_since(received);
+        let advanced = elapsed.as_secs_f32() / self.day_length.as_secs_f32().max(f32::EPSILON);
+        let corrected = (elapsed.as_secs_f32() / DRIFT_CORRECTION_TIME.as_secs_f32()).min(1.0);
+        Some((time_of_day + advanced + self.correction * corrected).rem_euclid(1.0))
+    }
+
+    pub fn get_weather(&self) -> &String {
+        &self.weather
+    }
+
+    /// Server tick of the last message applied
+    pub fn get_tick(&self) -> Option<u64> {
+        self.tick
+    }
+}
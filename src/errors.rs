@@ -0,0 +1,167 @@
+use std::{fmt, io, sync::Arc};
+
+use crate::client_id::ClientId;
+use crate::timeouts::PeerLoss;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorSeverity {
+    /// Nothing was lost
+    Warning,
+    /// A message was dropped, the connection is still usable
+    Recoverable,
+    /// The connection (or the server transport) is lost
+    Fatal,
+}
+
+/// Error reported by `drain_errors` / `iter_errors`.
+///
+/// `client_id` is the offending connection on the server and None on the client.
+#[derive(Clone, Debug)]
+pub enum NetworkError {
+    /// Message frame without a channel; it was dropped
+    MalformedFrame { client_id: Option<ClientId> },
+    /// Received message could not be decompressed, decoded or reassembled; it was dropped
+    Decode {
+        client_id: Option<ClientId>,
+        reason: String,
+    },
+    /// Message over its hard cap or the max message size; it was not sent
+    MessageTooLarge {
+        client_id: Option<ClientId>,
+        variant: String,
+        size: usize,
+    },
+    /// Quantization profiles of the handshake are unknown; the connection was dropped
+    Negotiation { client_id: ClientId, reason: String },
+    /// Reading or writing the connection socket failed
+    ConnectionLost {
+        client_id: Option<ClientId>,
+        error: Arc<io::Error>,
+    },
+    /// Transport update failed (renet backend)
+    Transport { reason: String },
+    /// Packets could not be sent (renet backend)
+    Send { reason: String },
+    /// Message sent through a handle of a closed connection; it was dropped,
+    /// see `crate::generation`
+    StaleConnection { client_id: ClientId, generation: u64 },
+    /// Message could not be encoded; it was not sent
+    Encode {
+        client_id: Option<ClientId>,
+        variant: String,
+        reason: String,
+    },
+    /// Message failed `ClientConfig::validation`; it was not sent
+    InvalidMessage {
+        client_id: Option<ClientId>,
+        variant: String,
+        reason: String,
+    },
+}
+
+impl NetworkError {
+    pub fn get_severity(&self) -> ErrorSeverity {
+        match self {
+            Self::ConnectionLost { .. } | Self::Transport { .. } => ErrorSeverity::Fatal,
+            Self::MalformedFrame { .. }
+            | Self::Decode { .. }
+            | Self::MessageTooLarge { .. }
+            | Self::Negotiation { .. }
+            | Self::Send { .. }
+            | Self::StaleConnection { .. }
+            | Self::Encode { .. }
+            | Self::InvalidMessage { .. } => ErrorSeverity::Recoverable,
+        }
+    }
+
+    pub fn get_client_id(&self) -> Option<ClientId> {
+        match self {
+            Self::MalformedFrame { client_id }
+            | Self::Decode { client_id, .. }
+            | Self::MessageTooLarge { client_id, .. }
+            | Self::ConnectionLost { client_id, .. }
+            | Self::Encode { client_id, .. }
+            | Self::InvalidMessage { client_id, .. } => *client_id,
+            Self::Negotiation { client_id, .. } | Self::StaleConnection { client_id, .. } => Some(*client_id),
+            Self::Transport { .. } | Self::Send { .. } => None,
+        }
+    }
+
+    /// Message variant the error is about, where known
+    pub fn get_variant(&self) -> Option<&str> {
+        match self {
+            Self::MessageTooLarge { variant, .. }
+            | Self::Encode { variant, .. }
+            | Self::InvalidMessage { variant, .. } => Some(variant),
+            _ => None,
+        }
+    }
+
+    pub fn get_io_error(&self) -> Option<&io::Error> {
+        match self {
+            Self::ConnectionLost { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+
+    /// Whether the peer of a lost connection closed its end or can't be reached, see `crate::timeouts`
+    pub fn get_peer_loss(&self) -> Option<PeerLoss> {
+        self.get_io_error().map(PeerLoss::from_io)
+    }
+
+    pub fn is_fatal(&self) -> bool {
+        self.get_severity() == ErrorSeverity::Fatal
+    }
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: ", self.get_severity())?;
+        if let Some(client_id) = self.get_client_id() {
+            write!(f, "client {}: ", client_id)?;
+        }
+        match self {
+            Self::MalformedFrame { .. } => write!(f, "Message frame without channel"),
+            Self::Decode { reason, .. } => write!(f, "Message decode error: {}", reason),
+            Self::MessageTooLarge { variant, size, .. } => {
+                write!(f, "Message {} of {} bytes exceeds the size limit", variant, size)
+            }
+            Self::Negotiation { reason, .. } => write!(f, "Negotiation error: {}", reason),
+            Self::ConnectionLost { error, .. } => write!(f, "Connection lost: {}", error),
+            Self::Transport { reason } => write!(f, "Transport error: {}", reason),
+            Self::Send { reason } => write!(f, "Send error: {}", reason),
+            Self::StaleConnection { generation, .. } => {
+                write!(f, "Send through a stale handle of connection generation {}", generation)
+            }
+            Self::Encode { variant, reason, .. } => write!(f, "Message {} encode error: {}", variant, reason),
+            Self::InvalidMessage { variant, reason, .. } => write!(f, "Message {} is invalid: {}", variant, reason),
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.get_io_error().map(|e| e as _)
+    }
+}
+
+/// Run a step of the receive path, such as decoding a message.
+///
+/// With the `contain-panics` feature, a panic in it (e.g. in a `Deserialize`
+/// impl fed a malformed packet) is caught and returned as the error, so the
+/// message is dropped instead of the server going down.
+pub(crate) fn contain_panics<T>(f: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+    #[cfg(feature = "contain-panics")]
+    {
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+            let reason = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            Err(format!("Panic: {}", reason))
+        })
+    }
+    #[cfg(not(feature = "contain-panics"))]
+    f()
+}
@@ -0,0 +1,119 @@
+//! Fragmentation of oversized reliable server messages.
+//!
+//! A message on a reliable channel encoded larger than `FRAGMENT_SIZE` is
+//! split into `ServerMessages::MessageFragment` messages sent on the same
+//! channel, and reassembled by the client before it is decoded. Messages
+//! over the max message size are not sent at all; the server reports them
+//! through `drain_errors()`.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::messages::{NetworkMessageType, ServerMessages};
+
+/// Maximum encoded size sent as a single message on a reliable channel
+pub const FRAGMENT_SIZE: usize = 256 * 1024;
+
+/// Max message size if `ServerConfig::max_message_size` or `ClientConfig::max_message_size` is unset
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+static NEXT_MESSAGE_ID: AtomicU32 = AtomicU32::new(1);
+
+pub(crate) fn needs_fragmentation(message_type: NetworkMessageType, size: usize) -> bool {
+    !message_type.is_optional() && size > FRAGMENT_SIZE
+}
+
+/// Split an encoded message into fragments of up to `FRAGMENT_SIZE` bytes
+pub(crate) fn split_message(encoded: &[u8]) -> Vec<ServerMessages> {
+    let message_id = NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed);
+    let count = encoded.len().div_ceil(FRAGMENT_SIZE) as u32;
+    encoded
+        .chunks(FRAGMENT_SIZE)
+        .enumerate()
+        .map(|(index, data)| ServerMessages::MessageFragment {
+            message_id,
+            index: index as u32,
+            count,
+            data: data.to_vec(),
+        })
+        .collect()
+}
+
+struct PartialMessage {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    size: usize,
+}
+
+/// Client side reassembly of fragmented messages.
+pub(crate) struct Reassembly {
+    max_message_size: usize,
+    partial: HashMap<u32, PartialMessage>,
+}
+
+impl Reassembly {
+    pub fn new(max_message_size: usize) -> Self {
+        Self {
+            max_message_size,
+            partial: Default::default(),
+        }
+    }
+
+    /// Consume fragment messages; any other message is returned back.
+    ///
+    /// Once every fragment of a message arrived, it is decoded with `decode` and returned.
+    pub fn route(
+        &mut self,
+        message: ServerMessages,
+        decode: impl FnOnce(&[u8]) -> Result<ServerMessages, String>,
+    ) -> Result<Option<ServerMessages>, String> {
+        let (message_id, index, count, data) = match message {
+            ServerMessages::MessageFragment {
+                message_id,
+                index,
+                count,
+                data,
+            } => (message_id, index, count, data),
+            message => return Ok(Some(message)),
+        };
+        match self.push(message_id, index as usize, count as usize, data)? {
+            Some(encoded) => decode(&encoded).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn push(&mut self, message_id: u32, index: usize, count: usize, data: Vec<u8>) -> Result<Option<Vec<u8>>, String> {
+        if count > self.max_message_size.div_ceil(FRAGMENT_SIZE) {
+            self.partial.remove(&message_id);
+            return Err(format!(
+                "Fragmented message of {} fragments exceeds the max message size {}",
+                count, self.max_message_size
+            ));
+        }
+        let partial = self.partial.entry(message_id).or_insert_with(|| PartialMessage {
+            fragments: vec![None; count],
+            received: 0,
+            size: 0,
+        });
+        if index >= partial.fragments.len() {
+            return Err(format!("Fragment {} of message {} is out of range", index, message_id));
+        }
+        if partial.fragments[index].is_none() {
+            partial.size += data.len();
+            partial.received += 1;
+            partial.fragments[index] = Some(data);
+        }
+        if partial.size > self.max_message_size {
+            self.partial.remove(&message_id);
+            return Err(format!(
+                "Fragmented message exceeds the max message size {}",
+                self.max_message_size
+            ));
+        }
+        if partial.received < partial.fragments.len() {
+            return Ok(None);
+        }
+        let partial = self.partial.remove(&message_id).unwrap();
+        Ok(Some(partial.fragments.into_iter().flatten().flatten().collect()))
+    }
+}
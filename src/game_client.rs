@@ -0,0 +1,297 @@
+//! High-level client facade.
+//!
+//! `IClientNetwork` hands out the raw `ServerMessages`, and every game ends
+//! up writing the same match over them plus the clock sync and the entity
+//! interpolation of `crate::interpolation`. `GameClient` wraps a client,
+//! turns the messages into typed `GameEvent`s and keeps the interpolated
+//! pose of every streamed entity, sampled with `get_entity_pose`. Messages
+//! without an event of their own come out as `GameEvent::Message`, and the
+//! wrapped client stays reachable with `get_network` for everything else.
+
+use common::chunks::chunk_data::ChunkData;
+use common::chunks::chunk_position::ChunkPosition;
+use common::chunks::position::Vector3;
+use common::chunks::rotation::Rotation;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::approval::RejectionReason;
+use crate::client::IClientNetwork;
+use crate::entities::{AnimationState, EntityNetworkComponent};
+use crate::interpolation::adaptive_delay::AdaptiveDelay;
+use crate::interpolation::clock_sync::ClockSync;
+use crate::interpolation::history_buffer::{HistoryBuffer, SampleResult};
+use crate::interpolation::traits::Interpolatable;
+use crate::messages::ServerMessages;
+use crate::quantization::{from_lanes, to_lanes};
+
+/// Poses kept per entity
+const HISTORY_SIZE: usize = 64;
+
+/// Offset samples of the clock sync
+const CLOCK_SAMPLES: usize = 32;
+
+/// Position, rotation and animation of an entity at one moment
+#[derive(Debug, Clone)]
+pub struct EntityPose {
+    pub position: Vector3,
+    pub rotation: Rotation,
+    pub animation_state: AnimationState,
+}
+
+/// Interpolate each f32 field of the value
+fn lerp_lanes<T: Serialize + DeserializeOwned + Clone>(from: &T, to: &T, t: f32) -> T {
+    let (Ok(from_values), Ok(to_values)) = (to_lanes(from), to_lanes(to)) else {
+        return from.clone();
+    };
+    let lanes: Vec<f32> = from_values
+        .iter()
+        .zip(to_values.iter())
+        .map(|(a, b)| a + (b - a) * t)
+        .collect();
+    from_lanes(&lanes).unwrap_or_else(|_| from.clone())
+}
+
+impl Interpolatable for EntityPose {
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        Self {
+            position: lerp_lanes(&self.position, &other.position, t),
+            rotation: lerp_lanes(&self.rotation, &other.rotation, t),
+            animation_state: match t < 0.5 {
+                true => self.animation_state,
+                false => other.animation_state,
+            },
+        }
+    }
+}
+
+/// What a server message means to the game
+#[derive(Debug, Clone)]
+pub enum GameEvent {
+    /// Server accepted the connection
+    Allowed,
+    ChatReceived {
+        message: String,
+    },
+    /// Server closed the connection
+    Kicked {
+        reason: Option<String>,
+    },
+    /// Server refused the connection, see `crate::approval`
+    Rejected {
+        reason: RejectionReason,
+    },
+    WorldSpawned {
+        world_slug: String,
+    },
+    PlayerSpawned {
+        world_slug: String,
+        position: Vector3,
+        rotation: Rotation,
+        components: Vec<EntityNetworkComponent>,
+    },
+    ChunkLoaded {
+        world_slug: String,
+        chunk_position: ChunkPosition,
+        sections: ChunkData,
+    },
+    ChunksUnloaded {
+        world_slug: String,
+        chunks: Vec<ChunkPosition>,
+    },
+    EntitySpawned {
+        world_slug: String,
+        id: u32,
+        pose: EntityPose,
+        components: Vec<EntityNetworkComponent>,
+    },
+    /// Pose as received; `GameClient::get_entity_pose` gives the interpolated one
+    EntityMoved {
+        world_slug: String,
+        id: u32,
+        pose: EntityPose,
+    },
+    EntitiesDespawned {
+        world_slug: String,
+        ids: Vec<u32>,
+    },
+    ShutdownNotice {
+        shutdown_in: Duration,
+        message: Option<String>,
+    },
+    /// Message without an event of its own
+    Message(ServerMessages),
+}
+
+struct EntityTrack {
+    history: HistoryBuffer<EntityPose>,
+    delay: AdaptiveDelay,
+}
+
+/// Client wrapper exposing typed events and interpolated entities
+pub struct GameClient<N: IClientNetwork> {
+    network: N,
+    tick_rate: u32,
+    started: Instant,
+    clock: ClockSync,
+    entities: HashMap<u32, EntityTrack>,
+}
+
+impl<N: IClientNetwork> GameClient<N> {
+    /// `tick_rate`: ticks per second of the server, sets the interpolation delay
+    pub fn new(network: N, tick_rate: u32) -> Self {
+        Self {
+            network,
+            tick_rate,
+            started: Instant::now(),
+            clock: ClockSync::new(CLOCK_SAMPLES),
+            entities: Default::default(),
+        }
+    }
+
+    /// The wrapped client, for the low-level API
+    pub fn get_network(&self) -> &N {
+        &self.network
+    }
+
+    pub fn into_network(self) -> N {
+        self.network
+    }
+
+    fn local_time(&self) -> f64 {
+        self.started.elapsed().as_secs_f64()
+    }
+
+    /// Events of the messages received since the last call; call after `step()`
+    pub fn drain_events(&mut self) -> Vec<GameEvent> {
+        let messages: Vec<ServerMessages> = self.network.iter_server_messages().collect();
+        messages.into_iter().map(|message| self.handle(message)).collect()
+    }
+
+    fn track(&mut self, id: u32, pose: EntityPose, local_time: f64) {
+        let tick_rate = self.tick_rate as f64;
+        let track = self.entities.entry(id).or_insert_with(|| EntityTrack {
+            history: HistoryBuffer::new(HISTORY_SIZE),
+            delay: AdaptiveDelay::new(tick_rate),
+        });
+        track.history.push(pose, local_time);
+    }
+
+    fn handle(&mut self, message: ServerMessages) -> GameEvent {
+        let local_time = self.local_time();
+        match message {
+            ServerMessages::AllowConnection => GameEvent::Allowed,
+            ServerMessages::ConsoleOutput { message } => GameEvent::ChatReceived { message },
+            ServerMessages::Disconnect { message } => GameEvent::Kicked { reason: message },
+            ServerMessages::ConnectionRejected { reason } => GameEvent::Rejected { reason },
+            ServerMessages::SpawnWorld { world_slug } => {
+                // Entity ids are per world
+                self.entities.clear();
+                GameEvent::WorldSpawned { world_slug }
+            }
+            ServerMessages::PlayerSpawn {
+                world_slug,
+                position,
+                rotation,
+                components,
+            } => GameEvent::PlayerSpawned {
+                world_slug,
+                position,
+                rotation,
+                components,
+            },
+            ServerMessages::ChunkSectionInfo {
+                world_slug,
+                chunk_position,
+                sections,
+            } => GameEvent::ChunkLoaded {
+                world_slug,
+                chunk_position,
+                sections,
+            },
+            ServerMessages::UnloadChunks { world_slug, chunks } => GameEvent::ChunksUnloaded { world_slug, chunks },
+            ServerMessages::StartStreamingEntity {
+                world_slug,
+                id,
+                position,
+                rotation,
+                components,
+            } => {
+                let pose = EntityPose {
+                    position,
+                    rotation,
+                    animation_state: AnimationState::Idle,
+                };
+                self.entities.remove(&id);
+                self.track(id, pose.clone(), local_time);
+                GameEvent::EntitySpawned {
+                    world_slug,
+                    id,
+                    pose,
+                    components,
+                }
+            }
+            ServerMessages::EntityMove {
+                world_slug,
+                id,
+                position,
+                rotation,
+                animation_state,
+                timestamp,
+            } => {
+                let pose = EntityPose {
+                    position,
+                    rotation,
+                    animation_state,
+                };
+                self.clock.record_sample(local_time, timestamp);
+                let arrival = self.clock.server_to_local(timestamp);
+                self.track(id, pose.clone(), arrival);
+                if let Some(track) = self.entities.get_mut(&id) {
+                    track.delay.record_arrival(local_time);
+                }
+                GameEvent::EntityMoved { world_slug, id, pose }
+            }
+            ServerMessages::StopStreamingEntities { world_slug, ids } => {
+                for id in ids.iter() {
+                    self.entities.remove(id);
+                }
+                GameEvent::EntitiesDespawned { world_slug, ids }
+            }
+            ServerMessages::ShutdownNotice { shutdown_in, message } => {
+                GameEvent::ShutdownNotice { shutdown_in, message }
+            }
+            message => GameEvent::Message(message),
+        }
+    }
+
+    /// Pose of a streamed entity to render now, interpolated behind the
+    /// latest one by the adaptive delay; None if it is not streamed
+    pub fn get_entity_pose(&self, id: u32) -> Option<EntityPose> {
+        let track = self.entities.get(&id)?;
+        let render_time = self.local_time() - track.delay.delay();
+        match track.history.sample(render_time) {
+            SampleResult::Empty => None,
+            SampleResult::Single(snapshot) => Some(snapshot.value),
+            SampleResult::Interpolate { before, after, t } => Some(before.value.lerp(&after.value, t)),
+            // No extrapolation: the entity holds its last pose
+            SampleResult::Extrapolate { last, .. } => Some(last.value),
+        }
+    }
+
+    /// Ids of the entities streamed to the client
+    pub fn iter_entities(&self) -> impl Iterator<Item = u32> + '_ {
+        self.entities.keys().copied()
+    }
+
+    /// Step the wrapped client and drop the poses no longer needed
+    pub async fn step(&mut self, delta: Duration) -> bool {
+        let connected = self.network.step(delta).await;
+        let local_time = self.local_time();
+        for track in self.entities.values_mut() {
+            track.history.cleanup_before(local_time - track.delay.delay(), 2);
+        }
+        connected
+    }
+}
@@ -0,0 +1,69 @@
+//! Generations of server connections.
+//!
+//! Every accepted connection gets a new generation id, also when the same
+//! client id reconnects. Once the server removes the connection, each of
+//! its handles turns stale, clones still held by the application included:
+//! messages sent through it are dropped instead of being buffered for a
+//! peer that will never receive them. The first dropped send is reported as
+//! `NetworkError::StaleConnection`; `IServerConnection::try_send_message`
+//! returns the error to the caller instead.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::errors::NetworkError;
+use crate::labels::ConnectionLabel;
+
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Default)]
+struct GenerationState {
+    stale: AtomicBool,
+    reported: AtomicBool,
+}
+
+/// Generation of one connection, shared by its handles
+#[derive(Debug, Clone)]
+pub(crate) struct Generation {
+    id: u64,
+    state: Arc<GenerationState>,
+}
+
+impl Generation {
+    pub fn new() -> Self {
+        Self {
+            id: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed),
+            state: Default::default(),
+        }
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    /// Called once the server removed the connection
+    pub fn expire(&self) {
+        self.state.stale.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.state.stale.load(Ordering::SeqCst)
+    }
+
+    /// True if a message may be sent through the handle; the first refused
+    /// send is reported on `errors`
+    pub fn check_send(&self, client: &ConnectionLabel, errors: &flume::Sender<NetworkError>) -> bool {
+        if !self.is_stale() {
+            return true;
+        }
+        if !self.state.reported.swap(true, Ordering::SeqCst) {
+            log::warn!(target: "network", "Send through a stale handle of client {}; dropped", client);
+            let error = NetworkError::StaleConnection {
+                client_id: client.get_client_id(),
+                generation: self.id,
+            };
+            errors.send(error).ok();
+        }
+        false
+    }
+}
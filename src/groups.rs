@@ -0,0 +1,54 @@
+//! Named groups of connections for `IServerNetwork::send_to_group`.
+//!
+//! Groups are created on the first join and removed with their last
+//! member; a disconnected client leaves all its groups.
+
+use parking_lot::RwLock;
+use std::collections::{HashMap, HashSet};
+
+use crate::client_id::ClientId;
+
+#[derive(Default)]
+pub struct ConnectionGroups {
+    groups: RwLock<HashMap<String, HashSet<ClientId>>>,
+}
+
+impl ConnectionGroups {
+    pub fn join(&self, group: &str, client_id: ClientId) {
+        self.groups.write().entry(group.to_string()).or_default().insert(client_id);
+    }
+
+    pub fn leave(&self, group: &str, client_id: ClientId) {
+        let mut groups = self.groups.write();
+        if let Some(members) = groups.get_mut(group) {
+            members.remove(&client_id);
+            if members.is_empty() {
+                groups.remove(group);
+            }
+        }
+    }
+
+    pub fn get_members(&self, group: &str) -> Vec<ClientId> {
+        match self.groups.read().get(group) {
+            Some(members) => members.iter().copied().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Names of the groups the client is a member of
+    pub fn get_groups_of(&self, client_id: ClientId) -> Vec<String> {
+        self.groups
+            .read()
+            .iter()
+            .filter(|(_, members)| members.contains(&client_id))
+            .map(|(group, _)| group.clone())
+            .collect()
+    }
+
+    pub(crate) fn remove_client(&self, client_id: ClientId) {
+        self.groups.write().retain(|_, members| {
+            members.remove(&client_id);
+            !members.is_empty()
+        });
+    }
+}
@@ -0,0 +1,732 @@
+//! Connection handshake.
+//!
+//! Optional features (quantization profiles, compression, ...) are negotiated here.
+//! A client lacking a feature the server wants to use is never rejected
+//! for it: the server falls back to the baseline behavior for that
+//! connection and reports it as `ServerEvents::FeatureFallback`. Only
+//! authentication failures and a peer older than
+//! `MIN_SUPPORTED_PROTOCOL_VERSION` reject the handshake.
+//!
+//! | Feature | Client lacks it | Server doesn't use it |
+//! |---|---|---|
+//! | Quantization profile | Full precision on the channel, reported | Full precision |
+//! | Compression | Uncompressed both ways, reported | Uncompressed both ways |
+//! | Key rotation | Connect token keys kept, reported | Connect token keys kept |
+//! | Clear channels | Channels the client does not allow stay encrypted, reported | Every channel encrypted |
+//! | Send batching | Nothing to negotiate: the client reads batched frames as any other | - |
+//!
+//! Voice is not a feature of the crate: it travels as game messages, sent
+//! to every client that knows their variants.
+//!
+//! Each side also declares the message variants it knows, so a message
+//! appended by a newer peer of the same protocol version is skipped instead
+//! of failing to decode (see `newer_variant`). Renet connects without the
+//! handshake: its peers are matched by the netcode `PROTOCOL_ID` only.
+
+use hmac::{Hmac, Mac};
+use parking_lot::Mutex;
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::Duration;
+use strum::EnumCount;
+
+use crate::auth::{server_id, AuthIdentity};
+use crate::client::ClientConfig;
+use crate::client_id::ClientId;
+use crate::messages::{ClientMessages, ServerMessages, MIN_SUPPORTED_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::quantization::QuantizationProfile;
+use crate::resume::{ResumeOffer, ResumeRequest};
+use crate::security::{PrivateKey, RekeyPolicy, SealedToken, SessionKeys, KEY_SIZE};
+use crate::server::{ServerConfig, ServerEvents};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub(crate) const CHALLENGE_SIZE: usize = 32;
+pub(crate) const PROOF_SIZE: usize = 32;
+/// Proof and its time, see `timed_psk_proof`
+pub(crate) const TIMED_PROOF_SIZE: usize = PROOF_SIZE + 8;
+
+/// Longest a renet pre-shared key proof is accepted after it was made;
+/// the clocks of the client and the server must agree within it
+pub(crate) const TIMED_PROOF_WINDOW: Duration = Duration::from_secs(30);
+
+/// First handshake frame, sent by the server right after the connection is accepted.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ServerHello {
+    pub challenge: [u8; CHALLENGE_SIZE],
+}
+
+impl ServerHello {
+    pub fn new() -> Self {
+        Self {
+            challenge: rand::random(),
+        }
+    }
+}
+
+/// Client answer to the `ServerHello`.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ClientHello {
+    /// Pre-shared key proof for the server challenge
+    pub proof: Option<[u8; PROOF_SIZE]>,
+    /// Quantization profiles known to the client
+    pub quantization_profiles: Vec<String>,
+    /// Client can decompress message payloads, see `crate::compression`
+    #[serde(default, deserialize_with = "appended")]
+    pub compression: bool,
+    /// Largest texture the client renders, see `EntitySkinData::Variants`
+    #[serde(default, deserialize_with = "appended")]
+    pub max_texture_size: Option<u32>,
+    /// Sealed part of the client connect token, see `crate::security`
+    #[serde(default, deserialize_with = "appended")]
+    pub connect_token: Option<SealedToken>,
+    /// Tenant the client connects to, see `crate::tenants`
+    #[serde(default, deserialize_with = "appended")]
+    pub tenant: Option<String>,
+    /// `PROTOCOL_VERSION` of the client; 0 for clients predating it
+    #[serde(default, deserialize_with = "appended")]
+    pub protocol_version: u32,
+    /// `ClientMessages` variants known to the client
+    #[serde(default, deserialize_with = "appended")]
+    pub schema: u32,
+    /// Session to resume instead of starting a new one, see `crate::resume`
+    #[serde(default, deserialize_with = "appended")]
+    pub resume: Option<ResumeRequest>,
+    /// Credential for `ServerConfig::auth`, see `crate::auth`
+    #[serde(default, deserialize_with = "appended")]
+    pub credential: Option<Vec<u8>>,
+    /// Client rotates the session keys, see `crate::security`
+    #[serde(default, deserialize_with = "appended")]
+    pub rekey: bool,
+    /// Channel ids the client lets be sent in the clear (`ClientConfig::clear_channels`),
+    /// see `crate::security`
+    #[serde(default, deserialize_with = "appended")]
+    pub clear_channels: Vec<u8>,
+}
+
+impl ClientHello {
+    pub fn new(server_hello: &ServerHello, config: &ClientConfig, resume: Option<ResumeRequest>) -> Self {
+        Self {
+            proof: config.passphrase.as_ref().map(|p| psk_proof(p, &server_hello.challenge)),
+            quantization_profiles: QuantizationProfile::supported_names(),
+            compression: true,
+            max_texture_size: config.max_texture_size,
+            connect_token: config.connect_token.as_ref().map(|t| t.get_sealed().clone()),
+            tenant: config.tenant.clone(),
+            protocol_version: PROTOCOL_VERSION,
+            schema: ClientMessages::COUNT as u32,
+            resume,
+            credential: config
+                .credential
+                .as_ref()
+                .map(|c| c.get(&server_id(&server_hello.challenge))),
+            rekey: true,
+            clear_channels: config.clear_channels.iter().map(|c| c.channel_id()).collect(),
+        }
+    }
+}
+
+/// Field appended after a released version: a peer of that version does
+/// not send it, so running out of input yields the default value. Any
+/// other error, e.g. a corrupt field, fails the decode.
+///
+/// Only valid for trailing fields; the old peer ignores the extra bytes.
+fn appended<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    match T::deserialize(deserializer) {
+        Ok(value) => Ok(value),
+        Err(e) if is_end_of_input(&e) => Ok(T::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The handshake is bincode, whose error for running out of input is
+/// recognized by its message: the error type is generic here
+fn is_end_of_input(error: &impl std::fmt::Display) -> bool {
+    static END_OF_INPUT: OnceLock<String> = OnceLock::new();
+    let end_of_input = END_OF_INPUT.get_or_init(|| match bincode::deserialize::<u8>(&[]) {
+        Ok(_) => String::new(),
+        Err(e) => e.to_string(),
+    });
+    error.to_string() == *end_of_input
+}
+
+/// Optional feature the client could not use, with the behavior applied instead
+#[derive(Debug, Clone)]
+pub(crate) struct FeatureFallback {
+    pub feature: String,
+    pub fallback: String,
+}
+
+/// Session settings chosen by the server from the client capabilities.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct SessionParameters {
+    /// Quantization profile name per channel id
+    pub quantization: Vec<(u8, String)>,
+
+    /// Prefix of client datagrams, identifying the connection
+    pub datagram_token: u64,
+
+    /// Payloads larger than this many bytes are compressed; None disables compression
+    #[serde(default, deserialize_with = "appended")]
+    pub compression_threshold: Option<u32>,
+
+    /// Server side only; reported as `ServerEvents::FeatureFallback`
+    #[serde(skip)]
+    pub fallbacks: Vec<FeatureFallback>,
+
+    /// Server side only; declared by the client
+    #[serde(skip)]
+    pub max_texture_size: Option<u32>,
+
+    /// Random seed of the session, known to both ends; e.g. for cosmetic
+    /// effects that must match without a message of their own
+    #[serde(default, deserialize_with = "appended")]
+    pub seed: Option<u64>,
+
+    /// Seed signature with the server passphrase, see `sign_seed`
+    #[serde(default, deserialize_with = "appended")]
+    pub seed_proof: Option<[u8; PROOF_SIZE]>,
+
+    /// Frames after the handshake are encrypted with the connect token keys
+    #[serde(default, deserialize_with = "appended")]
+    pub encrypted: bool,
+
+    /// Server side only; read from the client connect token
+    #[serde(skip)]
+    pub keys: Option<SessionKeys>,
+
+    /// Server side only; checked against `ServerConfig::tenants`
+    #[serde(skip)]
+    pub tenant: Option<String>,
+
+    /// `PROTOCOL_VERSION` of the server; 0 for servers predating it
+    #[serde(default, deserialize_with = "appended")]
+    pub protocol_version: u32,
+
+    /// `ServerMessages` variants known to the server
+    #[serde(default, deserialize_with = "appended")]
+    pub schema: u32,
+
+    /// Server side only; `ClientHello::schema`
+    #[serde(skip)]
+    pub client_schema: u32,
+
+    /// Token to resume the session with, set with `ServerConfig::session_resume`
+    #[serde(default, deserialize_with = "appended")]
+    pub resume: Option<ResumeOffer>,
+
+    /// Server side only; verified by `ServerConfig::auth`
+    #[serde(skip)]
+    pub identity: Option<AuthIdentity>,
+
+    /// Key rotation of both directions of an encrypted session; None keeps the connect token keys
+    #[serde(default, deserialize_with = "appended")]
+    pub rekey: Option<RekeyPolicy>,
+
+    /// Channel ids authenticated but not encrypted in an encrypted session
+    #[serde(default, deserialize_with = "appended")]
+    pub clear_channels: Vec<u8>,
+}
+
+impl SessionParameters {
+    pub fn negotiate(config: &ServerConfig, client_hello: &ClientHello) -> Self {
+        let mut session = Self {
+            datagram_token: rand::random(),
+            max_texture_size: client_hello.max_texture_size,
+            seed: Some(rand::random()),
+            protocol_version: PROTOCOL_VERSION,
+            schema: ServerMessages::COUNT as u32,
+            client_schema: client_hello.schema,
+            resume: config.session_resume.map(|grace| ResumeOffer {
+                token: rand::random(),
+                grace,
+            }),
+            ..Default::default()
+        };
+        for (message_type, profile) in config.quantization.iter() {
+            if !client_hello.quantization_profiles.contains(profile) {
+                session.fallbacks.push(FeatureFallback {
+                    feature: format!("quantization:{}", profile),
+                    fallback: "full precision".to_string(),
+                });
+                continue;
+            }
+            session.quantization.push((message_type.channel_id(), profile.clone()));
+        }
+        if let Some(threshold) = config.compression_threshold {
+            match client_hello.compression {
+                true => session.compression_threshold = Some(threshold),
+                false => session.fallbacks.push(FeatureFallback {
+                    feature: "compression".to_string(),
+                    fallback: "uncompressed".to_string(),
+                }),
+            }
+        }
+        // Only encrypted sessions have keys to rotate
+        if let (Some(policy), Some(_)) = (config.rekey, config.private_key.as_ref()) {
+            match client_hello.rekey {
+                true => session.rekey = Some(policy),
+                false => session.fallbacks.push(FeatureFallback {
+                    feature: "rekey".to_string(),
+                    fallback: "connect token keys".to_string(),
+                }),
+            }
+        }
+        if config.private_key.is_some() {
+            let (clear, refused): (Vec<u8>, Vec<u8>) = config
+                .clear_channels
+                .iter()
+                .map(|c| c.channel_id())
+                .partition(|channel_id| client_hello.clear_channels.contains(channel_id));
+            session.clear_channels = clear;
+            if !refused.is_empty() {
+                session.fallbacks.push(FeatureFallback {
+                    feature: "clear channels".to_string(),
+                    fallback: "encrypted".to_string(),
+                });
+            }
+        }
+        session
+    }
+
+    /// Session of a backend without negotiation: every optional feature falls back
+    #[cfg(feature = "network-renet")]
+    pub fn without_negotiation(config: &ServerConfig) -> Self {
+        let client_hello = ClientHello {
+            proof: None,
+            quantization_profiles: Vec::new(),
+            compression: false,
+            max_texture_size: None,
+            connect_token: None,
+            tenant: None,
+            protocol_version: PROTOCOL_VERSION,
+            schema: 0,
+            resume: None,
+            credential: None,
+            rekey: false,
+            clear_channels: Vec::new(),
+        };
+        Self::negotiate(config, &client_hello)
+    }
+
+    /// Sign the seed with the passphrase, bound to this handshake challenge.
+    ///
+    /// Without a passphrase there is no shared secret and the seed is sent unsigned.
+    pub fn sign_seed(&mut self, passphrase: Option<&String>, challenge: &[u8]) {
+        self.seed_proof = match (passphrase, self.seed) {
+            (Some(passphrase), Some(seed)) => Some(psk_proof(passphrase, &seed_message(challenge, seed))),
+            _ => None,
+        };
+    }
+
+    /// Check the seed signature on the client; servers predating the seed send neither
+    /// Check the channels the server sends in the clear against `ClientConfig::clear_channels`
+    pub fn check_clear_channels(&self, config: &ClientConfig) -> Result<(), String> {
+        let allowed: Vec<u8> = config.clear_channels.iter().map(|c| c.channel_id()).collect();
+        match self.clear_channels.iter().find(|c| !allowed.contains(c)) {
+            Some(channel_id) => Err(format!("Server sends channel {} in the clear; not allowed", channel_id)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn verify_seed(&self, passphrase: Option<&String>, challenge: &[u8]) -> Result<(), String> {
+        let Some(seed) = self.seed else {
+            return Ok(());
+        };
+        let proof = self.seed_proof.as_ref().map(|p| p.as_slice());
+        verify_psk(passphrase, &seed_message(challenge, seed), proof)
+            .map_err(|_| "Session seed is not signed by the server".to_string())
+    }
+
+    pub fn emit_fallbacks(&self, events: &flume::Sender<ServerEvents>, client_id: ClientId) {
+        for fallback in self.fallbacks.iter() {
+            log::warn!(target: "network", "Client {} lacks feature {}; using {}", client_id, fallback.feature, fallback.fallback);
+            events
+                .send(ServerEvents::FeatureFallback {
+                    client_id,
+                    feature: fallback.feature.clone(),
+                    fallback: fallback.fallback.clone(),
+                })
+                .ok();
+        }
+    }
+}
+
+/// Check the protocol version of the peer; returns the rejection reason if it is no longer spoken.
+///
+/// The newer side decides: a peer of a later version is accepted, it
+/// checks this side against its own `MIN_SUPPORTED_PROTOCOL_VERSION`.
+pub(crate) fn check_protocol_version(peer: u32) -> Result<(), String> {
+    if (MIN_SUPPORTED_PROTOCOL_VERSION..).contains(&peer) {
+        return Ok(());
+    }
+    Err(format!(
+        "Protocol version {} of the peer is no longer supported (oldest {}, current {})",
+        peer, MIN_SUPPORTED_PROTOCOL_VERSION, PROTOCOL_VERSION
+    ))
+}
+
+/// Final handshake frame, sent by the server.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum HandshakeResult {
+    Accepted(SessionParameters),
+    Rejected { reason: String },
+}
+
+fn seed_message(challenge: &[u8], seed: u64) -> Vec<u8> {
+    [challenge, &seed.to_le_bytes()].concat()
+}
+
+/// Compute the pre-shared key proof: HMAC-SHA256(passphrase, challenge)
+pub(crate) fn psk_proof(passphrase: &str, challenge: &[u8]) -> [u8; PROOF_SIZE] {
+    let mut mac = HmacSha256::new_from_slice(passphrase.as_bytes()).expect("hmac accepts any key size");
+    mac.update(challenge);
+    mac.finalize().into_bytes().into()
+}
+
+fn timed_challenge(client_id: u64, timestamp: u64) -> [u8; 16] {
+    let mut challenge = [0; 16];
+    challenge[..8].copy_from_slice(&client_id.to_le_bytes());
+    challenge[8..].copy_from_slice(&timestamp.to_le_bytes());
+    challenge
+}
+
+/// Pre-shared key proof of a renet client, sent in the netcode user data.
+///
+/// Netcode has no challenge round-trip in unsecure mode, so the proof is
+/// bound to the client id and the time instead: HMAC-SHA256(passphrase,
+/// client_id | timestamp), followed by the timestamp (u64 LE, seconds since
+/// the Unix epoch). The server accepts each proof once, within `TIMED_PROOF_WINDOW`.
+pub(crate) fn timed_psk_proof(passphrase: &str, client_id: u64, timestamp: u64) -> [u8; TIMED_PROOF_SIZE] {
+    let mut proof = [0; TIMED_PROOF_SIZE];
+    proof[..PROOF_SIZE].copy_from_slice(&psk_proof(passphrase, &timed_challenge(client_id, timestamp)));
+    proof[PROOF_SIZE..].copy_from_slice(&timestamp.to_le_bytes());
+    proof
+}
+
+/// Proofs of `timed_psk_proof` accepted within the window, so a captured one can't connect again
+#[derive(Default)]
+pub(crate) struct TimedProofs {
+    accepted: Mutex<HashMap<[u8; PROOF_SIZE], u64>>,
+}
+
+impl TimedProofs {
+    /// Check the proof in the user data of the client at `now` (seconds since the Unix epoch).
+    ///
+    /// Returns the rejection reason if the client must not be accepted.
+    pub fn verify(
+        &self,
+        passphrase: Option<&String>,
+        client_id: u64,
+        user_data: Option<&[u8]>,
+        now: u64,
+    ) -> Result<(), String> {
+        if passphrase.is_none() {
+            return Ok(());
+        }
+        let Some(user_data) = user_data.filter(|d| d.len() >= TIMED_PROOF_SIZE) else {
+            return Err("Server requires a password".to_string());
+        };
+        let (proof, timestamp) = user_data[..TIMED_PROOF_SIZE].split_at(PROOF_SIZE);
+        let timestamp = u64::from_le_bytes(timestamp.try_into().unwrap());
+        let window = TIMED_PROOF_WINDOW.as_secs();
+        if now.abs_diff(timestamp) > window {
+            return Err("Password proof expired; check the clock of the client".to_string());
+        }
+        verify_psk(passphrase, &timed_challenge(client_id, timestamp), Some(proof))?;
+
+        let mut accepted = self.accepted.lock();
+        accepted.retain(|_, timestamp| now.abs_diff(*timestamp) <= window);
+        if accepted.insert(proof.try_into().unwrap(), timestamp).is_some() {
+            return Err("Password proof replayed".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Authenticate the encoded `HandshakeResult::Accepted` with the session key
+/// from the server: HMAC-SHA256(key, "session" | accepted). The result is sent
+/// in the clear, so the client checks it before the first sealed frame
+pub(crate) fn session_mac(key: &[u8; KEY_SIZE], accepted: &[u8]) -> [u8; PROOF_SIZE] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("hmac accepts any key size");
+    mac.update(b"session");
+    mac.update(accepted);
+    mac.finalize().into_bytes().into()
+}
+
+pub(crate) fn verify_session_mac(key: &[u8; KEY_SIZE], accepted: &[u8], tag: &[u8]) -> Result<(), String> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("hmac accepts any key size");
+    mac.update(b"session");
+    mac.update(accepted);
+    // Constant-time comparison
+    mac.verify_slice(tag)
+        .map_err(|_| "Session parameters were tampered with".to_string())
+}
+
+/// Open the client connect token if the server requires one (`ServerConfig::private_key`).
+///
+/// Returns the rejection reason if the client must not be accepted.
+pub(crate) fn verify_connect_token(
+    private_key: Option<&PrivateKey>,
+    token: Option<&SealedToken>,
+) -> Result<Option<SessionKeys>, String> {
+    let Some(private_key) = private_key else {
+        return Ok(None);
+    };
+    let Some(token) = token else {
+        return Err("Server requires a connect token".to_string());
+    };
+    token.open(private_key).map(Some)
+}
+
+/// Check the client proof against the server passphrase.
+///
+/// Returns the rejection reason if the client must not be accepted.
+pub(crate) fn verify_psk(passphrase: Option<&String>, challenge: &[u8], proof: Option<&[u8]>) -> Result<(), String> {
+    let Some(passphrase) = passphrase else {
+        return Ok(());
+    };
+    let Some(proof) = proof else {
+        return Err("Server requires a password".to_string());
+    };
+    let mut mac = HmacSha256::new_from_slice(passphrase.as_bytes()).expect("hmac accepts any key size");
+    mac.update(challenge);
+    // Constant-time comparison
+    match mac.verify_slice(proof) {
+        Ok(()) => Ok(()),
+        Err(_) => Err("Wrong server password".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batching::SendBatching;
+    use crate::messages::NetworkMessageType;
+    use crate::security::{generate_private_key, RekeyPolicy};
+    use std::time::Duration;
+
+    /// Hello of a current client; `minimal` lacks every optional feature
+    fn client_hello(minimal: bool) -> ClientHello {
+        ClientHello {
+            proof: None,
+            quantization_profiles: match minimal {
+                true => Vec::new(),
+                false => QuantizationProfile::supported_names(),
+            },
+            compression: !minimal,
+            max_texture_size: None,
+            connect_token: None,
+            tenant: None,
+            protocol_version: PROTOCOL_VERSION,
+            schema: ClientMessages::COUNT as u32,
+            resume: None,
+            credential: None,
+            rekey: !minimal,
+            clear_channels: match minimal {
+                true => Vec::new(),
+                false => vec![NetworkMessageType::Unreliable.channel_id()],
+            },
+        }
+    }
+
+    fn fallbacks(session: &SessionParameters) -> Vec<&str> {
+        session.fallbacks.iter().map(|f| f.feature.as_str()).collect()
+    }
+
+    #[test]
+    fn appended_fields_default_at_end_of_input() {
+        // Hello of a client predating every appended field
+        let old: Option<[u8; PROOF_SIZE]> = None;
+        let encoded = bincode::serialize(&(old, vec!["centimeter".to_string()])).unwrap();
+        let hello: ClientHello = bincode::deserialize(&encoded).unwrap();
+        assert!(!hello.compression);
+        assert_eq!(hello.protocol_version, 0);
+        assert_eq!(hello.credential, None);
+
+        // A corrupt appended field is an error, not its default
+        let mut corrupt = encoded.clone();
+        corrupt.push(2);
+        assert!(bincode::deserialize::<ClientHello>(&corrupt).is_err());
+    }
+
+    #[test]
+    fn timed_proofs_connect_once_within_the_window() {
+        let passphrase = "secret".to_string();
+        let proofs = TimedProofs::default();
+        let now = 1_700_000_000;
+        let proof = timed_psk_proof(&passphrase, 7, now - 5);
+        assert!(proofs.verify(Some(&passphrase), 7, Some(&proof), now).is_ok());
+        // Captured and sent again
+        assert!(proofs.verify(Some(&passphrase), 7, Some(&proof), now).is_err());
+        // Bound to the client id and the passphrase
+        let proof = timed_psk_proof(&passphrase, 7, now);
+        assert!(proofs.verify(Some(&passphrase), 8, Some(&proof), now).is_err());
+        assert!(proofs.verify(Some(&"other".to_string()), 7, Some(&proof), now).is_err());
+        // Too old, even if never used
+        let proof = timed_psk_proof(&passphrase, 9, now - TIMED_PROOF_WINDOW.as_secs() - 1);
+        assert!(proofs.verify(Some(&passphrase), 9, Some(&proof), now).is_err());
+        assert!(proofs.verify(Some(&passphrase), 9, None, now).is_err());
+        assert!(proofs.verify(None, 9, None, now).is_ok());
+    }
+
+    #[test]
+    fn peers_below_the_minimum_version_are_rejected() {
+        // Peers predating the version field report 0, as clients and as servers
+        let old: Option<[u8; PROOF_SIZE]> = None;
+        let encoded = bincode::serialize(&(old, Vec::<String>::new())).unwrap();
+        let hello: ClientHello = bincode::deserialize(&encoded).unwrap();
+        assert!(check_protocol_version(hello.protocol_version).is_err());
+        assert!(check_protocol_version(MIN_SUPPORTED_PROTOCOL_VERSION - 1).is_err());
+        assert!(check_protocol_version(MIN_SUPPORTED_PROTOCOL_VERSION).is_ok());
+        assert!(check_protocol_version(PROTOCOL_VERSION).is_ok());
+        // The newer side decides
+        assert!(check_protocol_version(PROTOCOL_VERSION + 1).is_ok());
+    }
+
+    #[test]
+    fn quantization_falls_back_to_full_precision() {
+        let profile = QuantizationProfile::supported_names().remove(0);
+        let config = ServerConfig::default().with_quantization(NetworkMessageType::WorldInfo, &profile);
+
+        let session = SessionParameters::negotiate(&config, &client_hello(false));
+        assert_eq!(session.quantization.len(), 1);
+        assert!(session.fallbacks.is_empty());
+
+        let session = SessionParameters::negotiate(&config, &client_hello(true));
+        let feature = format!("quantization:{}", profile);
+        assert!(session.quantization.is_empty());
+        assert_eq!(fallbacks(&session), vec![feature.as_str()]);
+
+        let session = SessionParameters::negotiate(&ServerConfig::default(), &client_hello(false));
+        assert!(session.quantization.is_empty());
+        assert!(session.fallbacks.is_empty());
+    }
+
+    #[test]
+    fn compression_matrix() {
+        let compressing = ServerConfig::default().with_compression(256);
+        let plain = ServerConfig::default();
+        let cases = [
+            (&compressing, true, Some(256), vec![]),
+            (&compressing, false, None, vec!["compression"]),
+            (&plain, true, None, vec![]),
+            (&plain, false, None, vec![]),
+        ];
+        for (config, client_compression, threshold, expected) in cases {
+            let mut hello = client_hello(false);
+            hello.compression = client_compression;
+            let session = SessionParameters::negotiate(config, &hello);
+            assert_eq!(session.compression_threshold, threshold);
+            assert_eq!(fallbacks(&session), expected);
+        }
+    }
+
+    #[test]
+    fn rekey_falls_back_to_token_keys() {
+        let policy = RekeyPolicy::new(Duration::from_secs(600));
+        let mut config = ServerConfig::default().with_rekey(policy);
+
+        // Unencrypted sessions have no keys to rotate
+        let session = SessionParameters::negotiate(&config, &client_hello(false));
+        assert_eq!(session.rekey, None);
+
+        config.private_key = Some(generate_private_key());
+        let session = SessionParameters::negotiate(&config, &client_hello(false));
+        assert_eq!(session.rekey, Some(policy));
+        assert!(session.fallbacks.is_empty());
+
+        let session = SessionParameters::negotiate(&config, &client_hello(true));
+        assert_eq!(session.rekey, None);
+        assert_eq!(fallbacks(&session), vec!["rekey"]);
+    }
+
+    #[test]
+    fn clear_channels_fall_back_to_encrypted() {
+        let mut config = ServerConfig::default().with_clear_channel(NetworkMessageType::Unreliable);
+        config.private_key = Some(generate_private_key());
+        let channel_id = NetworkMessageType::Unreliable.channel_id();
+
+        let session = SessionParameters::negotiate(&config, &client_hello(false));
+        assert_eq!(session.clear_channels, vec![channel_id]);
+        assert!(session.fallbacks.is_empty());
+
+        let session = SessionParameters::negotiate(&config, &client_hello(true));
+        assert!(session.clear_channels.is_empty());
+        assert_eq!(fallbacks(&session), vec!["clear channels"]);
+
+        // Only the channels the client allows go in the clear
+        let config = config.with_clear_channel(NetworkMessageType::WorldInfo);
+        let session = SessionParameters::negotiate(&config, &client_hello(false));
+        assert_eq!(session.clear_channels, vec![channel_id]);
+        assert_eq!(fallbacks(&session), vec!["clear channels"]);
+    }
+
+    #[test]
+    fn client_refuses_channels_it_did_not_allow() {
+        let channel = NetworkMessageType::Unreliable;
+        let session = SessionParameters {
+            clear_channels: vec![channel.channel_id()],
+            ..Default::default()
+        };
+        assert!(session.check_clear_channels(&ClientConfig::default()).is_err());
+        let config = ClientConfig::default().with_clear_channel(channel);
+        assert!(session.check_clear_channels(&config).is_ok());
+    }
+
+    #[test]
+    fn session_mac_detects_rewritten_parameters() {
+        let key = [7; KEY_SIZE];
+        let session = SessionParameters {
+            clear_channels: vec![NetworkMessageType::Unreliable.channel_id()],
+            ..Default::default()
+        };
+        let accepted = bincode::serialize(&HandshakeResult::Accepted(session.clone())).unwrap();
+        let tag = session_mac(&key, &accepted);
+        assert!(verify_session_mac(&key, &accepted, &tag).is_ok());
+
+        let rewritten = SessionParameters {
+            clear_channels: (0..5).collect(),
+            ..session
+        };
+        let rewritten = bincode::serialize(&HandshakeResult::Accepted(rewritten)).unwrap();
+        assert!(verify_session_mac(&key, &rewritten, &tag).is_err());
+        assert!(verify_session_mac(&[8; KEY_SIZE], &accepted, &tag).is_err());
+    }
+
+    #[test]
+    fn batching_needs_no_negotiation() {
+        let config = ServerConfig::default().with_send_batching(SendBatching::default());
+        for minimal in [false, true] {
+            let session = SessionParameters::negotiate(&config, &client_hello(minimal));
+            assert!(session.fallbacks.is_empty());
+        }
+    }
+
+    #[test]
+    fn fallbacks_add_up() {
+        let profile = QuantizationProfile::supported_names().remove(0);
+        let mut config = ServerConfig::default()
+            .with_compression(256)
+            .with_quantization(NetworkMessageType::WorldInfo, &profile)
+            .with_send_batching(SendBatching::default())
+            .with_rekey(RekeyPolicy::new(Duration::from_secs(600)))
+            .with_clear_channel(NetworkMessageType::Unreliable);
+        config.private_key = Some(generate_private_key());
+
+        let session = SessionParameters::negotiate(&config, &client_hello(true));
+        let mut features = fallbacks(&session);
+        features.sort();
+        let quantization = format!("quantization:{}", profile);
+        assert_eq!(
+            features,
+            vec!["clear channels", "compression", quantization.as_str(), "rekey"]
+        );
+    }
+}
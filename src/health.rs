@@ -0,0 +1,137 @@
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Number of recent ticks used for the tick time percentile
+const TICK_SAMPLES: usize = 1024;
+
+/// Server statistics collected by `step()`.
+pub(crate) struct ServerStats {
+    started: Instant,
+    players: AtomicUsize,
+    last_tick: Mutex<Option<Instant>>,
+    tick_times: Mutex<VecDeque<Duration>>,
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            players: Default::default(),
+            last_tick: Default::default(),
+            tick_times: Mutex::new(VecDeque::with_capacity(TICK_SAMPLES)),
+        }
+    }
+}
+
+impl ServerStats {
+    /// Called at the start of every `step()`; the tick time is the
+    /// interval between two consecutive steps. Returns that interval.
+    pub fn record_tick(&self, players: usize) -> Option<Duration> {
+        self.players.store(players, Ordering::Relaxed);
+
+        let now = Instant::now();
+        let last_tick = self.last_tick.lock().replace(now)?;
+        let tick_time = now - last_tick;
+        let mut tick_times = self.tick_times.lock();
+        if tick_times.len() == TICK_SAMPLES {
+            tick_times.pop_front();
+        }
+        tick_times.push_back(tick_time);
+        Some(tick_time)
+    }
+
+    /// Connections at the last `step()`
+    pub fn get_players(&self) -> usize {
+        self.players.load(Ordering::Relaxed)
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn since_last_tick(&self) -> Option<Duration> {
+        self.last_tick.lock().map(|t| t.elapsed())
+    }
+
+    pub fn tick_time_percentile(&self, percentile: f64) -> Duration {
+        let mut samples: Vec<Duration> = self.tick_times.lock().iter().copied().collect();
+        if samples.is_empty() {
+            return Duration::ZERO;
+        }
+        samples.sort_unstable();
+        let index = ((samples.len() - 1) as f64 * percentile).round() as usize;
+        samples[index]
+    }
+
+    #[cfg_attr(not(feature = "health-http"), allow(dead_code))]
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"players\":{},\"uptime_secs\":{},\"tick_time_p99_ms\":{:.3}}}",
+            self.players.load(Ordering::Relaxed),
+            self.started.elapsed().as_secs(),
+            self.tick_time_percentile(0.99).as_secs_f64() * 1000.0,
+        )
+    }
+}
+
+/// Start the endpoint configured by `ServerConfig::health_address`
+pub(crate) async fn start_health_endpoint(address: Option<SocketAddr>, stats: &Arc<ServerStats>) {
+    let Some(address) = address else {
+        return;
+    };
+    #[cfg(feature = "health-http")]
+    if let Err(e) = serve_health(address, stats.clone()).await {
+        log::error!(target: "network", "{}", e);
+    }
+    #[cfg(not(feature = "health-http"))]
+    {
+        let _ = stats;
+        log::warn!(target: "network", "Health endpoint {} requires the health-http feature", address);
+    }
+}
+
+/// Serve the statistics as JSON on `GET /health`.
+#[cfg(feature = "health-http")]
+async fn serve_health(address: SocketAddr, stats: Arc<ServerStats>) -> Result<(), String> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind(address)
+        .await
+        .map_err(|e| format!("Health endpoint bind error: {}", e))?;
+    log::info!(target: "network", "Health endpoint listening on {}", address);
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut stream, _addr)) = listener.accept().await else {
+                continue;
+            };
+            let stats = stats.clone();
+            tokio::spawn(async move {
+                let mut request = [0u8; 1024];
+                let Ok(size) = stream.read(&mut request).await else {
+                    return;
+                };
+                let request = String::from_utf8_lossy(&request[..size]);
+                let response = match request.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+                    ["GET", "/health"] => {
+                        let body = stats.to_json();
+                        format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            body.len(),
+                            body
+                        )
+                    }
+                    _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+                };
+                stream.write_all(response.as_bytes()).await.ok();
+            });
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,123 @@
+use common::chunks::chunk_position::ChunkPosition;
+use std::collections::{HashMap, HashSet};
+
+use crate::messages::ServerMessages;
+
+fn in_radius(radius: u32, center: &ChunkPosition, chunk_position: &ChunkPosition) -> bool {
+    let radius = radius as i64;
+    (chunk_position.x - center.x).abs() <= radius && (chunk_position.z - center.z).abs() <= radius
+}
+
+/// Chunks a connection currently holds, per world.
+///
+/// Chunks are recorded as they are sent to the client; when the
+/// connection center moves, everything outside the radius is reported
+/// back as `ServerMessages::UnloadChunks`.
+#[derive(Debug, Default)]
+pub struct ChunkInterest {
+    radius: u32,
+    loaded: HashMap<String, HashSet<ChunkPosition>>,
+}
+
+impl ChunkInterest {
+    pub fn new(radius: u32) -> Self {
+        Self {
+            radius,
+            loaded: Default::default(),
+        }
+    }
+
+    pub fn get_radius(&self) -> u32 {
+        self.radius
+    }
+
+    pub fn is_loaded(&self, world_slug: &str, chunk_position: &ChunkPosition) -> bool {
+        self.loaded
+            .get(world_slug)
+            .map(|chunks| chunks.contains(chunk_position))
+            .unwrap_or(false)
+    }
+
+    pub fn is_in_radius(&self, center: &ChunkPosition, chunk_position: &ChunkPosition) -> bool {
+        in_radius(self.radius, center, chunk_position)
+    }
+
+    /// Record a chunk of the world, e.g. a subscription of `crate::area_of_interest`
+    pub(crate) fn insert(&mut self, world_slug: &str, chunk_position: ChunkPosition) {
+        self.loaded
+            .entry(world_slug.to_string())
+            .or_default()
+            .insert(chunk_position);
+    }
+
+    pub(crate) fn remove(&mut self, world_slug: &str, chunk_position: &ChunkPosition) {
+        if let Some(loaded) = self.loaded.get_mut(world_slug) {
+            loaded.remove(chunk_position);
+            if loaded.is_empty() {
+                self.loaded.remove(world_slug);
+            }
+        }
+    }
+
+    /// Every chunk recorded, with its world
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&String, &ChunkPosition)> {
+        self.loaded
+            .iter()
+            .flat_map(|(world_slug, chunks)| chunks.iter().map(move |chunk_position| (world_slug, chunk_position)))
+    }
+
+    /// Track chunk loads and unloads sent to the client
+    pub(crate) fn observe(&mut self, message: &ServerMessages) {
+        match message {
+            ServerMessages::ChunkSectionInfo {
+                world_slug,
+                chunk_position,
+                ..
+            }
+            | ServerMessages::ChunkSectionInfoEncoded {
+                world_slug,
+                chunk_position,
+                ..
+            } => {
+                self.insert(world_slug, *chunk_position);
+            }
+            ServerMessages::UnloadChunks { world_slug, chunks } => {
+                for chunk_position in chunks.iter() {
+                    self.remove(world_slug, chunk_position);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Move the connection center and return the unload messages to send.
+    ///
+    /// Chunks of other worlds are unloaded entirely.
+    pub(crate) fn set_center(&mut self, world_slug: &str, center: ChunkPosition) -> Vec<ServerMessages> {
+        let radius = self.radius;
+        let mut messages = Vec::new();
+        for (slug, loaded) in self.loaded.iter_mut() {
+            let chunks: Vec<ChunkPosition> = if slug == world_slug {
+                loaded
+                    .iter()
+                    .filter(|c| !in_radius(radius, &center, c))
+                    .copied()
+                    .collect()
+            } else {
+                loaded.iter().copied().collect()
+            };
+            if chunks.is_empty() {
+                continue;
+            }
+            for chunk_position in chunks.iter() {
+                loaded.remove(chunk_position);
+            }
+            messages.push(ServerMessages::UnloadChunks {
+                world_slug: slug.clone(),
+                chunks,
+            });
+        }
+        self.loaded.retain(|_, loaded| !loaded.is_empty());
+        messages
+    }
+}
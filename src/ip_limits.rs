@@ -0,0 +1,93 @@
+//! Limit of concurrent connections from one IP address.
+//!
+//! Players behind one NAT (a café, a school, a household) legitimately share
+//! an address, while a griefer can open dozens of connections from one box;
+//! no single number fits both, so `ServerConfig::ip_limit` takes a default
+//! limit with overrides for known addresses and an `IpLimitPolicy` for what
+//! happens to the connection over the limit. Every case is surfaced as
+//! `ServerEvents::IpLimitReached`, so the game can tell the two apart.
+//!
+//! Connections without an IP address, such as the local socket, are not
+//! limited. Unlimited by default.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::client_id::ClientId;
+
+/// What happens to a connection over `IpLimit`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpLimitPolicy {
+    /// Refuse the new connection with `RejectionReason::TooManyConnections`
+    #[default]
+    Reject,
+    /// Accept the new connection and disconnect the oldest one of the address
+    ReplaceOldest,
+    /// Accept the new connection and only report it
+    Report,
+}
+
+/// Concurrent connections allowed per IP address, see `ServerConfig::with_ip_limit`
+#[derive(Debug, Clone)]
+pub struct IpLimit {
+    max_per_ip: usize,
+    policy: IpLimitPolicy,
+    overrides: HashMap<IpAddr, usize>,
+}
+
+impl IpLimit {
+    pub fn new(max_per_ip: usize) -> Self {
+        Self {
+            max_per_ip,
+            policy: Default::default(),
+            overrides: HashMap::new(),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: IpLimitPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Own limit of an address known to be shared, or known to be abused
+    pub fn with_override(mut self, ip: IpAddr, max: usize) -> Self {
+        self.overrides.insert(ip, max);
+        self
+    }
+
+    pub fn get_policy(&self) -> IpLimitPolicy {
+        self.policy
+    }
+
+    pub fn get_max(&self, ip: &IpAddr) -> usize {
+        self.overrides.get(ip).copied().unwrap_or(self.max_per_ip)
+    }
+
+    /// `existing` are the client ids already connected from `ip`, the oldest first
+    pub(crate) fn check(&self, ip: &IpAddr, existing: &[ClientId]) -> IpLimitDecision {
+        if existing.len() < self.get_max(ip) {
+            return IpLimitDecision::Accept;
+        }
+        match self.policy {
+            IpLimitPolicy::Reject => IpLimitDecision::Reject,
+            IpLimitPolicy::ReplaceOldest => match existing.first() {
+                Some(&oldest) => IpLimitDecision::Replace(oldest),
+                None => IpLimitDecision::Reject,
+            },
+            IpLimitPolicy::Report => IpLimitDecision::Report,
+        }
+    }
+}
+
+pub(crate) enum IpLimitDecision {
+    Accept,
+    Reject,
+    /// Disconnect this client id of the same address
+    Replace(ClientId),
+    Report,
+}
+
+/// Address of a connection; None for the local socket
+pub(crate) fn parse_ip(ip: &str) -> Option<IpAddr> {
+    ip.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
@@ -0,0 +1,95 @@
+//! Journal of recently sent client messages, for crash reports.
+//!
+//! Enabled with `ClientConfig::with_message_journal`; only the metadata
+//! of each message is kept, never its content.
+
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::messages::NetworkMessageType;
+
+/// Upper bound of the journal, whatever the retention
+pub const MAX_JOURNAL_ENTRIES: usize = 4096;
+
+/// How long a crash handler waits for a journal held by the crashing thread
+const DUMP_LOCK_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+pub struct JournalEntry {
+    /// Milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub variant: String,
+    pub message_type: NetworkMessageType,
+    /// Bytes sent, after compression
+    pub size: usize,
+}
+
+impl fmt::Display for JournalEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {:?} {}b",
+            self.timestamp, self.variant, self.message_type, self.size
+        )
+    }
+}
+
+/// Ring buffer of the messages sent during the last `retention`.
+///
+/// Clones share the buffer, so a crash handler can hold one of its own.
+#[derive(Debug, Clone)]
+pub struct MessageJournal {
+    retention: Duration,
+    entries: Arc<Mutex<VecDeque<(Instant, JournalEntry)>>>,
+}
+
+impl MessageJournal {
+    pub fn new(retention: Duration) -> Self {
+        Self {
+            retention,
+            entries: Default::default(),
+        }
+    }
+
+    pub(crate) fn record(&self, variant: &str, message_type: NetworkMessageType, size: usize) {
+        let now = Instant::now();
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let mut entries = self.entries.lock();
+        while let Some((sent, _)) = entries.front() {
+            if now.duration_since(*sent) <= self.retention && entries.len() < MAX_JOURNAL_ENTRIES {
+                break;
+            }
+            entries.pop_front();
+        }
+        let entry = JournalEntry {
+            timestamp,
+            variant: variant.to_string(),
+            message_type,
+            size,
+        };
+        entries.push_back((now, entry));
+    }
+
+    /// Entries of the last `retention`, oldest first.
+    ///
+    /// Empty if the journal stays locked, e.g. by the thread that panicked.
+    pub fn dump(&self) -> Vec<JournalEntry> {
+        let Some(entries) = self.entries.try_lock_for(DUMP_LOCK_TIMEOUT) else {
+            return Vec::new();
+        };
+        let now = Instant::now();
+        entries
+            .iter()
+            .filter(|(sent, _)| now.duration_since(*sent) <= self.retention)
+            .map(|(_, entry)| entry.clone())
+            .collect()
+    }
+}
@@ -0,0 +1,302 @@
+//! Small replicated key-value stores: scores, world time, weather.
+//!
+//! The server keeps a `KeyedState` per world or per connection and changes
+//! it with `set` and `remove`; `IServerConnection::sync_state` then sends the
+//! client the keys changed since the version it last acknowledged, over the
+//! unreliable channel. A connection that acknowledged no version of the
+//! store, such as a client that reconnected, gets every key instead and its
+//! replica is replaced.
+//!
+//! The client applies `ServerMessages::StateSync` to its `StateReplica`,
+//! reports each changed key as a `StateChange`
+//! (`IClientNetwork::drain_state_changes`) and acknowledges the version from
+//! `step()`. A lost sync is never resent as such: the next one starts from
+//! the older acknowledged version and covers it, so the replica catches up
+//! without the head-of-line blocking of the reliable channel. Call
+//! `sync_state` every tick; an unacknowledged version is resent every
+//! `STATE_RESEND_INTERVAL` until it is.
+
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::messages::{ClientMessages, ServerMessages};
+use crate::scoreboard::{Scoreboard, SCOREBOARD_STATE};
+
+/// Interval between two syncs of the same version while it is unacknowledged
+pub const STATE_RESEND_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Server side store; values are bincode encoded
+#[derive(Debug, Clone)]
+pub struct KeyedState {
+    name: String,
+    version: u64,
+    /// Version of the last change of each key; None once removed
+    entries: BTreeMap<String, (u64, Option<Vec<u8>>)>,
+}
+
+impl KeyedState {
+    /// `name` tells the stores apart on the client
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            version: 0,
+            entries: Default::default(),
+        }
+    }
+
+    pub fn get_name(&self) -> &String {
+        &self.name
+    }
+
+    /// Bumped by every change
+    pub fn get_version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn set<T: Serialize>(&mut self, key: &str, value: &T) -> Result<(), String> {
+        let encoded = bincode::serialize(value).map_err(|e| e.to_string())?;
+        self.set_raw(key, encoded);
+        Ok(())
+    }
+
+    /// Setting the current value again is not a change
+    pub fn set_raw(&mut self, key: &str, value: Vec<u8>) {
+        if self.get_raw(key) == Some(&value) {
+            return;
+        }
+        self.version += 1;
+        self.entries.insert(key.to_string(), (self.version, Some(value)));
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        if self.get_raw(key).is_none() {
+            return;
+        }
+        self.version += 1;
+        self.entries.insert(key.to_string(), (self.version, None));
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        bincode::deserialize(self.get_raw(key)?).ok()
+    }
+
+    pub fn get_raw(&self, key: &str) -> Option<&Vec<u8>> {
+        self.entries.get(key)?.1.as_ref()
+    }
+
+    /// Keys set, in order
+    pub fn keys(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter(|(_, (_, value))| value.is_some())
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.values().filter(|(_, value)| value.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug, Default)]
+struct StoreSync {
+    acked: Option<u64>,
+    /// Last version sent, and when
+    sent: Option<(u64, Instant)>,
+}
+
+/// Versions of each store sent to a connection and acknowledged by it
+#[derive(Debug, Default)]
+pub struct StateSyncs {
+    synced: Mutex<HashMap<String, StoreSync>>,
+}
+
+impl StateSyncs {
+    /// `ServerMessages::StateSync` of the changes since the acknowledged version;
+    /// None if the client has the current one or it was sent recently
+    pub(crate) fn delta(&self, state: &KeyedState) -> Option<ServerMessages> {
+        let mut synced = self.synced.lock();
+        let sync = synced.entry(state.name.clone()).or_default();
+        if sync.acked == Some(state.version) {
+            return None;
+        }
+        let recent = |(version, at): (u64, Instant)| version == state.version && at.elapsed() < STATE_RESEND_INTERVAL;
+        if sync.sent.is_some_and(recent) {
+            return None;
+        }
+        sync.sent = Some((state.version, Instant::now()));
+
+        let since = sync.acked.unwrap_or_default();
+        let mut changed = Vec::new();
+        let mut removed = Vec::new();
+        for (key, (version, value)) in state.entries.iter().filter(|(_, (version, _))| *version > since) {
+            match value {
+                Some(value) => changed.push((key.clone(), value.clone())),
+                None if sync.acked.is_some() => removed.push(key.clone()),
+                None => {}
+            }
+        }
+        Some(ServerMessages::StateSync {
+            name: state.name.clone(),
+            baseline: sync.acked,
+            changed,
+            removed,
+            version: state.version,
+        })
+    }
+
+    /// `ClientMessages::StateAck`; versions not sent yet or older than the last ack are ignored
+    pub(crate) fn ack(&self, name: &str, version: u64) {
+        let mut synced = self.synced.lock();
+        let Some(sync) = synced.get_mut(name) else {
+            return;
+        };
+        let sent = sync.sent.is_some_and(|(sent, _)| version <= sent);
+        if sent && sync.acked.map_or(true, |acked| version > acked) {
+            sync.acked = Some(version);
+        }
+    }
+
+    /// Sync the store from scratch on the next `sync_state`
+    pub fn forget(&self, name: &str) {
+        self.synced.lock().remove(name);
+    }
+}
+
+/// A key of a store changed on the client
+#[derive(Debug, Clone)]
+pub struct StateChange {
+    pub name: String,
+    pub key: String,
+    /// None if the key was removed
+    pub value: Option<Vec<u8>>,
+}
+
+impl StateChange {
+    pub fn decode<T: DeserializeOwned>(&self) -> Option<T> {
+        bincode::deserialize(self.value.as_ref()?).ok()
+    }
+}
+
+#[derive(Debug, Default)]
+struct ReplicaStore {
+    version: u64,
+    entries: BTreeMap<String, Vec<u8>>,
+}
+
+/// Client copy of the stores synced by the server
+#[derive(Debug, Default)]
+pub struct StateReplica {
+    stores: Mutex<HashMap<String, ReplicaStore>>,
+    changes: Mutex<Vec<StateChange>>,
+    /// Version of each store to acknowledge on the next `step()`
+    pending_acks: Mutex<HashMap<String, u64>>,
+}
+
+impl StateReplica {
+    pub fn get<T: DeserializeOwned>(&self, name: &str, key: &str) -> Option<T> {
+        bincode::deserialize(&self.get_raw(name, key)?).ok()
+    }
+
+    pub fn get_raw(&self, name: &str, key: &str) -> Option<Vec<u8>> {
+        self.stores.lock().get(name)?.entries.get(key).cloned()
+    }
+
+    /// Keys of the store, in order
+    pub fn keys(&self, name: &str) -> Vec<String> {
+        self.stores
+            .lock()
+            .get(name)
+            .map(|store| store.entries.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Version of the store the replica is at; None until its first sync
+    pub fn get_version(&self, name: &str) -> Option<u64> {
+        Some(self.stores.lock().get(name)?.version)
+    }
+
+    /// Consume state syncs; any other message is returned back.
+    /// The scoreboard is rebuilt from its store, see `crate::scoreboard`
+    pub(crate) fn route(&self, message: ServerMessages, scoreboard: &Scoreboard) -> Option<ServerMessages> {
+        let ServerMessages::StateSync {
+            name,
+            baseline,
+            changed,
+            removed,
+            version,
+        } = message
+        else {
+            return Some(message);
+        };
+        let mut stores = self.stores.lock();
+        let current = stores.get(&name).map(|store| store.version);
+        match (baseline, current) {
+            // Late or resent; acknowledged again in case the ack was lost
+            (_, Some(current)) if version <= current => {
+                self.pending_acks.lock().insert(name, current);
+                return None;
+            }
+            (Some(baseline), current) if current.map_or(true, |current| current < baseline) => {
+                log::warn!(target: "network", "State sync {} of \"{}\" from unknown version {}; dropped", version, name, baseline);
+                return None;
+            }
+            _ => {}
+        }
+        let store = stores.entry(name.clone()).or_default();
+        store.version = version;
+        let store = &mut store.entries;
+        let mut changes = Vec::new();
+        if baseline.is_none() {
+            let mut previous = std::mem::take(store);
+            for (key, value) in changed {
+                if previous.remove(&key).as_ref() != Some(&value) {
+                    changes.push((key.clone(), Some(value.clone())));
+                }
+                store.insert(key, value);
+            }
+            changes.extend(previous.into_keys().map(|key| (key, None)));
+        } else {
+            for key in removed {
+                if store.remove(&key).is_some() {
+                    changes.push((key, None));
+                }
+            }
+            for (key, value) in changed {
+                store.insert(key.clone(), value.clone());
+                changes.push((key, Some(value)));
+            }
+        }
+        if name == SCOREBOARD_STATE {
+            scoreboard.rebuild(store);
+        }
+        self.pending_acks.lock().insert(name.clone(), version);
+        self.changes
+            .lock()
+            .extend(changes.into_iter().map(|(key, value)| StateChange {
+                name: name.clone(),
+                key,
+                value,
+            }));
+        None
+    }
+
+    pub(crate) fn take_changes(&self) -> Vec<StateChange> {
+        std::mem::take(&mut *self.changes.lock())
+    }
+
+    /// `ClientMessages::StateAck` of the syncs received since the last call; sent by the client `step()`
+    pub(crate) fn take_acks(&self) -> Vec<ClientMessages> {
+        self.pending_acks
+            .lock()
+            .drain()
+            .map(|(name, version)| ClientMessages::StateAck { name, version })
+            .collect()
+    }
+}
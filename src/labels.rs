@@ -0,0 +1,52 @@
+//! Human-readable names of connections for operator-facing logs.
+//!
+//! `IServerConnection::set_label` names a connection, e.g. after the
+//! player. The logs of the crate then show `name (#client_id)` for it
+//! instead of the bare client id; events and errors keep the client id.
+
+use parking_lot::RwLock;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::client_id::ClientId;
+
+/// Label of one connection, shared by its handles and tasks
+#[derive(Debug, Clone)]
+pub struct ConnectionLabel {
+    client_id: ClientId,
+    label: Arc<RwLock<Option<String>>>,
+}
+
+impl ConnectionLabel {
+    pub(crate) fn new(client_id: ClientId) -> Self {
+        Self {
+            client_id,
+            label: Default::default(),
+        }
+    }
+
+    pub fn get_client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    pub fn get(&self) -> Option<String> {
+        self.label.read().clone()
+    }
+
+    pub fn set(&self, label: impl Into<String>) {
+        *self.label.write() = Some(label.into());
+    }
+
+    pub fn clear(&self) {
+        *self.label.write() = None;
+    }
+}
+
+impl fmt::Display for ConnectionLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.label.read().as_ref() {
+            Some(label) => write!(f, "{} (#{})", label, self.client_id),
+            None => write!(f, "{}", self.client_id),
+        }
+    }
+}
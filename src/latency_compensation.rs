@@ -0,0 +1,100 @@
+//! Latency-compensated broadcasts.
+//!
+//! `IServerNetwork::broadcast_synchronized` sends a time-critical event
+//! (round start, countdown) so that it reaches every client at about the
+//! same moment: the slowest connection gets it at once, the others are held
+//! back by the difference of their one-way latency, half their round-trip
+//! time, to the slowest one. Compensation is capped by
+//! `ServerConfig::max_latency_compensation`; clients slower than the cap get
+//! the message at once and late.
+//!
+//! Held messages go out in the first `step()` after they are due, so the
+//! spread left is about one tick; run the server at a higher tick rate for
+//! tighter timing. A connection without a round-trip sample yet counts as
+//! having no latency.
+
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::client_id::ClientId;
+use crate::messages::{NetworkMessageType, ServerMessages};
+
+/// Longest a message is held for the fastest client if the config sets no cap
+pub const DEFAULT_MAX_COMPENSATION: Duration = Duration::from_millis(200);
+
+struct HeldBroadcast {
+    message_type: NetworkMessageType,
+    message: ServerMessages,
+    /// Clients not sent to yet, with the moment they are due
+    sends: Vec<(Instant, ClientId)>,
+}
+
+/// Broadcasts held by `IServerNetwork::broadcast_synchronized`
+#[derive(Default)]
+pub struct SynchronizedBroadcasts {
+    held: Mutex<Vec<HeldBroadcast>>,
+}
+
+impl SynchronizedBroadcasts {
+    /// Hold the message for the clients by their one-way latency; returns
+    /// the clients to send it to at once
+    pub(crate) fn schedule(
+        &self,
+        round_trips: Vec<(ClientId, Duration)>,
+        max_compensation: Duration,
+        message_type: NetworkMessageType,
+        message: &ServerMessages,
+    ) -> Vec<ClientId> {
+        let latencies: Vec<(ClientId, Duration)> = round_trips.into_iter().map(|(id, rtt)| (id, rtt / 2)).collect();
+        let slowest = latencies.iter().map(|(_, latency)| *latency).max().unwrap_or_default();
+        let target = slowest.min(max_compensation);
+
+        let now = Instant::now();
+        let mut immediate = Vec::new();
+        let mut sends = Vec::new();
+        for (client_id, latency) in latencies {
+            match target.checked_sub(latency) {
+                Some(hold) if !hold.is_zero() => sends.push((now + hold, client_id)),
+                _ => immediate.push(client_id),
+            }
+        }
+        if !sends.is_empty() {
+            self.held.lock().push(HeldBroadcast {
+                message_type,
+                message: message.clone(),
+                sends,
+            });
+        }
+        immediate
+    }
+
+    /// Messages due by `now`, with the clients to send each to
+    pub(crate) fn take_due(&self, now: Instant) -> Vec<(NetworkMessageType, ServerMessages, Vec<ClientId>)> {
+        let mut held = self.held.lock();
+        let mut due = Vec::new();
+        for broadcast in held.iter_mut() {
+            let client_ids: Vec<ClientId> = broadcast
+                .sends
+                .iter()
+                .filter(|(at, _)| *at <= now)
+                .map(|(_, client_id)| *client_id)
+                .collect();
+            if client_ids.is_empty() {
+                continue;
+            }
+            broadcast.sends.retain(|(at, _)| *at > now);
+            due.push((broadcast.message_type, broadcast.message.clone(), client_ids));
+        }
+        held.retain(|broadcast| !broadcast.sends.is_empty());
+        due
+    }
+
+    /// Sends still held
+    pub fn len(&self) -> usize {
+        self.held.lock().iter().map(|broadcast| broadcast.sends.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.held.lock().is_empty()
+    }
+}
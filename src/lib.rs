@@ -3,6 +3,80 @@ pub mod client;
 pub mod server;
 pub mod entities;
 pub mod interpolation;
+pub mod socket_options;
+pub mod proxy;
+pub(crate) mod handshake;
+pub mod audit;
+pub mod size_limits;
+pub mod quantization;
+pub mod interest;
+pub mod streams;
+pub(crate) mod health;
+pub mod conditions;
+pub mod watchdog;
+pub mod errors;
+pub mod tick;
+pub mod tick_report;
+pub mod datagram;
+pub mod network_info;
+pub mod shaping;
+pub(crate) mod compression;
+pub mod routing;
+pub mod fragmentation;
+pub mod groups;
+pub mod area_of_interest;
+pub mod approval;
+pub mod journal;
+pub mod security;
+pub mod draining;
+pub mod tenants;
+pub mod rate_limits;
+pub mod timeouts;
+pub mod thresholds;
+pub mod snapshots;
+pub mod decode_budget;
+pub mod time_sync;
+pub mod priorities;
+pub mod rpc;
+pub mod retries;
+pub mod coalescing;
+pub mod generation;
+pub mod recording;
+pub mod labels;
+pub mod blocking;
+pub mod phases;
+pub mod resume;
+pub mod discovery;
+pub mod ip_limits;
+pub mod raw_messages;
+pub mod batching;
+pub mod validation;
+pub mod non_finite;
+pub mod socket_errors;
+pub mod game_client;
+pub mod dispatch;
+pub mod latency_compensation;
+pub mod write_ahead;
+pub mod echo;
+pub mod keyed_state;
+pub mod scoreboard;
+pub mod environment;
+pub mod diagnostics;
+pub mod system;
+pub mod client_id;
+pub mod auth;
+pub mod tuning;
+pub mod trace_context;
+pub mod degraded;
+
+#[cfg(feature = "test-vectors")]
+pub mod test_vectors;
+
+#[cfg(all(feature = "network-renet", feature = "network-tokio"))]
+compile_error!("Enable one backend: network-tokio is a default feature, disable it with --no-default-features");
+
+#[cfg(all(feature = "io-uring", not(feature = "network-renet")))]
+compile_error!("The io-uring feature runs the renet server: --no-default-features --features network-renet,io-uring");
 
 #[cfg(feature = "network-renet")]
 pub mod renet;
@@ -19,6 +93,9 @@ pub type NetworkServerConnection = renet::server::RenetServerConnection;
 #[cfg(feature = "network-tokio")]
 pub mod tokio;
 
+#[cfg(feature = "network-tokio")]
+pub mod protocol;
+
 #[cfg(feature = "network-tokio")]
 pub type NetworkClient = tokio::client::TokioClient;
 
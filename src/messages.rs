@@ -9,12 +9,36 @@ use common::inventory::inventory::{ClientInventory, InventoryType};
 use common::inventory::item::ClientItem;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
 use strum_macros::AsRefStr;
 use strum_macros::Display;
+use strum_macros::EnumCount;
+use strum_macros::EnumDiscriminants;
+use strum_macros::FromRepr;
+use strum_macros::IntoStaticStr;
 
+use crate::approval::RejectionReason;
 use crate::entities::{AnimationState, EntityNetworkComponent};
+use crate::rpc::RpcFrame;
+use crate::scoreboard::DisplaySlot;
+use crate::snapshots::EntityState;
+use crate::trace_context::TraceContext;
 
-#[derive(Debug, Serialize, Deserialize, Clone, Display)]
+/// Version of the wire format, checked in the handshake: peers older than
+/// `MIN_SUPPORTED_PROTOCOL_VERSION` are rejected. Bump it on changes that
+/// break the decoding of existing messages; appending a variant does not,
+/// see `newer_variant`
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest `PROTOCOL_VERSION` of a peer still spoken, the first whose wire
+/// format this one decodes. Peers predating the version field (the 0.1.0
+/// release) report 0: their frames and handshake are not the current ones
+pub const MIN_SUPPORTED_PROTOCOL_VERSION: u32 = 1;
+
+// `ClientMessagesDiscriminants` names the variant of an undecoded message, see crate::raw_messages
+#[derive(Debug, Serialize, Deserialize, Clone, Display, AsRefStr, EnumCount, EnumDiscriminants)]
+#[strum(serialize_all = "kebab-case")]
+#[strum_discriminants(derive(IntoStaticStr, FromRepr), strum(serialize_all = "kebab-case"))]
 pub enum ClientMessages {
     ConnectionInfo {
         login: String,
@@ -26,7 +50,9 @@ pub enum ClientMessages {
         command: String,
     },
     PlayerMove {
+        #[serde(with = "crate::quantization::position")]
         position: Vector3,
+        #[serde(with = "crate::quantization::rotation")]
         rotation: Rotation,
         animation_state: AnimationState,
     },
@@ -47,6 +73,55 @@ pub enum ClientMessages {
     SettingsLoaded,
 
     InventoryAction(InventoryAction),
+
+    /// Sent by `IClientNetwork::disconnect_with_reason` before closing;
+    /// the server reports the message in `ConnectionMessages::Disconnect`
+    Disconnect {
+        message: Option<String>,
+    },
+
+    // Acknowledges `ServerMessages::EntitySnapshot`, see crate::snapshots
+    SnapshotAck {
+        world_slug: String,
+        sequence: u32,
+    },
+
+    // Time sync request on the client clock, see crate::time_sync
+    TimeSync {
+        client_time: f64,
+    },
+
+    // Request or response of a call, see crate::rpc
+    Rpc(RpcFrame),
+
+    // Acknowledges `ServerMessages::BoundedReliable`, see crate::retries
+    BoundedAck {
+        ids: Vec<u32>,
+    },
+
+    // Payload the server returns at once, see crate::echo
+    Echo {
+        id: u32,
+        payload: Vec<u8>,
+    },
+
+    // Acknowledges `ServerMessages::StateSync`, see crate::keyed_state
+    StateAck {
+        name: String,
+        version: u64,
+    },
+
+    // Bytes of a stream the client read, the server may send as many more; see crate::streams
+    StreamCredit {
+        stream_id: u32,
+        bytes: u32,
+    },
+
+    // Message with the trace context of the client, see crate::trace_context
+    Traced {
+        context: TraceContext,
+        message: Box<ClientMessages>,
+    },
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -97,7 +172,7 @@ pub struct ResurceScheme {
     pub media: HashMap<String, String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Display, AsRefStr)]
+#[derive(Debug, Serialize, Deserialize, Clone, Display, AsRefStr, EnumCount, EnumDiscriminants)]
 #[strum(serialize_all = "kebab-case")]
 pub enum ServerMessages {
     AllowConnection,
@@ -132,7 +207,9 @@ pub enum ServerMessages {
     },
     PlayerSpawn {
         world_slug: String,
+        #[serde(with = "crate::non_finite::lanes")]
         position: Vector3,
+        #[serde(with = "crate::non_finite::lanes")]
         rotation: Rotation,
         components: Vec<EntityNetworkComponent>,
     },
@@ -155,7 +232,9 @@ pub enum ServerMessages {
     StartStreamingEntity {
         world_slug: String,
         id: u32,
+        #[serde(with = "crate::quantization::position")]
         position: Vector3,
+        #[serde(with = "crate::quantization::rotation")]
         rotation: Rotation,
         components: Vec<EntityNetworkComponent>,
     },
@@ -172,10 +251,13 @@ pub enum ServerMessages {
     EntityMove {
         world_slug: String,
         id: u32,
+        #[serde(with = "crate::quantization::position")]
         position: Vector3,
+        #[serde(with = "crate::quantization::rotation")]
         rotation: Rotation,
         animation_state: AnimationState,
-        /// Server time in seconds since startup
+        /// Server time in seconds since startup, see `IServerNetwork::server_time`
+        #[serde(with = "crate::non_finite::timestamp")]
         timestamp: f64,
     },
 
@@ -190,6 +272,157 @@ pub enum ServerMessages {
     },
 
     InventoryStream(InventoryStream),
+
+    // Part of a message over the fragment size, see crate::fragmentation
+    MessageFragment {
+        message_id: u32,
+        index: u32,
+        count: u32,
+        data: Vec<u8>,
+    },
+
+    // Sent before disconnecting a client refused by crate::approval
+    ConnectionRejected {
+        reason: RejectionReason,
+    },
+
+    // The server is draining and shuts down in `shutdown_in`, see crate::draining
+    ShutdownNotice {
+        shutdown_in: Duration,
+        message: Option<String>,
+    },
+
+    // Entities changed since the `baseline` snapshot, all of them without one; see crate::snapshots
+    EntitySnapshot {
+        world_slug: String,
+        sequence: u32,
+        baseline: Option<u32>,
+        /// Server time in seconds since startup
+        #[serde(with = "crate::non_finite::timestamp")]
+        timestamp: f64,
+        changed: Vec<(u32, EntityState)>,
+        removed: Vec<u32>,
+    },
+
+    // Answer to `ClientMessages::TimeSync`: when the server received and answered it, see crate::time_sync
+    TimeSync {
+        client_time: f64,
+        received: f64,
+        sent: f64,
+    },
+
+    // Request or response of a call, see crate::rpc
+    Rpc(RpcFrame),
+
+    // Message resent until acknowledged or out of retries, see crate::retries
+    BoundedReliable {
+        id: u32,
+        message: Box<ServerMessages>,
+    },
+
+    // `ClientMessages::Echo` returned, see crate::echo
+    Echo {
+        id: u32,
+        payload: Vec<u8>,
+    },
+
+    // Keys of a store changed from the acknowledged `baseline` to `version`,
+    // all of them without a baseline; see crate::keyed_state
+    StateSync {
+        name: String,
+        baseline: Option<u64>,
+        changed: Vec<(String, Vec<u8>)>,
+        removed: Vec<String>,
+        version: u64,
+    },
+
+    // Create or rename an objective; remove it with its scores if `display_name` is None.
+    // See crate::scoreboard
+    ScoreboardObjective {
+        name: String,
+        display_name: Option<String>,
+    },
+
+    // Score of an entry (a player name, a team); removed if None
+    ScoreboardScore {
+        objective: String,
+        entry: String,
+        score: Option<i32>,
+    },
+
+    // Objective shown in a slot; the slot is cleared if None
+    ScoreboardDisplay {
+        slot: DisplaySlot,
+        objective: Option<String>,
+    },
+
+    // Time of day (fraction of the day passed) and weather at a server tick, see crate::environment
+    WorldEnvironment {
+        time_of_day: f32,
+        weather: String,
+        tick: u64,
+    },
+
+    // Message with the trace context of the server, see crate::trace_context
+    Traced {
+        context: TraceContext,
+        message: Box<ServerMessages>,
+    },
+
+    // Byte streams, see crate::streams
+    StreamOpen {
+        stream_id: u32,
+        label: String,
+    },
+    StreamData {
+        stream_id: u32,
+        data: Vec<u8>,
+    },
+    StreamClose {
+        stream_id: u32,
+    },
+}
+
+/// Variant index of an encoded message that failed to decode, if the variant
+/// was appended after the `known` ones but is within `peer_schema`, the variant
+/// count the peer declared in the handshake: a message of a newer peer, to skip
+/// rather than report as a decode error
+pub(crate) fn newer_variant(payload: &[u8], known: usize, peer_schema: u32) -> Option<u32> {
+    // bincode leads with the variant index as a little endian u32
+    let index = u32::from_le_bytes(payload.get(..4)?.try_into().ok()?);
+    (index as usize >= known && index < peer_schema).then_some(index)
+}
+
+impl ServerMessages {
+    /// The message with skin variants resolved for one client, see `EntitySkinData::Variants`;
+    /// None if it carries no variants and is sent as is
+    pub(crate) fn resolve_skins(&self, max_texture_size: Option<u32>) -> Option<Self> {
+        let components = match self {
+            Self::UpdatePlayerComponent { component } | Self::UpdateEntityComponent { component, .. } => {
+                std::slice::from_ref(component)
+            }
+            Self::PlayerSpawn { components, .. } | Self::StartStreamingEntity { components, .. } => {
+                components.as_slice()
+            }
+            _ => return None,
+        };
+        if !components.iter().any(|c| c.has_skin_variants()) {
+            return None;
+        }
+        let mut message = self.clone();
+        match &mut message {
+            Self::UpdatePlayerComponent { component } | Self::UpdateEntityComponent { component, .. } => {
+                *component = component.for_texture_size(max_texture_size);
+            }
+            Self::PlayerSpawn { components, .. } | Self::StartStreamingEntity { components, .. } => {
+                for component in components.iter_mut() {
+                    *component = component.for_texture_size(max_texture_size);
+                }
+            }
+            _ => {}
+        }
+        Some(message)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -215,9 +448,47 @@ pub enum InventoryStream {
     },
 }
 
+/// Delivery mode of a message.
+///
+/// The tokio transport is a single ordered stream, so there every mode
+/// is delivered reliably and in order; the modes matter for renet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum NetworkMessageType {
     ReliableOrdered,
+    /// Reliable, but a lost message does not hold back the ones after it
     ReliableUnordered,
     Unreliable,
     WorldInfo,
+    /// Unreliable; a message older than the last one received on the
+    /// channel is dropped instead of delivered out of order
+    UnreliableSequenced,
+}
+
+impl NetworkMessageType {
+    /// Channel id carried on the wire
+    pub fn channel_id(&self) -> u8 {
+        match self {
+            Self::ReliableOrdered => 0,
+            Self::ReliableUnordered => 1,
+            Self::Unreliable => 2,
+            Self::WorldInfo => 3,
+            Self::UnreliableSequenced => 4,
+        }
+    }
+
+    pub fn from_channel_id(channel_id: u8) -> Option<Self> {
+        match channel_id {
+            0 => Some(Self::ReliableOrdered),
+            1 => Some(Self::ReliableUnordered),
+            2 => Some(Self::Unreliable),
+            3 => Some(Self::WorldInfo),
+            4 => Some(Self::UnreliableSequenced),
+            _ => None,
+        }
+    }
+
+    /// Channels whose messages may be dropped, e.g. by `ClientConfig::bandwidth_cap`
+    pub fn is_optional(&self) -> bool {
+        matches!(self, Self::Unreliable | Self::UnreliableSequenced)
+    }
 }
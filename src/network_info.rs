@@ -0,0 +1,210 @@
+//! Live connection statistics.
+//!
+//! Returned by `IServerConnection::get_network_info` and
+//! `IClientNetwork::get_network_info`, e.g. for a ping display or to
+//! adapt send rates. Clients also report their data usage per channel
+//! with `IClientNetwork::get_bandwidth_usage`.
+
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::messages::NetworkMessageType;
+
+/// Interval over which the byte rates are averaged
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NetworkInfo {
+    /// Round-trip time; zero until the first ping is answered
+    pub rtt: Duration,
+    /// Lost packets, 0.0 - 100.0. Always zero over a stream transport,
+    /// where losses show up as higher `rtt` instead
+    pub packet_loss: f64,
+    pub bytes_sent_per_sec: f64,
+    pub bytes_received_per_sec: f64,
+    /// Messages queued and not yet handed to the socket
+    pub send_queue: usize,
+}
+
+/// Byte counters of a connection, updated by the transport tasks
+#[derive(Debug)]
+pub(crate) struct TrafficMeter {
+    sent: AtomicU64,
+    received: AtomicU64,
+    rtt_nanos: AtomicU64,
+    /// Frames held back by traffic shaping
+    held: AtomicUsize,
+    /// Window start with the totals at that moment, and the last rates
+    window: Mutex<(Instant, u64, u64, f64, f64)>,
+}
+
+impl Default for TrafficMeter {
+    fn default() -> Self {
+        Self {
+            sent: Default::default(),
+            received: Default::default(),
+            rtt_nanos: Default::default(),
+            held: Default::default(),
+            window: Mutex::new((Instant::now(), 0, 0, 0.0, 0.0)),
+        }
+    }
+}
+
+impl TrafficMeter {
+    pub fn add_sent(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_received(&self, bytes: usize) {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_rtt(&self, rtt: Duration) {
+        self.rtt_nanos.store(rtt.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_held(&self, frames: usize) {
+        self.held.store(frames, Ordering::Relaxed);
+    }
+
+    pub fn get_held(&self) -> usize {
+        self.held.load(Ordering::Relaxed)
+    }
+
+    pub fn get_rtt(&self) -> Duration {
+        Duration::from_nanos(self.rtt_nanos.load(Ordering::Relaxed))
+    }
+
+    /// Rates of the last complete window
+    fn get_rates(&self) -> (f64, f64) {
+        let mut window = self.window.lock();
+        let (started, sent, received, sent_rate, received_rate) = *window;
+        let elapsed = started.elapsed();
+        if elapsed < RATE_WINDOW {
+            return (sent_rate, received_rate);
+        }
+        let total_sent = self.sent.load(Ordering::Relaxed);
+        let total_received = self.received.load(Ordering::Relaxed);
+        let seconds = elapsed.as_secs_f64();
+        let sent_rate = (total_sent - sent) as f64 / seconds;
+        let received_rate = (total_received - received) as f64 / seconds;
+        *window = (Instant::now(), total_sent, total_received, sent_rate, received_rate);
+        (sent_rate, received_rate)
+    }
+
+    pub fn get_network_info(&self, send_queue: usize) -> NetworkInfo {
+        let (bytes_sent_per_sec, bytes_received_per_sec) = self.get_rates();
+        NetworkInfo {
+            rtt: self.get_rtt(),
+            packet_loss: 0.0,
+            bytes_sent_per_sec,
+            bytes_received_per_sec,
+            send_queue: send_queue + self.held.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Message bytes of one channel, or of all of them
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChannelUsage {
+    /// Since the connection was opened
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Over the last complete second
+    pub bytes_sent_per_sec: f64,
+    pub bytes_received_per_sec: f64,
+}
+
+impl ChannelUsage {
+    fn add(&mut self, other: &ChannelUsage) {
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.bytes_sent_per_sec += other.bytes_sent_per_sec;
+        self.bytes_received_per_sec += other.bytes_received_per_sec;
+    }
+}
+
+/// Client data usage, returned by `IClientNetwork::get_bandwidth_usage`
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthUsage {
+    pub channels: HashMap<NetworkMessageType, ChannelUsage>,
+    pub total: ChannelUsage,
+}
+
+#[derive(Debug, Default)]
+struct ChannelCounter {
+    usage: ChannelUsage,
+    window_sent: u64,
+    window_received: u64,
+}
+
+/// Per-channel message bytes of a client connection
+#[derive(Debug)]
+pub(crate) struct UsageMeter {
+    window_started: Instant,
+    channels: HashMap<u8, ChannelCounter>,
+}
+
+impl Default for UsageMeter {
+    fn default() -> Self {
+        Self {
+            window_started: Instant::now(),
+            channels: Default::default(),
+        }
+    }
+}
+
+impl UsageMeter {
+    /// Start a new window once the current one is complete
+    fn roll(&mut self) {
+        let elapsed = self.window_started.elapsed();
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+        let seconds = elapsed.as_secs_f64();
+        for counter in self.channels.values_mut() {
+            counter.usage.bytes_sent_per_sec = counter.window_sent as f64 / seconds;
+            counter.usage.bytes_received_per_sec = counter.window_received as f64 / seconds;
+            counter.window_sent = 0;
+            counter.window_received = 0;
+        }
+        self.window_started = Instant::now();
+    }
+
+    pub fn add_sent(&mut self, channel_id: u8, bytes: usize) {
+        self.roll();
+        let counter = self.channels.entry(channel_id).or_default();
+        counter.usage.bytes_sent += bytes as u64;
+        counter.window_sent += bytes as u64;
+    }
+
+    pub fn add_received(&mut self, channel_id: u8, bytes: usize) {
+        self.roll();
+        let counter = self.channels.entry(channel_id).or_default();
+        counter.usage.bytes_received += bytes as u64;
+        counter.window_received += bytes as u64;
+    }
+
+    pub fn get_usage(&mut self) -> BandwidthUsage {
+        self.roll();
+        let mut usage = BandwidthUsage::default();
+        for (channel_id, counter) in self.channels.iter() {
+            usage.total.add(&counter.usage);
+            if let Some(message_type) = NetworkMessageType::from_channel_id(*channel_id) {
+                usage.channels.insert(message_type, counter.usage);
+            }
+        }
+        usage
+    }
+
+    /// Optional messages are dropped while the total rate is over the cap
+    pub fn is_over_cap(&mut self, cap: Option<u32>) -> bool {
+        let Some(cap) = cap else {
+            return false;
+        };
+        let total = self.get_usage().total;
+        total.bytes_sent_per_sec + total.bytes_received_per_sec > cap as f64
+    }
+}
@@ -0,0 +1,191 @@
+//! Policy for NaN and infinite floats in the messages.
+//!
+//! A physics glitch that produces a NaN position is otherwise encoded as is
+//! and reaches every client that streams the entity. The policy is applied
+//! to the position, rotation and timestamp fields of the messages when they
+//! are encoded and when they are decoded, separately for each direction:
+//! `Reject` fails the message (an encode or decode error), `Clamp` replaces
+//! NaN with zero and infinities with the largest finite value, and
+//! `Passthrough`, the default, leaves them as they are. Every non-finite
+//! value seen is counted, see `get_counters`.
+//!
+//! The policy is crate-wide, as the fields are (de)serialized far from any
+//! config; set it once at startup.
+
+use serde::de::{DeserializeOwned, Error as DeError};
+use serde::ser::Error as SerError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use crate::quantization::{from_lanes, to_lanes};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum NonFinitePolicy {
+    /// Fail the message
+    Reject,
+    /// NaN becomes zero, an infinity the largest finite value of its sign
+    Clamp,
+    #[default]
+    Passthrough,
+}
+
+impl NonFinitePolicy {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Reject,
+            1 => Self::Clamp,
+            _ => Self::Passthrough,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Encode,
+    Decode,
+}
+
+/// Non-finite values seen in one direction
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NonFiniteCounters {
+    pub rejected: u64,
+    pub clamped: u64,
+    pub passed: u64,
+}
+
+struct Counters {
+    rejected: AtomicU64,
+    clamped: AtomicU64,
+    passed: AtomicU64,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            rejected: AtomicU64::new(0),
+            clamped: AtomicU64::new(0),
+            passed: AtomicU64::new(0),
+        }
+    }
+}
+
+static POLICIES: [AtomicU8; 2] = [
+    AtomicU8::new(NonFinitePolicy::Passthrough as u8),
+    AtomicU8::new(NonFinitePolicy::Passthrough as u8),
+];
+
+static COUNTERS: [Counters; 2] = [Counters::new(), Counters::new()];
+
+pub fn set_policy(direction: Direction, policy: NonFinitePolicy) {
+    POLICIES[direction as usize].store(policy as u8, Ordering::Relaxed);
+}
+
+pub fn get_policy(direction: Direction) -> NonFinitePolicy {
+    NonFinitePolicy::from_u8(POLICIES[direction as usize].load(Ordering::Relaxed))
+}
+
+pub fn get_counters(direction: Direction) -> NonFiniteCounters {
+    let counters = &COUNTERS[direction as usize];
+    NonFiniteCounters {
+        rejected: counters.rejected.load(Ordering::Relaxed),
+        clamped: counters.clamped.load(Ordering::Relaxed),
+        passed: counters.passed.load(Ordering::Relaxed),
+    }
+}
+
+/// Count a value with `count` non-finite lanes; Ok(true) if it must be clamped
+fn admit(direction: Direction, count: usize) -> Result<bool, String> {
+    let counters = &COUNTERS[direction as usize];
+    match get_policy(direction) {
+        NonFinitePolicy::Reject => {
+            counters.rejected.fetch_add(count as u64, Ordering::Relaxed);
+            Err("non-finite float".to_string())
+        }
+        NonFinitePolicy::Clamp => {
+            counters.clamped.fetch_add(count as u64, Ordering::Relaxed);
+            Ok(true)
+        }
+        NonFinitePolicy::Passthrough => {
+            counters.passed.fetch_add(count as u64, Ordering::Relaxed);
+            Ok(false)
+        }
+    }
+}
+
+/// Apply the policy to the lanes of a position or rotation before they are quantized
+pub(crate) fn scrub_lanes(direction: Direction, lanes: &mut [f32]) -> Result<(), String> {
+    let count = lanes.iter().filter(|lane| !lane.is_finite()).count();
+    if count > 0 && admit(direction, count)? {
+        for lane in lanes.iter_mut() {
+            *lane = match *lane {
+                lane if lane.is_nan() => 0.0,
+                lane => lane.clamp(f32::MIN, f32::MAX),
+            };
+        }
+    }
+    Ok(())
+}
+
+/// The value to (de)serialize in place of `value`, if it was clamped
+pub(crate) fn scrub_value<T: Serialize + DeserializeOwned>(
+    direction: Direction,
+    value: &T,
+) -> Result<Option<T>, String> {
+    let mut lanes = to_lanes(value)?;
+    if lanes.iter().all(|lane| lane.is_finite()) {
+        return Ok(None);
+    }
+    scrub_lanes(direction, &mut lanes)?;
+    from_lanes(&lanes).map(Some)
+}
+
+fn scrub_f64(direction: Direction, value: f64) -> Result<f64, String> {
+    if value.is_finite() || !admit(direction, 1)? {
+        return Ok(value);
+    }
+    Ok(match value.is_nan() {
+        true => 0.0,
+        false => value.clamp(f64::MIN, f64::MAX),
+    })
+}
+
+/// `#[serde(with = "crate::non_finite::lanes")]` for the positions and
+/// rotations that are not quantized
+pub mod lanes {
+    use super::*;
+
+    pub fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Serialize + DeserializeOwned,
+        S: Serializer,
+    {
+        match scrub_value(Direction::Encode, value).map_err(S::Error::custom)? {
+            Some(clamped) => clamped.serialize(serializer),
+            None => value.serialize(serializer),
+        }
+    }
+
+    pub fn deserialize<'de, T: Serialize + DeserializeOwned, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        let value = T::deserialize(deserializer)?;
+        let clamped = scrub_value(Direction::Decode, &value).map_err(D::Error::custom)?;
+        Ok(clamped.unwrap_or(value))
+    }
+}
+
+/// `#[serde(with = "crate::non_finite::timestamp")]`
+pub mod timestamp {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        scrub_f64(Direction::Encode, *value)
+            .map_err(S::Error::custom)?
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        scrub_f64(Direction::Decode, f64::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
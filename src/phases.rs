@@ -0,0 +1,172 @@
+//! Game phases gating the channels a connection is sent messages on.
+//!
+//! The game moves a connection between phases with
+//! `IServerConnection::set_phase`, e.g. to `GamePhase::Loading` while the
+//! client loads its chunks. Messages sent to it on a channel its phase does
+//! not allow (`ServerConfig::phase_channels`) are dropped by the send path
+//! of the connection, so a phase bug can't leak combat updates to a client
+//! still loading. Connections start in `GamePhase::Playing`.
+//!
+//! Messages of the crate itself, such as connection control, time sync and
+//! RPC, pass in every phase, and so do the stream and bounded retry frames
+//! (`is_transport_message`): dropping one would break the stream or burn
+//! the retries, whatever message they carry.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::messages::{NetworkMessageType, ServerMessages};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum GamePhase {
+    Loading,
+    #[default]
+    Playing,
+    Paused,
+}
+
+impl GamePhase {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Loading,
+            2 => Self::Paused,
+            _ => Self::Playing,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::Loading => 0,
+            Self::Playing => 1,
+            Self::Paused => 2,
+        }
+    }
+}
+
+/// Channels allowed in each phase; a phase without an entry allows all of them.
+///
+/// By default `GamePhase::Loading` allows only `NetworkMessageType::WorldInfo`
+/// and `GamePhase::Paused` only the reliable channels.
+#[derive(Debug, Clone)]
+pub struct PhaseChannels {
+    channels: HashMap<GamePhase, Vec<NetworkMessageType>>,
+}
+
+impl Default for PhaseChannels {
+    fn default() -> Self {
+        Self::all_channels()
+            .with_channels(GamePhase::Loading, &[NetworkMessageType::WorldInfo])
+            .with_channels(
+                GamePhase::Paused,
+                &[
+                    NetworkMessageType::ReliableOrdered,
+                    NetworkMessageType::ReliableUnordered,
+                    NetworkMessageType::WorldInfo,
+                ],
+            )
+    }
+}
+
+impl PhaseChannels {
+    /// Every phase allows every channel
+    pub fn all_channels() -> Self {
+        Self {
+            channels: HashMap::new(),
+        }
+    }
+
+    pub fn with_channels(mut self, phase: GamePhase, channels: &[NetworkMessageType]) -> Self {
+        self.channels.insert(phase, channels.to_vec());
+        self
+    }
+
+    pub fn allows(&self, phase: GamePhase, message_type: NetworkMessageType) -> bool {
+        match self.channels.get(&phase) {
+            Some(channels) => channels.contains(&message_type),
+            None => true,
+        }
+    }
+}
+
+/// Messages the crate sends on its own, passed in every phase
+pub(crate) fn is_system_message(message: &ServerMessages) -> bool {
+    matches!(
+        message,
+        ServerMessages::AllowConnection
+            | ServerMessages::Disconnect { .. }
+            | ServerMessages::ConnectionRejected { .. }
+            | ServerMessages::ShutdownNotice { .. }
+            | ServerMessages::TimeSync { .. }
+            | ServerMessages::Rpc(..)
+            | ServerMessages::Echo { .. }
+            | ServerMessages::StateSync { .. }
+    )
+}
+
+/// Frames of the delivery the crate runs for the game (`crate::streams`,
+/// `crate::retries`), passed in every phase: both ends keep state for them,
+/// a dropped `StreamData` corrupts the stream and its credit never returns
+pub(crate) fn is_transport_message(message: &ServerMessages) -> bool {
+    matches!(
+        message,
+        ServerMessages::BoundedReliable { .. }
+            | ServerMessages::StreamOpen { .. }
+            | ServerMessages::StreamData { .. }
+            | ServerMessages::StreamClose { .. }
+    )
+}
+
+/// Phase of one connection
+#[derive(Debug)]
+pub(crate) struct PhaseGate {
+    phase: AtomicU8,
+}
+
+impl Default for PhaseGate {
+    fn default() -> Self {
+        Self {
+            phase: AtomicU8::new(GamePhase::default().to_u8()),
+        }
+    }
+}
+
+impl PhaseGate {
+    pub fn get(&self) -> GamePhase {
+        GamePhase::from_u8(self.phase.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, phase: GamePhase) {
+        self.phase.store(phase.to_u8(), Ordering::Relaxed);
+    }
+
+    /// False if the message must be dropped in the current phase
+    pub fn check(&self, channels: &PhaseChannels, message_type: NetworkMessageType, message: &ServerMessages) -> bool {
+        channels.allows(self.get(), message_type) || is_system_message(message) || is_transport_message(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transport_messages_pass_every_phase() {
+        let channels = PhaseChannels::default();
+        let gate = PhaseGate::default();
+        let data = ServerMessages::StreamData {
+            stream_id: 1,
+            data: vec![1, 2, 3],
+        };
+        let retry = ServerMessages::BoundedReliable {
+            id: 1,
+            message: Box::new(ServerMessages::ServerStatus { tps: 20.0 }),
+        };
+        let status = ServerMessages::ServerStatus { tps: 20.0 };
+        for phase in [GamePhase::Loading, GamePhase::Paused] {
+            gate.set(phase);
+            assert!(gate.check(&channels, NetworkMessageType::ReliableOrdered, &data));
+            assert!(gate.check(&channels, NetworkMessageType::Unreliable, &retry));
+            assert!(!gate.check(&channels, NetworkMessageType::Unreliable, &status));
+        }
+    }
+}
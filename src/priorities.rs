@@ -0,0 +1,65 @@
+//! Send order of the channels when the send budget is tight.
+//!
+//! Renet fills the packets of a tick channel by channel, each reliable
+//! channel resends first, until `ChannelPriorities::bytes_per_tick` is
+//! spent; the rest waits for the next tick. By default the channels go in
+//! the order of their ids, so the reliable resends of chunk fragments
+//! (`WorldInfo`) can hold back fresh `Unreliable` movement. Each pair of
+//! `ChannelPriorities::with_before` moves a channel ahead of another one.
+//!
+//! The tokio transport is a single TCP stream retransmitted by the kernel;
+//! messages go out in the order they are sent and priorities do not apply.
+
+use crate::messages::NetworkMessageType;
+
+/// Renet send budget if `ChannelPriorities::bytes_per_tick` is unset
+pub const DEFAULT_BYTES_PER_TICK: u64 = 1024 * 1024;
+
+/// Channels by id, the default send order
+const CHANNELS: [NetworkMessageType; 5] = [
+    NetworkMessageType::ReliableOrdered,
+    NetworkMessageType::ReliableUnordered,
+    NetworkMessageType::Unreliable,
+    NetworkMessageType::WorldInfo,
+    NetworkMessageType::UnreliableSequenced,
+];
+
+#[derive(Clone, Debug, Default)]
+pub struct ChannelPriorities {
+    pairs: Vec<(NetworkMessageType, NetworkMessageType)>,
+    bytes_per_tick: Option<u64>,
+}
+
+impl ChannelPriorities {
+    /// Send `first` (new messages and resends) before `then`;
+    /// a later pair wins over an earlier one it contradicts
+    pub fn with_before(mut self, first: NetworkMessageType, then: NetworkMessageType) -> Self {
+        self.pairs.push((first, then));
+        self
+    }
+
+    /// Bytes each connection may put on the wire per tick
+    pub fn with_bytes_per_tick(mut self, bytes: u64) -> Self {
+        self.bytes_per_tick = Some(bytes);
+        self
+    }
+
+    #[cfg_attr(not(feature = "network-renet"), allow(dead_code))]
+    pub(crate) fn get_bytes_per_tick(&self) -> u64 {
+        self.bytes_per_tick.unwrap_or(DEFAULT_BYTES_PER_TICK)
+    }
+
+    /// Channels in the order they are filled
+    pub fn send_order(&self) -> Vec<NetworkMessageType> {
+        let mut order = CHANNELS.to_vec();
+        for (first, then) in self.pairs.iter() {
+            let position = |channel| order.iter().position(|c| c == channel).unwrap();
+            let (first_index, then_index) = (position(first), position(then));
+            if first_index > then_index {
+                let channel = order.remove(first_index);
+                order.insert(then_index, channel);
+            }
+        }
+        order
+    }
+}
@@ -0,0 +1,542 @@
+//! The wire protocol of the tokio backend without sockets or a runtime.
+//!
+//! `ClientProtocol` and `ServerProtocol` hold the state of one end of a
+//! connection once the handshake is done: bytes received go in with
+//! `receive`, which returns what they meant as `ProtocolEvent`s, and the
+//! bytes to write come out of `take_output`. The tokio backend frames,
+//! quantizes, compresses and fragments its messages through these same
+//! types, so property tests can drive both ends against each other in
+//! memory and another front-end (a custom event loop, a WASM host) can
+//! speak the protocol over its own I/O.
+//!
+//! Time is passed in (`now`) rather than read, so a test controls the clock.
+//! Left to the caller: the handshake and the encryption of the frames
+//! (`crate::tokio`), timeouts, rate limits, session resume and the routing
+//! of RPC, stream and snapshot messages.
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use strum::EnumCount;
+
+use crate::client_id::ClientId;
+use crate::compression::{compress_payload_at, decompress_payload, DEFAULT_COMPRESSION_LEVEL};
+use crate::errors::{contain_panics, NetworkError};
+use crate::fragmentation::{needs_fragmentation, split_message, Reassembly, DEFAULT_MAX_MESSAGE_SIZE};
+use crate::handshake::SessionParameters;
+use crate::messages::{newer_variant, ClientMessages, NetworkMessageType, ServerMessages};
+use crate::quantization::{with_profile, ChannelProfiles, QuantizationProfile};
+use crate::system::{decode_system, SystemMessage};
+use crate::tokio::{
+    ack_frame, parse_ack, system_frame, FRAME_ACK, FRAME_MESSAGE, FRAME_PING, FRAME_PONG, FRAME_SYSTEM, MAX_FRAME_SIZE,
+};
+
+/// Session parameters both ends agreed on in the handshake
+#[derive(Debug, Clone)]
+pub struct ProtocolConfig {
+    compression_threshold: Option<u32>,
+    quantization: Vec<(u8, String)>,
+    max_message_size: usize,
+    peer_schema: u32,
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            compression_threshold: None,
+            quantization: Vec::new(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            peer_schema: 0,
+        }
+    }
+}
+
+impl ProtocolConfig {
+    /// Compress payloads larger than `threshold` bytes
+    pub fn with_compression(mut self, threshold: u32) -> Self {
+        self.compression_threshold = Some(threshold);
+        self
+    }
+
+    pub fn with_quantization(mut self, message_type: NetworkMessageType, profile: QuantizationProfile) -> Self {
+        self.quantization
+            .push((message_type.channel_id(), profile.name.to_string()));
+        self
+    }
+
+    /// Largest message reassembled from fragments
+    pub fn with_max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Variant count the peer declared in the handshake; variants of a newer
+    /// peer beyond the known ones are skipped rather than reported
+    pub fn with_peer_schema(mut self, schema: u32) -> Self {
+        self.peer_schema = schema;
+        self
+    }
+
+    /// What the handshake negotiated; `peer_schema` is the schema of the other end
+    pub(crate) fn negotiated(session: &SessionParameters, peer_schema: u32, max_message_size: usize) -> Self {
+        Self {
+            compression_threshold: session.compression_threshold,
+            quantization: session.quantization.clone(),
+            max_message_size,
+            peer_schema,
+        }
+    }
+}
+
+/// What received bytes meant
+#[derive(Debug)]
+pub enum ProtocolEvent<M> {
+    Message(M),
+    /// Answer to `ping`
+    Pong {
+        rtt: Duration,
+    },
+    /// Message frames the peer received so far, see `crate::resume`
+    Ack {
+        received: u64,
+    },
+    /// A message was dropped; the connection goes on
+    Error(NetworkError),
+    /// Message of the crate itself, see `crate::system`
+    System(SystemMessage),
+}
+
+/// Message frame decoded by `decode_frame`
+pub(crate) struct DecodedFrame<'a, T> {
+    pub channel: u8,
+    /// Quantized payload, decompressed
+    pub payload: Cow<'a, [u8]>,
+    pub message: T,
+}
+
+/// Framing shared by both ends
+struct Framing {
+    /// Client id reported in errors; None on the client
+    client_id: Option<ClientId>,
+    profiles: Arc<ChannelProfiles>,
+    compression_threshold: Option<u32>,
+    peer_schema: u32,
+    received: Vec<u8>,
+    output: Vec<u8>,
+    ping_sent: Option<Instant>,
+}
+
+impl Framing {
+    fn new(client_id: Option<ClientId>, config: &ProtocolConfig) -> Result<Self, String> {
+        Ok(Self {
+            client_id,
+            profiles: Arc::new(ChannelProfiles::from_names(&config.quantization)?),
+            compression_threshold: config.compression_threshold,
+            peer_schema: config.peer_schema,
+            received: Vec::new(),
+            output: Vec::new(),
+            ping_sent: None,
+        })
+    }
+
+    /// Frames completed by `data`; Err if the stream is corrupt and the connection must close
+    fn frames(&mut self, data: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        self.received.extend_from_slice(data);
+        let mut frames = Vec::new();
+        let mut offset = 0;
+        while let Some(length) = self.received.get(offset..offset + 4) {
+            let length = u32::from_le_bytes(length.try_into().unwrap());
+            if length > MAX_FRAME_SIZE {
+                return Err(format!("frame size {} exceeds maximum {}", length, MAX_FRAME_SIZE));
+            }
+            let end = offset + 4 + length as usize;
+            let Some(frame) = self.received.get(offset + 4..end) else {
+                break;
+            };
+            frames.push(frame.to_vec());
+            offset = end;
+        }
+        self.received.drain(..offset);
+        Ok(frames)
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) {
+        self.output.extend((frame.len() as u32).to_le_bytes());
+        self.output.extend_from_slice(frame);
+    }
+
+    /// Compress the payload past the threshold and put it in a message frame
+    fn message_frame(&self, channel: u8, payload: Vec<u8>, level: u8) -> Vec<u8> {
+        let (channel, payload) = compress_payload_at(self.compression_threshold, level, channel, payload);
+        let mut frame = vec![FRAME_MESSAGE, channel];
+        frame.extend(payload);
+        frame
+    }
+
+    fn write_message(&mut self, channel: u8, payload: Vec<u8>) {
+        let frame = self.message_frame(channel, payload, DEFAULT_COMPRESSION_LEVEL);
+        self.write_frame(&frame);
+    }
+
+    /// Serialize with the quantization profile of the channel
+    fn encode<T: Serialize + AsRef<str>>(
+        &self,
+        message_type: NetworkMessageType,
+        message: &T,
+    ) -> Result<Vec<u8>, NetworkError> {
+        let profile = self.profiles.get(message_type.channel_id());
+        with_profile(profile, || bincode::serialize(message)).map_err(|e| NetworkError::Encode {
+            client_id: self.client_id,
+            variant: message.as_ref().to_string(),
+            reason: e.to_string(),
+        })
+    }
+
+    fn decode<T: DeserializeOwned>(&self, channel: u8, payload: &[u8]) -> Result<T, String> {
+        let profile = self.profiles.get(channel);
+        contain_panics(|| with_profile(profile, || bincode::deserialize::<T>(payload)).map_err(|e| e.to_string()))
+    }
+
+    /// Decompress and decode a message frame; None for a variant of a newer peer
+    fn decode_frame<'a, T: DeserializeOwned + EnumCount>(
+        &self,
+        frame: &'a [u8],
+    ) -> Result<Option<DecodedFrame<'a, T>>, String> {
+        let (channel, payload) = decompress_payload(frame[1], &frame[2..])?;
+        match self.decode(channel, &payload) {
+            Ok(message) => Ok(Some(DecodedFrame {
+                channel,
+                payload,
+                message,
+            })),
+            Err(e) => match newer_variant(&payload, T::COUNT, self.peer_schema) {
+                Some(index) => {
+                    let peer = match self.client_id {
+                        Some(client_id) => format!("Client {}", client_id),
+                        None => "Server".to_string(),
+                    };
+                    log::warn!(target: "network", "{} sent unknown message variant {}; skipped", peer, index);
+                    Ok(None)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Answer a ping, time a pong, read an ack or a system message; None for a message frame
+    fn control<M>(&mut self, frame: &[u8], now: Instant) -> Option<Option<ProtocolEvent<M>>> {
+        match frame[0] {
+            FRAME_MESSAGE => None,
+            FRAME_PING => {
+                self.write_frame(&[FRAME_PONG]);
+                Some(None)
+            }
+            FRAME_PONG => Some(self.ping_sent.take().map(|sent| ProtocolEvent::Pong {
+                rtt: now.saturating_duration_since(sent),
+            })),
+            FRAME_ACK => Some(parse_ack(frame).map(|received| ProtocolEvent::Ack { received })),
+            FRAME_SYSTEM => Some(decode_system(&frame[1..]).map(ProtocolEvent::System)),
+            _ => Some(None),
+        }
+    }
+
+    fn write_system(&mut self, message: &SystemMessage) {
+        if let Some(frame) = system_frame(message) {
+            self.write_frame(&frame);
+        }
+    }
+
+    fn malformed<M>(&self) -> ProtocolEvent<M> {
+        ProtocolEvent::Error(NetworkError::MalformedFrame {
+            client_id: self.client_id,
+        })
+    }
+
+    fn decode_error<M>(&self, reason: String) -> ProtocolEvent<M> {
+        ProtocolEvent::Error(NetworkError::Decode {
+            client_id: self.client_id,
+            reason,
+        })
+    }
+}
+
+/// Client end of a connection, see `crate::protocol`
+pub struct ClientProtocol {
+    framing: Framing,
+    fragments: Reassembly,
+}
+
+impl ClientProtocol {
+    /// Fails on an unknown quantization profile
+    pub fn new(config: &ProtocolConfig) -> Result<Self, String> {
+        Ok(Self {
+            framing: Framing::new(None, config)?,
+            fragments: Reassembly::new(config.max_message_size),
+        })
+    }
+
+    /// Feed bytes read from the server.
+    ///
+    /// Err if the stream is corrupt; the connection must be closed.
+    pub fn receive(&mut self, data: &[u8], now: Instant) -> Result<Vec<ProtocolEvent<ServerMessages>>, String> {
+        let mut events = Vec::new();
+        for frame in self.framing.frames(data)? {
+            if frame.is_empty() {
+                continue;
+            }
+            if let Some(event) = self.framing.control(&frame, now) {
+                events.extend(event);
+                continue;
+            }
+            if frame.len() < 2 {
+                events.push(self.framing.malformed());
+                continue;
+            }
+            match self.decode_message(&frame) {
+                Ok(Some(message)) => events.push(ProtocolEvent::Message(message)),
+                Ok(None) => {}
+                Err(e) => events.push(self.framing.decode_error(e)),
+            }
+        }
+        Ok(events)
+    }
+
+    pub fn send(&mut self, message_type: NetworkMessageType, message: &ClientMessages) -> Result<(), NetworkError> {
+        let payload = self.framing.encode(message_type, message)?;
+        self.framing.write_message(message_type.channel_id(), payload);
+        Ok(())
+    }
+
+    /// Send a message of the crate itself, e.g. `SystemMessage::TuningApplied`
+    pub fn send_system(&mut self, message: &SystemMessage) {
+        self.framing.write_system(message);
+    }
+
+    /// Send a ping; the pong comes back as `ProtocolEvent::Pong`
+    pub fn ping(&mut self, now: Instant) {
+        self.framing.ping_sent = Some(now);
+        self.framing.write_frame(&[FRAME_PING]);
+    }
+
+    /// Acknowledge `received` message frames, see `crate::resume`
+    pub fn ack(&mut self, received: u64) {
+        self.framing.write_frame(&ack_frame(received));
+    }
+
+    /// Bytes to write to the server
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.framing.output)
+    }
+
+    pub(crate) fn get_profiles(&self) -> &Arc<ChannelProfiles> {
+        &self.framing.profiles
+    }
+
+    /// Decode a message frame of at least two bytes; None while a
+    /// fragmented message is incomplete or for a variant of a newer server
+    pub(crate) fn decode_message(&mut self, frame: &[u8]) -> Result<Option<ServerMessages>, String> {
+        let framing = &self.framing;
+        match framing.decode_frame::<ServerMessages>(frame)? {
+            Some(DecodedFrame { channel, message, .. }) => self
+                .fragments
+                .route(message, |payload| framing.decode(channel, payload)),
+            None => Ok(None),
+        }
+    }
+
+    /// Quantized payload of a message, checked before it is framed by `message_frame`
+    pub(crate) fn encode(
+        &self,
+        message_type: NetworkMessageType,
+        message: &ClientMessages,
+    ) -> Result<Vec<u8>, NetworkError> {
+        self.framing.encode(message_type, message)
+    }
+
+    /// Message frame of an encoded payload, compressed past the threshold
+    pub(crate) fn message_frame(&self, channel: u8, payload: Vec<u8>) -> Vec<u8> {
+        self.framing.message_frame(channel, payload, DEFAULT_COMPRESSION_LEVEL)
+    }
+}
+
+/// Server end of a connection, see `crate::protocol`
+pub struct ServerProtocol {
+    framing: Framing,
+}
+
+impl ServerProtocol {
+    /// Fails on an unknown quantization profile
+    pub fn new(client_id: ClientId, config: &ProtocolConfig) -> Result<Self, String> {
+        Ok(Self {
+            framing: Framing::new(Some(client_id), config)?,
+        })
+    }
+
+    /// Feed bytes read from the client.
+    ///
+    /// Err if the stream is corrupt; the connection must be closed.
+    pub fn receive(&mut self, data: &[u8], now: Instant) -> Result<Vec<ProtocolEvent<ClientMessages>>, String> {
+        let mut events = Vec::new();
+        for frame in self.framing.frames(data)? {
+            if frame.is_empty() {
+                continue;
+            }
+            if let Some(event) = self.framing.control(&frame, now) {
+                events.extend(event);
+                continue;
+            }
+            if frame.len() < 2 {
+                events.push(self.framing.malformed());
+                continue;
+            }
+            match self.decode_frame(&frame) {
+                Ok(Some(decoded)) => events.push(ProtocolEvent::Message(decoded.message)),
+                Ok(None) => {}
+                Err(e) => events.push(self.framing.decode_error(e)),
+            }
+        }
+        Ok(events)
+    }
+
+    /// Send a message, in fragments if it is too large for one frame
+    pub fn send(&mut self, message_type: NetworkMessageType, message: &ServerMessages) -> Result<(), NetworkError> {
+        let payload = self.framing.encode(message_type, message)?;
+        let channel = message_type.channel_id();
+        for payload in self.fragment(message_type, message, payload)? {
+            self.framing.write_message(channel, payload);
+        }
+        Ok(())
+    }
+
+    /// Send a message of the crate itself, see `crate::system`
+    pub fn send_system(&mut self, message: &SystemMessage) {
+        self.framing.write_system(message);
+    }
+
+    /// Send a ping; the pong comes back as `ProtocolEvent::Pong`
+    pub fn ping(&mut self, now: Instant) {
+        self.framing.ping_sent = Some(now);
+        self.framing.write_frame(&[FRAME_PING]);
+    }
+
+    /// Acknowledge `received` message frames, see `crate::resume`
+    pub fn ack(&mut self, received: u64) {
+        self.framing.write_frame(&ack_frame(received));
+    }
+
+    /// Bytes to write to the client
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.framing.output)
+    }
+
+    pub(crate) fn get_profiles(&self) -> &Arc<ChannelProfiles> {
+        &self.framing.profiles
+    }
+
+    /// Decompress and decode a message frame of at least two bytes; None for a variant of a newer client
+    pub(crate) fn decode_frame<'a>(&self, frame: &'a [u8]) -> Result<Option<DecodedFrame<'a, ClientMessages>>, String> {
+        self.framing.decode_frame(frame)
+    }
+
+    /// Quantized payload of a message, checked before it is framed by `message_frame`
+    pub(crate) fn encode(
+        &self,
+        message_type: NetworkMessageType,
+        message: &ServerMessages,
+    ) -> Result<Vec<u8>, NetworkError> {
+        self.framing.encode(message_type, message)
+    }
+
+    /// The payload, or the payloads of its fragments if it is too large for one frame
+    pub(crate) fn fragment(
+        &self,
+        message_type: NetworkMessageType,
+        message: &ServerMessages,
+        payload: Vec<u8>,
+    ) -> Result<Vec<Vec<u8>>, NetworkError> {
+        if !needs_fragmentation(message_type, payload.len()) {
+            return Ok(vec![payload]);
+        }
+        split_message(&payload)
+            .iter()
+            .map(|fragment| self.framing.encode(message_type, fragment))
+            .collect::<Result<_, _>>()
+            .map_err(|e| match e {
+                NetworkError::Encode { client_id, reason, .. } => NetworkError::Encode {
+                    client_id,
+                    variant: message.as_ref().to_string(),
+                    reason,
+                },
+                e => e,
+            })
+    }
+
+    /// Message frame of an encoded payload, compressed at `level` past the threshold
+    pub(crate) fn message_frame(&self, channel: u8, payload: Vec<u8>, level: u8) -> Vec<u8> {
+        self.framing.message_frame(channel, payload, level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_id() -> ClientId {
+        ClientId::new(1).unwrap()
+    }
+
+    #[test]
+    fn messages_round_trip_in_memory() {
+        let config = ProtocolConfig::default().with_compression(64);
+        let mut client = ClientProtocol::new(&config).unwrap();
+        let mut server = ServerProtocol::new(client_id(), &config).unwrap();
+        let now = Instant::now();
+
+        let message = ClientMessages::TimeSync { client_time: 1.5 };
+        client.send(NetworkMessageType::ReliableOrdered, &message).unwrap();
+        client.ping(now);
+        client.send_system(&SystemMessage::TuningApplied { revision: 2 });
+        let events = server.receive(&client.take_output(), now).unwrap();
+        assert!(matches!(
+            events.as_slice(),
+            [
+                ProtocolEvent::Message(ClientMessages::TimeSync { client_time }),
+                ProtocolEvent::System(SystemMessage::TuningApplied { revision: 2 }),
+            ] if *client_time == 1.5
+        ));
+
+        // Large enough to be compressed and fragmented; fed in chunks splitting the frames
+        let data = vec![7u8; crate::fragmentation::FRAGMENT_SIZE * 2 + 1];
+        let message = ServerMessages::ResourcesPart {
+            index: 0,
+            total: 1,
+            data: data.clone(),
+        };
+        server.send(NetworkMessageType::ReliableOrdered, &message).unwrap();
+        server.send_system(&SystemMessage::DegradedMode { active: true });
+        server.ack(3);
+        let output = server.take_output();
+        let later = now + Duration::from_millis(20);
+        let events: Vec<_> = output
+            .chunks(4096)
+            .flat_map(|chunk| client.receive(chunk, later).unwrap())
+            .collect();
+        assert!(matches!(
+            events.as_slice(),
+            [
+                ProtocolEvent::Pong { rtt },
+                ProtocolEvent::Message(ServerMessages::ResourcesPart { data: received, .. }),
+                ProtocolEvent::System(SystemMessage::DegradedMode { active: true }),
+                ProtocolEvent::Ack { received: 3 },
+            ] if *rtt == Duration::from_millis(20) && *received == data
+        ));
+    }
+
+    #[test]
+    fn corrupt_stream_closes_the_connection() {
+        let mut server = ServerProtocol::new(client_id(), &ProtocolConfig::default()).unwrap();
+        let oversized = (MAX_FRAME_SIZE + 1).to_le_bytes();
+        assert!(server.receive(&oversized, Instant::now()).is_err());
+    }
+}
@@ -0,0 +1,120 @@
+//! TCP-only proxy: the client reaches the server through a SOCKS5 CONNECT
+//! tunnel (RFC 1928).
+//!
+//! Only the TCP connection of the tokio backend goes through the proxy.
+//! There is no UDP ASSOCIATE, so:
+//! - the renet backend, which is UDP, rejects a configured proxy;
+//! - datagrams (`IClientNetwork::send_datagram`) are unavailable on a
+//!   proxied connection, as they would go straight to the server;
+//! - local socket and WebSocket addresses connect directly.
+
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const SOCKS_VERSION: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASSWORD: u8 = 0x02;
+
+const COMMAND_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// SOCKS5 proxy used by the client to reach the server, TCP only; see `crate::proxy`
+#[derive(Clone, Debug)]
+pub struct Socks5Proxy {
+    pub address: SocketAddr,
+    /// Username/password authentication (RFC 1929)
+    pub credentials: Option<(String, String)>,
+}
+
+impl Socks5Proxy {
+    pub fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            credentials: None,
+        }
+    }
+
+    pub fn with_credentials(mut self, username: String, password: String) -> Self {
+        self.credentials = Some((username, password));
+        self
+    }
+}
+
+/// Negotiate a SOCKS5 CONNECT tunnel to `target` over an already
+/// established connection to the proxy.
+pub async fn socks5_connect<S>(stream: &mut S, proxy: &Socks5Proxy, target: SocketAddr) -> Result<(), String>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let io_err = |e: std::io::Error| format!("SOCKS5 proxy {} error: {}", proxy.address, e);
+
+    // Method selection
+    let method = match proxy.credentials {
+        Some(_) => METHOD_USER_PASSWORD,
+        None => METHOD_NO_AUTH,
+    };
+    stream.write_all(&[SOCKS_VERSION, 1, method]).await.map_err(io_err)?;
+    let mut reply = [0_u8; 2];
+    stream.read_exact(&mut reply).await.map_err(io_err)?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(format!("SOCKS5 proxy replied with version {}", reply[0]));
+    }
+    if reply[1] != method {
+        return Err("SOCKS5 proxy rejected the authentication method".to_string());
+    }
+
+    if let Some((username, password)) = proxy.credentials.as_ref() {
+        if username.len() > 255 || password.len() > 255 {
+            return Err("SOCKS5 username and password must be at most 255 bytes".to_string());
+        }
+        let mut auth = vec![AUTH_VERSION, username.len() as u8];
+        auth.extend_from_slice(username.as_bytes());
+        auth.push(password.len() as u8);
+        auth.extend_from_slice(password.as_bytes());
+        stream.write_all(&auth).await.map_err(io_err)?;
+
+        let mut reply = [0_u8; 2];
+        stream.read_exact(&mut reply).await.map_err(io_err)?;
+        if reply[1] != 0x00 {
+            return Err("SOCKS5 proxy authentication failed".to_string());
+        }
+    }
+
+    // Connect request
+    let mut request = vec![SOCKS_VERSION, COMMAND_CONNECT, 0x00];
+    match target.ip() {
+        IpAddr::V4(ip) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+    }
+    request.extend_from_slice(&target.port().to_be_bytes());
+    stream.write_all(&request).await.map_err(io_err)?;
+
+    let mut header = [0_u8; 4];
+    stream.read_exact(&mut header).await.map_err(io_err)?;
+    if header[1] != 0x00 {
+        return Err(format!("SOCKS5 connect to {} failed with code {}", target, header[1]));
+    }
+
+    // Skip the bound address sent by the proxy
+    let address_len = match header[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await.map_err(io_err)? as usize,
+        atyp => return Err(format!("SOCKS5 proxy replied with unknown address type {}", atyp)),
+    };
+    let mut bound = vec![0_u8; address_len + 2];
+    stream.read_exact(&mut bound).await.map_err(io_err)?;
+    Ok(())
+}
@@ -0,0 +1,216 @@
+//! Fixed-point quantization of position and rotation fields.
+//!
+//! Message fields annotated with `#[serde(with = "...")]` from this module are
+//! encoded as fixed-point integers when a profile is active for the channel
+//! being (de)serialized, so both ends see exactly the same values. Profiles
+//! are negotiated during the handshake; without one the fields are encoded
+//! as plain floats. Non-finite values are handled by `crate::non_finite`
+//! first.
+
+use serde::de::{DeserializeOwned, Error as DeError, SeqAccess, Visitor};
+use serde::ser::{Error as SerError, SerializeTuple};
+use serde::{Deserializer, Serialize, Serializer};
+use std::{cell::Cell, collections::HashMap, fmt, marker::PhantomData};
+
+use crate::non_finite::{self, Direction};
+
+/// Maximum number of float lanes of a quantized value
+const MAX_LANES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuantizationProfile {
+    pub name: &'static str,
+    /// Position grid step (meters)
+    pub position_step: f32,
+    /// Rotation grid step
+    pub rotation_step: f32,
+}
+
+pub const PROFILE_BLOCK_PRECISION: QuantizationProfile = QuantizationProfile {
+    name: "block-precision",
+    position_step: 1.0,
+    rotation_step: 0.01,
+};
+
+pub const PROFILE_CENTIMETER: QuantizationProfile = QuantizationProfile {
+    name: "centimeter",
+    position_step: 0.01,
+    rotation_step: 0.001,
+};
+
+pub const PROFILE_SUB_MILLIMETER: QuantizationProfile = QuantizationProfile {
+    name: "sub-millimeter",
+    position_step: 0.0005,
+    rotation_step: 0.0001,
+};
+
+pub const PROFILES: [QuantizationProfile; 3] = [PROFILE_BLOCK_PRECISION, PROFILE_CENTIMETER, PROFILE_SUB_MILLIMETER];
+
+impl QuantizationProfile {
+    pub fn from_name(name: &str) -> Option<Self> {
+        PROFILES.iter().find(|p| p.name == name).copied()
+    }
+
+    /// Names of all profiles known to this build, sent during the handshake
+    pub fn supported_names() -> Vec<String> {
+        PROFILES.iter().map(|p| p.name.to_string()).collect()
+    }
+}
+
+thread_local! {
+    static ACTIVE_PROFILE: Cell<Option<QuantizationProfile>> = const { Cell::new(None) };
+}
+
+/// Run `f` (a serialize or deserialize call) with the channel profile active
+pub(crate) fn with_profile<R>(profile: Option<QuantizationProfile>, f: impl FnOnce() -> R) -> R {
+    let previous = ACTIVE_PROFILE.with(|p| p.replace(profile));
+    let result = f();
+    ACTIVE_PROFILE.with(|p| p.set(previous));
+    result
+}
+
+fn active_profile() -> Option<QuantizationProfile> {
+    ACTIVE_PROFILE.with(|p| p.get())
+}
+
+/// Split a value made only of f32 fields into its lanes
+pub(crate) fn to_lanes<T: Serialize>(value: &T) -> Result<Vec<f32>, String> {
+    let bytes = bincode::serialize(value).map_err(|e| e.to_string())?;
+    if bytes.len() % 4 != 0 || bytes.len() / 4 > MAX_LANES {
+        return Err("quantized type must consist of f32 fields only".to_string());
+    }
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect())
+}
+
+pub(crate) fn from_lanes<T: DeserializeOwned>(lanes: &[f32]) -> Result<T, String> {
+    let bytes: Vec<u8> = lanes.iter().flat_map(|v| v.to_le_bytes()).collect();
+    bincode::deserialize(&bytes).map_err(|e| e.to_string())
+}
+
+fn serialize_quantized<T, S>(
+    value: &T,
+    step: impl Fn(&QuantizationProfile) -> f32,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    T: Serialize + DeserializeOwned,
+    S: Serializer,
+{
+    let Some(profile) = active_profile() else {
+        return non_finite::lanes::serialize(value, serializer);
+    };
+    let step = step(&profile);
+    let mut lanes = to_lanes(value).map_err(S::Error::custom)?;
+    non_finite::scrub_lanes(Direction::Encode, &mut lanes).map_err(S::Error::custom)?;
+    let mut tuple = serializer.serialize_tuple(lanes.len() + 1)?;
+    tuple.serialize_element(&(lanes.len() as u8))?;
+    for lane in lanes {
+        let quantized = (lane / step).round().clamp(i32::MIN as f32, i32::MAX as f32) as i32;
+        tuple.serialize_element(&quantized)?;
+    }
+    tuple.end()
+}
+
+struct QuantizedVisitor<T> {
+    step: f32,
+    _marker: PhantomData<T>,
+}
+
+impl<'de, T: DeserializeOwned> Visitor<'de> for QuantizedVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("quantized lanes")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<T, A::Error> {
+        let count: u8 = seq
+            .next_element()?
+            .ok_or_else(|| A::Error::custom("missing lane count"))?;
+        if count as usize > MAX_LANES {
+            return Err(A::Error::custom("too many quantized lanes"));
+        }
+        let mut lanes = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let quantized: i32 = seq
+                .next_element()?
+                .ok_or_else(|| A::Error::custom("missing quantized lane"))?;
+            lanes.push(quantized as f32 * self.step);
+        }
+        from_lanes(&lanes).map_err(A::Error::custom)
+    }
+}
+
+fn deserialize_quantized<'de, T, D>(step: impl Fn(&QuantizationProfile) -> f32, deserializer: D) -> Result<T, D::Error>
+where
+    T: Serialize + DeserializeOwned,
+    D: Deserializer<'de>,
+{
+    let Some(profile) = active_profile() else {
+        return non_finite::lanes::deserialize(deserializer);
+    };
+    let visitor = QuantizedVisitor {
+        step: step(&profile),
+        _marker: PhantomData,
+    };
+    deserializer.deserialize_tuple(MAX_LANES + 1, visitor)
+}
+
+/// `#[serde(with = "crate::quantization::position")]`
+pub mod position {
+    use super::*;
+
+    pub fn serialize<T: Serialize + DeserializeOwned, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serialize_quantized(value, |p| p.position_step, serializer)
+    }
+
+    pub fn deserialize<'de, T: Serialize + DeserializeOwned, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        deserialize_quantized(|p| p.position_step, deserializer)
+    }
+}
+
+/// `#[serde(with = "crate::quantization::rotation")]`
+pub mod rotation {
+    use super::*;
+
+    pub fn serialize<T: Serialize + DeserializeOwned, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serialize_quantized(value, |p| p.rotation_step, serializer)
+    }
+
+    pub fn deserialize<'de, T: Serialize + DeserializeOwned, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        deserialize_quantized(|p| p.rotation_step, deserializer)
+    }
+}
+
+/// Profiles negotiated for each channel id
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ChannelProfiles(HashMap<u8, QuantizationProfile>);
+
+impl ChannelProfiles {
+    pub fn from_names(names: &[(u8, String)]) -> Result<Self, String> {
+        let mut profiles = HashMap::new();
+        for (channel, name) in names {
+            let profile =
+                QuantizationProfile::from_name(name).ok_or_else(|| format!("Unknown quantization profile {}", name))?;
+            profiles.insert(*channel, profile);
+        }
+        Ok(Self(profiles))
+    }
+
+    pub fn get(&self, channel: u8) -> Option<QuantizationProfile> {
+        self.0.get(&channel).copied()
+    }
+}
@@ -0,0 +1,166 @@
+//! Per-connection rate limits on client messages.
+//!
+//! `RateLimits` caps the messages and bytes per second a connection may
+//! send on each channel. The check runs on the server before a message is
+//! decoded, so a flooding client costs little more than reading its
+//! socket. Excess messages are dropped and counted
+//! (`IServerConnection::get_rate_limited`); `step()` reports them with
+//! `ServerEvents::RateLimited` and disconnects connections over
+//! `RateLimits::with_disconnect_after`.
+
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+
+use crate::messages::NetworkMessageType;
+
+/// Allowed rate of one channel; one second of it may be sent in a burst
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RateLimit {
+    pub messages_per_sec: Option<u32>,
+    pub bytes_per_sec: Option<u32>,
+}
+
+/// Rate limits per client channel.
+///
+/// Channels without a limit and without a default are not limited.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimits {
+    limits: HashMap<NetworkMessageType, RateLimit>,
+    default: Option<RateLimit>,
+    disconnect_after: Option<u64>,
+}
+
+impl RateLimits {
+    pub fn with_limit(
+        mut self,
+        channel: NetworkMessageType,
+        messages_per_sec: Option<u32>,
+        bytes_per_sec: Option<u32>,
+    ) -> Self {
+        let limit = RateLimit {
+            messages_per_sec,
+            bytes_per_sec,
+        };
+        self.limits.insert(channel, limit);
+        self
+    }
+
+    /// Limit applied to channels without an explicit entry
+    pub fn with_default(mut self, messages_per_sec: Option<u32>, bytes_per_sec: Option<u32>) -> Self {
+        self.default = Some(RateLimit {
+            messages_per_sec,
+            bytes_per_sec,
+        });
+        self
+    }
+
+    /// Disconnect a connection once this many of its messages were dropped
+    pub fn with_disconnect_after(mut self, dropped: u64) -> Self {
+        self.disconnect_after = Some(dropped);
+        self
+    }
+
+    pub fn get_limit(&self, channel: NetworkMessageType) -> Option<&RateLimit> {
+        self.limits.get(&channel).or(self.default.as_ref())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty() && self.default.is_none()
+    }
+}
+
+/// Token bucket refilled at `rate` per second, holding at most one second of burst
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u32) -> Self {
+        Self {
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, rate: u32, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate as f64).min(rate as f64);
+    }
+}
+
+#[derive(Debug, Default)]
+struct ChannelBuckets {
+    messages: Option<Bucket>,
+    bytes: Option<Bucket>,
+}
+
+/// Buckets and dropped message count of one connection
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    buckets: Mutex<HashMap<NetworkMessageType, ChannelBuckets>>,
+    dropped: AtomicU64,
+    unreported: AtomicU64,
+    kick: AtomicBool,
+}
+
+impl RateLimiter {
+    /// True if a message of `size` bytes may be received on the channel.
+    /// Otherwise it is counted as dropped; reaching
+    /// `RateLimits::with_disconnect_after` requests a kick.
+    pub fn check(&self, limits: &RateLimits, channel: NetworkMessageType, size: usize) -> bool {
+        let Some(limit) = limits.get_limit(channel) else {
+            return true;
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+        let ChannelBuckets { messages, bytes } = buckets.entry(channel).or_default();
+
+        let mut charges: Vec<(&mut Bucket, f64)> = Vec::with_capacity(2);
+        for (bucket, rate, cost) in [
+            (messages, limit.messages_per_sec, 1.0),
+            (bytes, limit.bytes_per_sec, size as f64),
+        ] {
+            if let Some(rate) = rate {
+                let bucket = bucket.get_or_insert_with(|| Bucket::new(rate));
+                bucket.refill(rate, now);
+                charges.push((bucket, cost));
+            }
+        }
+        if charges.iter().all(|(bucket, cost)| bucket.tokens >= *cost) {
+            for (bucket, cost) in charges {
+                bucket.tokens -= cost;
+            }
+            return true;
+        }
+
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        self.unreported.fetch_add(1, Ordering::Relaxed);
+        if limits.disconnect_after == Some(dropped) {
+            self.kick.store(true, Ordering::Relaxed);
+        }
+        false
+    }
+
+    /// Messages dropped since the connection opened
+    pub fn get_dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Messages dropped since the last call, None if there were none
+    pub fn take_unreported(&self) -> Option<u64> {
+        match self.unreported.swap(0, Ordering::Relaxed) {
+            0 => None,
+            dropped => Some(dropped),
+        }
+    }
+
+    /// True once after the connection reached the disconnect threshold
+    pub fn take_kick(&self) -> bool {
+        self.kick.swap(false, Ordering::Relaxed)
+    }
+}
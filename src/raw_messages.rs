@@ -0,0 +1,100 @@
+//! Received messages left undecoded, for servers decoding off the game loop.
+//!
+//! With `ServerConfig::raw_receive` the game messages of a client are not
+//! decoded on receive: `IServerConnection::drain_raw_messages` hands out
+//! their payload, a slice of the receive buffer of the connection, and
+//! `RawClientMessage::decode` decodes it later, e.g. on a worker thread.
+//! Size limits and message routes are checked by variant, without decoding.
+//!
+//! The messages the crate acts on itself (disconnect, acknowledgements, time
+//! sync, RPC, the `ConnectionInfo` of an approval and the positions of
+//! `ServerConfig::position_tracking`) are still decoded on receive and come
+//! out of `drain_client_messages`, as do traced messages, checked by their
+//! inner variant, and everything received before the connection is approved.
+//! The order between the two drains is not kept.
+
+use bytes::Bytes;
+use std::borrow::Cow;
+
+use crate::compression::decompress_payload;
+use crate::errors::contain_panics;
+use crate::messages::{ClientMessages, ClientMessagesDiscriminants, NetworkMessageType};
+use crate::quantization::{with_profile, QuantizationProfile};
+
+/// Game message of a client received with `ServerConfig::raw_receive`
+#[derive(Debug, Clone)]
+pub struct RawClientMessage {
+    channel: u8,
+    variant: ClientMessagesDiscriminants,
+    payload: Bytes,
+    profile: Option<QuantizationProfile>,
+}
+
+impl RawClientMessage {
+    /// The message, if it is left to the game to decode; None if the crate decodes it on receive
+    pub(crate) fn split(
+        channel: u8,
+        payload: Bytes,
+        profile: Option<QuantizationProfile>,
+        position_tracking: bool,
+    ) -> Option<Self> {
+        // bincode leads with the variant index as a little endian u32
+        let index = u32::from_le_bytes(payload.get(..4)?.try_into().ok()?);
+        let variant = ClientMessagesDiscriminants::from_repr(index as usize)?;
+        let decoded_by_crate = match variant {
+            ClientMessagesDiscriminants::ConnectionInfo
+            | ClientMessagesDiscriminants::Disconnect
+            | ClientMessagesDiscriminants::SnapshotAck
+            | ClientMessagesDiscriminants::TimeSync
+            | ClientMessagesDiscriminants::Rpc
+            | ClientMessagesDiscriminants::BoundedAck
+            | ClientMessagesDiscriminants::Echo
+            | ClientMessagesDiscriminants::StateAck
+            | ClientMessagesDiscriminants::StreamCredit
+            | ClientMessagesDiscriminants::Traced => true,
+            ClientMessagesDiscriminants::PlayerMove => position_tracking,
+            _ => false,
+        };
+        if decoded_by_crate {
+            return None;
+        }
+        Some(Self {
+            channel,
+            variant,
+            payload,
+            profile,
+        })
+    }
+
+    pub fn get_channel(&self) -> Option<NetworkMessageType> {
+        NetworkMessageType::from_channel_id(self.channel)
+    }
+
+    /// Name of the variant, as in `MessageRoutes`
+    pub fn get_variant(&self) -> &'static str {
+        self.variant.into()
+    }
+
+    /// Encoded message, decompressed
+    pub fn get_payload(&self) -> &Bytes {
+        &self.payload
+    }
+
+    /// Decode with the quantization of the channel negotiated for the connection
+    pub fn decode(&self) -> Result<ClientMessages, String> {
+        contain_panics(|| {
+            with_profile(self.profile, || bincode::deserialize::<ClientMessages>(&self.payload))
+                .map_err(|e| e.to_string())
+        })
+    }
+}
+
+/// Payload of a message frame and its channel; copied only if it was compressed
+pub(crate) fn frame_payload(channel: u8, payload: Bytes) -> Result<(u8, Bytes), String> {
+    let (channel, inflated) = decompress_payload(channel, &payload)?;
+    let inflated = match inflated {
+        Cow::Owned(inflated) => Some(inflated),
+        Cow::Borrowed(_) => None,
+    };
+    Ok((channel, inflated.map(Bytes::from).unwrap_or(payload)))
+}
@@ -0,0 +1,208 @@
+//! Capture of the messages of a server, for debugging desyncs.
+//!
+//! `IServerNetwork::enable_recording` writes every message received from or
+//! sent to a client to a file: when it passed, the client, the channel, the
+//! variant name and the encoded bytes. `Replay` reads a capture back in the
+//! recorded order, so a harness can feed the `Direction::Inbound` messages
+//! to a server or the `Direction::Outbound` ones of a client to a client,
+//! advancing its own clock by `RecordedMessage::get_offset` instead of
+//! waiting in real time.
+//!
+//! The variant name is stored next to the bytes: once the protocol changed,
+//! a capture still tells what was sent even where the bytes no longer decode.
+//!
+//! A capture is `RECORDING_MAGIC`, `RECORDING_VERSION` as u32 LE, then each
+//! message as a u32 LE length followed by the bincode `RecordedMessage`.
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::client_id::ClientId;
+use crate::messages::{ClientMessages, NetworkMessageType, ServerMessages};
+use crate::quantization::{with_profile, QuantizationProfile};
+
+pub const RECORDING_MAGIC: &[u8; 4] = b"BNRC";
+pub const RECORDING_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    /// `ClientMessages` received by the server
+    Inbound,
+    /// `ServerMessages` sent by the server
+    Outbound,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    /// Microseconds since the recording started
+    pub offset: u64,
+    pub direction: Direction,
+    pub client_id: ClientId,
+    /// `NetworkMessageType::channel_id`
+    pub channel: u8,
+    /// Quantization profile the payload is encoded with
+    pub profile: Option<String>,
+    pub variant: String,
+    /// Encoded message, before compression and fragmentation
+    pub payload: Vec<u8>,
+}
+
+impl RecordedMessage {
+    pub fn get_offset(&self) -> Duration {
+        Duration::from_micros(self.offset)
+    }
+
+    pub fn get_message_type(&self) -> Option<NetworkMessageType> {
+        NetworkMessageType::from_channel_id(self.channel)
+    }
+
+    /// Decode a `Direction::Inbound` message
+    pub fn decode_client(&self) -> Result<ClientMessages, String> {
+        let message: ClientMessages = self.decode()?;
+        self.check_variant(message.as_ref())?;
+        Ok(message)
+    }
+
+    /// Decode a `Direction::Outbound` message
+    pub fn decode_server(&self) -> Result<ServerMessages, String> {
+        let message: ServerMessages = self.decode()?;
+        self.check_variant(message.as_ref())?;
+        Ok(message)
+    }
+
+    fn decode<T: serde::de::DeserializeOwned>(&self) -> Result<T, String> {
+        let profile = match self.profile.as_ref() {
+            Some(name) => match QuantizationProfile::from_name(name) {
+                Some(profile) => Some(profile),
+                None => return Err(format!("Unknown quantization profile {}", name)),
+            },
+            None => None,
+        };
+        with_profile(profile, || bincode::deserialize(&self.payload)).map_err(|e| e.to_string())
+    }
+
+    /// The bytes may decode as another variant if variants were inserted since
+    fn check_variant(&self, decoded: &str) -> Result<(), String> {
+        match decoded == self.variant {
+            true => Ok(()),
+            false => Err(format!("Recorded as {} but decodes as {}", self.variant, decoded)),
+        }
+    }
+}
+
+struct Capture {
+    started: Instant,
+    writer: BufWriter<File>,
+}
+
+/// Capture file being written, shared by the server and its connections
+#[derive(Default)]
+pub struct MessageRecorder {
+    capture: Mutex<Option<Capture>>,
+}
+
+impl MessageRecorder {
+    /// Start writing to `path`, replacing the file; a running capture is closed first
+    pub fn start(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(RECORDING_MAGIC)?;
+        writer.write_all(&RECORDING_VERSION.to_le_bytes())?;
+        let previous = self.capture.lock().replace(Capture {
+            started: Instant::now(),
+            writer,
+        });
+        if let Some(mut previous) = previous {
+            previous.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Close the capture, flushing what is buffered
+    pub fn stop(&self) -> io::Result<()> {
+        match self.capture.lock().take() {
+            Some(mut capture) => capture.writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.capture.lock().is_some()
+    }
+
+    pub(crate) fn record(
+        &self,
+        direction: Direction,
+        client_id: ClientId,
+        channel: u8,
+        profile: Option<QuantizationProfile>,
+        variant: &str,
+        payload: &[u8],
+    ) {
+        let mut capture = self.capture.lock();
+        let Some(current) = capture.as_mut() else {
+            return;
+        };
+        let message = RecordedMessage {
+            offset: current.started.elapsed().as_micros() as u64,
+            direction,
+            client_id,
+            channel,
+            profile: profile.map(|p| p.name.to_string()),
+            variant: variant.to_string(),
+            payload: payload.to_vec(),
+        };
+        let encoded = bincode::serialize(&message).map_err(io::Error::other);
+        let written = encoded.and_then(|encoded| {
+            current.writer.write_all(&(encoded.len() as u32).to_le_bytes())?;
+            current.writer.write_all(&encoded)
+        });
+        if let Err(e) = written {
+            log::warn!(target: "network", "Message recording stopped: {}", e);
+            *capture = None;
+        }
+    }
+}
+
+/// Messages of a capture, in the recorded order
+pub struct Replay {
+    reader: BufReader<File>,
+}
+
+impl Replay {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != RECORDING_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a message recording"));
+        }
+        let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if version != RECORDING_VERSION {
+            let reason = format!("Unsupported recording version {}", version);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+        }
+        Ok(Self { reader })
+    }
+}
+
+impl Iterator for Replay {
+    type Item = io::Result<RecordedMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut length = [0u8; 4];
+        match self.reader.read_exact(&mut length) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(e) => return Some(Err(e)),
+        }
+        let mut encoded = vec![0u8; u32::from_le_bytes(length) as usize];
+        if let Err(e) = self.reader.read_exact(&mut encoded) {
+            return Some(Err(e));
+        }
+        Some(bincode::deserialize(&encoded).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+    }
+}
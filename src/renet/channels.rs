@@ -1,3 +1,5 @@
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Mutex;
 use std::time::Duration;
 
 use renet::{ChannelConfig, SendType};
@@ -9,6 +11,9 @@ pub enum ClientChannel {
     ReliableUnordered,
     Unreliable,
     World,
+    UnreliableSequenced,
+    /// `SystemMessage`s of the crate, e.g. `TuningApplied`
+    System,
 }
 
 impl From<ClientChannel> for u8 {
@@ -18,12 +23,22 @@ impl From<ClientChannel> for u8 {
             ClientChannel::ReliableUnordered => 1,
             ClientChannel::Unreliable => 2,
             ClientChannel::World => 3,
+            ClientChannel::UnreliableSequenced => 4,
+            ClientChannel::System => 5,
         }
     }
 }
 
 pub fn get_client_channels_config() -> Vec<ChannelConfig> {
     vec![
+        // First, as on the server
+        ChannelConfig {
+            channel_id: ClientChannel::System.into(),
+            max_memory_usage_bytes: 1024 * 1024,
+            send_type: SendType::ReliableOrdered {
+                resend_time: Duration::from_secs_f32(0.5_f32),
+            },
+        },
         ChannelConfig {
             channel_id: ClientChannel::ReliableOrdered.into(),
             max_memory_usage_bytes: 1024 * 1024 * 5,
@@ -50,6 +65,11 @@ pub fn get_client_channels_config() -> Vec<ChannelConfig> {
                 resend_time: Duration::from_secs_f32(0.5_f32),
             },
         },
+        ChannelConfig {
+            channel_id: ClientChannel::UnreliableSequenced.into(),
+            max_memory_usage_bytes: 1024 * 256,
+            send_type: SendType::Unreliable,
+        },
     ]
 }
 
@@ -59,6 +79,9 @@ pub enum ServerChannel {
     ReliableUnordered,
     Unreliable,
     World,
+    UnreliableSequenced,
+    /// `SystemMessage`s, outside the game channels
+    System,
 }
 
 impl From<ServerChannel> for u8 {
@@ -68,12 +91,22 @@ impl From<ServerChannel> for u8 {
             ServerChannel::ReliableUnordered => 1,
             ServerChannel::Unreliable => 2,
             ServerChannel::World => 3,
+            ServerChannel::UnreliableSequenced => 4,
+            ServerChannel::System => 5,
         }
     }
 }
 
 pub fn get_server_channels_config() -> Vec<ChannelConfig> {
     vec![
+        // First, so that system messages are packed ahead of the game messages
+        ChannelConfig {
+            channel_id: ServerChannel::System.into(),
+            max_memory_usage_bytes: 1024 * 1024,
+            send_type: SendType::ReliableOrdered {
+                resend_time: Duration::from_secs_f32(0.5_f32),
+            },
+        },
         ChannelConfig {
             channel_id: ServerChannel::ReliableOrdered.into(),
             max_memory_usage_bytes: 1024 * 1024 * 5,
@@ -100,5 +133,49 @@ pub fn get_server_channels_config() -> Vec<ChannelConfig> {
                 resend_time: Duration::from_secs_f32(0.5_f32),
             },
         },
+        ChannelConfig {
+            channel_id: ServerChannel::UnreliableSequenced.into(),
+            max_memory_usage_bytes: 1024 * 256,
+            send_type: SendType::Unreliable,
+        },
     ]
 }
+
+/// Sequence numbers of the `UnreliableSequenced` channels of one connection.
+///
+/// Renet only offers plain unreliable delivery, so every message is
+/// prefixed with a u16 sequence number and the receiver drops messages
+/// older than the last one it accepted.
+#[derive(Default)]
+pub(crate) struct Sequencer {
+    next_sent: AtomicU16,
+    last_received: Mutex<Option<u16>>,
+}
+
+impl Sequencer {
+    pub fn prefix(&self, encoded: Vec<u8>) -> Vec<u8> {
+        let sequence = self.next_sent.fetch_add(1, Ordering::Relaxed);
+        let mut data = Vec::with_capacity(encoded.len() + 2);
+        data.extend(sequence.to_le_bytes());
+        data.extend(encoded);
+        data
+    }
+
+    /// Strip the prefix; None if the message is stale or malformed
+    pub fn accept<'a>(&self, data: &'a [u8]) -> Option<&'a [u8]> {
+        if data.len() < 2 {
+            return None;
+        }
+        let sequence = u16::from_le_bytes([data[0], data[1]]);
+        let mut last_received = self.last_received.lock().unwrap();
+        if let Some(last) = *last_received {
+            // Wrapping comparison: newer if ahead by less than half the range
+            let ahead = sequence.wrapping_sub(last);
+            if ahead == 0 || ahead > u16::MAX / 2 {
+                return None;
+            }
+        }
+        *last_received = Some(sequence);
+        Some(&data[2..])
+    }
+}
@@ -1,19 +1,44 @@
 use common::utils::debug::info::{DebugInfo, DebugValue};
 use flume::{Drain, Receiver, Sender};
 use parking_lot::RwLockReadGuard;
-use parking_lot::{RwLock, RwLockWriteGuard};
+use parking_lot::{Mutex, RwLock, RwLockWriteGuard};
 use renet::RenetClient;
-use renet_netcode::{ClientAuthentication, NetcodeClientTransport};
+use renet_netcode::{ClientAuthentication, NetcodeClientTransport, NETCODE_USER_DATA_BYTES};
 use socket2::{Domain, Protocol, Socket, Type};
-use std::{net::UdpSocket, sync::Arc, time::SystemTime};
+use std::{
+    net::UdpSocket,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
 use strum::IntoEnumIterator;
 
-use crate::client::{resolve_connect_domain, IClientNetwork};
+use crate::client::{resolve_connect_domain, ClientConfig, IClientNetwork};
+use crate::conditions::NetworkConditions;
+use crate::decode_budget::DecodeQueue;
+use crate::diagnostics::ClientDiagnostics;
+use crate::echo::EchoProbe;
+use crate::keyed_state::StateReplica;
+use crate::handshake::timed_psk_proof;
 use crate::messages::ClientMessages;
 use crate::messages::NetworkMessageType;
+use crate::errors::{contain_panics, NetworkError};
+use crate::fragmentation::Reassembly;
+use crate::journal::MessageJournal;
 use crate::messages::ServerMessages;
-
-use super::channels::ServerChannel;
+use crate::network_info::{BandwidthUsage, NetworkInfo, UsageMeter};
+use crate::retries::BoundedReceiver;
+use crate::rpc::RpcEndpoint;
+use crate::scoreboard::Scoreboard;
+use crate::snapshots::{IncomingSnapshots, Snapshot};
+use crate::streams::{IncomingStreams, StreamReader};
+use crate::system::{decode_system, encode_system, SystemMessage};
+use crate::time_sync::TimeSync;
+use crate::tuning::TuningReceiver;
+
+use super::channels::{ClientChannel, Sequencer, ServerChannel};
 use super::{connection_config, PROTOCOL_ID};
 
 type ClientLock = Arc<RwLock<RenetClient>>;
@@ -23,17 +48,38 @@ type ClientMessageType = (u8, Vec<u8>);
 
 #[derive(Clone)]
 pub struct RenetClientNetwork {
+    config: Arc<ClientConfig>,
     client: ClientLock,
     transport: TransferLock,
 
     debug_info: Arc<RwLock<DebugInfo>>,
 
     network_decoder_out: (Sender<ServerMessages>, Receiver<ServerMessages>),
-    network_errors_out: (Sender<String>, Receiver<String>),
+    streams: Arc<IncomingStreams>,
+    snapshots: Arc<IncomingSnapshots>,
+    time_sync: Arc<TimeSync>,
+    echo: Arc<EchoProbe>,
+    state: Arc<StateReplica>,
+    scoreboard: Arc<Scoreboard>,
+    diagnostics: Arc<ClientDiagnostics>,
+    rpc: Arc<RpcEndpoint>,
+    bounded: Arc<BoundedReceiver>,
+    tuning: TuningReceiver,
+    fragments: Arc<Mutex<Reassembly>>,
+    decode_queue: Option<Arc<DecodeQueue>>,
+    network_errors_out: (Sender<NetworkError>, Receiver<NetworkError>),
+    sequencer: Arc<Sequencer>,
+    usage: Arc<RwLock<UsageMeter>>,
+    journal: Option<MessageJournal>,
+
+    // Set by `disconnect_with_reason`; the client disconnects once the queued messages are sent
+    closing: Arc<AtomicBool>,
 
     // Messages was sended by the client
     // must be sended to the server
     network_client_sended: (Sender<ClientMessageType>, Receiver<ClientMessageType>),
+
+    system_messages: (Sender<SystemMessage>, Receiver<SystemMessage>),
 }
 
 impl RenetClientNetwork {
@@ -50,8 +96,38 @@ impl RenetClientNetwork {
     }
 
     /// Send error message to thread server messages channel
-    fn send_network_error(&self, message: String) {
-        self.network_errors_out.0.send(message).unwrap();
+    fn send_network_error(&self, error: NetworkError) {
+        self.network_errors_out.0.send(error).ok();
+    }
+
+    /// Decode a received message and route it to the server messages channel
+    fn receive_payload(&self, payload: &[u8]) {
+        let decode = |payload: &[u8]| {
+            contain_panics(|| bincode::deserialize::<ServerMessages>(payload).map_err(|e| e.to_string()))
+        };
+        let decoded = match decode(payload).and_then(|d| self.fragments.lock().route(d, decode)) {
+            Ok(Some(d)) => d,
+            Ok(None) => return,
+            Err(e) => {
+                self.send_network_error(NetworkError::Decode {
+                    client_id: None,
+                    reason: e,
+                });
+                return;
+            }
+        };
+        // Routed by the inner message, see `crate::trace_context`
+        let (decoded, context) = decoded.untraced();
+        let decoded = self.bounded.route(decoded).and_then(|d| self.streams.route(d));
+        let decoded = decoded.and_then(|d| self.snapshots.route(d));
+        let decoded = decoded.and_then(|d| self.time_sync.route(d));
+        let decoded = decoded.and_then(|d| self.echo.route(d));
+        let decoded = decoded.and_then(|d| self.state.route(d, &self.scoreboard));
+        let Some(decoded) = decoded.and_then(|d| self.rpc.route_server_message(d)) else {
+            return;
+        };
+        self.scoreboard.apply(&decoded);
+        self.network_decoder_out.0.send(decoded.retraced(context)).ok();
     }
 
     fn map_type_channel(message_type: NetworkMessageType) -> ServerChannel {
@@ -60,13 +136,26 @@ impl RenetClientNetwork {
             NetworkMessageType::ReliableUnordered => ServerChannel::ReliableUnordered,
             NetworkMessageType::Unreliable => ServerChannel::Unreliable,
             NetworkMessageType::WorldInfo => ServerChannel::World,
+            NetworkMessageType::UnreliableSequenced => ServerChannel::UnreliableSequenced,
         }
     }
 }
 
 impl IClientNetwork for RenetClientNetwork {
-    async fn new(ip_port: String) -> Result<Self, String> {
-        let client = RenetClient::new(connection_config());
+    async fn new_with_config(ip_port: String, config: ClientConfig) -> Result<Self, String> {
+        if config.proxy.is_some() {
+            // The proxy is TCP-only, see `crate::proxy`; netcode writes its
+            // datagrams to the socket itself, so they can't be encapsulated
+            return Err("The SOCKS5 proxy is TCP-only and the renet transport is UDP".to_string());
+        }
+        if config.network_conditions.is_some() {
+            log::warn!(target: "network", "Network condition simulation is not supported by the renet backend");
+        }
+        if config.connection_timeout.is_some() || config.keep_alive.is_some() {
+            log::warn!(target: "network", "Timeouts and keep-alive are fixed by netcode in the renet backend");
+        }
+
+        let client = RenetClient::new(connection_config(&config.channel_priorities));
 
         // Setup transport layer
         let server_addr = match resolve_connect_domain(&ip_port, 25565_u16).await {
@@ -76,47 +165,84 @@ impl IClientNetwork for RenetClientNetwork {
 
         let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
         let client_id = current_time.as_millis() as u64;
-        let authentication = ClientAuthentication::Unsecure {
-            server_addr: server_addr,
-            client_id,
-            user_data: None,
-            protocol_id: PROTOCOL_ID,
+
+        // Netcode has no challenge round-trip in unsecure mode,
+        // so the pre-shared key proof is bound to the client id and the time
+        let user_data = config.passphrase.as_ref().map(|passphrase| {
+            let proof = timed_psk_proof(passphrase, client_id, current_time.as_secs());
+            let mut user_data = [0_u8; NETCODE_USER_DATA_BYTES];
+            user_data[..proof.len()].copy_from_slice(&proof);
+            user_data
+        });
+        let authentication = match config.connect_token.as_ref() {
+            Some(token) => ClientAuthentication::Secure {
+                connect_token: token.to_netcode()?,
+            },
+            None => ClientAuthentication::Unsecure {
+                server_addr: server_addr,
+                client_id,
+                user_data,
+                protocol_id: PROTOCOL_ID,
+            },
         };
 
         let socket2 = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
             .map_err(|e| format!("Socket create error: {e}"))?;
-        socket2
-            .set_send_buffer_size(8 * 1024 * 1024)
-            .map_err(|e| format!("Set send buffer error: {e}"))?;
-        socket2
-            .set_recv_buffer_size(8 * 1024 * 1024)
-            .map_err(|e| format!("Set recv buffer error: {e}"))?;
+        config.socket.apply(&socket2)?;
         socket2
             .set_nonblocking(true)
             .map_err(|e| format!("Set nonblocking error: {e}"))?;
-        socket2
-            .bind(&"0.0.0.0:0".parse::<std::net::SocketAddr>().unwrap().into())
-            .map_err(|e| format!("Bind error: {e}"))?;
+        config.bind_local(&socket2)?;
 
         let socket: UdpSocket = socket2.into();
 
         let transport = NetcodeClientTransport::new(current_time, authentication, socket).unwrap();
+        let fragments = Reassembly::new(config.get_max_message_size());
+        let journal = config.create_message_journal();
+        let decode_queue = config.create_decode_queue().map(Arc::new);
+        let rpc = Arc::new(config.create_rpc_endpoint());
+        let diagnostics = Arc::new(ClientDiagnostics::new("renet"));
+        diagnostics.add_setting("protocol_id", PROTOCOL_ID);
+        diagnostics.add_setting("secure", config.connect_token.is_some());
+        diagnostics.record_event("Connecting");
         let network = Self {
+            config: Arc::new(config),
             client: Arc::new(RwLock::new(client)),
             transport: Arc::new(RwLock::new(transport)),
 
             debug_info: Arc::new(RwLock::new(Default::default())),
             network_decoder_out: flume::unbounded(),
+            streams: Arc::new(IncomingStreams::new()),
+            snapshots: Arc::new(IncomingSnapshots::new()),
+            time_sync: Arc::new(TimeSync::new()),
+            echo: Default::default(),
+            state: Default::default(),
+            scoreboard: Default::default(),
+            diagnostics,
+            rpc,
+            bounded: Default::default(),
+            tuning: Default::default(),
+            fragments: Arc::new(Mutex::new(fragments)),
+            decode_queue,
             network_errors_out: flume::unbounded(),
+            sequencer: Default::default(),
+            usage: Default::default(),
+            journal,
+            closing: Default::default(),
             network_client_sended: flume::unbounded(),
+            system_messages: flume::unbounded(),
         };
         Ok(network)
     }
 
     async fn step(&self, delta: std::time::Duration) -> bool {
+        self.diagnostics.sample(|| self.get_network_info());
         let mut client = self.get_client_mut();
 
         if client.is_disconnected() {
+            let cause = format!("{:?}", client.disconnect_reason());
+            self.diagnostics.record_disconnect(&cause);
+            self.rpc.close();
             return false;
         }
 
@@ -143,32 +269,79 @@ impl IClientNetwork for RenetClientNetwork {
         client.update(delta);
         let mut transport = self.get_transport_mut();
         if let Err(e) = transport.update(delta, &mut client) {
-            self.send_network_error(e.to_string());
+            self.send_network_error(NetworkError::Transport { reason: e.to_string() });
             return false;
         }
 
         // Отправляем исходящие сообщения (PlayerMove и т.д.) ДО декомпрессии чанков,
         // чтобы они не задерживались тяжёлой обработкой входящих данных.
+        if let Some(ack) = self.snapshots.take_ack() {
+            self.send_message(NetworkMessageType::Unreliable, &ack);
+        }
+        if let Some(request) = self.time_sync.take_request() {
+            self.send_message(NetworkMessageType::Unreliable, &request);
+        }
+        if let Some(ack) = self.bounded.take_ack() {
+            self.send_message(NetworkMessageType::Unreliable, &ack);
+        }
+        for ack in self.state.take_acks() {
+            self.send_message(NetworkMessageType::Unreliable, &ack);
+        }
+        // Credit adds up, so only its delivery matters
+        for credit in self.streams.take_credits() {
+            self.send_message(NetworkMessageType::ReliableUnordered, &credit);
+        }
+        // Nothing to apply on renet but the acknowledgement, see `crate::tuning`
+        if let Some(encoded) = self.tuning.take().and_then(|(_, applied)| encode_system(&applied)) {
+            client.send_message(ClientChannel::System, encoded);
+        }
         for (channel, message) in self.network_client_sended.1.drain() {
             client.send_message(channel, message);
         }
 
         if let Err(e) = transport.send_packets(&mut client) {
-            self.send_network_error(e.to_string());
+            self.send_network_error(NetworkError::Send { reason: e.to_string() });
+        }
+        if self.closing.swap(false, Ordering::SeqCst) {
+            transport.disconnect();
+            self.rpc.close();
+            log::info!(target: "renet", "{}", "Disconnected from the server");
+            return false;
         }
 
         for channel_type in ServerChannel::iter() {
             while let Some(server_message) = client.receive_message(channel_type) {
-                let decoded: ServerMessages = match bincode::deserialize(&server_message) {
-                    Ok(d) => d,
-                    Err(e) => {
-                        self.send_network_error(format!("message decode error: {}", e.to_string()));
+                self.usage.write().add_received(channel_type.into(), server_message.len());
+                let payload = match channel_type {
+                    ServerChannel::UnreliableSequenced => match self.sequencer.accept(&server_message) {
+                        Some(payload) => payload,
+                        None => continue,
+                    },
+                    ServerChannel::System => {
+                        match decode_system(&server_message) {
+                            Some(SystemMessage::ChannelTuning { revision, tuning }) => {
+                                self.tuning.push(revision, tuning);
+                            }
+                            Some(message) => {
+                                self.system_messages.0.send(message).ok();
+                            }
+                            None => {}
+                        }
                         continue;
                     }
+                    _ => &server_message[..],
                 };
-                self.network_decoder_out.0.send(decoded).unwrap();
+                let deferred = DecodeQueue::is_deferred(channel_type.into());
+                if let Some(queue) = self.decode_queue.as_ref().filter(|_| deferred) {
+                    queue.push(payload.to_vec());
+                    continue;
+                }
+                self.receive_payload(payload);
             }
         }
+        if let Some(queue) = self.decode_queue.as_ref() {
+            queue.run(|payload| self.receive_payload(&payload));
+        }
         log::trace!(target: "network", "network step (executed:{:.2?})", delta);
         return true;
     }
@@ -177,30 +350,153 @@ impl IClientNetwork for RenetClientNetwork {
         self.network_decoder_out.1.drain()
     }
 
-    fn iter_errors(&self) -> Drain<'_, String> {
+    fn iter_errors(&self) -> Drain<'_, NetworkError> {
         self.network_errors_out.1.drain()
     }
 
+    fn iter_streams(&self) -> Drain<'_, StreamReader> {
+        self.streams.drain()
+    }
+
+    fn iter_snapshots(&self) -> Drain<'_, Snapshot> {
+        self.snapshots.drain()
+    }
+
+    fn iter_system_messages(&self) -> Drain<'_, SystemMessage> {
+        self.system_messages.1.drain()
+    }
+
     fn is_connected(&self) -> bool {
         self.get_transport().disconnect_reason().is_none()
     }
 
     fn send_message(&self, message_type: NetworkMessageType, message: &ClientMessages) {
         // log::info!(target: "network", "client send_message message:{}", message);
-        let encoded = bincode::serialize(message).unwrap();
+        if let Err(e) = self.config.check_message(message) {
+            self.send_network_error(e);
+            return;
+        }
+        let mut encoded = match bincode::serialize(message) {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                self.send_network_error(NetworkError::Encode {
+                    client_id: None,
+                    variant: message.as_ref().to_string(),
+                    reason: e.to_string(),
+                });
+                return;
+            }
+        };
+        if let Err(e) = self.config.check_message_size(message.as_ref(), encoded.len()) {
+            self.send_network_error(e);
+            return;
+        }
+        {
+            let mut usage = self.usage.write();
+            if message_type.is_optional() && usage.is_over_cap(self.config.bandwidth_cap) {
+                return;
+            }
+            usage.add_sent(message_type.channel_id(), encoded.len());
+        }
+        if let Some(journal) = self.journal.as_ref() {
+            journal.record(message.as_ref(), message_type, encoded.len());
+        }
+        if message_type == NetworkMessageType::UnreliableSequenced {
+            encoded = self.sequencer.prefix(encoded);
+        }
         let msg = (RenetClientNetwork::map_type_channel(message_type).into(), encoded);
-        self.network_client_sended.0.send(msg).unwrap();
+        self.network_client_sended.0.send(msg).ok();
+    }
+
+    fn send_datagram(&self, _data: &[u8]) -> Result<(), String> {
+        Err("Datagrams are not supported by the renet backend".to_string())
+    }
+
+    fn iter_datagrams(&self) -> impl Iterator<Item = Vec<u8>> {
+        std::iter::empty()
     }
 
     fn disconnect(&self) {
         let mut transport = self.get_transport_mut();
         if transport.disconnect_reason().is_none() {
+            self.diagnostics.record_disconnect("by the client");
             transport.disconnect();
+            self.rpc.close();
             log::info!(target: "renet", "{}", "Disconnected from the server");
         }
     }
 
+    fn disconnect_with_reason(&self, reason: Option<String>) {
+        self.send_message(
+            NetworkMessageType::ReliableOrdered,
+            &ClientMessages::Disconnect { message: reason },
+        );
+        // Renet does not resend after the disconnect; a lost reason falls back to the netcode one
+        self.diagnostics.record_disconnect("by the client");
+        self.closing.store(true, Ordering::SeqCst);
+    }
+
     fn get_debug_info(&self) -> RwLockReadGuard<'_, DebugInfo> {
         self.debug_info.read()
     }
+
+    fn get_bandwidth_usage(&self) -> BandwidthUsage {
+        self.usage.write().get_usage()
+    }
+
+    fn set_network_conditions(&self, _conditions: NetworkConditions) {
+        log::warn!(target: "network", "Network condition simulation is not supported by the renet backend");
+    }
+
+    fn get_session_seed(&self) -> Option<u64> {
+        None
+    }
+
+    fn get_message_journal(&self) -> Option<MessageJournal> {
+        self.journal.clone()
+    }
+
+    fn get_echo_probe(&self) -> &EchoProbe {
+        &self.echo
+    }
+
+    fn get_state_replica(&self) -> &StateReplica {
+        &self.state
+    }
+
+    fn get_scoreboard(&self) -> &Scoreboard {
+        &self.scoreboard
+    }
+
+    fn get_diagnostics(&self) -> &ClientDiagnostics {
+        &self.diagnostics
+    }
+
+    fn get_network_info(&self) -> NetworkInfo {
+        let client = self.client.read();
+        NetworkInfo {
+            rtt: std::time::Duration::from_secs_f64(client.rtt()),
+            packet_loss: client.packet_loss() * 100.0,
+            bytes_sent_per_sec: client.bytes_sent_per_sec(),
+            bytes_received_per_sec: client.bytes_received_per_sec(),
+            send_queue: self.network_client_sended.1.len(),
+        }
+    }
+
+    fn server_time(&self) -> Option<f64> {
+        self.time_sync.server_time()
+    }
+
+    fn rtt(&self) -> Option<std::time::Duration> {
+        self.time_sync.rtt()
+    }
+
+    fn get_rpc(&self) -> &RpcEndpoint {
+        &self.rpc
+    }
+
+    fn is_trace_supported(&self) -> bool {
+        // Renet peers are of the same build, see `PROTOCOL_ID`
+        true
+    }
 }
@@ -1,17 +1,101 @@
 use renet::ConnectionConfig;
 
 use self::channels::{get_client_channels_config, get_server_channels_config};
+use crate::priorities::ChannelPriorities;
 
 pub mod client;
 pub mod server;
 pub mod channels;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
 
 pub const PROTOCOL_ID: u64 = 7;
 
-pub fn connection_config() -> ConnectionConfig {
+pub fn connection_config(priorities: &ChannelPriorities) -> ConnectionConfig {
+    // Renet fills the packets channel by channel, in the order of the config
+    let order = priorities.send_order();
+    let rank = |channel_id: u8| order.iter().position(|c| c.channel_id() == channel_id);
+    let mut client_channels_config = get_client_channels_config();
+    client_channels_config.sort_by_key(|c| rank(c.channel_id));
+    let mut server_channels_config = get_server_channels_config();
+    server_channels_config.sort_by_key(|c| rank(c.channel_id));
     ConnectionConfig {
-        available_bytes_per_tick: 1024 * 1024,
-        client_channels_config: get_client_channels_config(),
-        server_channels_config: get_server_channels_config(),
+        available_bytes_per_tick: priorities.get_bytes_per_tick(),
+        client_channels_config,
+        server_channels_config,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use common::chunks::position::Vector3;
+    use common::chunks::rotation::Rotation;
+    use renet::{RenetClient, RenetServer};
+
+    use super::channels::ServerChannel;
+    use super::connection_config;
+    use crate::entities::AnimationState;
+    use crate::messages::{NetworkMessageType, ServerMessages};
+    use crate::priorities::ChannelPriorities;
+
+    const CLIENT_ID: u64 = 1;
+
+    fn entity_move() -> ServerMessages {
+        ServerMessages::EntityMove {
+            world_slug: "default".to_string(),
+            id: 1,
+            position: Vector3 { x: 1.0, y: 2.0, z: 3.0 },
+            rotation: Rotation::new(0.0, 0.0),
+            animation_state: AnimationState::Idle,
+            timestamp: 1.0,
+        }
+    }
+
+    /// Whether the client gets an `EntityMove` sent while chunk fragments are due for resend
+    fn entity_move_arrives(priorities: ChannelPriorities) -> bool {
+        // Fragments smaller than the move, so the budget left after them can't fit it
+        let config = connection_config(&priorities.with_bytes_per_tick(1000));
+        let mut server = RenetServer::new(config.clone());
+        let mut client = RenetClient::new(config);
+        server.add_connection(CLIENT_ID);
+        client.set_connected();
+
+        for _ in 0..500 {
+            server.send_message(CLIENT_ID, ServerChannel::World, vec![0; 10]);
+        }
+        // Sent and lost
+        for _ in 0..100 {
+            if server.get_packets_to_send(CLIENT_ID).unwrap().is_empty() {
+                break;
+            }
+        }
+        server.update(Duration::from_secs(1));
+
+        let encoded = bincode::serialize(&entity_move()).unwrap();
+        server.send_message(CLIENT_ID, ServerChannel::Unreliable, encoded);
+        for packet in server.get_packets_to_send(CLIENT_ID).unwrap() {
+            client.process_packet(&packet);
+        }
+        let Some(message) = client.receive_message(ServerChannel::Unreliable) else {
+            return false;
+        };
+        let message: ServerMessages = bincode::deserialize(&message).unwrap();
+        matches!(message, ServerMessages::EntityMove { .. })
+    }
+
+    #[test]
+    fn movement_goes_before_resends() {
+        let priorities =
+            ChannelPriorities::default().with_before(NetworkMessageType::Unreliable, NetworkMessageType::WorldInfo);
+        assert!(entity_move_arrives(priorities));
+    }
+
+    #[test]
+    fn resends_go_before_movement() {
+        let priorities =
+            ChannelPriorities::default().with_before(NetworkMessageType::WorldInfo, NetworkMessageType::Unreliable);
+        assert!(!entity_move_arrives(priorities));
     }
 }
@@ -1,36 +1,138 @@
+use common::chunks::chunk_position::ChunkPosition;
 use flume::{Receiver, Sender};
-use renet::{RenetServer, ServerEvent};
-use renet_netcode::{NetcodeServerTransport, ServerAuthentication, ServerConfig};
+use parking_lot::Mutex;
+use renet::{RenetServer, SendType, ServerEvent};
+use renet_netcode::{
+    NetcodeServerTransport, NetcodeTransportError, ServerAuthentication, ServerConfig as NetcodeServerConfig,
+};
 use socket2::{Domain, Protocol, Socket, Type};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    io,
     net::{SocketAddr, UdpSocket},
     sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 use strum::IntoEnumIterator;
 
 use super::{
-    channels::{ClientChannel, ServerChannel},
+    channels::{get_server_channels_config, ClientChannel, Sequencer, ServerChannel},
     connection_config, PROTOCOL_ID,
 };
 use crate::{
+    approval::{ApprovalGate, RejectionReason},
+    area_of_interest::AreaOfInterest,
+    audit::{AuditEvent, AuditLog},
+    auth::AuthIdentity,
+    client_id::ClientId,
+    coalescing::Coalescer,
+    compression::AdaptiveCompression,
+    conditions::NetworkConditions,
+    degraded::Degradation,
+    discovery::start_discovery,
+    draining::Draining,
+    echo::echo_reply,
+    errors::{contain_panics, NetworkError},
+    fragmentation::{needs_fragmentation, split_message},
+    generation::Generation,
+    groups::ConnectionGroups,
+    handshake::{SessionParameters, TimedProofs},
+    health::{start_health_endpoint, ServerStats},
+    interest::ChunkInterest,
+    ip_limits::{parse_ip, IpLimitDecision},
+    keyed_state::StateSyncs,
+    labels::ConnectionLabel,
+    latency_compensation::SynchronizedBroadcasts,
+    watchdog::{emit_stall_report, spawn_watchdog, StallReport},
+    write_ahead::WriteAheadLog,
     messages::{ClientMessages, NetworkMessageType, ServerMessages},
-    server::{ConnectionMessages, IServerConnection, IServerNetwork},
+    network_info::NetworkInfo,
+    phases::{GamePhase, PhaseGate},
+    rate_limits::RateLimiter,
+    raw_messages::RawClientMessage,
+    recording::{Direction, MessageRecorder},
+    retries::{BoundedSender, RetryPolicy},
+    routing::{PermissionGate, Permissions},
+    rpc::RpcEndpoint,
+    shaping::{BurstPriority, BurstReservation, Shaper},
+    socket_errors::{report_socket_error, SocketFailures, SocketRecovery},
+    snapshots::{SnapshotBuilder, SnapshotSender},
+    streams::StreamWindows,
+    system::{decode_system, encode_system, SystemMessage},
+    tenants::Tenant,
+    thresholds::ConnectionThresholds,
+    tick_report::TickCounters,
+    time_sync::TimeSyncRequests,
+    tuning::ConnectionTuning,
+    server::{ConnectionMessages, IServerConnection, IServerNetwork, ServerConfig, ServerEvents},
 };
 
 type ServerLock = Arc<RwLock<RenetServer>>;
-type TransferLock = Arc<RwLock<NetcodeServerTransport>>;
+/// Packet loop of the netcode server; io_uring based with the `io-uring` feature on Linux
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+pub type ServerTransport = NetcodeServerTransport;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub type ServerTransport = super::uring::UringServerTransport;
+
+type TransferLock = Arc<RwLock<ServerTransport>>;
 
 pub struct RenetServerNetwork {
     server: ServerLock,
     transport: TransferLock,
-    connections: Arc<RwLock<HashMap<u64, RenetServerConnection>>>,
+    connections: Arc<RwLock<HashMap<ClientId, RenetServerConnection>>>,
     channel_connections: (
         Sender<ConnectionMessages<RenetServerConnection>>,
         Receiver<ConnectionMessages<RenetServerConnection>>,
     ),
-    channel_errors: (Sender<String>, Receiver<String>),
+    channel_errors: (Sender<NetworkError>, Receiver<NetworkError>),
+    channel_events: (Sender<ServerEvents>, Receiver<ServerEvents>),
+    config: Arc<ServerConfig>,
+    audit_log: AuditLog,
+    stats: Arc<ServerStats>,
+    tick_counters: Arc<TickCounters>,
+    groups: ConnectionGroups,
+    area_of_interest: AreaOfInterest,
+    draining: Draining,
+    thresholds: ConnectionThresholds,
+    recorder: Arc<MessageRecorder>,
+    write_ahead: WriteAheadLog,
+    /// Address the socket is bound to, to rebind it after errors
+    address: SocketAddr,
+    socket_failures: Mutex<SocketFailures>,
+    synchronized: SynchronizedBroadcasts,
+    /// Pre-shared key proofs already used, see `crate::handshake::timed_psk_proof`
+    timed_proofs: TimedProofs,
+}
+
+/// Bind the netcode transport of the server; returns it with the bound address.
+///
+/// The socket is bound with SO_REUSEADDR, so a new one can take the address
+/// while the failed one is still open.
+fn bind_transport(addr: SocketAddr, config: &ServerConfig) -> Result<(ServerTransport, SocketAddr), String> {
+    let socket2 =
+        Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP)).map_err(|e| e.to_string())?;
+    config.socket.apply(&socket2)?;
+    socket2.set_reuse_address(true).map_err(|e| e.to_string())?;
+    socket2.set_nonblocking(true).map_err(|e| e.to_string())?;
+    socket2.bind(&addr.into()).map_err(|e| e.to_string())?;
+
+    let socket: UdpSocket = socket2.into();
+    let address = socket.local_addr().map_err(|e| e.to_string())?;
+
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+    let server_config = NetcodeServerConfig {
+        current_time,
+        max_clients: 64,
+        protocol_id: PROTOCOL_ID,
+        public_addresses: vec![address],
+        authentication: match config.private_key {
+            Some(private_key) => ServerAuthentication::Secure { private_key },
+            None => ServerAuthentication::Unsecure,
+        },
+    };
+
+    let transport = ServerTransport::new(server_config, socket).map_err(|e| e.to_string())?;
+    Ok((transport, address))
 }
 
 impl RenetServerNetwork {
@@ -42,115 +144,566 @@ impl RenetServerNetwork {
         self.server.as_ref().write().expect("poisoned")
     }
 
-    pub fn get_transport(&self) -> RwLockReadGuard<'_, NetcodeServerTransport> {
+    pub fn get_transport(&self) -> RwLockReadGuard<'_, ServerTransport> {
         self.transport.as_ref().read().expect("poisoned")
     }
 
-    fn get_transport_mut(&self) -> RwLockWriteGuard<'_, NetcodeServerTransport> {
+    fn get_transport_mut(&self) -> RwLockWriteGuard<'_, ServerTransport> {
         self.transport.as_ref().write().expect("poisoned")
     }
 
+    /// Retry the socket on the next tick, or bind it again once the error persists
+    fn recover_socket(&self, server: &mut RenetServer, transport: &mut ServerTransport, error: &io::Error) {
+        if !self.socket_failures.lock().failed(error) {
+            report_socket_error(&self.channel_events.0, "udp", error, SocketRecovery::Retried);
+            return;
+        }
+        let recovery = match bind_transport(self.address, &self.config) {
+            Ok((rebound, _)) => {
+                // Netcode sessions lived in the old transport; the clients reconnect
+                server.disconnect_all();
+                *transport = rebound;
+                SocketRecovery::Rebound
+            }
+            Err(e) => {
+                log::warn!(target: "renet", "UDP socket rebind on {} failed: {}", self.address, e);
+                SocketRecovery::RebindFailed
+            }
+        };
+        report_socket_error(&self.channel_events.0, "udp", error, recovery);
+    }
+
     fn map_type_channel(message_type: NetworkMessageType) -> ServerChannel {
         match message_type {
             NetworkMessageType::ReliableOrdered => ServerChannel::ReliableOrdered,
             NetworkMessageType::Unreliable => ServerChannel::Unreliable,
             NetworkMessageType::ReliableUnordered => ServerChannel::ReliableUnordered,
             NetworkMessageType::WorldInfo => ServerChannel::World,
+            NetworkMessageType::UnreliableSequenced => ServerChannel::UnreliableSequenced,
+        }
+    }
+
+    /// Apply `ServerConfig::ip_limit` to a new connection from `step()`,
+    /// which holds the locks; false if it is rejected
+    fn check_ip_limit_locked(
+        &self,
+        server: &mut RenetServer,
+        connections: &HashMap<ClientId, RenetServerConnection>,
+        connection: &RenetServerConnection,
+    ) -> bool {
+        let (Some(limit), Some(ip)) = (self.config.ip_limit.as_ref(), parse_ip(&connection.ip)) else {
+            return true;
+        };
+        let mut existing: Vec<ClientId> = connections
+            .values()
+            // Not the ones already disconnecting, rejected or replaced
+            .filter(|c| c.disconnect_at.read().unwrap().is_none() && parse_ip(&c.ip) == Some(ip))
+            .map(|c| c.client_id)
+            .collect();
+        existing.sort_unstable();
+        let decision = limit.check(&ip, &existing);
+        if matches!(decision, IpLimitDecision::Accept) {
+            return true;
+        }
+        log::warn!(target: "renet", "Client {} from {} is over {} connections per address", connection.label, ip, limit.get_max(&ip));
+        let event = ServerEvents::IpLimitReached {
+            client_id: connection.client_id,
+            ip,
+            connections: existing.len(),
+            policy: limit.get_policy(),
+        };
+        self.channel_events.0.send(event).ok();
+        match decision {
+            IpLimitDecision::Reject => {
+                let reason = RejectionReason::TooManyConnections;
+                self.audit_log.record(AuditEvent::ConnectionRejected {
+                    client_id: connection.client_id,
+                    ip: connection.ip.clone(),
+                    reason: reason.to_string(),
+                });
+                // Never reported as connected
+                connection.approval.reject();
+                connection.send_locked(server, &ServerMessages::ConnectionRejected { reason });
+                connection.disconnect();
+                false
+            }
+            IpLimitDecision::Replace(oldest) => {
+                if let Some(oldest) = connections.get(&oldest) {
+                    oldest.kick_locked(server, "Replaced by a newer connection from the same address");
+                }
+                true
+            }
+            IpLimitDecision::Accept | IpLimitDecision::Report => true,
         }
     }
 }
 
 impl IServerNetwork<RenetServerConnection> for RenetServerNetwork {
-    async fn new(ip_port: String) -> Self {
-        let server = RenetServer::new(connection_config());
+    async fn new_with_config(ip_port: String, config: ServerConfig) -> Self {
+        let server = RenetServer::new(connection_config(&config.channel_priorities));
+        if !config.tenants.is_empty() {
+            log::warn!(target: "network", "Tenants are not supported by the renet backend");
+        }
 
         let addr: SocketAddr = ip_port.parse().unwrap();
-
-        let socket2 = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
-        socket2.set_send_buffer_size(8 * 1024 * 1024).unwrap();
-        socket2.set_recv_buffer_size(8 * 1024 * 1024).unwrap();
-        socket2.set_nonblocking(true).unwrap();
-        socket2.bind(&addr.into()).unwrap();
-
-        let socket: UdpSocket = socket2.into();
-
-        let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
-        let server_config = ServerConfig {
-            current_time,
-            max_clients: 64,
-            protocol_id: PROTOCOL_ID,
-            public_addresses: vec![socket.local_addr().unwrap()],
-            authentication: ServerAuthentication::Unsecure,
-        };
-
-        let transport = NetcodeServerTransport::new(server_config, socket).unwrap();
+        let (transport, address) = bind_transport(addr, &config).unwrap();
+        if config.network_conditions.is_some() {
+            log::warn!(target: "network", "Network condition simulation is not supported by the renet backend");
+        }
+        if let Some(path) = config.local_socket.as_ref() {
+            log::warn!(target: "network", "Local socket {} is not supported by the renet backend", path.display());
+        }
+        if let Some(address) = config.websocket_address {
+            log::warn!(target: "network", "WebSocket address {} is not supported by the renet backend", address);
+        }
+        if config.compression_threshold.is_some() {
+            log::warn!(target: "network", "Compression is not negotiated by the renet backend; sending uncompressed");
+        }
+        if !config.quantization.is_empty() {
+            log::warn!(target: "network", "Quantization profiles are not negotiated by the renet backend; sending full precision");
+        }
+        if config.connection_timeout.is_some() || config.keep_alive.is_some() {
+            log::warn!(target: "network", "Timeouts and keep-alive are fixed by netcode in the renet backend");
+        }
+        if config.rekey.is_some() {
+            log::warn!(target: "network", "Session keys are not rotated by the renet backend");
+        }
+        if !config.clear_channels.is_empty() {
+            log::warn!(target: "network", "Netcode encrypts every channel in the renet backend");
+        }
+        if config.max_pending_connections.is_some() {
+            log::warn!(target: "network", "Max pending connections is not supported by the renet backend");
+        }
+        if config.session_resume.is_some() {
+            log::warn!(target: "network", "Session resume is not supported by the renet backend");
+        }
+        if config.auth.is_some() {
+            log::warn!(target: "network", "Auth providers are not supported by the renet backend; every client is rejected");
+        }
+        let audit_log = AuditLog::new(config.audit_key.clone().unwrap_or_default());
         let network = Self {
             server: Arc::new(RwLock::new(server)),
             transport: Arc::new(RwLock::new(transport)),
             connections: Default::default(),
             channel_connections: flume::unbounded(),
             channel_errors: flume::unbounded(),
+            channel_events: flume::unbounded(),
+            thresholds: ConnectionThresholds::new(config.max_connections),
+            config: Arc::new(config),
+            audit_log,
+            stats: Default::default(),
+            tick_counters: Default::default(),
+            groups: Default::default(),
+            area_of_interest: Default::default(),
+            draining: Default::default(),
+            recorder: Default::default(),
+            write_ahead: Default::default(),
+            address,
+            socket_failures: Default::default(),
+            synchronized: Default::default(),
+            timed_proofs: Default::default(),
         };
+        start_health_endpoint(network.config.health_address, &network.stats).await;
+        let discovery = network.config.discovery.as_ref();
+        start_discovery(discovery, &network.stats, addr.port(), network.config.max_connections);
+        if let Some(threshold) = network.config.stall_threshold {
+            spawn_watchdog(&network.stats, threshold);
+        }
         network
     }
 
     async fn step(&self, delta: Duration) {
+        let step_started = Instant::now();
+        let tick_time = self.stats.record_tick(self.connections_count());
+        if self.config.is_stalled(tick_time) {
+            // Renet keeps its send queues internal; only received messages are counted
+            let report = StallReport {
+                gap: tick_time.unwrap_or_default(),
+                queued_client_messages: self
+                    .connections
+                    .read()
+                    .unwrap()
+                    .values()
+                    .map(|c| c.channel_client_messages.1.len())
+                    .sum(),
+                ..Default::default()
+            };
+            emit_stall_report(&self.channel_events.0, report);
+        }
+        // Before the locks below, which `send_to_clients` takes too
+        for (message_type, message, client_ids) in self.synchronized.take_due(step_started) {
+            self.send_to_clients(&client_ids, message_type, &message);
+        }
         let mut server = self.get_server_mut();
         let mut transport = self.get_transport_mut();
         server.update(delta);
 
-        if let Err(e) = transport.update(delta, &mut server) {
-            self.channel_errors.0.send(e.to_string()).unwrap();
-            return;
+        match transport.update(delta, &mut server) {
+            Ok(()) => self.socket_failures.lock().succeeded(),
+            // Messages already received are still handled this tick
+            Err(NetcodeTransportError::IO(e)) => self.recover_socket(&mut server, &mut transport, &e),
+            Err(e) => {
+                let error = NetworkError::Transport { reason: e.to_string() };
+                self.channel_errors.0.send(error).ok();
+                return;
+            }
         }
 
+        // Unreliable messages left in the channel when the budget runs out
+        // are decoded on the next tick
+        let mut deferred = 0;
         let mut connections = self.connections.write().unwrap();
         for connection in connections.values() {
             for channel_type in ClientChannel::iter() {
-                while let Some(client_message) = server.receive_message(connection.client_id, channel_type) {
-                    let decoded: ClientMessages = match bincode::deserialize(&client_message) {
+                let deferrable = matches!(
+                    channel_type,
+                    ClientChannel::Unreliable | ClientChannel::UnreliableSequenced
+                );
+                if deferrable && self.config.is_over_budget(step_started) {
+                    deferred += 1;
+                    continue;
+                }
+                while let Some(client_message) = server.receive_message(connection.client_id.get(), channel_type) {
+                    let payload = match channel_type {
+                        ClientChannel::UnreliableSequenced => match connection.sequencer.accept(&client_message) {
+                            Some(payload) => payload,
+                            None => continue,
+                        },
+                        ClientChannel::System => {
+                            if let Some(SystemMessage::TuningApplied { revision }) = decode_system(&client_message) {
+                                connection.tuning.ack(revision);
+                            }
+                            continue;
+                        }
+                        _ => &client_message[..],
+                    };
+                    let channel = NetworkMessageType::from_channel_id(channel_type.into());
+                    if let Some(channel) = channel {
+                        // Checked before decoding, so a flood costs little CPU
+                        if !connection
+                            .rate_limiter
+                            .check(&self.config.rate_limits, channel, payload.len())
+                        {
+                            self.tick_counters.add_dropped();
+                            continue;
+                        }
+                    }
+                    // Game messages pass undecoded, see `crate::raw_messages`
+                    if self.config.raw_receive && connection.approval.is_approved() {
+                        let payload = client_message.slice_ref(payload);
+                        let raw =
+                            RawClientMessage::split(channel_type.into(), payload, None, self.config.position_tracking);
+                        if let Some(raw) = raw {
+                            let (variant, size) = (raw.get_variant(), raw.get_payload().len());
+                            self.recorder.record(
+                                Direction::Inbound,
+                                connection.client_id,
+                                channel_type.into(),
+                                None,
+                                variant,
+                                raw.get_payload(),
+                            );
+                            let events = &self.channel_events.0;
+                            let label = &connection.label;
+                            if self.config.check_message_size(events, label, variant, size)
+                                && self
+                                    .config
+                                    .check_message_route(events, &connection.permissions, label, variant)
+                            {
+                                self.tick_counters.add_in(size);
+                                let channel_id = channel_type.into();
+                                let payload = raw.get_payload().clone();
+                                let raw_tx = connection.channel_raw_messages.0.clone();
+                                self.write_ahead
+                                    .append(label, channel_id, None, variant, &payload, move || {
+                                        raw_tx.send(raw).ok();
+                                    });
+                            } else {
+                                self.tick_counters.add_dropped();
+                            }
+                            continue;
+                        }
+                    }
+                    let decoded =
+                        contain_panics(|| bincode::deserialize::<ClientMessages>(payload).map_err(|e| e.to_string()));
+                    let decoded = match decoded {
                         Ok(d) => d,
                         Err(e) => {
                             log::error!(target: "renet", "Decode client {} message error: {}", channel_type, e);
+                            self.tick_counters.add_dropped();
                             continue;
                         }
                     };
+                    self.recorder.record(
+                        Direction::Inbound,
+                        connection.client_id,
+                        channel_type.into(),
+                        None,
+                        decoded.as_ref(),
+                        payload,
+                    );
+                    // Checked and routed by the inner message, see `crate::trace_context`
+                    let (decoded, context) = decoded.untraced();
+                    if let ClientMessages::Disconnect { message } = decoded {
+                        // Netcode reports the disconnect next
+                        *connection.disconnect_reason.lock() = message;
+                        continue;
+                    }
+                    if let ClientMessages::SnapshotAck { world_slug, sequence } = decoded {
+                        connection.snapshots.lock().ack(&world_slug, sequence);
+                        continue;
+                    }
+                    if let ClientMessages::TimeSync { client_time } = decoded {
+                        connection.time_sync.push(client_time);
+                        continue;
+                    }
+                    if let ClientMessages::BoundedAck { ids } = decoded {
+                        connection.bounded.ack(&ids);
+                        continue;
+                    }
+                    if let ClientMessages::StateAck { name, version } = decoded {
+                        connection.state_syncs.ack(&name, version);
+                        continue;
+                    }
+                    if let ClientMessages::StreamCredit { stream_id, bytes } = decoded {
+                        connection.stream_windows.grant(stream_id, bytes);
+                        continue;
+                    }
+                    if let ClientMessages::Echo { id, payload } = decoded {
+                        if let Some(channel) = channel {
+                            connection.answer_echo_locked(&mut server, channel, id, payload);
+                        }
+                        continue;
+                    }
                     // log::info!(target: "network", "server receive message:{}", decoded);
-                    connection.channel_client_messages.0.send(decoded).unwrap();
+                    let size = payload.len();
+                    let events = &self.channel_events.0;
+                    let client_id = connection.client_id;
+                    let label = &connection.label;
+                    if self.config.check_message_size(events, label, decoded.as_ref(), size)
+                        && self
+                            .config
+                            .check_message_route(events, &connection.permissions, label, decoded.as_ref())
+                        && connection
+                            .approval
+                            .check(self.config.approval.as_ref(), client_id, &connection.ip, &decoded)
+                    {
+                        self.tick_counters.add_in(size);
+                        if self.config.position_tracking {
+                            self.area_of_interest.observe(client_id, &decoded);
+                        }
+                        if let Some(decoded) = connection.rpc.route_client_message(decoded) {
+                            let decoded = decoded.retraced(context);
+                            let channel_id = channel_type.into();
+                            let variant = decoded.as_ref().to_string();
+                            let tx = connection.channel_client_messages.0.clone();
+                            self.write_ahead
+                                .append(label, channel_id, None, &variant, payload, move || {
+                                    tx.send(decoded).ok();
+                                });
+                        }
+                    } else {
+                        self.tick_counters.add_dropped();
+                    }
+                    if connection.permissions.take_kick() {
+                        self.audit_log.record(AuditEvent::PermissionKick {
+                            client_id,
+                            ip: connection.ip.clone(),
+                        });
+                        connection.kick_locked(&mut server, "Forbidden message");
+                    }
+
+                    if deferrable && self.config.is_over_budget(step_started) {
+                        deferred += 1;
+                        break;
+                    }
                 }
             }
+
+            connection.answer_time_sync_locked(&mut server, self.server_time());
+            connection.resend_bounded_locked(&mut server);
+            connection.apply_tuning();
+            connection.update_degradation_locked(&mut server);
+
+            // Report and kick connections over `ServerConfig::rate_limits`
+            let limiter = &connection.rate_limiter;
+            if let Some(dropped) = limiter.take_unreported() {
+                let total = limiter.get_dropped();
+                log::warn!(target: "renet", "Client {} rate limited; {} messages dropped", connection.label, total);
+                let event = ServerEvents::RateLimited {
+                    client_id: connection.client_id,
+                    dropped,
+                    total,
+                };
+                self.channel_events.0.send(event).ok();
+            }
+            if limiter.take_kick() {
+                self.audit_log.record(AuditEvent::RateLimitKick {
+                    client_id: connection.client_id,
+                    ip: connection.ip.clone(),
+                });
+                connection.kick_locked(&mut server, "Rate limit exceeded");
+            }
         }
 
         while let Some(event) = server.get_event() {
             match event {
                 ServerEvent::ClientConnected { client_id } => {
-                    let addr = transport.client_addr(client_id.clone()).unwrap();
-                    let connection = RenetServerConnection::create(self.server.clone(), client_id, addr.to_string());
-                    let connect = ConnectionMessages::Connect {
-                        connection: connection.clone(),
+                    // Netcode never hands out 0
+                    let Some(id) = ClientId::new(client_id) else {
+                        server.disconnect(client_id);
+                        continue;
+                    };
+                    // With connect tokens netcode authenticates the client and the user data
+                    // comes from the token, so there is no passphrase proof to check
+                    let user_data = transport.user_data(client_id);
+                    let passphrase = self.config.passphrase.as_ref().filter(|_| self.config.private_key.is_none());
+                    let now = SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap_or_default();
+                    let authenticated = self
+                        .timed_proofs
+                        .verify(passphrase, client_id, user_data.as_ref().map(|d| &d[..]), now.as_secs())
+                        // Without the handshake there is no credential for `ServerConfig::auth`
+                        .and_then(|()| match self.config.auth {
+                            Some(_) => Err("Auth providers need the tokio backend".to_string()),
+                            None => Ok(()),
+                        });
+                    if let Err(e) = authenticated {
+                        log::warn!(target: "renet", "Client {} rejected: {}", client_id, e);
+                        let ip = transport.client_addr(client_id).map(|a| a.to_string());
+                        self.audit_log.record(AuditEvent::AuthFailed {
+                            ip: ip.unwrap_or_default(),
+                            reason: e,
+                        });
+                        server.disconnect(client_id);
+                        continue;
+                    }
+
+                    SessionParameters::without_negotiation(&self.config).emit_fallbacks(&self.channel_events.0, id);
+
+                    let Some(addr) = transport.client_addr(client_id) else {
+                        log::warn!(target: "renet", "Client {} connected without an address; disconnected", client_id);
+                        server.disconnect(client_id);
+                        continue;
                     };
-                    self.channel_connections.0.send(connect).unwrap();
+                    let connection = RenetServerConnection::create(
+                        self.server.clone(),
+                        id,
+                        addr.to_string(),
+                        self.config.clone(),
+                        self.channel_events.0.clone(),
+                        self.channel_errors.0.clone(),
+                        self.tick_counters.clone(),
+                        self.recorder.clone(),
+                    );
+                    if !self.check_ip_limit_locked(&mut server, &connections, &connection) {
+                        // Rejected over `ServerConfig::ip_limit`, see `crate::ip_limits`
+                    } else if self.draining.is_draining() {
+                        // Netcode has no handshake to refuse it in; never reported as connected
+                        let reason = RejectionReason::ShuttingDown;
+                        connection.send_locked(&mut server, &ServerMessages::ConnectionRejected { reason });
+                        connection.disconnect();
+                    } else if self.config.approval.is_some() {
+                        // Reported once approved, see `crate::approval`
+                        connection.send_locked(&mut server, &ServerMessages::AllowConnection);
+                    } else {
+                        let connect = ConnectionMessages::Connect {
+                            connection: connection.clone(),
+                        };
+                        self.channel_connections.0.send(connect).ok();
+                    }
                     connections.insert(connection.get_client_id(), connection);
                 }
                 ServerEvent::ClientDisconnected { client_id, reason } => {
-                    connections.remove(&client_id);
+                    // Clients rejected by the handshake were never reported as connected
+                    let Some(connection) = ClientId::new(client_id).and_then(|id| connections.remove(&id)) else {
+                        continue;
+                    };
+                    connection.generation.expire();
+                    self.groups.remove_client(connection.client_id);
+                    self.area_of_interest.remove_client(connection.client_id);
+                    connection.rpc.close();
+                    connection.stream_windows.close_all();
+                    // Neither were clients awaiting or refused approval
+                    if !connection.approval.is_approved() {
+                        continue;
+                    }
+                    let sent_reason = connection.disconnect_reason.lock().take();
                     let connect = ConnectionMessages::Disconnect {
-                        client_id: client_id,
-                        reason: reason.to_string(),
+                        client_id: connection.client_id,
+                        reason: sent_reason.unwrap_or_else(|| reason.to_string()),
                     };
-                    self.channel_connections.0.send(connect).unwrap();
+                    self.channel_connections.0.send(connect).ok();
                 }
             }
         }
 
+        // Report or reject connections decided by `ServerConfig::approval`
+        for connection in connections.values() {
+            match connection.approval.take_decision() {
+                Some(Ok(())) => {
+                    if let Some(notice) = self.draining.notice() {
+                        connection.send_locked(&mut server, &notice);
+                    }
+                    let connect = ConnectionMessages::Connect {
+                        connection: connection.clone(),
+                    };
+                    self.channel_connections.0.send(connect).ok();
+                }
+                Some(Err(reason)) => {
+                    let event = AuditEvent::rejection(connection.client_id, &connection.ip, &reason);
+                    self.audit_log.record(event);
+                    connection.send_locked(&mut server, &ServerMessages::ConnectionRejected { reason });
+                    connection.disconnect();
+                }
+                None => {}
+            }
+        }
+
+        for connection in connections.values() {
+            connection.flush_fragments(&mut server);
+            connection.flush_deadline_messages(&mut server);
+            connection.flush_latest_messages(&mut server);
+            connection.flush_shaped_messages(&mut server);
+        }
         transport.send_packets(&mut server);
 
         connections.retain(|_key, c| {
-            if c.is_to_disconnect() {
-                server.disconnect(c.get_client_id());
+            let to_disconnect = c.is_to_disconnect_locked(&server);
+            if to_disconnect {
+                server.disconnect(c.get_client_id().get());
             }
-            !c.is_to_disconnect()
+            !to_disconnect
         });
+
+        // `connections_count` would take the server lock held by `step()`
+        if let Some(drained) = self.draining.check(server.connected_clients()) {
+            self.channel_events.0.send(drained).ok();
+        }
+        for crossing in self.thresholds.update(server.connected_clients()) {
+            let event = ServerEvents::ThresholdCrossed { crossing };
+            self.channel_events.0.send(event).ok();
+        }
+
+        if let Some(budget) = self.config.step_budget {
+            let elapsed = step_started.elapsed();
+            if elapsed > budget {
+                let overloaded = ServerEvents::Overloaded {
+                    elapsed,
+                    budget,
+                    deferred,
+                };
+                self.channel_events.0.send(overloaded).ok();
+            }
+        }
+
+        if self.config.tick_reports {
+            let report = self
+                .tick_counters
+                .take_report(tick_time, step_started.elapsed(), connections.len());
+            self.channel_events.0.send(ServerEvents::TickReport { report }).ok();
+        }
         log::trace!(target: "network", "network step (executed:{:.2?})", delta);
     }
 
@@ -158,41 +711,491 @@ impl IServerNetwork<RenetServerConnection> for RenetServerNetwork {
         self.channel_connections.1.drain()
     }
 
-    fn drain_errors(&self) -> impl Iterator<Item = String> {
+    fn drain_errors(&self) -> impl Iterator<Item = NetworkError> {
         self.channel_errors.1.drain()
     }
 
+    fn drain_events(&self) -> impl Iterator<Item = ServerEvents> {
+        self.channel_events.1.drain()
+    }
+
     fn is_connected(&self, connection: &RenetServerConnection) -> bool {
         if connection.is_to_disconnect() {
             return false;
         }
-        self.get_server().is_connected(connection.get_client_id())
+        self.get_server().is_connected(connection.get_client_id().get())
     }
 
     fn connections_count(&self) -> usize {
         self.get_server().connected_clients()
     }
+
+    fn connections_snapshot(&self) -> Vec<RenetServerConnection> {
+        self.connections
+            .read()
+            .unwrap()
+            .values()
+            .filter(|c| c.approval.is_approved())
+            .cloned()
+            .collect()
+    }
+
+    fn get_audit_log(&self) -> &AuditLog {
+        &self.audit_log
+    }
+
+    fn get_config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    fn server_time(&self) -> f64 {
+        self.stats.uptime().as_secs_f64()
+    }
+
+    fn get_foreign_datagrams(&self) -> u64 {
+        // No out-of-band datagrams; netcode drops packets of another protocol id itself, uncounted
+        0
+    }
+
+    fn get_tenant(&self, _name: &str) -> Option<&Tenant<RenetServerConnection>> {
+        None
+    }
+
+    fn set_network_conditions(&self, _conditions: NetworkConditions) {
+        log::warn!(target: "network", "Network condition simulation is not supported by the renet backend");
+    }
+
+    fn get_groups(&self) -> &ConnectionGroups {
+        &self.groups
+    }
+
+    fn get_area_of_interest(&self) -> &AreaOfInterest {
+        &self.area_of_interest
+    }
+
+    fn get_draining(&self) -> &Draining {
+        &self.draining
+    }
+
+    fn get_synchronized_broadcasts(&self) -> &SynchronizedBroadcasts {
+        &self.synchronized
+    }
+
+    fn get_recorder(&self) -> &MessageRecorder {
+        &self.recorder
+    }
+
+    fn get_write_ahead_log(&self) -> &WriteAheadLog {
+        &self.write_ahead
+    }
+
+    fn get_thresholds(&self) -> &ConnectionThresholds {
+        &self.thresholds
+    }
+
+    fn send_to_clients(&self, client_ids: &[ClientId], message_type: NetworkMessageType, message: &ServerMessages) {
+        let resolved = message.resolve_skins(None);
+        let message = resolved.as_ref().unwrap_or(message);
+        let encoded = match bincode::serialize(message) {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                self.tick_counters.add_dropped();
+                let error = NetworkError::Encode {
+                    client_id: None,
+                    variant: message.as_ref().to_string(),
+                    reason: e.to_string(),
+                };
+                self.channel_errors.0.send(error).ok();
+                return;
+            }
+        };
+        // Same lock order as step
+        let mut server = self.get_server_mut();
+        let connections = self.connections.read().unwrap();
+        for client_id in client_ids {
+            let Some(connection) = connections.get(client_id) else {
+                continue;
+            };
+            let Some(encoded) = connection.check_encoded(message_type, message, encoded.clone()) else {
+                continue;
+            };
+            let group = connection.get_group(message, encoded.len());
+            connection.send_shaped(&mut server, message_type, group, encoded);
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct RenetServerConnection {
     server: ServerLock,
-    client_id: u64,
+    client_id: ClientId,
     ip: String,
+    config: Arc<ServerConfig>,
+    channel_events: Sender<ServerEvents>,
+    channel_errors: Sender<NetworkError>,
     disconnect_at: Arc<RwLock<Option<std::time::Instant>>>,
 
     channel_client_messages: (Sender<ClientMessages>, Receiver<ClientMessages>),
+    /// Set with `ServerConfig::raw_receive`
+    channel_raw_messages: (Sender<RawClientMessage>, Receiver<RawClientMessage>),
+    chunk_interest: Option<Arc<parking_lot::RwLock<ChunkInterest>>>,
+
+    // Messages sent with a deadline, handed to renet right before `send_packets`
+    deadline_messages: Arc<Mutex<Vec<DeadlineMessage>>>,
+
+    // Messages of `send_latest`, the latest per key, handed to renet right before `send_packets`
+    latest_messages: Arc<Coalescer<(NetworkMessageType, Option<usize>, Vec<u8>)>>,
+
+    // Messages of topic groups over their bandwidth share
+    shaper: Option<Arc<Mutex<Shaper<ShapedMessage>>>>,
+    burst: Arc<BurstReservation>,
+
+    // Fragments of oversized reliable messages and the reliable messages after them,
+    // handed to renet as its channels have room
+    fragments: Arc<Mutex<VecDeque<ShapedMessage>>>,
+    sequencer: Arc<Sequencer>,
+    tick_counters: Arc<TickCounters>,
+    permissions: Arc<PermissionGate>,
+    approval: Arc<ApprovalGate>,
+    rate_limiter: Arc<RateLimiter>,
+    /// Reason of the client `ClientMessages::Disconnect`
+    disconnect_reason: Arc<Mutex<Option<String>>>,
+    snapshots: Arc<Mutex<SnapshotSender>>,
+    time_sync: Arc<TimeSyncRequests>,
+    rpc: Arc<RpcEndpoint>,
+    bounded: Arc<BoundedSender>,
+    generation: Generation,
+    recorder: Arc<MessageRecorder>,
+    label: ConnectionLabel,
+    phase: Arc<PhaseGate>,
+    state_syncs: Arc<StateSyncs>,
+    stream_windows: Arc<StreamWindows>,
+    compression: Arc<AdaptiveCompression>,
+    tuning: Arc<ConnectionTuning>,
+    degradation: Arc<Degradation>,
+}
+
+type ShapedMessage = (NetworkMessageType, Vec<u8>);
+
+struct DeadlineMessage {
+    deadline: Instant,
+    message_type: NetworkMessageType,
+    variant: String,
+    group: Option<usize>,
+    encoded: Vec<u8>,
 }
 
 impl RenetServerConnection {
-    fn create(server: ServerLock, client_id: u64, ip: String) -> Self {
+    fn create(
+        server: ServerLock,
+        client_id: ClientId,
+        ip: String,
+        config: Arc<ServerConfig>,
+        channel_events: Sender<ServerEvents>,
+        channel_errors: Sender<NetworkError>,
+        tick_counters: Arc<TickCounters>,
+        recorder: Arc<MessageRecorder>,
+    ) -> Self {
+        let burst: Arc<BurstReservation> = Default::default();
         Self {
             server,
             client_id,
             ip,
+            channel_events,
+            channel_errors,
             disconnect_at: Arc::new(RwLock::new(None)),
 
             channel_client_messages: flume::unbounded(),
+            channel_raw_messages: flume::unbounded(),
+            chunk_interest: config.create_chunk_interest(),
+            deadline_messages: Default::default(),
+            latest_messages: Default::default(),
+            shaper: config
+                .traffic_shaping
+                .as_ref()
+                .map(|shaping| Arc::new(Mutex::new(Shaper::new(shaping, burst.clone())))),
+            burst,
+            fragments: Default::default(),
+            sequencer: Default::default(),
+            tick_counters,
+            permissions: Default::default(),
+            approval: Arc::new(ApprovalGate::new(config.approval.as_ref())),
+            rate_limiter: Default::default(),
+            disconnect_reason: Default::default(),
+            snapshots: Default::default(),
+            time_sync: Default::default(),
+            rpc: Arc::new(config.create_rpc_endpoint()),
+            bounded: Default::default(),
+            generation: Generation::new(),
+            recorder,
+            label: ConnectionLabel::new(client_id),
+            phase: Default::default(),
+            state_syncs: Default::default(),
+            // Renet peers are of the same build, see `PROTOCOL_ID`
+            stream_windows: Arc::new(StreamWindows::new(true)),
+            compression: Default::default(),
+            tuning: Arc::new(ConnectionTuning::new(config.traffic_shaping.as_ref())),
+            degradation: Default::default(),
+            config,
+        }
+    }
+
+    /// Encode and check the message; None if it must not be sent
+    fn encode_message(&self, message_type: NetworkMessageType, message: &ServerMessages) -> Option<Vec<u8>> {
+        if !self.generation.check_send(&self.label, &self.channel_errors) {
+            return None;
+        }
+        // Renet clients can't declare a texture size, so they get the largest skin variant
+        let resolved = message.resolve_skins(None);
+        let message = resolved.as_ref().unwrap_or(message);
+        match bincode::serialize(message) {
+            Ok(encoded) => self.check_encoded(message_type, message, encoded),
+            Err(e) => {
+                self.tick_counters.add_dropped();
+                let error = NetworkError::Encode {
+                    client_id: Some(self.client_id),
+                    variant: message.as_ref().to_string(),
+                    reason: e.to_string(),
+                };
+                self.channel_errors.send(error).ok();
+                None
+            }
+        }
+    }
+
+    /// Run the outgoing checks on an already serialized message
+    fn check_encoded(
+        &self,
+        message_type: NetworkMessageType,
+        message: &ServerMessages,
+        encoded: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        if !self.phase.check(&self.config.phase_channels, message_type, message) {
+            self.tick_counters.add_dropped();
+            return None;
+        }
+        if !self
+            .degradation
+            .check(self.config.degraded_mode.as_ref(), message_type, message)
+        {
+            self.tick_counters.add_dropped();
+            return None;
+        }
+        if !self
+            .config
+            .check_message_size(&self.channel_events, &self.label, message.as_ref(), encoded.len())
+        {
+            self.tick_counters.add_dropped();
+            return None;
+        }
+        if !self
+            .config
+            .check_max_message_size(&self.channel_errors, &self.label, message.as_ref(), encoded.len())
+        {
+            self.tick_counters.add_dropped();
+            return None;
+        }
+        self.tick_counters.add_out(encoded.len());
+        self.recorder.record(
+            Direction::Outbound,
+            self.client_id,
+            message_type.channel_id(),
+            None,
+            message.as_ref(),
+            &encoded,
+        );
+        if let Some(interest) = self.chunk_interest.as_ref() {
+            interest.write().observe(message);
+        }
+        if message_type == NetworkMessageType::UnreliableSequenced {
+            return Some(self.sequencer.prefix(encoded));
+        }
+        Some(encoded)
+    }
+
+    /// Hand the message to renet unless its topic group is over budget
+    fn send_shaped(
+        &self,
+        server: &mut RenetServer,
+        message_type: NetworkMessageType,
+        group: Option<usize>,
+        encoded: Vec<u8>,
+    ) {
+        let (message_type, encoded) = match (self.shaper.as_ref(), group) {
+            (Some(shaper), Some(group)) => {
+                let size = encoded.len();
+                match shaper.lock().push(group, size, (message_type, encoded)) {
+                    Some(message) => message,
+                    None => return,
+                }
+            }
+            _ => (message_type, encoded),
+        };
+        self.send_fragmented(server, message_type, encoded);
+    }
+
+    fn flush_shaped_messages(&self, server: &mut RenetServer) {
+        let Some(shaper) = self.shaper.as_ref() else {
+            return;
+        };
+        for (message_type, encoded) in shaper.lock().pop_ready() {
+            self.send_fragmented(server, message_type, encoded);
+        }
+    }
+
+    /// Hand the message to renet, splitting reliable messages over `FRAGMENT_SIZE`.
+    ///
+    /// While fragments are queued, later reliable messages wait behind them to keep their order.
+    fn send_fragmented(&self, server: &mut RenetServer, message_type: NetworkMessageType, encoded: Vec<u8>) {
+        let channel = RenetServerNetwork::map_type_channel(message_type);
+        if message_type.is_optional() {
+            server.send_message(self.client_id.get(), channel, encoded);
+            return;
+        }
+        let mut fragments = self.fragments.lock();
+        if needs_fragmentation(message_type, encoded.len()) {
+            for fragment in split_message(&encoded) {
+                fragments.push_back((message_type, bincode::serialize(&fragment).unwrap()));
+            }
+        } else if fragments.is_empty() {
+            server.send_message(self.client_id.get(), channel, encoded);
+            return;
+        } else {
+            fragments.push_back((message_type, encoded));
+        }
+        drop(fragments);
+        self.flush_fragments(server);
+    }
+
+    fn flush_fragments(&self, server: &mut RenetServer) {
+        let mut fragments = self.fragments.lock();
+        while let Some((message_type, encoded)) = fragments.front() {
+            let channel = RenetServerNetwork::map_type_channel(*message_type);
+            if !server.can_send_message(self.client_id.get(), channel, encoded.len()) {
+                break;
+            }
+            let (_, encoded) = fragments.pop_front().unwrap();
+            server.send_message(self.client_id.get(), channel, encoded);
+        }
+    }
+
+    /// None for the messages of a burst, see `IServerConnection::reserve_burst`
+    fn get_group(&self, message: &ServerMessages, size: usize) -> Option<usize> {
+        if self.burst.take(size) {
+            return None;
+        }
+        self.config.traffic_shaping.as_ref().and_then(|s| s.group_of(message))
+    }
+
+    fn flush_deadline_messages(&self, server: &mut RenetServer) {
+        let now = Instant::now();
+        for message in self.deadline_messages.lock().drain(..) {
+            if now > message.deadline {
+                let event = ServerEvents::DeadlineMissed {
+                    client_id: self.client_id,
+                    variant: message.variant,
+                    late: now - message.deadline,
+                };
+                self.tick_counters.add_dropped();
+                self.channel_events.send(event).ok();
+                continue;
+            }
+            self.send_shaped(server, message.message_type, message.group, message.encoded);
+        }
+    }
+
+    fn flush_latest_messages(&self, server: &mut RenetServer) {
+        for (message_type, group, encoded) in self.latest_messages.drain() {
+            self.send_shaped(server, message_type, group, encoded);
+        }
+    }
+
+    /// Disconnect after `MessageRoutes::with_kick_after` forbidden messages or
+    /// `RateLimits::with_disconnect_after` dropped ones; called from `step()`,
+    /// which holds the server lock
+    fn kick_locked(&self, server: &mut RenetServer, reason: &str) {
+        let message = ServerMessages::Disconnect {
+            message: Some(reason.to_string()),
+        };
+        self.send_locked(server, &message);
+        self.disconnect();
+    }
+
+    /// Send a reliable message from `step()`, which holds the server lock
+    fn send_locked(&self, server: &mut RenetServer, message: &ServerMessages) {
+        if let Some(encoded) = self.encode_message(NetworkMessageType::ReliableOrdered, message) {
+            self.send_shaped(server, NetworkMessageType::ReliableOrdered, None, encoded);
+        }
+    }
+
+    /// Answer the clock requests received this step, see `crate::time_sync`
+    fn answer_time_sync_locked(&self, server: &mut RenetServer, server_time: f64) {
+        for reply in self.time_sync.take_replies(server_time) {
+            if let Some(encoded) = self.encode_message(NetworkMessageType::Unreliable, &reply) {
+                self.send_shaped(server, NetworkMessageType::Unreliable, None, encoded);
+            }
+        }
+    }
+
+    /// Return `ClientMessages::Echo` on its channel at once, see `crate::echo`
+    fn answer_echo_locked(
+        &self,
+        server: &mut RenetServer,
+        message_type: NetworkMessageType,
+        id: u32,
+        payload: Vec<u8>,
+    ) {
+        let Some(reply) = echo_reply(id, payload) else {
+            return;
+        };
+        if let Some(encoded) = self.encode_message(message_type, &reply) {
+            self.send_shaped(server, message_type, None, encoded);
+        }
+    }
+
+    /// Resend or give up unacknowledged messages, see `crate::retries`
+    fn resend_bounded_locked(&self, server: &mut RenetServer) {
+        let (resends, dropped) = self.bounded.take_due(&self.label, self.tuning.get_resend_interval());
+        for message in resends {
+            if let Some(encoded) = self.encode_message(NetworkMessageType::Unreliable, &message) {
+                self.send_shaped(server, NetworkMessageType::Unreliable, None, encoded);
+            }
+        }
+        for event in dropped {
+            self.tick_counters.add_dropped();
+            self.channel_events.send(event).ok();
+        }
+    }
+
+    /// Apply the tunings the client applied, see `crate::tuning`
+    fn apply_tuning(&self) {
+        for (revision, tuning) in self.tuning.take_applied() {
+            if let Some(shaper) = self.shaper.as_ref() {
+                shaper.lock().set_shares(&self.tuning.get_shares());
+            }
+            let event = ServerEvents::ChannelsRetuned {
+                client_id: self.client_id,
+                revision,
+                tuning,
+            };
+            self.channel_events.send(event).ok();
+        }
+    }
+
+    /// Trip or restore the unreliable-only mode, see `crate::degraded`
+    fn update_degradation_locked(&self, server: &mut RenetServer) {
+        if let (Some(mode), Ok(info)) = (
+            self.config.degraded_mode.as_ref(),
+            server.network_info(self.client_id.get()),
+        ) {
+            let rtt = Duration::from_secs_f64(info.rtt);
+            self.degradation.update(mode, rtt, info.packet_loss * 100.0);
+        }
+        for (degraded, event) in self.degradation.take_changes(self.client_id) {
+            if let Some(encoded) = encode_system(&SystemMessage::DegradedMode { active: degraded }) {
+                server.send_message(self.client_id.get(), ServerChannel::System, encoded);
+            }
+            self.channel_events.send(event).ok();
         }
     }
 
@@ -203,6 +1206,33 @@ impl RenetServerConnection {
             false
         }
     }
+
+    /// Also true once the reliable messages of a disconnected client were acked,
+    /// see `ServerConfig::disconnect_grace`
+    fn is_to_disconnect_locked(&self, server: &RenetServer) -> bool {
+        if self.disconnect_at.read().unwrap().is_none() {
+            return false;
+        }
+        self.is_to_disconnect() || (self.config.disconnect_grace.is_some() && self.is_flushed_locked(server))
+    }
+
+    /// Nothing queued and every reliable message acked by the client
+    fn is_flushed_locked(&self, server: &RenetServer) -> bool {
+        let queued = !self.fragments.lock().is_empty()
+            || !self.deadline_messages.lock().is_empty()
+            || self.latest_messages.len() > 0
+            || self.shaper.as_ref().is_some_and(|shaper| shaper.lock().queued() > 0);
+        if queued {
+            return false;
+        }
+        get_server_channels_config()
+            .into_iter()
+            .filter(|channel| !matches!(channel.send_type, SendType::Unreliable))
+            .all(|channel| {
+                server.channel_available_memory(self.client_id.get(), channel.channel_id)
+                    == channel.max_memory_usage_bytes
+            })
+    }
 }
 
 impl IServerConnection for RenetServerConnection {
@@ -210,29 +1240,195 @@ impl IServerConnection for RenetServerConnection {
         &self.ip
     }
 
-    fn get_client_id(&self) -> u64 {
+    fn get_client_id(&self) -> ClientId {
         self.client_id
     }
 
+    fn get_generation(&self) -> u64 {
+        self.generation.get_id()
+    }
+
+    fn is_stale(&self) -> bool {
+        self.generation.is_stale()
+    }
+
+    fn get_label(&self) -> &ConnectionLabel {
+        &self.label
+    }
+
+    fn get_tenant(&self) -> Option<&String> {
+        None
+    }
+
+    fn get_identity(&self) -> Option<&AuthIdentity> {
+        None
+    }
+
+    fn get_rate_limited(&self) -> u64 {
+        self.rate_limiter.get_dropped()
+    }
+
+    fn get_network_info(&self) -> NetworkInfo {
+        // Renet keeps its send queues internal; only messages held by this crate are counted
+        let shaped = self.shaper.as_ref().map(|s| s.lock().queued()).unwrap_or(0);
+        let send_queue = self.deadline_messages.lock().len() + self.latest_messages.len() + shaped;
+        let server = self.server.as_ref().read().expect("poisoned");
+        let Ok(info) = server.network_info(self.client_id.get()) else {
+            return NetworkInfo {
+                send_queue,
+                ..Default::default()
+            };
+        };
+        NetworkInfo {
+            rtt: Duration::from_secs_f64(info.rtt),
+            packet_loss: info.packet_loss * 100.0,
+            bytes_sent_per_sec: info.bytes_sent_per_second,
+            bytes_received_per_sec: info.bytes_received_per_second,
+            send_queue,
+        }
+    }
+
     fn send_message(&self, message_type: NetworkMessageType, message: &ServerMessages) {
-        let encoded = bincode::serialize(message).unwrap();
+        let Some(encoded) = self.encode_message(message_type, message) else {
+            return;
+        };
+        let group = self.get_group(message, encoded.len());
         let mut server = self.server.as_ref().write().expect("poisoned");
-        server.send_message(
-            self.client_id,
-            RenetServerNetwork::map_type_channel(message_type),
+        self.send_shaped(&mut server, message_type, group, encoded);
+    }
+
+    fn get_permissions(&self) -> Permissions {
+        self.permissions.get()
+    }
+
+    fn set_permissions(&self, permissions: Permissions) {
+        self.permissions.set(permissions);
+    }
+
+    fn get_phase(&self) -> GamePhase {
+        self.phase.get()
+    }
+
+    fn set_phase(&self, phase: GamePhase) {
+        self.phase.set(phase);
+    }
+
+    fn get_session_seed(&self) -> Option<u64> {
+        // Renet connects without the handshake exchanging it
+        None
+    }
+
+    fn send_message_with_deadline(&self, message_type: NetworkMessageType, message: &ServerMessages, deadline: Instant) {
+        let Some(encoded) = self.encode_message(message_type, message) else {
+            return;
+        };
+        self.deadline_messages.lock().push(DeadlineMessage {
+            deadline,
+            message_type,
+            variant: message.as_ref().to_string(),
+            group: self.get_group(message, encoded.len()),
             encoded,
-        );
+        });
+    }
+
+    fn send_latest(&self, key: u64, message_type: NetworkMessageType, message: &ServerMessages) {
+        if !message_type.is_optional() {
+            return self.send_message(message_type, message);
+        }
+        let Some(encoded) = self.encode_message(message_type, message) else {
+            return;
+        };
+        let group = self.get_group(message, encoded.len());
+        self.latest_messages
+            .put(message.as_ref(), key, (message_type, group, encoded));
+    }
+
+    fn reserve_burst(&self, bytes: usize, priority: BurstPriority) {
+        self.burst.reserve(bytes, priority);
+    }
+
+    fn flush(&self) {
+        // Renet packs the queued messages into packets when the server steps
+    }
+
+    fn send_message_bounded(&self, message: &ServerMessages, policy: &RetryPolicy) {
+        let message = self.bounded.send(message, policy);
+        self.send_message(NetworkMessageType::Unreliable, &message);
+    }
+
+    fn send_snapshot(&self, snapshot: &SnapshotBuilder) {
+        let message = self.snapshots.lock().encode(snapshot);
+        self.send_message(NetworkMessageType::Unreliable, &message);
+    }
+
+    fn send_system(&self, message: &SystemMessage) {
+        let Some(encoded) = encode_system(message) else {
+            return;
+        };
+        let mut server = self.server.as_ref().write().expect("poisoned");
+        server.send_message(self.client_id.get(), ServerChannel::System, encoded);
+    }
+
+    fn get_rpc(&self) -> &RpcEndpoint {
+        &self.rpc
+    }
+
+    fn get_state_syncs(&self) -> &StateSyncs {
+        &self.state_syncs
+    }
+
+    fn get_stream_windows(&self) -> &StreamWindows {
+        &self.stream_windows
+    }
+
+    fn get_tuning(&self) -> &ConnectionTuning {
+        &self.tuning
+    }
+
+    fn is_trace_supported(&self) -> bool {
+        // Renet peers are of the same build, see `PROTOCOL_ID`
+        true
+    }
+
+    fn get_degradation(&self) -> &Degradation {
+        &self.degradation
+    }
+
+    fn get_compression(&self) -> &AdaptiveCompression {
+        &self.compression
+    }
+
+    fn set_chunk_center(&self, world_slug: &str, center: ChunkPosition) {
+        let Some(interest) = self.chunk_interest.as_ref() else {
+            return;
+        };
+        let unloads = interest.write().set_center(world_slug, center);
+        for message in unloads.iter() {
+            self.send_message(NetworkMessageType::ReliableOrdered, message);
+        }
     }
 
     fn drain_client_messages(&self) -> impl Iterator<Item = ClientMessages> {
         self.channel_client_messages.1.drain()
     }
 
+    fn drain_raw_messages(&self) -> impl Iterator<Item = RawClientMessage> {
+        self.channel_raw_messages.1.drain()
+    }
+
+    fn send_datagram(&self, _data: &[u8]) -> Result<(), String> {
+        Err("Datagrams are not supported by the renet backend".to_string())
+    }
+
+    fn drain_datagrams(&self) -> impl Iterator<Item = Vec<u8>> {
+        std::iter::empty()
+    }
+
     fn disconnect(&self) {
-        // Отключить через 200ms, чтобы сообщение успело уйти
+        // Отключить с задержкой, чтобы сообщения успели уйти
         let mut disconnect_at = self.disconnect_at.write().unwrap();
         if disconnect_at.is_none() {
-            *disconnect_at = Some(std::time::Instant::now() + Duration::from_millis(200));
+            *disconnect_at = Some(std::time::Instant::now() + self.config.get_disconnect_delay());
         }
     }
 }
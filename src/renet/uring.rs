@@ -0,0 +1,369 @@
+//! io_uring packet loop of the renet server, with the `io-uring` feature on Linux.
+//!
+//! `NetcodeServerTransport` makes a `recv_from` call per datagram until the
+//! socket would block, and a `send_to` call per packet; with a thousand
+//! players the tick is spent in syscalls. This transport drives the same
+//! netcode server state over io_uring instead: receives stay posted in a
+//! ring and `update` reaps the completed ones with one `io_uring_enter`,
+//! and the packets of a tick are submitted in batches. It keeps the methods
+//! of `NetcodeServerTransport` the server uses, which swaps it in with the
+//! feature (`super::server::ServerTransport`).
+
+use io_uring::{opcode, types, IoUring};
+use renet::{ClientId, RenetServer};
+use renet_netcode::{
+    NetcodeServer, NetcodeTransportError, ServerConfig, ServerResult, NETCODE_MAX_PACKET_BYTES, NETCODE_USER_DATA_BYTES,
+};
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::time::Duration;
+
+/// Receives kept posted in the ring
+const RECV_SLOTS: usize = 256;
+
+/// Sends submitted per `io_uring_enter`
+const SEND_BATCH: usize = 256;
+
+/// Marks the completions of the cancels on drop
+const CANCEL_TAG: u64 = u64::MAX;
+
+/// Datagram buffer with the message header pointing into it; boxed, so the
+/// kernel can write through the pointers while it is posted
+struct Slot {
+    buffer: [u8; NETCODE_MAX_PACKET_BYTES],
+    addr: libc::sockaddr_storage,
+    iov: libc::iovec,
+    msg: libc::msghdr,
+}
+
+impl Slot {
+    fn new() -> Box<Self> {
+        let mut slot = Box::new(Self {
+            buffer: [0; NETCODE_MAX_PACKET_BYTES],
+            // SAFETY: plain C structs, for which all zeroes is valid
+            addr: unsafe { mem::zeroed() },
+            iov: libc::iovec {
+                iov_base: std::ptr::null_mut(),
+                iov_len: 0,
+            },
+            msg: unsafe { mem::zeroed() },
+        });
+        slot.iov.iov_base = slot.buffer.as_mut_ptr().cast();
+        slot.msg.msg_name = (&mut slot.addr as *mut libc::sockaddr_storage).cast();
+        slot.msg.msg_iov = &mut slot.iov;
+        slot.msg.msg_iovlen = 1;
+        slot
+    }
+
+    /// Reset for a receive of a whole buffer
+    fn rearm(&mut self) {
+        self.iov.iov_len = NETCODE_MAX_PACKET_BYTES;
+        self.msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    }
+
+    fn fill(&mut self, addr: SocketAddr, packet: &[u8]) {
+        self.buffer[..packet.len()].copy_from_slice(packet);
+        self.iov.iov_len = packet.len();
+        self.msg.msg_namelen = to_sockaddr(addr, &mut self.addr);
+    }
+}
+
+/// Packets copied as the tick produces them, sent by `flush`
+struct SendQueue {
+    fd: types::Fd,
+    ring: IoUring,
+    slots: Vec<Box<Slot>>,
+    queued: usize,
+}
+
+impl SendQueue {
+    fn push(&mut self, addr: SocketAddr, packet: &[u8]) {
+        if self.queued == self.slots.len() {
+            self.slots.push(Slot::new());
+        }
+        self.slots[self.queued].fill(addr, packet);
+        self.queued += 1;
+    }
+
+    /// Send the queued packets and wait for them, a batch per `io_uring_enter`
+    fn flush(&mut self) -> io::Result<()> {
+        let queued = mem::take(&mut self.queued);
+        let result = (0..queued).step_by(SEND_BATCH).try_for_each(|start| {
+            let batch = start..queued.min(start + SEND_BATCH);
+            for index in batch.clone() {
+                let entry = opcode::SendMsg::new(self.fd, &self.slots[index].msg)
+                    .build()
+                    .user_data(index as u64);
+                // SAFETY: the slot is boxed and untouched until its completion is reaped below
+                while unsafe { self.ring.submission().push(&entry) }.is_err() {
+                    self.ring.submit()?;
+                }
+            }
+            self.ring.submit_and_wait(batch.len())?;
+            for entry in self.ring.completion() {
+                if entry.result() < 0 {
+                    let e = io::Error::from_raw_os_error(-entry.result());
+                    let addr = from_sockaddr(&self.slots[entry.user_data() as usize].addr);
+                    log::error!(target: "renet", "Failed to send packet to {:?}: {}", addr, e);
+                }
+            }
+            Ok(())
+        });
+        if result.is_err() {
+            // Sends may still be in flight; their buffers must outlive them
+            mem::forget(mem::take(&mut self.slots));
+        }
+        result
+    }
+}
+
+pub struct UringServerTransport {
+    socket: UdpSocket,
+    netcode_server: NetcodeServer,
+    recv_ring: IoUring,
+    recv_slots: Vec<Box<Slot>>,
+    sends: SendQueue,
+}
+
+// SAFETY: the raw pointers of the slots point into memory owned by the
+// transport, only used through `&mut self` or by the kernel
+unsafe impl Send for UringServerTransport {}
+unsafe impl Sync for UringServerTransport {}
+
+impl UringServerTransport {
+    pub fn new(server_config: ServerConfig, socket: UdpSocket) -> Result<Self, io::Error> {
+        // Blocking: io_uring then waits for a datagram instead of completing with EAGAIN
+        socket.set_nonblocking(false)?;
+        let fd = types::Fd(socket.as_raw_fd());
+        let mut transport = Self {
+            netcode_server: NetcodeServer::new(server_config),
+            recv_ring: IoUring::new(RECV_SLOTS as u32)?,
+            recv_slots: (0..RECV_SLOTS).map(|_| Slot::new()).collect(),
+            sends: SendQueue {
+                fd,
+                ring: IoUring::new(SEND_BATCH as u32)?,
+                slots: Vec::new(),
+                queued: 0,
+            },
+            socket,
+        };
+        for index in 0..RECV_SLOTS {
+            transport.post_receive(index);
+        }
+        transport.recv_ring.submit()?;
+        Ok(transport)
+    }
+
+    pub fn user_data(&self, client_id: ClientId) -> Option<[u8; NETCODE_USER_DATA_BYTES]> {
+        self.netcode_server.user_data(client_id)
+    }
+
+    pub fn client_addr(&self, client_id: ClientId) -> Option<SocketAddr> {
+        self.netcode_server.client_addr(client_id)
+    }
+
+    pub fn connected_clients(&self) -> usize {
+        self.netcode_server.connected_clients()
+    }
+
+    fn post_receive(&mut self, index: usize) {
+        let slot = &mut self.recv_slots[index];
+        slot.rearm();
+        let entry = opcode::RecvMsg::new(types::Fd(self.socket.as_raw_fd()), &mut slot.msg)
+            .build()
+            .user_data(index as u64);
+        // SAFETY: the slot is boxed and stays allocated until its completion is reaped;
+        // each slot is posted at most once, so the ring has room for it
+        unsafe { self.recv_ring.submission().push(&entry) }.expect("a ring entry per slot");
+    }
+
+    /// Process the datagrams received and post their slots again
+    fn receive(&mut self, server: &mut RenetServer) -> Result<(), NetcodeTransportError> {
+        let mut error = None;
+        loop {
+            // Posts the slots again and runs the completions due
+            self.recv_ring.submit()?;
+            let completed: Vec<(usize, i32)> = self
+                .recv_ring
+                .completion()
+                .map(|entry| (entry.user_data() as usize, entry.result()))
+                .collect();
+            if completed.is_empty() {
+                break;
+            }
+            for (index, result) in completed {
+                let slot = &mut self.recv_slots[index];
+                match result {
+                    len if len >= 0 => {
+                        if let Some(addr) = from_sockaddr(&slot.addr) {
+                            let len = (len as usize).min(NETCODE_MAX_PACKET_BYTES);
+                            let result = self.netcode_server.process_packet(addr, &mut slot.buffer[..len]);
+                            handle_server_result(result, &mut self.sends, server);
+                        }
+                    }
+                    _ => {
+                        let e = io::Error::from_raw_os_error(-result);
+                        if e.kind() != io::ErrorKind::ConnectionReset && e.kind() != io::ErrorKind::ConnectionRefused {
+                            error.get_or_insert(e);
+                        }
+                    }
+                }
+                self.post_receive(index);
+            }
+        }
+        match error {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn update(&mut self, duration: Duration, server: &mut RenetServer) -> Result<(), NetcodeTransportError> {
+        self.netcode_server.update(duration);
+        let received = self.receive(server);
+
+        for client_id in self.netcode_server.clients_id() {
+            let result = self.netcode_server.update_client(client_id);
+            handle_server_result(result, &mut self.sends, server);
+        }
+        for client_id in server.disconnections_id() {
+            let result = self.netcode_server.disconnect(client_id);
+            handle_server_result(result, &mut self.sends, server);
+        }
+        self.sends.flush()?;
+        received
+    }
+
+    pub fn send_packets(&mut self, server: &mut RenetServer) {
+        for client_id in server.clients_id() {
+            let Ok(packets) = server.get_packets_to_send(client_id) else {
+                continue;
+            };
+            for packet in packets {
+                match self.netcode_server.generate_payload_packet(client_id, &packet) {
+                    Ok((addr, payload)) => self.sends.push(addr, payload),
+                    Err(e) => {
+                        log::error!(target: "renet", "Failed to encrypt payload packet for client {}: {}", client_id, e);
+                        break;
+                    }
+                }
+            }
+        }
+        if let Err(e) = self.sends.flush() {
+            log::error!(target: "renet", "Failed to submit packets: {}", e);
+        }
+    }
+}
+
+impl Drop for UringServerTransport {
+    /// Cancel the posted receives before their slots are freed
+    fn drop(&mut self) {
+        for index in 0..RECV_SLOTS {
+            let entry = opcode::AsyncCancel::new(index as u64).build().user_data(CANCEL_TAG);
+            // SAFETY: a cancel carries no buffer
+            while unsafe { self.recv_ring.submission().push(&entry) }.is_err() {
+                if self.recv_ring.submit().is_err() {
+                    mem::forget(mem::take(&mut self.recv_slots));
+                    return;
+                }
+            }
+        }
+        let mut ended = 0;
+        while ended < RECV_SLOTS {
+            if self.recv_ring.submit_and_wait(1).is_err() {
+                mem::forget(mem::take(&mut self.recv_slots));
+                return;
+            }
+            ended += self
+                .recv_ring
+                .completion()
+                .filter(|entry| entry.user_data() != CANCEL_TAG)
+                .count();
+        }
+    }
+}
+
+fn handle_server_result(result: ServerResult, sends: &mut SendQueue, server: &mut RenetServer) {
+    match result {
+        ServerResult::None => {}
+        ServerResult::PacketToSend { payload, addr } => sends.push(addr, payload),
+        ServerResult::Payload { client_id, payload } => {
+            if let Err(e) = server.process_packet_from(payload, client_id) {
+                log::error!(target: "renet", "Error while processing payload for client {}: {}", client_id, e);
+            }
+        }
+        ServerResult::ClientConnected {
+            client_id,
+            addr,
+            payload,
+            ..
+        } => {
+            server.add_connection(client_id);
+            sends.push(addr, payload);
+        }
+        ServerResult::ClientDisconnected {
+            client_id,
+            addr,
+            payload,
+            ..
+        } => {
+            server.remove_connection(client_id);
+            if let Some(payload) = payload {
+                sends.push(addr, payload);
+            }
+        }
+    }
+}
+
+fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // SAFETY: the family says the storage holds a sockaddr_in
+            let addr = unsafe { &*(storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in>() };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+        }
+        libc::AF_INET6 => {
+            // SAFETY: the family says the storage holds a sockaddr_in6
+            let addr = unsafe { &*(storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in6>() };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            let port = u16::from_be(addr.sin6_port);
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                ip,
+                port,
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+/// Write the address into the storage; returns its length
+fn to_sockaddr(addr: SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+    match addr {
+        SocketAddr::V4(addr) => {
+            // SAFETY: sockaddr_storage is large and aligned enough for any address
+            let out = unsafe { &mut *(storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in>() };
+            out.sin_family = libc::AF_INET as libc::sa_family_t;
+            out.sin_port = addr.port().to_be();
+            out.sin_addr = libc::in_addr {
+                s_addr: u32::from(*addr.ip()).to_be(),
+            };
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+        }
+        SocketAddr::V6(addr) => {
+            // SAFETY: as above
+            let out = unsafe { &mut *(storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in6>() };
+            out.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            out.sin6_port = addr.port().to_be();
+            out.sin6_flowinfo = addr.flowinfo();
+            out.sin6_addr = libc::in6_addr {
+                s6_addr: addr.ip().octets(),
+            };
+            out.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Session resume after a dropped connection, tokio backend only.
+//!
+//! With `ServerConfig::session_resume` the server issues a session token
+//! during the handshake. When the socket of the session drops without
+//! either side asking to disconnect (Wi-Fi roam, mobile handover), the
+//! client reconnects on its own within the grace period and presents the
+//! token instead of starting over. Meanwhile the server keeps the connection
+//! registered and queues what is sent to it; once resumed it emits
+//! `ServerEvents::Reconnected` rather than a disconnect and a connect, and
+//! the game keeps its handle.
+//!
+//! Both sides count the message frames they receive and acknowledge the
+//! count on every ping, and keep the frames they sent until acknowledged.
+//! On resume each side resends what the other did not receive, so no
+//! message is lost or duplicated. A session whose unacknowledged frames
+//! outgrow `MAX_RESEND_BYTES` can't be resumed and ends as a disconnect.
+
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::client_id::ClientId;
+
+/// Unacknowledged bytes kept for a resume, per side of a session
+pub const MAX_RESEND_BYTES: usize = 8 * 1024 * 1024;
+
+/// Interval between the reconnection attempts of the client
+pub const RESUME_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+pub(crate) type SessionToken = [u8; 16];
+
+/// Offered by the server in the handshake
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct ResumeOffer {
+    pub token: SessionToken,
+    /// How long the server holds the session after its socket dropped
+    pub grace: Duration,
+}
+
+/// Sent by a reconnecting client in the handshake
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct ResumeRequest {
+    pub token: SessionToken,
+    /// Message frames the client received
+    pub received: u64,
+}
+
+#[derive(Default)]
+struct SentFrames {
+    /// Sequence of the first kept frame
+    first: u64,
+    frames: VecDeque<Vec<u8>>,
+    bytes: usize,
+    overflowed: bool,
+}
+
+/// Frame counters of one side of a resumable session
+#[derive(Default)]
+pub(crate) struct ResumeState {
+    received: AtomicU64,
+    sent: Mutex<SentFrames>,
+}
+
+impl ResumeState {
+    pub fn count_received(&self) {
+        self.received.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn get_received(&self) -> u64 {
+        self.received.load(Ordering::SeqCst)
+    }
+
+    /// Keep a message frame written to the socket until the peer acknowledges it
+    pub fn push_sent(&self, frame: &[u8]) {
+        let mut sent = self.sent.lock();
+        if sent.overflowed {
+            return;
+        }
+        sent.bytes += frame.len();
+        sent.frames.push_back(frame.to_vec());
+        if sent.bytes > MAX_RESEND_BYTES {
+            log::warn!(target: "network", "Over {} unacknowledged bytes; the session can't be resumed", MAX_RESEND_BYTES);
+            sent.overflowed = true;
+            sent.frames.clear();
+        }
+    }
+
+    /// The peer received `received` message frames
+    pub fn ack(&self, received: u64) {
+        let mut sent = self.sent.lock();
+        while sent.first < received {
+            let Some(frame) = sent.frames.pop_front() else {
+                break;
+            };
+            sent.bytes -= frame.len();
+            sent.first += 1;
+        }
+    }
+
+    /// Frames to send again to a peer that received `received` of them;
+    /// None if some of them are no longer kept
+    pub fn resend_from(&self, received: u64) -> Option<Vec<Vec<u8>>> {
+        self.ack(received);
+        let sent = self.sent.lock();
+        if sent.overflowed || sent.first != received {
+            return None;
+        }
+        Some(sent.frames.iter().cloned().collect())
+    }
+}
+
+/// Tokens of the resumable sessions of a server, with their client id
+#[derive(Default)]
+pub(crate) struct SessionRegistry {
+    sessions: RwLock<HashMap<SessionToken, ClientId>>,
+}
+
+impl SessionRegistry {
+    pub fn insert(&self, token: SessionToken, client_id: ClientId) {
+        self.sessions.write().insert(token, client_id);
+    }
+
+    pub fn remove(&self, token: &SessionToken) {
+        self.sessions.write().remove(token);
+    }
+
+    pub fn get(&self, token: &SessionToken) -> Option<ClientId> {
+        self.sessions.read().get(token).copied()
+    }
+}
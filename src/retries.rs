@@ -0,0 +1,186 @@
+//! Reliable delivery with bounded retries, for server messages that are
+//! nice to deliver but not worth resending forever, like the spawn of a
+//! distant entity.
+//!
+//! `IServerConnection::send_message_bounded` sends the message over the
+//! unreliable channel and the server `step()` resends it every
+//! `RetryPolicy::resend_interval` until the client acknowledges it. Once
+//! `RetryPolicy::max_attempts` sends or `RetryPolicy::max_age` pass without
+//! an ack, the server gives up and emits `ServerEvents::DeliveryDropped`.
+//!
+//! The client drops duplicates and acknowledges from `step()`. Delivery is
+//! unordered, and the message is not seen by the chunk tracking or the skin
+//! resolution of the server, so chunks and skins should not go this way.
+
+use parking_lot::Mutex;
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::labels::ConnectionLabel;
+use crate::messages::{ClientMessages, ServerMessages};
+use crate::server::ServerEvents;
+
+/// Interval between two sends if `RetryPolicy::resend_interval` is unset
+pub const DEFAULT_RESEND_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Ids of recent messages the client keeps to drop duplicates
+const RECEIVED_HISTORY: usize = 1024;
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    max_age: Option<Duration>,
+    resend_interval: Option<Duration>,
+}
+
+impl RetryPolicy {
+    /// Give up after `max_attempts` sends, the first one included
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            max_age: None,
+            resend_interval: None,
+        }
+    }
+
+    /// Also give up once `max_age` passed since the first send
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn with_resend_interval(mut self, interval: Duration) -> Self {
+        self.resend_interval = Some(interval);
+        self
+    }
+
+    fn get_resend_interval(&self) -> Duration {
+        self.resend_interval.unwrap_or(DEFAULT_RESEND_INTERVAL)
+    }
+}
+
+struct PendingMessage {
+    id: u32,
+    message: ServerMessages,
+    policy: RetryPolicy,
+    first_sent: Instant,
+    last_sent: Instant,
+    attempts: u32,
+}
+
+#[derive(Default)]
+struct SenderState {
+    next_id: u32,
+    pending: Vec<PendingMessage>,
+}
+
+/// Unacknowledged bounded messages of one connection
+#[derive(Default)]
+pub(crate) struct BoundedSender {
+    state: Mutex<SenderState>,
+}
+
+impl BoundedSender {
+    /// Register the message; returns the `ServerMessages::BoundedReliable` to send now
+    pub fn send(&self, message: &ServerMessages, policy: &RetryPolicy) -> ServerMessages {
+        let mut state = self.state.lock();
+        let id = state.next_id;
+        state.next_id = id.wrapping_add(1);
+        let now = Instant::now();
+        state.pending.push(PendingMessage {
+            id,
+            message: message.clone(),
+            policy: policy.clone(),
+            first_sent: now,
+            last_sent: now,
+            attempts: 1,
+        });
+        ServerMessages::BoundedReliable {
+            id,
+            message: Box::new(message.clone()),
+        }
+    }
+
+    /// `ClientMessages::BoundedAck`
+    pub fn ack(&self, ids: &[u32]) {
+        self.state.lock().pending.retain(|p| !ids.contains(&p.id));
+    }
+
+    /// Messages due for a resend, and `ServerEvents::DeliveryDropped` of the
+    /// ones out of attempts or age; called by the server `step()`.
+    ///
+    /// `resend_interval` replaces the one of every policy, see `crate::tuning`
+    pub fn take_due(
+        &self,
+        client: &ConnectionLabel,
+        resend_interval: Option<Duration>,
+    ) -> (Vec<ServerMessages>, Vec<ServerEvents>) {
+        let now = Instant::now();
+        let mut resends = Vec::new();
+        let mut dropped = Vec::new();
+        self.state.lock().pending.retain_mut(|p| {
+            if now - p.last_sent < resend_interval.unwrap_or_else(|| p.policy.get_resend_interval()) {
+                return true;
+            }
+            let too_old = p.policy.max_age.is_some_and(|age| now - p.first_sent >= age);
+            if p.attempts >= p.policy.max_attempts || too_old {
+                log::warn!(target: "network", "Client {} did not ack {} after {} attempts; dropped", client, p.message, p.attempts);
+                dropped.push(ServerEvents::DeliveryDropped {
+                    client_id: client.get_client_id(),
+                    variant: p.message.as_ref().to_string(),
+                    attempts: p.attempts,
+                });
+                return false;
+            }
+            p.attempts += 1;
+            p.last_sent = now;
+            resends.push(ServerMessages::BoundedReliable {
+                id: p.id,
+                message: Box::new(p.message.clone()),
+            });
+            true
+        });
+        (resends, dropped)
+    }
+}
+
+#[derive(Default)]
+struct ReceiverState {
+    received: HashSet<u32>,
+    history: VecDeque<u32>,
+    pending_acks: Vec<u32>,
+}
+
+/// Client side: unwraps bounded messages and drops the duplicates
+#[derive(Default)]
+pub(crate) struct BoundedReceiver {
+    state: Mutex<ReceiverState>,
+}
+
+impl BoundedReceiver {
+    /// Unwrap `ServerMessages::BoundedReliable`; None for a duplicate.
+    /// Any other message is returned as is
+    pub fn route(&self, message: ServerMessages) -> Option<ServerMessages> {
+        let ServerMessages::BoundedReliable { id, message } = message else {
+            return Some(message);
+        };
+        let mut state = self.state.lock();
+        // A duplicate is acked again, the previous ack may be lost
+        state.pending_acks.push(id);
+        if !state.received.insert(id) {
+            return None;
+        }
+        state.history.push_back(id);
+        if state.history.len() > RECEIVED_HISTORY {
+            let oldest = state.history.pop_front().unwrap();
+            state.received.remove(&oldest);
+        }
+        Some(*message)
+    }
+
+    /// Ack of the messages received since the last call; sent by the client `step()`
+    pub fn take_ack(&self) -> Option<ClientMessages> {
+        let ids = std::mem::take(&mut self.state.lock().pending_acks);
+        (!ids.is_empty()).then_some(ClientMessages::BoundedAck { ids })
+    }
+}
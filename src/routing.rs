@@ -0,0 +1,109 @@
+//! Permission-gated routing of client messages.
+//!
+//! `MessageRoutes` maps `ClientMessages` variants to the permission flags
+//! a connection needs to send them. The check runs on the server right
+//! after a message is decoded, so a forbidden message never reaches
+//! `drain_client_messages` and the game code behind it.
+
+use std::collections::HashMap;
+use std::ops::BitOr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// Permission flags of a connection, set by the game with `IServerConnection::set_permissions`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Permissions(u32);
+
+impl Permissions {
+    pub const NONE: Self = Self(0);
+    pub const MODERATOR: Self = Self(1 << 0);
+    pub const ADMIN: Self = Self(1 << 1);
+
+    /// Game-defined flag; bits 0 and 1 are taken by `MODERATOR` and `ADMIN`
+    pub const fn flag(bit: u32) -> Self {
+        Self(1 << bit)
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// True if every flag of `required` is set
+    pub const fn contains(&self, required: Self) -> bool {
+        self.0 & required.0 == required.0
+    }
+}
+
+impl BitOr for Permissions {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// Required permissions per client message variant.
+///
+/// Variants are keyed by their kebab-case name (`ClientMessages::as_ref()`),
+/// e.g. "console-input". Variants without a route are open to everyone.
+#[derive(Clone, Debug, Default)]
+pub struct MessageRoutes {
+    routes: HashMap<String, Permissions>,
+    kick_after: Option<u32>,
+}
+
+impl MessageRoutes {
+    pub fn with_route(mut self, variant: &str, required: Permissions) -> Self {
+        self.routes.insert(variant.to_string(), required);
+        self
+    }
+
+    /// Disconnect a connection once it has sent this many forbidden messages
+    pub fn with_kick_after(mut self, rejections: u32) -> Self {
+        self.kick_after = Some(rejections);
+        self
+    }
+
+    pub fn get_required(&self, variant: &str) -> Permissions {
+        self.routes.get(variant).copied().unwrap_or(Permissions::NONE)
+    }
+}
+
+/// Permissions and forbidden message count of one connection
+#[derive(Debug, Default)]
+pub(crate) struct PermissionGate {
+    permissions: AtomicU32,
+    rejections: AtomicU32,
+    kick: AtomicBool,
+}
+
+impl PermissionGate {
+    pub fn get(&self) -> Permissions {
+        Permissions(self.permissions.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, permissions: Permissions) {
+        self.permissions.store(permissions.0, Ordering::Relaxed);
+    }
+
+    /// None if the variant may be sent, otherwise the number of forbidden
+    /// messages so far. Reaching `MessageRoutes::with_kick_after` requests a kick.
+    pub fn check(&self, routes: &MessageRoutes, variant: &str) -> Option<u32> {
+        if self.get().contains(routes.get_required(variant)) {
+            return None;
+        }
+        let rejections = self.rejections.fetch_add(1, Ordering::Relaxed) + 1;
+        if routes.kick_after == Some(rejections) {
+            self.kick.store(true, Ordering::Relaxed);
+        }
+        Some(rejections)
+    }
+
+    /// True once after the connection reached the kick threshold
+    pub fn take_kick(&self) -> bool {
+        self.kick.swap(false, Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,181 @@
+//! Request/response calls over the reliable channel.
+//!
+//! A type implementing `Request` names its method and response type.
+//! `IClientNetwork::request` and `IServerConnection::request` send it and
+//! resolve to the typed response, or to `RpcError::Timeout` if none arrives
+//! within the configured `rpc_timeout`; correlation ids are handled here.
+//! The other side takes the calls from `RpcEndpoint::drain_requests`,
+//! matches on `IncomingRequest::get_method` and answers with `respond`.
+//!
+//! Calls still pending when the connection closes fail with
+//! `RpcError::Disconnected`.
+
+use flume::{Drain, Receiver, Sender};
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+use crate::messages::{ClientMessages, ServerMessages};
+
+/// Time to wait for a response if `rpc_timeout` is unset
+pub const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub trait Request: Serialize + DeserializeOwned {
+    /// Name the receiving side matches on, unique per request type
+    const METHOD: &'static str;
+    type Response: Serialize + DeserializeOwned;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RpcError {
+    Timeout,
+    Disconnected,
+    /// The handler answered with an error
+    Remote(String),
+    Decode(String),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "Request timed out"),
+            Self::Disconnected => write!(f, "Disconnected before the response"),
+            Self::Remote(reason) => write!(f, "Request failed: {}", reason),
+            Self::Decode(reason) => write!(f, "Decode response error: {}", reason),
+        }
+    }
+}
+
+/// Wire form of a call, carried by `ClientMessages::Rpc` and `ServerMessages::Rpc`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RpcFrame {
+    Request { id: u32, method: String, payload: Vec<u8> },
+    Response { id: u32, result: Result<Vec<u8>, String> },
+}
+
+/// Call received from the other side, waiting for `respond`
+#[derive(Debug, Clone)]
+pub struct IncomingRequest {
+    id: u32,
+    method: String,
+    payload: Vec<u8>,
+}
+
+impl IncomingRequest {
+    pub fn get_method(&self) -> &String {
+        &self.method
+    }
+
+    pub fn decode<R: Request>(&self) -> Result<R, RpcError> {
+        bincode::deserialize(&self.payload).map_err(|e| RpcError::Decode(e.to_string()))
+    }
+
+    pub(crate) fn response_frame<R: Request>(&self, response: Result<&R::Response, String>) -> RpcFrame {
+        RpcFrame::Response {
+            id: self.id,
+            result: response.map(|response| bincode::serialize(response).unwrap()),
+        }
+    }
+}
+
+type PendingResponse = oneshot::Sender<Result<Vec<u8>, String>>;
+
+/// Pending calls and received requests of one connection
+pub struct RpcEndpoint {
+    timeout: Duration,
+    next_id: AtomicU32,
+    pending: Mutex<HashMap<u32, PendingResponse>>,
+    requests: (Sender<IncomingRequest>, Receiver<IncomingRequest>),
+}
+
+impl RpcEndpoint {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            next_id: Default::default(),
+            pending: Default::default(),
+            requests: flume::unbounded(),
+        }
+    }
+
+    pub fn drain_requests(&self) -> Drain<'_, IncomingRequest> {
+        self.requests.1.drain()
+    }
+
+    /// Register a call and hand its frame to `send`; the future resolves to the response
+    pub(crate) fn call<R: Request>(
+        &self,
+        request: &R,
+        send: impl FnOnce(RpcFrame),
+    ) -> impl Future<Output = Result<R::Response, RpcError>> + Send + '_ {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (response_tx, response_rx) = oneshot::channel();
+        self.pending.lock().insert(id, response_tx);
+        send(RpcFrame::Request {
+            id,
+            method: R::METHOD.to_string(),
+            payload: bincode::serialize(request).unwrap(),
+        });
+        async move {
+            let result = match tokio::time::timeout(self.timeout, response_rx).await {
+                Ok(result) => result,
+                Err(_) => {
+                    self.pending.lock().remove(&id);
+                    return Err(RpcError::Timeout);
+                }
+            };
+            match result {
+                Ok(Ok(payload)) => bincode::deserialize(&payload).map_err(|e| RpcError::Decode(e.to_string())),
+                Ok(Err(reason)) => Err(RpcError::Remote(reason)),
+                Err(_) => Err(RpcError::Disconnected),
+            }
+        }
+    }
+
+    fn route(&self, frame: RpcFrame) {
+        match frame {
+            RpcFrame::Request { id, method, payload } => {
+                self.requests.0.send(IncomingRequest { id, method, payload }).ok();
+            }
+            RpcFrame::Response { id, result } => match self.pending.lock().remove(&id) {
+                Some(pending) => {
+                    pending.send(result).ok();
+                }
+                None => log::warn!(target: "network", "Response to unknown or timed out request {}", id),
+            },
+        }
+    }
+
+    /// Consume `ClientMessages::Rpc`; any other message is returned back
+    pub(crate) fn route_client_message(&self, message: ClientMessages) -> Option<ClientMessages> {
+        match message {
+            ClientMessages::Rpc(frame) => {
+                self.route(frame);
+                None
+            }
+            message => Some(message),
+        }
+    }
+
+    /// Consume `ServerMessages::Rpc`; any other message is returned back
+    pub(crate) fn route_server_message(&self, message: ServerMessages) -> Option<ServerMessages> {
+        match message {
+            ServerMessages::Rpc(frame) => {
+                self.route(frame);
+                None
+            }
+            message => Some(message),
+        }
+    }
+
+    /// Fail the pending calls with `RpcError::Disconnected`
+    pub(crate) fn close(&self) {
+        self.pending.lock().clear();
+    }
+}
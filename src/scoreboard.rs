@@ -0,0 +1,224 @@
+//! Scoreboard objectives, scores and display slots.
+//!
+//! The server sends `ServerMessages::ScoreboardObjective`, `ScoreboardScore`
+//! and `ScoreboardDisplay` as any other game message; the client applies them
+//! to its `Scoreboard` (`IClientNetwork::get_scoreboard`) before handing them
+//! to the game, which can redraw on them and read the result from there.
+//!
+//! A server can keep a `Scoreboard` of its own by applying the messages it
+//! broadcasts, and send `Scoreboard::messages` to clients that join later.
+//!
+//! Instead of the messages, the server can mirror its scoreboard into a
+//! `KeyedState` with `Scoreboard::write_state` and sync that to each client
+//! (see `crate::keyed_state`); the client scoreboard is then rebuilt from
+//! every sync of the store, and lost syncs are caught up by the next ones.
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::keyed_state::KeyedState;
+use crate::messages::ServerMessages;
+
+/// Name of the `KeyedState` a scoreboard is synced in
+pub const SCOREBOARD_STATE: &str = "scoreboard";
+
+/// Separates the parts of a key of the scoreboard store
+const KEY_SEPARATOR: char = '\u{1f}';
+
+const SLOTS: [DisplaySlot; 3] = [DisplaySlot::Sidebar, DisplaySlot::PlayerList, DisplaySlot::BelowName];
+
+fn state_key(parts: &[&str]) -> String {
+    parts.join(&KEY_SEPARATOR.to_string())
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, String> {
+    bincode::serialize(value).map_err(|e| e.to_string())
+}
+
+/// Where the client shows an objective
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DisplaySlot {
+    Sidebar,
+    PlayerList,
+    BelowName,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Objective {
+    display_name: String,
+    scores: BTreeMap<String, i32>,
+}
+
+#[derive(Debug, Default)]
+struct ScoreboardState {
+    objectives: BTreeMap<String, Objective>,
+    slots: HashMap<DisplaySlot, String>,
+}
+
+/// Objectives and scores built from the scoreboard messages
+#[derive(Debug, Default)]
+pub struct Scoreboard {
+    state: Mutex<ScoreboardState>,
+}
+
+impl Scoreboard {
+    /// Apply a scoreboard message; any other message is ignored
+    pub fn apply(&self, message: &ServerMessages) {
+        let mut state = self.state.lock();
+        match message {
+            ServerMessages::ScoreboardObjective { name, display_name } => match display_name {
+                Some(display_name) => {
+                    let objective = state.objectives.entry(name.clone()).or_default();
+                    objective.display_name = display_name.clone();
+                }
+                None => {
+                    state.objectives.remove(name);
+                    state.slots.retain(|_, objective| objective != name);
+                }
+            },
+            ServerMessages::ScoreboardScore {
+                objective,
+                entry,
+                score,
+            } => {
+                let Some(objective) = state.objectives.get_mut(objective) else {
+                    log::warn!(target: "network", "Score of unknown objective \"{}\"; ignored", objective);
+                    return;
+                };
+                match score {
+                    Some(score) => {
+                        objective.scores.insert(entry.clone(), *score);
+                    }
+                    None => {
+                        objective.scores.remove(entry);
+                    }
+                }
+            }
+            ServerMessages::ScoreboardDisplay { slot, objective } => match objective {
+                Some(objective) => {
+                    state.slots.insert(*slot, objective.clone());
+                }
+                None => {
+                    state.slots.remove(slot);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    /// Names of the objectives, in order
+    pub fn get_objectives(&self) -> Vec<String> {
+        self.state.lock().objectives.keys().cloned().collect()
+    }
+
+    pub fn get_display_name(&self, objective: &str) -> Option<String> {
+        Some(self.state.lock().objectives.get(objective)?.display_name.clone())
+    }
+
+    pub fn get_score(&self, objective: &str, entry: &str) -> Option<i32> {
+        self.state.lock().objectives.get(objective)?.scores.get(entry).copied()
+    }
+
+    /// Scores of the objective, highest first
+    pub fn get_scores(&self, objective: &str) -> Vec<(String, i32)> {
+        let state = self.state.lock();
+        let Some(objective) = state.objectives.get(objective) else {
+            return Vec::new();
+        };
+        let mut scores: Vec<_> = objective
+            .scores
+            .iter()
+            .map(|(entry, score)| (entry.clone(), *score))
+            .collect();
+        scores.sort_by(|a, b| b.1.cmp(&a.1));
+        scores
+    }
+
+    /// Objective shown in the slot
+    pub fn get_displayed(&self, slot: DisplaySlot) -> Option<String> {
+        self.state.lock().slots.get(&slot).cloned()
+    }
+
+    /// Mirror the scoreboard into `state`, a `KeyedState` named `SCOREBOARD_STATE`;
+    /// keys of what was removed since the last call are removed
+    pub fn write_state(&self, state: &mut KeyedState) -> Result<(), String> {
+        let mut entries = BTreeMap::new();
+        {
+            let scoreboard = self.state.lock();
+            for (name, objective) in scoreboard.objectives.iter() {
+                entries.insert(state_key(&["o", name]), encode(&objective.display_name)?);
+                for (entry, score) in objective.scores.iter() {
+                    entries.insert(state_key(&["s", name, entry]), encode(score)?);
+                }
+            }
+            for (slot, objective) in scoreboard.slots.iter() {
+                entries.insert(state_key(&["d", &format!("{:?}", slot)]), encode(objective)?);
+            }
+        }
+        for key in state.keys() {
+            if !entries.contains_key(&key) {
+                state.remove(&key);
+            }
+        }
+        for (key, value) in entries {
+            state.set_raw(&key, value);
+        }
+        Ok(())
+    }
+
+    /// Replace the scoreboard with the one of a synced `SCOREBOARD_STATE` store
+    pub(crate) fn rebuild(&self, entries: &BTreeMap<String, Vec<u8>>) {
+        let mut rebuilt = ScoreboardState::default();
+        for (key, value) in entries.iter() {
+            let parts: Vec<&str> = key.splitn(3, KEY_SEPARATOR).collect();
+            match parts[..] {
+                ["o", name] => {
+                    if let Ok(display_name) = bincode::deserialize::<String>(value) {
+                        rebuilt.objectives.entry(name.to_string()).or_default().display_name = display_name;
+                    }
+                }
+                ["s", objective, entry] => {
+                    if let Ok(score) = bincode::deserialize::<i32>(value) {
+                        let objective = rebuilt.objectives.entry(objective.to_string()).or_default();
+                        objective.scores.insert(entry.to_string(), score);
+                    }
+                }
+                _ => {}
+            }
+        }
+        for slot in SLOTS {
+            let key = state_key(&["d", &format!("{:?}", slot)]);
+            if let Some(Ok(objective)) = entries.get(&key).map(|value| bincode::deserialize::<String>(value)) {
+                rebuilt.slots.insert(slot, objective);
+            }
+        }
+        *self.state.lock() = rebuilt;
+    }
+
+    /// Messages rebuilding the whole scoreboard, e.g. for a client that just joined
+    pub fn messages(&self) -> Vec<ServerMessages> {
+        let state = self.state.lock();
+        let mut messages = Vec::new();
+        for (name, objective) in state.objectives.iter() {
+            messages.push(ServerMessages::ScoreboardObjective {
+                name: name.clone(),
+                display_name: Some(objective.display_name.clone()),
+            });
+            for (entry, score) in objective.scores.iter() {
+                messages.push(ServerMessages::ScoreboardScore {
+                    objective: name.clone(),
+                    entry: entry.clone(),
+                    score: Some(*score),
+                });
+            }
+        }
+        for (slot, objective) in state.slots.iter() {
+            messages.push(ServerMessages::ScoreboardDisplay {
+                slot: *slot,
+                objective: Some(objective.clone()),
+            });
+        }
+        messages
+    }
+}
@@ -0,0 +1,472 @@
+//! Encrypted and authenticated connections with connect tokens.
+//!
+//! An auth service holding the server private key mints a `ConnectToken`
+//! per client and hands it over a secure channel (e.g. HTTPS). The token
+//! carries the session keys twice: in the clear for the client, and sealed
+//! with the private key for the server. A client without a valid token,
+//! or one that can't read the keys, can't complete the connection; every
+//! frame after the handshake is encrypted and authenticated.
+//!
+//! The tokio backend seals its own tokens; the renet backend uses
+//! netcode connect tokens minted alongside. Servers without a private
+//! key accept unencrypted connections (local testing).
+//!
+//! With `ServerConfig::rekey`, tokio connections rotate their keys on long
+//! sessions: once a direction has sealed `RekeyPolicy::bytes` or used its
+//! key for `RekeyPolicy::interval`, its sender seals a
+//! `SystemMessage::Rekey` with the current key and switches to the key of
+//! the next epoch, derived from the current one. The receiver switches on
+//! opening it, so no frame is lost or held back, and the keys of past
+//! epochs are forgotten by both ends.
+//!
+//! Channels set with `ServerConfig::with_clear_channel` are sent in the
+//! clear but still authenticated on tokio connections, so a relay can route
+//! or mix them (e.g. voice) without the session keys; a forged or replayed
+//! frame still fails to verify at the other end. Only the channels the
+//! client allows with `ClientConfig::with_clear_channel` go in the clear;
+//! the others stay encrypted. The handshake result naming them travels in
+//! the clear, so the server authenticates it with its session key and the
+//! client checks it before the first sealed frame: a rewritten list fails
+//! the connection instead of leaking it.
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::client_id::ClientId;
+
+pub const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+pub(crate) const TAG_SIZE: usize = 16;
+
+/// Bytes sealed with one key before it is rotated, if unset
+pub const DEFAULT_REKEY_BYTES: u64 = 1 << 30;
+
+/// Seconds without packets before netcode drops a connection made with the token
+#[cfg(feature = "network-renet")]
+const NETCODE_TIMEOUT_SECONDS: i32 = 15;
+
+/// Key shared by the server and the auth service minting its tokens
+pub type PrivateKey = [u8; KEY_SIZE];
+
+pub fn generate_private_key() -> PrivateKey {
+    rand::random()
+}
+
+fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize)]
+struct TokenContent {
+    client_id: ClientId,
+    expires_at: u64,
+    client_to_server_key: [u8; KEY_SIZE],
+    server_to_client_key: [u8; KEY_SIZE],
+}
+
+/// Token part only the server can open, sent in the handshake
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SealedToken {
+    expires_at: u64,
+    nonce: [u8; NONCE_SIZE],
+    data: Vec<u8>,
+}
+
+/// Keys of one encrypted session
+#[derive(Clone)]
+pub(crate) struct SessionKeys {
+    pub client_to_server: [u8; KEY_SIZE],
+    pub server_to_client: [u8; KEY_SIZE],
+}
+
+impl fmt::Debug for SessionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionKeys")
+    }
+}
+
+impl SealedToken {
+    /// Check the token against the server private key and read the session keys
+    pub fn open(&self, private_key: &PrivateKey) -> Result<SessionKeys, String> {
+        if self.expires_at <= unix_time().as_secs() {
+            return Err("Connect token expired".to_string());
+        }
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(private_key));
+        let payload = Payload {
+            msg: &self.data,
+            aad: &self.expires_at.to_le_bytes(),
+        };
+        let data = cipher
+            .decrypt(Nonce::from_slice(&self.nonce), payload)
+            .map_err(|_| "Invalid connect token".to_string())?;
+        let content: TokenContent = bincode::deserialize(&data).map_err(|_| "Invalid connect token".to_string())?;
+        Ok(SessionKeys {
+            client_to_server: content.client_to_server_key,
+            server_to_client: content.server_to_client_key,
+        })
+    }
+}
+
+/// Permission for one client to connect, minted by an auth service.
+///
+/// Send it to the client with `to_bytes`; the client passes it to
+/// `ClientConfig::with_connect_token`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ConnectToken {
+    client_id: ClientId,
+    /// Seconds since the unix epoch
+    expires_at: u64,
+    client_to_server_key: [u8; KEY_SIZE],
+    server_to_client_key: [u8; KEY_SIZE],
+    sealed: SealedToken,
+    /// Netcode connect token for the renet backend
+    #[cfg(feature = "network-renet")]
+    netcode: Vec<u8>,
+}
+
+impl ConnectToken {
+    /// Mint a token valid for `valid_for`.
+    ///
+    /// `server_addresses` are the addresses the client may connect to;
+    /// only netcode (renet backend) checks them.
+    pub fn generate(
+        private_key: &PrivateKey,
+        client_id: ClientId,
+        server_addresses: Vec<SocketAddr>,
+        valid_for: Duration,
+    ) -> Result<Self, String> {
+        let now = unix_time();
+        let content = TokenContent {
+            client_id,
+            expires_at: (now + valid_for).as_secs(),
+            client_to_server_key: rand::random(),
+            server_to_client_key: rand::random(),
+        };
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(private_key));
+        let payload = Payload {
+            msg: &bincode::serialize(&content).unwrap(),
+            aad: &content.expires_at.to_le_bytes(),
+        };
+        let data = cipher
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|e| format!("Connect token encrypt error: {}", e))?;
+
+        #[cfg(feature = "network-renet")]
+        let netcode = {
+            let token = renet_netcode::ConnectToken::generate(
+                now,
+                crate::renet::PROTOCOL_ID,
+                valid_for.as_secs(),
+                client_id.get(),
+                NETCODE_TIMEOUT_SECONDS,
+                server_addresses,
+                None,
+                private_key,
+            )
+            .map_err(|e| format!("Netcode token error: {}", e))?;
+            let mut netcode = Vec::new();
+            token
+                .write(&mut netcode)
+                .map_err(|e| format!("Netcode token error: {}", e))?;
+            netcode
+        };
+        #[cfg(not(feature = "network-renet"))]
+        let _ = server_addresses;
+
+        Ok(Self {
+            client_id,
+            expires_at: content.expires_at,
+            client_to_server_key: content.client_to_server_key,
+            server_to_client_key: content.server_to_client_key,
+            sealed: SealedToken {
+                expires_at: content.expires_at,
+                nonce,
+                data,
+            },
+            #[cfg(feature = "network-renet")]
+            netcode,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        bincode::deserialize(bytes).map_err(|e| format!("Connect token decode error: {}", e))
+    }
+
+    pub fn get_client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    /// Seconds since the unix epoch
+    pub fn get_expires_at(&self) -> u64 {
+        self.expires_at
+    }
+
+    pub(crate) fn get_sealed(&self) -> &SealedToken {
+        &self.sealed
+    }
+
+    pub(crate) fn get_keys(&self) -> SessionKeys {
+        SessionKeys {
+            client_to_server: self.client_to_server_key,
+            server_to_client: self.server_to_client_key,
+        }
+    }
+
+    #[cfg(feature = "network-renet")]
+    pub(crate) fn to_netcode(&self) -> Result<renet_netcode::ConnectToken, String> {
+        renet_netcode::ConnectToken::read(&mut self.netcode.as_slice())
+            .map_err(|e| format!("Netcode token error: {}", e))
+    }
+}
+
+impl fmt::Debug for ConnectToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectToken")
+            .field("client_id", &self.client_id)
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+/// When a direction of a connection rotates its key, see `crate::security`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RekeyPolicy {
+    /// Longest use of one key
+    pub interval: Duration,
+    /// Most bytes sealed with one key
+    pub bytes: u64,
+}
+
+impl RekeyPolicy {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            bytes: DEFAULT_REKEY_BYTES,
+        }
+    }
+
+    pub fn with_bytes(mut self, bytes: u64) -> Self {
+        self.bytes = bytes;
+        self
+    }
+}
+
+/// Key of the epoch after `epoch`: HMAC-SHA256(key, "rekey" | epoch)
+fn next_key(key: &[u8; KEY_SIZE], epoch: u32) -> [u8; KEY_SIZE] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key size");
+    mac.update(b"rekey");
+    mac.update(&epoch.to_le_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+/// Encryption of one direction of a connection.
+///
+/// Frames arrive in order on a stream transport, so the nonce is a
+/// counter both ends keep; a dropped, replayed or reordered frame fails to open.
+pub(crate) struct FrameCipher {
+    cipher: ChaCha20Poly1305,
+    key: [u8; KEY_SIZE],
+    counter: u64,
+    /// Rotations of the key, see `rekey`
+    epoch: u32,
+    sealed_bytes: u64,
+    keyed_at: Instant,
+}
+
+impl FrameCipher {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            key: *key,
+            counter: 0,
+            epoch: 0,
+            sealed_bytes: 0,
+            keyed_at: Instant::now(),
+        }
+    }
+
+    pub fn get_epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// The sender is due to rotate the key
+    pub fn is_rekey_due(&self, policy: &RekeyPolicy) -> bool {
+        self.sealed_bytes >= policy.bytes || self.keyed_at.elapsed() >= policy.interval
+    }
+
+    /// Switch to the key of the next epoch; the current key is forgotten
+    pub fn rekey(&mut self) -> u32 {
+        self.epoch += 1;
+        self.key = next_key(&self.key, self.epoch);
+        self.cipher = ChaCha20Poly1305::new(Key::from_slice(&self.key));
+        self.counter = 0;
+        self.sealed_bytes = 0;
+        self.keyed_at = Instant::now();
+        self.epoch
+    }
+
+    fn next_nonce(&mut self) -> [u8; NONCE_SIZE] {
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[NONCE_SIZE - 8..].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        nonce
+    }
+
+    pub fn seal(&mut self, frame: &[u8]) -> Vec<u8> {
+        let nonce = self.next_nonce();
+        self.sealed_bytes += frame.len() as u64;
+        self.cipher
+            .encrypt(Nonce::from_slice(&nonce), frame)
+            .expect("frames are below the cipher size limit")
+    }
+
+    pub fn open(&mut self, frame: &[u8]) -> Result<Vec<u8>, String> {
+        let nonce = self.next_nonce();
+        self.cipher
+            .decrypt(Nonce::from_slice(&nonce), frame)
+            .map_err(|_| "Frame authentication failed".to_string())
+    }
+
+    /// Tag of a frame sent in the clear; takes a nonce like `seal`
+    pub fn sign(&mut self, frame: &[u8]) -> Vec<u8> {
+        let nonce = self.next_nonce();
+        self.sealed_bytes += frame.len() as u64;
+        let payload = Payload { msg: &[], aad: frame };
+        self.cipher
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .expect("frames are below the cipher size limit")
+    }
+
+    pub fn verify(&mut self, frame: &[u8], tag: &[u8]) -> Result<(), String> {
+        let nonce = self.next_nonce();
+        let payload = Payload { msg: tag, aad: frame };
+        self.cipher
+            .decrypt(Nonce::from_slice(&nonce), payload)
+            .map(|_| ())
+            .map_err(|_| "Frame authentication failed".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{generate_private_key, ConnectToken, FrameCipher, PrivateKey, RekeyPolicy};
+    use crate::client_id::ClientId;
+
+    fn token(private_key: &PrivateKey, valid_for: Duration) -> ConnectToken {
+        let address = "127.0.0.1:25565".parse().unwrap();
+        ConnectToken::generate(private_key, ClientId::new(7).unwrap(), vec![address], valid_for).unwrap()
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let key = generate_private_key();
+        let (mut send, mut receive) = (FrameCipher::new(&key), FrameCipher::new(&key));
+        for frame in [&b"first"[..], &b""[..], &[0u8; 1024][..]] {
+            let sealed = send.seal(frame);
+            assert_ne!(sealed, frame);
+            assert_eq!(receive.open(&sealed).unwrap(), frame);
+        }
+        let tag = send.sign(b"clear");
+        assert!(receive.verify(b"clear", &tag).is_ok());
+    }
+
+    #[test]
+    fn frames_open_in_order_only() {
+        let key = generate_private_key();
+        let (mut send, mut receive) = (FrameCipher::new(&key), FrameCipher::new(&key));
+        let first = send.seal(b"first");
+        let second = send.seal(b"second");
+        // Same plaintext, another nonce
+        assert_ne!(send.seal(b"first"), first);
+        assert!(receive.open(&second).is_err());
+
+        let mut receive = FrameCipher::new(&key);
+        receive.open(&first).unwrap();
+        assert!(receive.open(&first).is_err(), "a replayed frame must not open");
+    }
+
+    #[test]
+    fn rekey_switches_both_ends() {
+        let key = generate_private_key();
+        let (mut send, mut receive) = (FrameCipher::new(&key), FrameCipher::new(&key));
+        let policy = RekeyPolicy::new(Duration::from_secs(3600)).with_bytes(8);
+        assert!(!send.is_rekey_due(&policy));
+        receive.open(&send.seal(b"twelve bytes")).unwrap();
+        assert!(send.is_rekey_due(&policy));
+
+        assert_eq!(send.rekey(), 1);
+        assert!(!send.is_rekey_due(&policy));
+        let sealed = send.seal(b"next epoch");
+        // Still on the key of epoch 0
+        assert!(FrameCipher::new(&key).open(&sealed).is_err());
+
+        assert_eq!(receive.rekey(), 1);
+        // The counter restarts with the key
+        assert_eq!(receive.open(&sealed).unwrap(), b"next epoch");
+        assert_eq!(receive.get_epoch(), send.get_epoch());
+    }
+
+    #[test]
+    fn tampered_frames_are_rejected() {
+        let key = generate_private_key();
+        let (mut send, mut receive) = (FrameCipher::new(&key), FrameCipher::new(&key));
+        let mut sealed = send.seal(b"frame");
+        sealed[0] ^= 1;
+        assert!(receive.open(&sealed).is_err());
+
+        let (mut send, mut receive) = (FrameCipher::new(&key), FrameCipher::new(&key));
+        let tag = send.sign(b"clear");
+        assert!(receive.verify(b"cleaR", &tag).is_err());
+
+        let mut other = FrameCipher::new(&generate_private_key());
+        assert!(other.open(&FrameCipher::new(&key).seal(b"frame")).is_err());
+    }
+
+    #[test]
+    fn sealed_token_opens_with_the_private_key() {
+        let private_key = generate_private_key();
+        let token = ConnectToken::from_bytes(&token(&private_key, Duration::from_secs(60)).to_bytes()).unwrap();
+        let keys = token.get_sealed().open(&private_key).unwrap();
+        assert_eq!(keys.client_to_server, token.get_keys().client_to_server);
+        assert_eq!(keys.server_to_client, token.get_keys().server_to_client);
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let private_key = generate_private_key();
+        let token = token(&private_key, Duration::ZERO);
+        assert!(token.get_sealed().open(&private_key).is_err());
+    }
+
+    #[test]
+    fn forged_tokens_are_rejected() {
+        let private_key = generate_private_key();
+        let token = token(&private_key, Duration::from_secs(60));
+        // Minted with another key
+        assert!(token.get_sealed().open(&generate_private_key()).is_err());
+
+        // Lifetime extended by the client
+        let mut extended = token.get_sealed().clone();
+        extended.expires_at += 3600;
+        assert!(extended.open(&private_key).is_err());
+
+        let mut tampered = token.get_sealed().clone();
+        tampered.data[0] ^= 1;
+        assert!(tampered.open(&private_key).is_err());
+    }
+}
@@ -1,28 +1,1128 @@
 #![allow(opaque_hidden_inferred_bound)]
 
-use std::{future::Future, time::Duration};
+use common::chunks::chunk_position::ChunkPosition;
+use common::chunks::position::Vector3;
+use parking_lot::RwLock;
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use super::messages::{ClientMessages, NetworkMessageType, ServerMessages};
+use crate::approval::{Approval, ConnectionApproval};
+use crate::area_of_interest::AreaOfInterest;
+use crate::auth::{Auth, AuthIdentity, AuthProvider};
+use crate::audit::AuditLog;
+use crate::batching::SendBatching;
+use crate::client_id::ClientId;
+use crate::errors::NetworkError;
+use crate::fragmentation::DEFAULT_MAX_MESSAGE_SIZE;
+use crate::groups::ConnectionGroups;
+use crate::labels::ConnectionLabel;
+use crate::latency_compensation::{SynchronizedBroadcasts, DEFAULT_MAX_COMPENSATION};
+use crate::interest::ChunkInterest;
+use crate::network_info::NetworkInfo;
+use crate::phases::{GamePhase, PhaseChannels};
+use crate::priorities::ChannelPriorities;
+use crate::quantization::QuantizationProfile;
+use crate::raw_messages::RawClientMessage;
+use crate::rate_limits::RateLimits;
+use crate::recording::MessageRecorder;
+use crate::retries::RetryPolicy;
+use crate::routing::{MessageRoutes, PermissionGate, Permissions};
+use crate::rpc::{IncomingRequest, Request, RpcEndpoint, RpcError, DEFAULT_RPC_TIMEOUT};
+use crate::security::{PrivateKey, RekeyPolicy};
+use crate::shaping::{BurstPriority, TrafficShaping};
+use crate::size_limits::{MessageSizeLimits, SizeCheck};
+use crate::snapshots::SnapshotBuilder;
+use crate::tenants::Tenant;
+use crate::thresholds::{ConnectionThresholds, ThresholdCrossing};
+use crate::conditions::NetworkConditions;
+use crate::datagram::DEFAULT_DATAGRAM_RATE;
+use crate::discovery::DiscoveryConfig;
+use crate::ip_limits::{IpLimit, IpLimitPolicy};
+use crate::socket_errors::SocketRecovery;
+use crate::draining::Draining;
+use crate::socket_options::SocketOptions;
+use crate::streams::{StreamWindows, StreamWriter};
+use crate::tick::TickSchedule;
+use crate::timeouts::{DEFAULT_CONNECTION_TIMEOUT, DEFAULT_KEEP_ALIVE, DISCONNECT_DELAY};
+use crate::tick_report::TickReport;
+use crate::watchdog::StallReport;
+use crate::write_ahead::WriteAheadLog;
+use crate::keyed_state::{KeyedState, StateSyncs};
+use crate::compression::AdaptiveCompression;
+use crate::system::SystemMessage;
+use crate::tuning::{ChannelTuning, ConnectionTuning};
+use crate::trace_context::TraceContext;
+use crate::degraded::{Degradation, DegradedMode};
 
-pub trait IServerNetwork<C: IServerConnection> {
-    fn new(ip_port: String) -> impl Future<Output = Self>;
+pub trait IServerNetwork<C: IServerConnection>: Sized {
+    fn new(ip_port: String) -> impl Future<Output = Self> {
+        Self::new_with_config(ip_port, ServerConfig::default())
+    }
+    fn new_with_config(ip_port: String, config: ServerConfig) -> impl Future<Output = Self>;
     fn step(&self, delta: Duration) -> impl Future<Output = ()>;
 
     fn drain_connections(&self) -> impl Iterator<Item = ConnectionMessages<C>>;
-    fn drain_errors(&self) -> impl Iterator<Item = String>;
+    fn drain_errors(&self) -> impl Iterator<Item = NetworkError>;
+    fn drain_events(&self) -> impl Iterator<Item = ServerEvents>;
     fn is_connected(&self, connection: &C) -> bool;
     fn connections_count(&self) -> usize;
+    fn connections_snapshot(&self) -> Vec<C>;
+
+    /// Hash-chained log of security events; register a sink to consume it
+    fn get_audit_log(&self) -> &AuditLog;
+
+    fn get_config(&self) -> &ServerConfig;
+
+    /// Seconds since the server started: the clock clients synchronize to
+    /// (see `crate::time_sync`) and to stamp outgoing messages with
+    fn server_time(&self) -> f64;
+
+    /// Out-of-band datagrams dropped for a missing magic or another version,
+    /// see `crate::datagram`. Only the tokio backend has out-of-band datagrams;
+    /// renet reports 0, netcode drops foreign packets on its socket uncounted
+    fn get_foreign_datagrams(&self) -> u64;
+
+    /// Tenant of `ServerConfig::tenants`, see `crate::tenants`
+    fn get_tenant(&self, name: &str) -> Option<&Tenant<C>>;
+
+    /// Change the simulated conditions of every connection (testing only).
+    ///
+    /// Only takes effect if `ServerConfig::network_conditions` was set, e.g. to the
+    /// "none" preset; a new seed applies to connections made afterwards.
+    fn set_network_conditions(&self, conditions: NetworkConditions);
+
+    /// Named groups of connections, see `send_to_group`
+    fn get_groups(&self) -> &ConnectionGroups;
+
+    /// Serialize the message once and send it to every listed client;
+    /// unknown or disconnected clients are skipped
+    fn send_to_clients(&self, client_ids: &[ClientId], message_type: NetworkMessageType, message: &ServerMessages);
+
+    /// Send the message to every connection, serializing it once
+    fn broadcast_message(&self, message_type: NetworkMessageType, message: &ServerMessages) {
+        let client_ids: Vec<ClientId> = self.connections_snapshot().iter().map(|c| c.get_client_id()).collect();
+        self.send_to_clients(&client_ids, message_type, message);
+    }
+
+    /// Send the message to every member of a group, e.g. "world:overworld"
+    fn send_to_group(&self, group: &str, message_type: NetworkMessageType, message: &ServerMessages) {
+        self.send_to_clients(&self.get_groups().get_members(group), message_type, message);
+    }
+
+    /// Connection positions and chunk subscriptions, see `crate::area_of_interest`
+    fn get_area_of_interest(&self) -> &AreaOfInterest;
+
+    /// Send the message to every client within `radius` meters of the position
+    fn send_to_radius(
+        &self,
+        position: &Vector3,
+        radius: f32,
+        message_type: NetworkMessageType,
+        message: &ServerMessages,
+    ) {
+        let client_ids = self.get_area_of_interest().get_in_radius(position, radius);
+        self.send_to_clients(&client_ids, message_type, message);
+    }
+
+    /// Send the message to every client subscribed to the chunk of the world
+    fn send_to_chunk(
+        &self,
+        world_slug: &str,
+        chunk_position: &ChunkPosition,
+        message_type: NetworkMessageType,
+        message: &ServerMessages,
+    ) {
+        let client_ids = self.get_area_of_interest().get_subscribers(world_slug, chunk_position);
+        self.send_to_clients(&client_ids, message_type, message);
+    }
+
+    fn join_group(&self, group: &str, client_id: ClientId) {
+        self.get_groups().join(group, client_id);
+    }
+
+    fn leave_group(&self, group: &str, client_id: ClientId) {
+        self.get_groups().leave(group, client_id);
+    }
+
+    /// Drain state, see `crate::draining`
+    fn get_draining(&self) -> &Draining;
+
+    /// Connection count thresholds, see `crate::thresholds`
+    fn get_thresholds(&self) -> &ConnectionThresholds;
+
+    /// Stop accepting connections and tell every client the server shuts down in `shutdown_in`.
+    ///
+    /// `ServerEvents::Drained` follows once the last connection closes or the time is up.
+    /// Does nothing if the server is already draining.
+    fn start_draining(&self, shutdown_in: Duration, message: Option<String>) {
+        if !self.get_draining().start(shutdown_in, message) {
+            return;
+        }
+        log::info!(target: "network", "Draining connections; shutdown in {:?}", shutdown_in);
+        if let Some(notice) = self.get_draining().notice() {
+            self.broadcast_message(NetworkMessageType::ReliableOrdered, &notice);
+        }
+    }
+
+    fn is_draining(&self) -> bool {
+        self.get_draining().is_draining()
+    }
+
+    /// Message capture, see `crate::recording`
+    fn get_recorder(&self) -> &MessageRecorder;
+
+    /// Write every message received or sent from now on to `path`; read it back with `Replay`
+    fn enable_recording(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.get_recorder().start(path)
+    }
+
+    fn disable_recording(&self) -> io::Result<()> {
+        self.get_recorder().stop()
+    }
+
+    /// Write-ahead log of the reliable client messages, see `crate::write_ahead`
+    fn get_write_ahead_log(&self) -> &WriteAheadLog;
+
+    /// Log every reliable message accepted from now on to `path`, keeping
+    /// the entries already there; read them back with `WalReplay`
+    fn enable_write_ahead_log(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.get_write_ahead_log().open(path)
+    }
+
+    /// Step the server `rate` times per second and call `callback` after every step.
+    ///
+    /// Ticks are scheduled against absolute deadlines (see `crate::tick`),
+    /// aligned to the wall clock if `ServerConfig::align_ticks_to_wall_clock` is set.
+    /// Returns when the callback returns false.
+    fn run_fixed<F>(&self, rate: u32, mut callback: F) -> impl Future<Output = ()>
+    where
+        F: FnMut(&Self, Duration) -> bool,
+    {
+        async move {
+            let mut schedule = TickSchedule::new(rate, self.get_config().align_ticks_to_wall_clock);
+            loop {
+                self.step(schedule.get_interval()).await;
+                if !callback(self, schedule.get_interval()) {
+                    return;
+                }
+                schedule.wait().await;
+            }
+        }
+    }
+
+    /// Disconnect every active connection.
+    ///
+    /// The reason is delivered reliably as `ServerMessages::Disconnect` before the socket is closed.
+    fn disconnect_all(&self, reason: Option<String>) -> usize {
+        self.disconnect_where(reason, |_| true)
+    }
+
+    /// Disconnect every active connection matching the predicate.
+    ///
+    /// Returns the number of connections scheduled for disconnection.
+    fn disconnect_where<F: Fn(&C) -> bool>(&self, reason: Option<String>, predicate: F) -> usize {
+        let mut count = 0;
+        for connection in self.connections_snapshot() {
+            if !self.is_connected(&connection) || !predicate(&connection) {
+                continue;
+            }
+            connection.disconnect_with_reason(reason.clone());
+            count += 1;
+        }
+        count
+    }
+
+    /// Broadcasts held by `broadcast_synchronized`
+    fn get_synchronized_broadcasts(&self) -> &SynchronizedBroadcasts;
+
+    /// Send the message to every connection so that it arrives everywhere at
+    /// about the same moment, holding it back for the faster clients; see
+    /// `crate::latency_compensation`
+    fn broadcast_synchronized(&self, message_type: NetworkMessageType, message: &ServerMessages) {
+        let round_trips: Vec<(ClientId, Duration)> = self
+            .connections_snapshot()
+            .iter()
+            .map(|c| (c.get_client_id(), c.get_network_info().rtt))
+            .collect();
+        let max_compensation = self
+            .get_config()
+            .max_latency_compensation
+            .unwrap_or(DEFAULT_MAX_COMPENSATION);
+        let immediate =
+            self.get_synchronized_broadcasts()
+                .schedule(round_trips, max_compensation, message_type, message);
+        self.send_to_clients(&immediate, message_type, message);
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ServerConfig {
+    /// Maximum time a single `step()` may spend on deferrable work:
+    /// accepting connections and decoding the messages of the unreliable channels.
+    ///
+    /// Once exceeded, the remaining work is left for the next tick
+    /// and `ServerEvents::Overloaded` is emitted.
+    pub step_budget: Option<Duration>,
+
+    pub socket: SocketOptions,
+
+    /// Simulated latency, jitter, loss and duplication of outgoing traffic (testing only);
+    /// changed at runtime with `set_network_conditions`
+    pub network_conditions: Option<NetworkConditions>,
+
+    /// Address of the HTTP health endpoint (`GET /health`),
+    /// served only with the `health-http` feature
+    pub health_address: Option<SocketAddr>,
+
+    /// Unix domain socket accepting connections alongside the network port,
+    /// for local tools and sidecar processes
+    pub local_socket: Option<PathBuf>,
+
+    /// Address accepting WebSocket connections from browser clients,
+    /// served only with the `websocket` feature
+    pub websocket_address: Option<SocketAddr>,
+
+    /// Private server password; clients without it are rejected
+    /// during the handshake, before they are reported as connected. Renet
+    /// clients prove it bound to the time, so the clocks must agree within
+    /// `TIMED_PROOF_WINDOW` (30 s)
+    pub passphrase: Option<String>,
+
+    /// Require a connect token minted with this key and encrypt every
+    /// connection (see `crate::security`); unset accepts unencrypted
+    /// connections, for local testing
+    pub private_key: Option<PrivateKey>,
+
+    /// Size limits for both sent and received messages
+    pub message_size_limits: MessageSizeLimits,
+
+    /// Largest encoded message sent, `DEFAULT_MAX_MESSAGE_SIZE` if unset.
+    /// Reliable messages over `FRAGMENT_SIZE` are sent in fragments
+    pub max_message_size: Option<usize>,
+
+    /// Permissions required to send each client message variant
+    pub message_routes: MessageRoutes,
+
+    /// Decides on `ClientMessages::ConnectionInfo` whether a client is accepted,
+    /// see `crate::approval`
+    pub approval: Option<Approval>,
+
+    /// Messages whose payload exceeds this many bytes are compressed,
+    /// if the client supports it (see `crate::compression`)
+    pub compression_threshold: Option<u32>,
+
+    /// Quantization profile name per channel, offered during the handshake
+    pub quantization: HashMap<NetworkMessageType, String>,
+
+    /// Start `run_fixed` ticks on wall clock multiples of the tick interval,
+    /// so shards with synchronized clocks tick in phase
+    pub align_ticks_to_wall_clock: bool,
+
+    /// Maximum expected interval between `step()` calls; longer gaps
+    /// are logged and reported as `ServerEvents::Stalled`
+    pub stall_threshold: Option<Duration>,
+
+    /// Chunk radius kept loaded around each connection center.
+    ///
+    /// When set, chunks sent to a connection are tracked and
+    /// `ServerMessages::UnloadChunks` is sent as they leave the radius.
+    pub chunk_unload_radius: Option<u32>,
+
+    /// Datagrams per second allowed in each direction of a connection,
+    /// `DEFAULT_DATAGRAM_RATE` if unset
+    pub datagram_rate: Option<u32>,
+
+    /// Outgoing bandwidth of each connection, split between world/topic groups
+    pub traffic_shaping: Option<TrafficShaping>,
+
+    /// Emit `ServerEvents::TickReport` at the end of every `step()`
+    pub tick_reports: bool,
+
+    /// Record the position of every `ClientMessages::PlayerMove`
+    /// in the area of interest, for `send_to_radius`
+    pub position_tracking: bool,
+
+    /// Tenants hosted on the port; when set, every client must name one
+    /// of them in the handshake (see `crate::tenants`)
+    pub tenants: Vec<String>,
+
+    /// Messages and bytes per second each connection may send per channel
+    /// (see `crate::rate_limits`); unset channels are not limited
+    pub rate_limits: RateLimits,
+
+    /// Time without a frame from a client before its connection is closed,
+    /// `DEFAULT_CONNECTION_TIMEOUT` if unset (see `crate::timeouts`)
+    pub connection_timeout: Option<Duration>,
+
+    /// Ping interval, `DEFAULT_KEEP_ALIVE` if unset
+    pub keep_alive: Option<Duration>,
+
+    /// Connections in the handshake or waiting for `step()`; further
+    /// sockets are closed right after accept. Unlimited if unset
+    pub max_pending_connections: Option<usize>,
+
+    /// Connections the server is sized for, reference of
+    /// `ConnectionThresholds::register_ratio`. Not enforced; refuse clients
+    /// over it with an approval returning `RejectionReason::ServerFull`
+    pub max_connections: Option<usize>,
+
+    /// Send order of the channels when the send budget is tight, renet only
+    /// (see `crate::priorities`)
+    pub channel_priorities: ChannelPriorities,
+
+    /// Time `IServerConnection::request` waits for the response,
+    /// `DEFAULT_RPC_TIMEOUT` if unset
+    pub rpc_timeout: Option<Duration>,
+
+    /// Channels a connection is sent messages on in each `GamePhase`
+    /// (see `crate::phases`)
+    pub phase_channels: PhaseChannels,
+
+    /// Time a dropped connection is held for its client to resume the
+    /// session (see `crate::resume`); unset disconnects it right away
+    pub session_resume: Option<Duration>,
+
+    /// Answer the discovery queries of the clients on the local network
+    /// (see `crate::discovery`)
+    pub discovery: Option<DiscoveryConfig>,
+
+    /// Concurrent connections allowed per IP address (see `crate::ip_limits`);
+    /// None means unlimited
+    pub ip_limit: Option<IpLimit>,
+
+    /// Leave the game messages undecoded for `IServerConnection::drain_raw_messages`
+    /// (see `crate::raw_messages`)
+    pub raw_receive: bool,
+
+    /// Hold the messages sent during a tick and write them together
+    /// (see `crate::batching`); None writes each as it is sent
+    pub send_batching: Option<SendBatching>,
+
+    /// Longest `IServerNetwork::broadcast_synchronized` holds a message back
+    /// for the fastest client; None uses `DEFAULT_MAX_COMPENSATION`
+    pub max_latency_compensation: Option<Duration>,
+
+    /// Longest a disconnected connection stays open for its pending reliable
+    /// messages (e.g. the last inventory sync) to reach the client; it closes
+    /// as soon as they did. None closes after `DISCONNECT_DELAY` whatever is pending
+    pub disconnect_grace: Option<Duration>,
+
+    /// CPU time per second the tokio server may spend compressing; over it,
+    /// every connection falls back to the fastest level (see `crate::compression`)
+    pub compression_cpu_budget: Option<Duration>,
+
+    /// Verifies the credential of every client in the handshake, tokio only
+    /// (see `crate::auth`)
+    pub auth: Option<Auth>,
+
+    /// Trip the unreliable-only mode of a connection on extreme loss
+    /// (see `crate::degraded`); unset, only `IServerConnection::degrade` trips it
+    pub degraded_mode: Option<DegradedMode>,
+
+    /// Rotate the keys of encrypted connections (see `crate::security`), tokio only;
+    /// clients that can't are kept on their connect token keys
+    pub rekey: Option<RekeyPolicy>,
+
+    /// Channels sent unencrypted but authenticated on encrypted connections,
+    /// e.g. for a relay to route voice (see `crate::security`), tokio only;
+    /// each client must allow them with `ClientConfig::with_clear_channel`
+    pub clear_channels: Vec<NetworkMessageType>,
+
+    /// HMAC key of the audit log chain (see `crate::audit`); keep it away
+    /// from the log, which can only be verified with it
+    pub audit_key: Option<Vec<u8>>,
+}
+
+impl ServerConfig {
+    pub fn with_chunk_unload_radius(mut self, radius: u32) -> Self {
+        self.chunk_unload_radius = Some(radius);
+        self
+    }
+
+    pub(crate) fn create_chunk_interest(&self) -> Option<Arc<RwLock<ChunkInterest>>> {
+        self.chunk_unload_radius
+            .map(|radius| Arc::new(RwLock::new(ChunkInterest::new(radius))))
+    }
+
+    pub fn with_datagram_rate(mut self, rate: u32) -> Self {
+        self.datagram_rate = Some(rate);
+        self
+    }
+
+    pub(crate) fn get_datagram_rate(&self) -> u32 {
+        self.datagram_rate.unwrap_or(DEFAULT_DATAGRAM_RATE)
+    }
+
+    pub fn with_traffic_shaping(mut self, shaping: TrafficShaping) -> Self {
+        self.traffic_shaping = Some(shaping);
+        self
+    }
+
+    pub fn with_step_budget(mut self, budget: Duration) -> Self {
+        self.step_budget = Some(budget);
+        self
+    }
+
+    pub fn with_wall_clock_alignment(mut self) -> Self {
+        self.align_ticks_to_wall_clock = true;
+        self
+    }
+
+    pub fn with_tick_reports(mut self) -> Self {
+        self.tick_reports = true;
+        self
+    }
+
+    pub fn with_position_tracking(mut self) -> Self {
+        self.position_tracking = true;
+        self
+    }
+
+    pub fn with_tenants(mut self, tenants: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.tenants = tenants.into_iter().map(|t| t.into()).collect();
+        self
+    }
+
+    pub fn with_rate_limits(mut self, limits: RateLimits) -> Self {
+        self.rate_limits = limits;
+        self
+    }
+
+    pub fn with_connection_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_timeout = Some(timeout);
+        self
+    }
+
+    pub(crate) fn get_connection_timeout(&self) -> Duration {
+        self.connection_timeout.unwrap_or(DEFAULT_CONNECTION_TIMEOUT)
+    }
+
+    pub fn with_keep_alive(mut self, interval: Duration) -> Self {
+        self.keep_alive = Some(interval);
+        self
+    }
+
+    pub(crate) fn get_keep_alive(&self) -> Duration {
+        self.keep_alive.unwrap_or(DEFAULT_KEEP_ALIVE)
+    }
+
+    pub fn with_max_pending_connections(mut self, max: usize) -> Self {
+        self.max_pending_connections = Some(max);
+        self
+    }
+
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    pub fn with_channel_priorities(mut self, priorities: ChannelPriorities) -> Self {
+        self.channel_priorities = priorities;
+        self
+    }
+
+    pub fn with_rpc_timeout(mut self, timeout: Duration) -> Self {
+        self.rpc_timeout = Some(timeout);
+        self
+    }
+
+    pub(crate) fn create_rpc_endpoint(&self) -> RpcEndpoint {
+        RpcEndpoint::new(self.rpc_timeout.unwrap_or(DEFAULT_RPC_TIMEOUT))
+    }
+
+    pub fn with_phase_channels(mut self, channels: PhaseChannels) -> Self {
+        self.phase_channels = channels;
+        self
+    }
+
+    pub fn with_session_resume(mut self, grace: Duration) -> Self {
+        self.session_resume = Some(grace);
+        self
+    }
+
+    pub fn with_discovery(mut self, discovery: DiscoveryConfig) -> Self {
+        self.discovery = Some(discovery);
+        self
+    }
+
+    pub fn with_ip_limit(mut self, ip_limit: IpLimit) -> Self {
+        self.ip_limit = Some(ip_limit);
+        self
+    }
+
+    pub fn with_raw_receive(mut self) -> Self {
+        self.raw_receive = true;
+        self
+    }
+
+    pub fn with_send_batching(mut self, send_batching: SendBatching) -> Self {
+        self.send_batching = Some(send_batching);
+        self
+    }
+
+    pub fn with_max_latency_compensation(mut self, max: Duration) -> Self {
+        self.max_latency_compensation = Some(max);
+        self
+    }
+
+    pub fn with_disconnect_grace(mut self, timeout: Duration) -> Self {
+        self.disconnect_grace = Some(timeout);
+        self
+    }
+
+    pub(crate) fn get_disconnect_delay(&self) -> Duration {
+        self.disconnect_grace.unwrap_or(DISCONNECT_DELAY)
+    }
+
+    pub fn with_compression_cpu_budget(mut self, budget: Duration) -> Self {
+        self.compression_cpu_budget = Some(budget);
+        self
+    }
+
+    /// Tenant of a client from its handshake.
+    ///
+    /// Returns the rejection reason if the client must not be accepted.
+    pub(crate) fn check_tenant(&self, tenant: Option<&String>) -> Result<Option<String>, String> {
+        if self.tenants.is_empty() {
+            return Ok(None);
+        }
+        match tenant {
+            Some(tenant) if self.tenants.contains(tenant) => Ok(Some(tenant.clone())),
+            Some(tenant) => Err(format!("Unknown tenant {}", tenant)),
+            None => Err("Server requires a tenant".to_string()),
+        }
+    }
+
+    pub fn with_stall_threshold(mut self, threshold: Duration) -> Self {
+        self.stall_threshold = Some(threshold);
+        self
+    }
+
+    pub(crate) fn is_stalled(&self, tick_time: Option<Duration>) -> bool {
+        match (self.stall_threshold, tick_time) {
+            (Some(threshold), Some(tick_time)) => tick_time > threshold,
+            _ => false,
+        }
+    }
+
+    pub fn with_socket_options(mut self, socket: SocketOptions) -> Self {
+        self.socket = socket;
+        self
+    }
+
+    pub fn with_network_conditions(mut self, conditions: NetworkConditions) -> Self {
+        self.network_conditions = Some(conditions);
+        self
+    }
+
+    /// Select simulated conditions by preset name: "none", "fiber", "wifi" or "mobile-3g"
+    pub fn with_network_preset(self, preset: &str) -> Self {
+        let conditions = NetworkConditions::from_name(preset).unwrap_or_else(|| {
+            panic!(
+                "unknown network preset {}; expected one of {:?}",
+                preset,
+                NetworkConditions::preset_names()
+            )
+        });
+        self.with_network_conditions(conditions)
+    }
+
+    pub fn with_health_endpoint(mut self, address: SocketAddr) -> Self {
+        self.health_address = Some(address);
+        self
+    }
+
+    pub fn with_local_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.local_socket = Some(path.into());
+        self
+    }
+
+    pub fn with_websocket(mut self, address: SocketAddr) -> Self {
+        self.websocket_address = Some(address);
+        self
+    }
+
+    pub fn with_passphrase(mut self, passphrase: String) -> Self {
+        self.passphrase = Some(passphrase);
+        self
+    }
+
+    pub fn with_connect_tokens(mut self, private_key: PrivateKey) -> Self {
+        self.private_key = Some(private_key);
+        self
+    }
+
+    pub fn with_max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = Some(size);
+        self
+    }
+
+    pub(crate) fn get_max_message_size(&self) -> usize {
+        self.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+
+    pub fn with_message_routes(mut self, routes: MessageRoutes) -> Self {
+        self.message_routes = routes;
+        self
+    }
+
+    pub fn with_approval(mut self, approval: impl ConnectionApproval + 'static) -> Self {
+        self.approval = Some(Approval::new(approval));
+        self
+    }
+
+    pub fn with_auth(mut self, provider: impl AuthProvider + 'static) -> Self {
+        self.auth = Some(Auth::new(provider));
+        self
+    }
+
+    pub fn with_degraded_mode(mut self, mode: DegradedMode) -> Self {
+        self.degraded_mode = Some(mode);
+        self
+    }
+
+    pub fn with_rekey(mut self, policy: RekeyPolicy) -> Self {
+        self.rekey = Some(policy);
+        self
+    }
+
+    pub fn with_clear_channel(mut self, message_type: NetworkMessageType) -> Self {
+        self.clear_channels.push(message_type);
+        self
+    }
+
+    pub fn with_audit_key(mut self, key: Vec<u8>) -> Self {
+        self.audit_key = Some(key);
+        self
+    }
+
+    pub fn with_compression(mut self, threshold: u32) -> Self {
+        self.compression_threshold = Some(threshold);
+        self
+    }
+
+    pub fn with_message_size_limits(mut self, limits: MessageSizeLimits) -> Self {
+        self.message_size_limits = limits;
+        self
+    }
+
+    pub fn with_quantization(mut self, message_type: NetworkMessageType, profile: &str) -> Self {
+        assert!(
+            QuantizationProfile::from_name(profile).is_some(),
+            "unknown quantization profile {}",
+            profile
+        );
+        self.quantization.insert(message_type, profile.to_string());
+        self
+    }
+
+    /// Report size outliers; returns false if the message must be dropped
+    pub(crate) fn check_message_size(
+        &self,
+        events: &flume::Sender<ServerEvents>,
+        client: &ConnectionLabel,
+        variant: &str,
+        size: usize,
+    ) -> bool {
+        let client_id = client.get_client_id();
+        let event = match self.message_size_limits.check(variant, size) {
+            SizeCheck::Ok => return true,
+            SizeCheck::Warning => ServerEvents::MessageSizeWarning {
+                client_id,
+                variant: variant.to_string(),
+                size,
+            },
+            SizeCheck::Rejected => {
+                log::warn!(target: "network", "Message {} of {} bytes for client {} exceeds the hard cap", variant, size, client);
+                ServerEvents::MessageSizeRejected {
+                    client_id,
+                    variant: variant.to_string(),
+                    size,
+                }
+            }
+        };
+        let accepted = !matches!(event, ServerEvents::MessageSizeRejected { .. });
+        events.send(event).ok();
+        accepted
+    }
+
+    /// Check an encoded server message against the max message size.
+    ///
+    /// Returns false and reports the error if the message must not be sent.
+    pub(crate) fn check_max_message_size(
+        &self,
+        errors: &flume::Sender<NetworkError>,
+        client: &ConnectionLabel,
+        variant: &str,
+        size: usize,
+    ) -> bool {
+        let max_message_size = self.get_max_message_size();
+        if size <= max_message_size {
+            return true;
+        }
+        log::warn!(
+            target: "network",
+            "Message {} of {} bytes for client {} exceeds the max message size {}",
+            variant, size, client, max_message_size
+        );
+        let error = NetworkError::MessageTooLarge {
+            client_id: Some(client.get_client_id()),
+            variant: variant.to_string(),
+            size,
+        };
+        errors.send(error).ok();
+        false
+    }
+
+    /// Check a decoded client message against `message_routes`.
+    ///
+    /// Returns false if the connection lacks the permissions to send it;
+    /// the message must then be dropped.
+    pub(crate) fn check_message_route(
+        &self,
+        events: &flume::Sender<ServerEvents>,
+        gate: &PermissionGate,
+        client: &ConnectionLabel,
+        variant: &str,
+    ) -> bool {
+        let Some(rejections) = gate.check(&self.message_routes, variant) else {
+            return true;
+        };
+        log::warn!(target: "network", "Client {} is not permitted to send {}", client, variant);
+        let event = ServerEvents::MessageForbidden {
+            client_id: client.get_client_id(),
+            variant: variant.to_string(),
+            rejections,
+        };
+        events.send(event).ok();
+        false
+    }
+
+    pub(crate) fn is_over_budget(&self, step_started: Instant) -> bool {
+        match self.step_budget {
+            Some(budget) => step_started.elapsed() > budget,
+            None => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ServerEvents {
+    /// `step()` exceeded its budget; `deferred` is the number of
+    /// work items postponed to the next tick
+    Overloaded {
+        elapsed: Duration,
+        budget: Duration,
+        deferred: usize,
+    },
+    /// Message is larger than the expected size of its variant
+    MessageSizeWarning {
+        client_id: ClientId,
+        variant: String,
+        size: usize,
+    },
+    /// Message exceeded the hard cap of its variant and was dropped
+    MessageSizeRejected {
+        client_id: ClientId,
+        variant: String,
+        size: usize,
+    },
+    /// Client lacks the permissions of the message variant (see `MessageRoutes`);
+    /// the message was dropped. `rejections` counts forbidden messages of the connection
+    MessageForbidden {
+        client_id: ClientId,
+        variant: String,
+        rejections: u32,
+    },
+    /// `step()` was not called for longer than `ServerConfig::stall_threshold`
+    Stalled { report: StallReport },
+    /// Network load of the tick, see `ServerConfig::tick_reports`
+    TickReport { report: TickReport },
+    /// Message sent with a deadline could not be put on the wire in time and was dropped
+    DeadlineMissed {
+        client_id: ClientId,
+        variant: String,
+        late: Duration,
+    },
+    /// Client lacks an optional feature; the connection was accepted
+    /// with the fallback instead of failing the handshake
+    FeatureFallback {
+        client_id: ClientId,
+        feature: String,
+        fallback: String,
+    },
+    /// Draining finished: every connection closed, or the deadline passed
+    /// with `remaining` connections still open (see `crate::draining`)
+    Drained { remaining: usize },
+    /// Messages of the client dropped by `ServerConfig::rate_limits` since the
+    /// last report; `total` counts every message dropped on the connection
+    RateLimited {
+        client_id: ClientId,
+        dropped: u64,
+        total: u64,
+    },
+    /// Connection count crossed a threshold registered on `IServerNetwork::get_thresholds`
+    ThresholdCrossed { crossing: ThresholdCrossing },
+    /// Message sent with `IServerConnection::send_message_bounded` was not
+    /// acknowledged within its `RetryPolicy` and is no longer resent
+    DeliveryDropped {
+        client_id: ClientId,
+        variant: String,
+        attempts: u32,
+    },
+    /// Client resumed its session over a new socket after its connection
+    /// dropped, see `crate::resume`; the connection handle stays valid
+    Reconnected { client_id: ClientId },
+    /// Connection from an address already at its `ServerConfig::ip_limit`;
+    /// `connections` counts the other connections of the address, and
+    /// `policy` tells whether the client was rejected, replaced one or was
+    /// only reported
+    IpLimitReached {
+        client_id: ClientId,
+        ip: IpAddr,
+        connections: usize,
+        policy: IpLimitPolicy,
+    },
+    /// Call on a server socket failed; `recovery` tells how the socket
+    /// carries on, see `crate::socket_errors`
+    SocketError {
+        socket: String,
+        error: String,
+        recovery: SocketRecovery,
+    },
+    /// Client sent nothing, probes answered included, for the connection
+    /// timeout and was dropped: its host is down or the network is
+    /// partitioned; see `crate::timeouts`
+    PeerUnreachable { client_id: ClientId, silent: Duration },
+    /// Socket of the client was closed or reset without a disconnect,
+    /// e.g. the game crashed; see `crate::timeouts`
+    PeerClosed { client_id: ClientId },
+    /// Both ends applied the `ChannelTuning` of `revision`, see `crate::tuning`
+    ChannelsRetuned {
+        client_id: ClientId,
+        revision: u32,
+        tuning: ChannelTuning,
+    },
+    /// The connection entered or left the unreliable-only mode, see `crate::degraded`;
+    /// on leaving, `suspended` reliable messages were dropped meanwhile
+    DegradedModeChanged {
+        client_id: ClientId,
+        degraded: bool,
+        suspended: u64,
+    },
 }
 
+/// Connection reports; a disconnect carries the reason sent with
+/// `IClientNetwork::disconnect_with_reason`, if any
 pub enum ConnectionMessages<C: IServerConnection> {
     Connect { connection: C },
-    Disconnect { client_id: u64, reason: String },
+    Disconnect { client_id: ClientId, reason: String },
 }
 
 pub trait IServerConnection: Clone {
     fn get_ip(&self) -> &String;
-    fn get_client_id(&self) -> u64;
+    fn get_client_id(&self) -> ClientId;
+
+    /// Id of this connection, new on each reconnect of the client; see `crate::generation`
+    fn get_generation(&self) -> u64;
+
+    /// The connection is closed; messages sent through this handle are dropped
+    fn is_stale(&self) -> bool;
+
+    /// Name of the connection in the logs, see `crate::labels`
+    fn get_label(&self) -> &ConnectionLabel;
+
+    /// Show the connection as `label` in the logs, e.g. the player name
+    fn set_label(&self, label: impl Into<String>) {
+        self.get_label().set(label);
+    }
+
+    /// Tenant named by the client in the handshake, see `crate::tenants`
+    fn get_tenant(&self) -> Option<&String>;
+
+    /// Account verified by `ServerConfig::auth` in the handshake, see `crate::auth`
+    fn get_identity(&self) -> Option<&AuthIdentity>;
+
+    /// Messages dropped by `ServerConfig::rate_limits` since the connection opened
+    fn get_rate_limited(&self) -> u64;
+
+    /// Round-trip time, loss, bandwidth and send queue of the connection
+    fn get_network_info(&self) -> NetworkInfo;
     fn drain_client_messages(&self) -> impl Iterator<Item = ClientMessages>;
+
+    /// Game messages left undecoded with `ServerConfig::raw_receive`, see `crate::raw_messages`
+    fn drain_raw_messages(&self) -> impl Iterator<Item = RawClientMessage>;
     fn send_message(&self, message_type: NetworkMessageType, message: &ServerMessages);
+
+    /// `send_message` that fails with `NetworkError::StaleConnection` once the connection is closed
+    fn try_send_message(&self, message_type: NetworkMessageType, message: &ServerMessages) -> Result<(), NetworkError> {
+        if self.is_stale() {
+            return Err(NetworkError::StaleConnection {
+                client_id: self.get_client_id(),
+                generation: self.get_generation(),
+            });
+        }
+        self.send_message(message_type, message);
+        Ok(())
+    }
+
+    /// The client reads trace contexts, see `crate::trace_context`
+    fn is_trace_supported(&self) -> bool;
+
+    /// Send the message with the trace context of the action it belongs to,
+    /// e.g. a child of the context of the client message it answers; a client
+    /// that predates tracing is sent the bare message
+    fn send_traced(&self, message_type: NetworkMessageType, message: &ServerMessages, context: &TraceContext) {
+        if !self.is_trace_supported() {
+            self.send_message(message_type, message);
+            return;
+        }
+        self.send_message(message_type, &message.clone().traced(context.clone()));
+    }
+
+    /// Unreliable-only mode of the connection, see `crate::degraded`
+    fn get_degradation(&self) -> &Degradation;
+
+    fn is_degraded(&self) -> bool {
+        self.get_degradation().is_degraded()
+    }
+
+    /// Suspend the reliable game messages until `restore`; both ends are told from `step()`
+    fn degrade(&self) {
+        self.get_degradation().trip();
+    }
+
+    fn restore(&self) {
+        self.get_degradation().restore();
+    }
+
+    /// Send the entities changed since the last snapshot the client acknowledged,
+    /// over the unreliable channel; see `crate::snapshots`
+    fn send_snapshot(&self, snapshot: &SnapshotBuilder);
+
+    /// Send a message of the crate outside the game channels, ahead of the
+    /// game messages queued; see `crate::system`
+    fn send_system(&self, message: &SystemMessage);
+
+    /// Permissions checked against `ServerConfig::message_routes`; none until set
+    fn get_permissions(&self) -> Permissions;
+    fn set_permissions(&self, permissions: Permissions);
+
+    /// Phase gating the channels the connection is sent messages on,
+    /// see `crate::phases`; `GamePhase::Playing` until set
+    fn get_phase(&self) -> GamePhase;
+    fn set_phase(&self, phase: GamePhase);
+
+    /// Random seed chosen by the server during the handshake, also known to
+    /// the client (`IClientNetwork::get_session_seed`); None on backends without one
+    fn get_session_seed(&self) -> Option<u64>;
+
+    /// Send a message that is useless after `deadline`.
+    ///
+    /// If it cannot be put on the wire by then, it is dropped and
+    /// `ServerEvents::DeadlineMissed` is emitted instead.
+    fn send_message_with_deadline(&self, message_type: NetworkMessageType, message: &ServerMessages, deadline: Instant);
+
+    /// Send a message over the unreliable channel and resend it until the
+    /// client acknowledges it or `policy` runs out, see `crate::retries`
+    fn send_message_bounded(&self, message: &ServerMessages, policy: &RetryPolicy);
+
+    /// Send a message that supersedes the queued one of the same `key` and
+    /// variant, e.g. `ServerMessages::EntityMove` by entity id; see `crate::coalescing`
+    fn send_latest(&self, key: u64, message_type: NetworkMessageType, message: &ServerMessages);
+
+    /// Send the next `bytes` past the topic groups of `ServerConfig::traffic_shaping`,
+    /// which yield bandwidth to them meanwhile, e.g. before an initial snapshot
+    /// or a teleport; see `crate::shaping`
+    fn reserve_burst(&self, bytes: usize, priority: BurstPriority);
+
+    /// Send the messages held by `ServerConfig::send_batching` now rather
+    /// than at the end of `step()`
+    fn flush(&self);
     fn disconnect(&self);
+
+    /// Send an out-of-band datagram: unordered, unreliable, outside the message channels.
+    ///
+    /// Fails if the payload exceeds `MAX_DATAGRAM_SIZE`, the rate limit is
+    /// reached, or the client address is not known yet.
+    fn send_datagram(&self, data: &[u8]) -> Result<(), String>;
+    fn drain_datagrams(&self) -> impl Iterator<Item = Vec<u8>>;
+
+    /// Move the connection center; tracked chunks outside
+    /// `ServerConfig::chunk_unload_radius` are unloaded on the client
+    fn set_chunk_center(&self, world_slug: &str, center: ChunkPosition);
+
+    /// Open a byte stream to the client over the reliable ordered channel.
+    ///
+    /// The client receives it from `IClientNetwork::iter_streams`.
+    fn open_stream(&self, label: &str) -> StreamWriter<Self> {
+        StreamWriter::open(self.clone(), label)
+    }
+
+    /// Calls of and to the client, see `crate::rpc`
+    fn get_rpc(&self) -> &RpcEndpoint;
+
+    /// Call the client over the reliable channel and wait for its response,
+    /// at most `ServerConfig::rpc_timeout`
+    fn request<R: Request>(&self, request: &R) -> impl Future<Output = Result<R::Response, RpcError>> + Send {
+        self.get_rpc().call(request, |frame| {
+            self.send_message(NetworkMessageType::ReliableOrdered, &ServerMessages::Rpc(frame));
+        })
+    }
+
+    /// Answer a call of the client taken from `RpcEndpoint::drain_requests`
+    fn respond<R: Request>(&self, request: &IncomingRequest, response: Result<&R::Response, String>) {
+        let frame = request.response_frame::<R>(response);
+        self.send_message(NetworkMessageType::ReliableOrdered, &ServerMessages::Rpc(frame));
+    }
+
+    /// Send the reason over the reliable channel and then disconnect
+    fn disconnect_with_reason(&self, reason: Option<String>) {
+        self.send_message(
+            NetworkMessageType::ReliableOrdered,
+            &ServerMessages::Disconnect { message: reason },
+        );
+        self.disconnect();
+    }
+
+    /// Versions of the stores synced to the client, see `crate::keyed_state`
+    fn get_state_syncs(&self) -> &StateSyncs;
+
+    /// Send windows of the streams opened with `open_stream`, see `crate::streams`
+    fn get_stream_windows(&self) -> &StreamWindows;
+
+    /// Deflate level of the messages sent to the client, adapted every step
+    /// unless overridden; see `crate::compression`. Unused on renet, which does not compress
+    fn get_compression(&self) -> &AdaptiveCompression;
+
+    /// Send the keys of `state` changed since the version this client
+    /// acknowledged, every key before its first ack; over the unreliable
+    /// channel. Call it every tick, see `crate::keyed_state`
+    fn sync_state(&self, state: &KeyedState) {
+        if let Some(message) = self.get_state_syncs().delta(state) {
+            self.send_message(NetworkMessageType::Unreliable, &message);
+        }
+    }
+
+    /// Channel parameters retuned at runtime, see `crate::tuning`
+    fn get_tuning(&self) -> &ConnectionTuning;
+
+    /// Change channel parameters of the connection at runtime; returns the
+    /// revision reported in `ServerEvents::ChannelsRetuned` once both ends applied it
+    fn retune(&self, tuning: &ChannelTuning) -> Result<u32, String> {
+        let revision = self.get_tuning().propose(tuning)?;
+        self.send_system(&SystemMessage::ChannelTuning {
+            revision,
+            tuning: tuning.clone(),
+        });
+        Ok(revision)
+    }
 }
@@ -0,0 +1,283 @@
+//! Outgoing traffic shaping per world/topic group.
+//!
+//! The outgoing bandwidth of every connection is split between groups,
+//! e.g. overworld replication 60% and chat 5%, so one busy world cannot
+//! starve the others sharing the same connection. A group never exceeds
+//! its share: messages over it wait in the group queue, in order.
+//! Messages outside every group are not shaped.
+//!
+//! `IServerConnection::reserve_burst` makes room for a one-shot burst, such
+//! as the initial snapshot after a join or the chunks after a teleport: the
+//! next bytes sent skip their groups, and every group yields the share of
+//! the `BurstPriority` until the burst is sent or `MAX_BURST_DURATION` passed.
+//! The configured shares are left untouched.
+
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::messages::ServerMessages;
+
+/// Messages of the worlds and variants listed, sharing one bandwidth share
+#[derive(Clone, Debug)]
+pub struct TopicGroup {
+    pub name: String,
+    /// Part of `TrafficShaping::bytes_per_sec`, above 0.0 and up to 1.0
+    pub share: f32,
+    /// World slugs; matches every message carrying one of these worlds
+    pub worlds: Vec<String>,
+    /// Variants by their kebab-case name (`ServerMessages::as_ref()`), e.g. "console-output"
+    pub variants: Vec<String>,
+}
+
+impl TopicGroup {
+    fn matches(&self, message: &ServerMessages) -> bool {
+        if let Some(world_slug) = get_world_slug(message) {
+            if self.worlds.iter().any(|w| w == world_slug) {
+                return true;
+            }
+        }
+        self.variants.iter().any(|v| v == message.as_ref())
+    }
+}
+
+/// Per-connection outgoing bandwidth and its split between topic groups.
+#[derive(Clone, Debug)]
+pub struct TrafficShaping {
+    pub bytes_per_sec: u32,
+    groups: Vec<TopicGroup>,
+}
+
+impl TrafficShaping {
+    pub fn new(bytes_per_sec: u32) -> Self {
+        Self {
+            bytes_per_sec,
+            groups: Default::default(),
+        }
+    }
+
+    /// Group of all messages of the listed worlds
+    pub fn with_world_group(self, name: &str, share: f32, worlds: &[&str]) -> Self {
+        self.with_group(name, share, worlds, &[])
+    }
+
+    /// Group of the listed message variants, e.g. "console-output"
+    pub fn with_variant_group(self, name: &str, share: f32, variants: &[&str]) -> Self {
+        self.with_group(name, share, &[], variants)
+    }
+
+    /// The first group matching a message takes it
+    pub fn with_group(mut self, name: &str, share: f32, worlds: &[&str], variants: &[&str]) -> Self {
+        assert!(share > 0.0 && share <= 1.0, "share of group {} must be within 0.0 - 1.0", name);
+        let total: f32 = self.groups.iter().map(|g| g.share).sum::<f32>() + share;
+        assert!(total <= 1.0 + f32::EPSILON, "shares of the topic groups exceed 1.0");
+        self.groups.push(TopicGroup {
+            name: name.to_string(),
+            share,
+            worlds: worlds.iter().map(|w| w.to_string()).collect(),
+            variants: variants.iter().map(|v| v.to_string()).collect(),
+        });
+        self
+    }
+
+    pub fn get_groups(&self) -> &Vec<TopicGroup> {
+        &self.groups
+    }
+
+    /// Index of the group shaping this message
+    pub(crate) fn group_of(&self, message: &ServerMessages) -> Option<usize> {
+        self.groups.iter().position(|g| g.matches(message))
+    }
+}
+
+fn get_world_slug(message: &ServerMessages) -> Option<&str> {
+    match message {
+        ServerMessages::SpawnWorld { world_slug }
+        | ServerMessages::PlayerSpawn { world_slug, .. }
+        | ServerMessages::ChunkSectionInfo { world_slug, .. }
+        | ServerMessages::ChunkSectionInfoEncoded { world_slug, .. }
+        | ServerMessages::UnloadChunks { world_slug, .. }
+        | ServerMessages::StartStreamingEntity { world_slug, .. }
+        | ServerMessages::UpdateEntityComponent { world_slug, .. }
+        | ServerMessages::StopStreamingEntities { world_slug, .. }
+        | ServerMessages::EntityMove { world_slug, .. }
+        | ServerMessages::EditBlock { world_slug, .. } => Some(world_slug),
+        _ => None,
+    }
+}
+
+/// Time after which a burst reservation lapses, even if not used up
+pub const MAX_BURST_DURATION: Duration = Duration::from_secs(5);
+
+/// Share of the group rates a burst takes while it is sent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BurstPriority {
+    /// A quarter of the group rates
+    Low,
+    /// Half of the group rates
+    #[default]
+    Normal,
+    /// Nine tenths of the group rates
+    High,
+}
+
+impl BurstPriority {
+    fn share(self) -> f64 {
+        match self {
+            Self::Low => 0.25,
+            Self::Normal => 0.5,
+            Self::High => 0.9,
+        }
+    }
+}
+
+struct Burst {
+    remaining: usize,
+    share: f64,
+    expires: Instant,
+}
+
+/// Burst reserved on one connection, shared with its shaper
+#[derive(Default)]
+pub(crate) struct BurstReservation {
+    burst: Mutex<Option<Burst>>,
+}
+
+impl BurstReservation {
+    /// Replaces the running reservation, if any
+    pub fn reserve(&self, bytes: usize, priority: BurstPriority) {
+        *self.burst.lock() = Some(Burst {
+            remaining: bytes,
+            share: priority.share(),
+            expires: Instant::now() + MAX_BURST_DURATION,
+        });
+    }
+
+    /// True if a message of `size` bytes sent now is part of the burst
+    pub fn take(&self, size: usize) -> bool {
+        let mut burst = self.burst.lock();
+        let Some(current) = burst.as_mut().filter(|b| Instant::now() < b.expires) else {
+            *burst = None;
+            return false;
+        };
+        current.remaining = current.remaining.saturating_sub(size);
+        if current.remaining == 0 {
+            *burst = None;
+        }
+        true
+    }
+
+    /// Part of the group rates left to the groups
+    fn group_scale(&self) -> f64 {
+        match self.burst.lock().as_ref() {
+            Some(burst) if Instant::now() < burst.expires => 1.0 - burst.share,
+            _ => 1.0,
+        }
+    }
+}
+
+/// Token bucket of one group, holding at most one second of burst
+struct GroupBucket<T> {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+    queue: VecDeque<(usize, T)>,
+}
+
+impl<T> GroupBucket<T> {
+    /// `scale` is the part of the rate not reserved by a burst
+    fn refill(&mut self, now: Instant, scale: f64) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate * scale).min(self.rate);
+    }
+}
+
+/// Queues of the shaped groups of one connection.
+///
+/// A message is sent while its group has tokens left; a single message
+/// larger than the remaining budget still goes out and puts the bucket
+/// in debt, so nothing is held back forever.
+pub(crate) struct Shaper<T> {
+    bytes_per_sec: u32,
+    buckets: Vec<GroupBucket<T>>,
+    burst: Arc<BurstReservation>,
+}
+
+impl<T> Shaper<T> {
+    pub fn new(shaping: &TrafficShaping, burst: Arc<BurstReservation>) -> Self {
+        let now = Instant::now();
+        let buckets = shaping
+            .groups
+            .iter()
+            .map(|group| {
+                let rate = shaping.bytes_per_sec as f64 * group.share as f64;
+                GroupBucket {
+                    rate,
+                    tokens: rate,
+                    last_refill: now,
+                    queue: Default::default(),
+                }
+            })
+            .collect();
+        Self {
+            bytes_per_sec: shaping.bytes_per_sec,
+            buckets,
+            burst,
+        }
+    }
+
+    /// Change the share of every group, by index; see `crate::tuning`
+    pub fn set_shares(&mut self, shares: &[f32]) {
+        for (bucket, share) in self.buckets.iter_mut().zip(shares) {
+            bucket.rate = self.bytes_per_sec as f64 * *share as f64;
+            bucket.tokens = bucket.tokens.min(bucket.rate);
+        }
+    }
+
+    /// Queue the item; returns it back if it may be sent right away
+    pub fn push(&mut self, group: usize, size: usize, item: T) -> Option<T> {
+        let scale = self.burst.group_scale();
+        let bucket = &mut self.buckets[group];
+        bucket.refill(Instant::now(), scale);
+        if bucket.queue.is_empty() && bucket.tokens > 0.0 {
+            bucket.tokens -= size as f64;
+            return Some(item);
+        }
+        bucket.queue.push_back((size, item));
+        None
+    }
+
+    /// Queued items that fit the budget now, group by group
+    pub fn pop_ready(&mut self) -> Vec<T> {
+        let now = Instant::now();
+        let scale = self.burst.group_scale();
+        let mut ready = Vec::new();
+        for bucket in self.buckets.iter_mut() {
+            bucket.refill(now, scale);
+            while bucket.tokens > 0.0 {
+                let Some((size, item)) = bucket.queue.pop_front() else {
+                    break;
+                };
+                bucket.tokens -= size as f64;
+                ready.push(item);
+            }
+        }
+        ready
+    }
+
+    /// Time until a queued item fits the budget; None if nothing is queued
+    pub fn next_ready_in(&self) -> Option<Duration> {
+        let scale = self.burst.group_scale();
+        self.buckets
+            .iter()
+            .filter(|b| !b.queue.is_empty())
+            .map(|b| Duration::from_secs_f64((-b.tokens).max(0.0) / (b.rate * scale)).max(Duration::from_millis(1)))
+            .min()
+    }
+
+    pub fn queued(&self) -> usize {
+        self.buckets.iter().map(|b| b.queue.len()).sum()
+    }
+}
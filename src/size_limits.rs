@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+
+/// Expected and maximum encoded size for one message variant.
+#[derive(Clone, Copy, Debug)]
+pub struct SizeLimit {
+    /// Messages above this size are reported as outliers
+    pub soft: usize,
+    /// Messages above this size are dropped
+    pub hard: usize,
+}
+
+pub(crate) enum SizeCheck {
+    Ok,
+    Warning,
+    Rejected,
+}
+
+/// Per-variant size limits.
+///
+/// Variants are keyed by their kebab-case name (`ServerMessages::as_ref()`),
+/// e.g. "chunk-section-info-encoded".
+#[derive(Clone, Debug, Default)]
+pub struct MessageSizeLimits {
+    limits: HashMap<String, SizeLimit>,
+    default: Option<SizeLimit>,
+}
+
+impl MessageSizeLimits {
+    pub fn with_limit(mut self, variant: &str, soft: usize, hard: usize) -> Self {
+        self.limits.insert(variant.to_string(), SizeLimit { soft, hard });
+        self
+    }
+
+    /// Limit applied to variants without an explicit entry
+    pub fn with_default(mut self, soft: usize, hard: usize) -> Self {
+        self.default = Some(SizeLimit { soft, hard });
+        self
+    }
+
+    pub fn get_limit(&self, variant: &str) -> Option<&SizeLimit> {
+        self.limits.get(variant).or(self.default.as_ref())
+    }
+
+    pub(crate) fn check(&self, variant: &str, size: usize) -> SizeCheck {
+        match self.get_limit(variant) {
+            Some(limit) if size > limit.hard => SizeCheck::Rejected,
+            Some(limit) if size > limit.soft => SizeCheck::Warning,
+            _ => SizeCheck::Ok,
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! Delta-compressed entity state snapshots.
+//!
+//! Each tick the server fills a `SnapshotBuilder` with the state of the
+//! entities a client sees and sends it with `IServerConnection::send_snapshot`.
+//! The client gets only the entities that changed since the last snapshot it
+//! acknowledged, or every entity when there is none: first snapshot, ack older
+//! than `SNAPSHOT_HISTORY` snapshots, or another world. Snapshots go over the
+//! unreliable channel; a lost one is covered by the next delta.
+//!
+//! The client acknowledges from `step()` and reads complete snapshots from
+//! `IClientNetwork::iter_snapshots`.
+
+use common::chunks::position::Vector3;
+use common::chunks::rotation::Rotation;
+use flume::{Drain, Receiver, Sender};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+
+use crate::entities::AnimationState;
+use crate::messages::{ClientMessages, ServerMessages};
+
+/// Snapshots kept on both sides as delta baselines
+pub const SNAPSHOT_HISTORY: usize = 32;
+
+type EntityStates = Arc<BTreeMap<u32, EntityState>>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EntityState {
+    #[serde(with = "crate::quantization::position")]
+    pub position: Vector3,
+    #[serde(with = "crate::quantization::rotation")]
+    pub rotation: Rotation,
+    pub animation_state: AnimationState,
+}
+
+/// Entity states of one world at one tick, shared by every client it is sent to
+#[derive(Debug, Clone)]
+pub struct SnapshotBuilder {
+    world_slug: String,
+    timestamp: f64,
+    entities: EntityStates,
+}
+
+impl SnapshotBuilder {
+    /// `timestamp` is the server time in seconds since startup
+    pub fn new(world_slug: &str, timestamp: f64) -> Self {
+        Self {
+            world_slug: world_slug.to_string(),
+            timestamp,
+            entities: Default::default(),
+        }
+    }
+
+    pub fn insert(&mut self, id: u32, state: EntityState) {
+        Arc::make_mut(&mut self.entities).insert(id, state);
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        Arc::make_mut(&mut self.entities).remove(&id);
+    }
+
+    pub fn get_world_slug(&self) -> &String {
+        &self.world_slug
+    }
+
+    pub fn entities_count(&self) -> usize {
+        self.entities.len()
+    }
+}
+
+/// Complete snapshot rebuilt by the client
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub world_slug: String,
+    pub sequence: u32,
+    /// Server time in seconds since startup
+    pub timestamp: f64,
+    pub entities: Arc<BTreeMap<u32, EntityState>>,
+}
+
+/// True if sequence `a` was sent after `b`, across wrap-around
+fn is_newer(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < u32::MAX / 2
+}
+
+fn push_history(history: &mut VecDeque<(u32, EntityStates)>, sequence: u32, entities: EntityStates) {
+    history.push_back((sequence, entities));
+    if history.len() > SNAPSHOT_HISTORY {
+        history.pop_front();
+    }
+}
+
+fn find_history(history: &VecDeque<(u32, EntityStates)>, sequence: u32) -> Option<&EntityStates> {
+    history
+        .iter()
+        .find(|(s, _)| *s == sequence)
+        .map(|(_, entities)| entities)
+}
+
+/// Snapshots sent to one connection and the last one it acknowledged
+#[derive(Debug, Default)]
+pub(crate) struct SnapshotSender {
+    world_slug: String,
+    next_sequence: u32,
+    sent: VecDeque<(u32, EntityStates)>,
+    acked: Option<u32>,
+}
+
+impl SnapshotSender {
+    /// `ServerMessages::EntitySnapshot` against the acknowledged snapshot
+    pub fn encode(&mut self, snapshot: &SnapshotBuilder) -> ServerMessages {
+        if self.world_slug != snapshot.world_slug {
+            self.world_slug = snapshot.world_slug.clone();
+            self.sent.clear();
+            self.acked = None;
+        }
+        let baseline = self
+            .acked
+            .and_then(|acked| find_history(&self.sent, acked).map(|entities| (acked, entities)));
+
+        let (changed, removed) = match baseline {
+            Some((_, baseline)) => {
+                let changed = snapshot
+                    .entities
+                    .iter()
+                    .filter(|(id, state)| baseline.get(id) != Some(state))
+                    .map(|(id, state)| (*id, state.clone()))
+                    .collect();
+                let removed = baseline
+                    .keys()
+                    .filter(|id| !snapshot.entities.contains_key(id))
+                    .copied()
+                    .collect();
+                (changed, removed)
+            }
+            None => {
+                let changed = snapshot
+                    .entities
+                    .iter()
+                    .map(|(id, state)| (*id, state.clone()))
+                    .collect();
+                (changed, Vec::new())
+            }
+        };
+        let message = ServerMessages::EntitySnapshot {
+            world_slug: snapshot.world_slug.clone(),
+            sequence: self.next_sequence,
+            baseline: baseline.map(|(sequence, _)| sequence),
+            timestamp: snapshot.timestamp,
+            changed,
+            removed,
+        };
+        push_history(&mut self.sent, self.next_sequence, snapshot.entities.clone());
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        message
+    }
+
+    /// `ClientMessages::SnapshotAck`; acks of another world or older than the last are ignored
+    pub fn ack(&mut self, world_slug: &str, sequence: u32) {
+        if self.world_slug != world_slug || find_history(&self.sent, sequence).is_none() {
+            return;
+        }
+        if self.acked.map_or(true, |acked| is_newer(sequence, acked)) {
+            self.acked = Some(sequence);
+        }
+    }
+}
+
+#[derive(Default)]
+struct ReceiverState {
+    world_slug: String,
+    received: VecDeque<(u32, EntityStates)>,
+    last: Option<u32>,
+    pending_ack: Option<ClientMessages>,
+}
+
+/// Rebuilds incoming `ServerMessages::EntitySnapshot` into complete snapshots.
+pub(crate) struct IncomingSnapshots {
+    state: Mutex<ReceiverState>,
+    snapshots: (Sender<Snapshot>, Receiver<Snapshot>),
+}
+
+impl IncomingSnapshots {
+    pub fn new() -> Self {
+        Self {
+            state: Default::default(),
+            snapshots: flume::unbounded(),
+        }
+    }
+
+    /// Consume snapshot messages; any other message is returned back
+    pub fn route(&self, message: ServerMessages) -> Option<ServerMessages> {
+        let ServerMessages::EntitySnapshot {
+            world_slug,
+            sequence,
+            baseline,
+            timestamp,
+            changed,
+            removed,
+        } = message
+        else {
+            return Some(message);
+        };
+        let mut state = self.state.lock();
+        if state.world_slug != world_slug {
+            state.world_slug = world_slug.clone();
+            state.received.clear();
+            state.last = None;
+        }
+        // Late snapshots are superseded by the ones already applied
+        if state.last.is_some_and(|last| !is_newer(sequence, last)) {
+            return None;
+        }
+        let mut entities = match baseline {
+            Some(baseline) => match find_history(&state.received, baseline) {
+                Some(entities) => BTreeMap::clone(entities),
+                None => {
+                    log::warn!(target: "network", "Snapshot {} baseline {} is unknown; dropped", sequence, baseline);
+                    return None;
+                }
+            },
+            None => BTreeMap::new(),
+        };
+        for id in removed {
+            entities.remove(&id);
+        }
+        entities.extend(changed);
+        let entities = Arc::new(entities);
+
+        push_history(&mut state.received, sequence, entities.clone());
+        state.last = Some(sequence);
+        state.pending_ack = Some(ClientMessages::SnapshotAck {
+            world_slug: world_slug.clone(),
+            sequence,
+        });
+        let snapshot = Snapshot {
+            world_slug,
+            sequence,
+            timestamp,
+            entities,
+        };
+        self.snapshots.0.send(snapshot).ok();
+        None
+    }
+
+    /// Ack of the latest snapshot, once; sent by the client `step()`
+    pub fn take_ack(&self) -> Option<ClientMessages> {
+        self.state.lock().pending_ack.take()
+    }
+
+    pub fn drain(&self) -> Drain<'_, Snapshot> {
+        self.snapshots.1.drain()
+    }
+}
@@ -0,0 +1,107 @@
+//! Recovery from errors of the server sockets.
+//!
+//! The OS can fail a socket call in the middle of a run: a datagram over
+//! the path MTU (EMSGSIZE), a route gone after the NIC was reconfigured
+//! (ENETUNREACH), full socket buffers (ENOBUFS). Such errors are transient:
+//! the call is retried, after `SOCKET_RETRY_DELAY` in the accept and
+//! receive loops and on the next tick in `step()`. An error that persists
+//! for `REBIND_AFTER` attempts in a row, or one that is not transient, has
+//! the socket bound again on the same address. Each failure is logged and
+//! reported as `ServerEvents::SocketError`.
+//!
+//! Rebinding the renet socket starts a new netcode transport, which drops
+//! every client connection; the clients reconnect. Renet logs the errors of
+//! `send_packets` itself.
+
+use std::io::{self, ErrorKind};
+use std::time::Duration;
+
+use crate::server::ServerEvents;
+
+/// Pause of an accept or receive loop after a failed call
+pub const SOCKET_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Failures in a row after which the socket is bound again
+pub const REBIND_AFTER: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketRecovery {
+    /// The call is retried on the same socket
+    Retried,
+    /// The socket was bound again on its address
+    Rebound,
+    /// Binding again failed; retried after the next failure
+    RebindFailed,
+}
+
+#[cfg(target_os = "linux")]
+const EMSGSIZE: i32 = 90;
+#[cfg(target_os = "linux")]
+const ENOBUFS: i32 = 105;
+#[cfg(all(unix, not(target_os = "linux")))]
+const EMSGSIZE: i32 = 40;
+#[cfg(all(unix, not(target_os = "linux")))]
+const ENOBUFS: i32 = 55;
+#[cfg(windows)]
+const EMSGSIZE: i32 = 10040;
+#[cfg(windows)]
+const ENOBUFS: i32 = 10055;
+
+/// Errors the socket recovers from on its own
+pub(crate) fn is_transient(error: &io::Error) -> bool {
+    #[cfg(any(unix, windows))]
+    if matches!(error.raw_os_error(), Some(EMSGSIZE) | Some(ENOBUFS)) {
+        return true;
+    }
+    matches!(
+        error.kind(),
+        ErrorKind::WouldBlock
+            | ErrorKind::Interrupted
+            | ErrorKind::TimedOut
+            // ICMP errors of earlier datagrams, reported on the next call
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NetworkUnreachable
+            | ErrorKind::HostUnreachable
+            | ErrorKind::OutOfMemory
+    )
+}
+
+/// Failures in a row of one socket
+#[derive(Debug, Default)]
+pub(crate) struct SocketFailures {
+    consecutive: u32,
+}
+
+impl SocketFailures {
+    pub fn succeeded(&mut self) {
+        self.consecutive = 0;
+    }
+
+    /// Count the failure; true if the socket should be bound again
+    pub fn failed(&mut self, error: &io::Error) -> bool {
+        self.consecutive += 1;
+        if is_transient(error) && self.consecutive < REBIND_AFTER {
+            return false;
+        }
+        self.consecutive = 0;
+        true
+    }
+}
+
+/// Log the failure and report it as `ServerEvents::SocketError`
+pub(crate) fn report_socket_error(
+    events: &flume::Sender<ServerEvents>,
+    socket: &str,
+    error: &io::Error,
+    recovery: SocketRecovery,
+) {
+    log::warn!(target: "network", "Socket {} error: {} ({:?})", socket, error, recovery);
+    let event = ServerEvents::SocketError {
+        socket: socket.to_string(),
+        error: error.to_string(),
+        recovery,
+    };
+    events.send(event).ok();
+}
@@ -0,0 +1,70 @@
+use socket2::SockRef;
+
+/// OS level socket options applied by both transports.
+/// Unset options keep the OS defaults.
+#[derive(Clone, Debug, Default)]
+pub struct SocketOptions {
+    /// SO_RCVBUF size in bytes
+    pub recv_buffer_size: Option<usize>,
+    /// SO_SNDBUF size in bytes
+    pub send_buffer_size: Option<usize>,
+    /// DSCP code point (0..=63) for QoS marking
+    pub dscp: Option<u8>,
+    /// IP time-to-live, the hop limit on IPv6 sockets
+    pub ttl: Option<u32>,
+}
+
+impl SocketOptions {
+    pub fn with_buffer_sizes(mut self, recv: usize, send: usize) -> Self {
+        self.recv_buffer_size = Some(recv);
+        self.send_buffer_size = Some(send);
+        self
+    }
+
+    pub fn with_dscp(mut self, dscp: u8) -> Self {
+        self.dscp = Some(dscp);
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: u32) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Apply the options to any socket (socket2, std or tokio)
+    pub fn apply<'s>(&self, socket: impl Into<SockRef<'s>>) -> Result<(), String> {
+        let socket = socket.into();
+        // An unbound socket reports its family with an unspecified address
+        let ipv6 = socket.local_addr().map(|addr| addr.is_ipv6()).unwrap_or(false);
+        if let Some(size) = self.recv_buffer_size {
+            socket
+                .set_recv_buffer_size(size)
+                .map_err(|e| format!("Set recv buffer error: {e}"))?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket
+                .set_send_buffer_size(size)
+                .map_err(|e| format!("Set send buffer error: {e}"))?;
+        }
+        if let Some(dscp) = self.dscp {
+            if dscp > 63 {
+                return Err(format!("DSCP value {} is out of range 0..=63", dscp));
+            }
+            // DSCP occupies the upper six bits of the TOS byte and of the traffic class
+            let tos = (dscp as u32) << 2;
+            match ipv6 {
+                true => socket.set_tclass_v6(tos),
+                false => socket.set_tos_v4(tos),
+            }
+            .map_err(|e| format!("Set DSCP error: {e}"))?;
+        }
+        if let Some(ttl) = self.ttl {
+            match ipv6 {
+                true => socket.set_unicast_hops_v6(ttl),
+                false => socket.set_ttl_v4(ttl),
+            }
+            .map_err(|e| format!("Set TTL error: {e}"))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,320 @@
+//! Byte streams from the server to a client, e.g. for asset and file transfers.
+//!
+//! Streams are flow controlled end to end, apart from the congestion control
+//! of the channel: the server may have `STREAM_WINDOW` bytes of a stream
+//! the client has not read yet, and `StreamWriter` waits for more credit
+//! beyond it. The client returns credit (`ClientMessages::StreamCredit`)
+//! from `step()` as the game reads the `StreamReader`, so a client slow to
+//! store what it receives holds back the writer instead of filling the
+//! server memory with queued chunks. Clients predating the credit are
+//! written to without a window.
+
+use flume::{Drain, Receiver, Sender};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::atomic::{AtomicU32, Ordering},
+    task::{Context, Poll, Waker},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::messages::{ClientMessages, NetworkMessageType, ServerMessages};
+use crate::server::IServerConnection;
+
+/// Maximum payload of a single `ServerMessages::StreamData`
+pub const STREAM_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Bytes of a stream the server may send ahead of what the client read
+pub const STREAM_WINDOW: u32 = 256 * 1024;
+
+/// Bytes the client reads before returning them as credit
+pub const STREAM_CREDIT_THRESHOLD: u32 = STREAM_WINDOW / 4;
+
+#[derive(Debug)]
+struct StreamWindow {
+    credit: u32,
+    /// Writer waiting for credit
+    waker: Option<Waker>,
+}
+
+/// Send windows of the streams opened on a connection
+#[derive(Debug)]
+pub struct StreamWindows {
+    /// False for clients that never return credit
+    flow_control: bool,
+    windows: Mutex<HashMap<u32, StreamWindow>>,
+}
+
+impl StreamWindows {
+    pub(crate) fn new(flow_control: bool) -> Self {
+        Self {
+            flow_control,
+            windows: Default::default(),
+        }
+    }
+
+    fn open(&self, stream_id: u32) {
+        let window = StreamWindow {
+            credit: STREAM_WINDOW,
+            waker: None,
+        };
+        self.windows.lock().insert(stream_id, window);
+    }
+
+    fn close(&self, stream_id: u32) {
+        self.windows.lock().remove(&stream_id);
+    }
+
+    /// Up to `size` bytes of credit; None once the connection is closed
+    fn take(&self, stream_id: u32, size: usize, cx: &Context<'_>) -> Poll<Option<usize>> {
+        let mut windows = self.windows.lock();
+        let Some(window) = windows.get_mut(&stream_id) else {
+            return Poll::Ready(None);
+        };
+        if !self.flow_control {
+            return Poll::Ready(Some(size));
+        }
+        if window.credit == 0 {
+            window.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let size = size.min(window.credit as usize);
+        window.credit -= size as u32;
+        Poll::Ready(Some(size))
+    }
+
+    /// `ClientMessages::StreamCredit`
+    pub(crate) fn grant(&self, stream_id: u32, bytes: u32) {
+        let mut windows = self.windows.lock();
+        let Some(window) = windows.get_mut(&stream_id) else {
+            return;
+        };
+        window.credit = window.credit.saturating_add(bytes).min(STREAM_WINDOW);
+        if let Some(waker) = window.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// The connection closed; writers waiting for credit fail
+    pub(crate) fn close_all(&self) {
+        for (_, window) in self.windows.lock().drain() {
+            if let Some(waker) = window.waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+static NEXT_STREAM_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Server side of a byte stream, sent over the reliable ordered channel.
+///
+/// Writes are split into `STREAM_CHUNK_SIZE` messages. The stream is
+/// closed by `shutdown()` or when the writer is dropped.
+pub struct StreamWriter<C: IServerConnection> {
+    connection: C,
+    stream_id: u32,
+    closed: bool,
+}
+
+impl<C: IServerConnection> StreamWriter<C> {
+    pub(crate) fn open(connection: C, label: &str) -> Self {
+        let stream_id = NEXT_STREAM_ID.fetch_add(1, Ordering::Relaxed);
+        connection.get_stream_windows().open(stream_id);
+        connection.send_message(
+            NetworkMessageType::ReliableOrdered,
+            &ServerMessages::StreamOpen {
+                stream_id,
+                label: label.to_string(),
+            },
+        );
+        Self {
+            connection,
+            stream_id,
+            closed: false,
+        }
+    }
+
+    pub fn get_stream_id(&self) -> u32 {
+        self.stream_id
+    }
+
+    fn close(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+        self.connection.get_stream_windows().close(self.stream_id);
+        self.connection.send_message(
+            NetworkMessageType::ReliableOrdered,
+            &ServerMessages::StreamClose {
+                stream_id: self.stream_id,
+            },
+        );
+    }
+}
+
+impl<C: IServerConnection + Unpin> AsyncWrite for StreamWriter<C> {
+    /// Pending while the client has `STREAM_WINDOW` bytes of the stream unread
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if self.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let size = buf.len().min(STREAM_CHUNK_SIZE);
+        let size = match self.connection.get_stream_windows().take(self.stream_id, size, cx) {
+            Poll::Ready(Some(size)) => size,
+            Poll::Ready(None) => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            Poll::Pending => return Poll::Pending,
+        };
+        self.connection.send_message(
+            NetworkMessageType::ReliableOrdered,
+            &ServerMessages::StreamData {
+                stream_id: self.stream_id,
+                data: buf[..size].to_vec(),
+            },
+        );
+        Poll::Ready(Ok(size))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<C: IServerConnection> Drop for StreamWriter<C> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Client side of a byte stream opened by the server.
+///
+/// Reading returns EOF once the server closes the stream.
+pub struct StreamReader {
+    stream_id: u32,
+    label: String,
+    rx: UnboundedReceiver<Vec<u8>>,
+    buffer: Vec<u8>,
+    position: usize,
+    /// Bytes read and not yet returned as credit
+    unread_credit: u32,
+    credits: Sender<(u32, u32)>,
+}
+
+impl StreamReader {
+    pub fn get_stream_id(&self) -> u32 {
+        self.stream_id
+    }
+
+    pub fn get_label(&self) -> &String {
+        &self.label
+    }
+}
+
+impl AsyncRead for StreamReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while self.position >= self.buffer.len() {
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(chunk)) => {
+                    self.buffer = chunk;
+                    self.position = 0;
+                }
+                // Stream closed
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let size = buf.remaining().min(self.buffer.len() - self.position);
+        let start = self.position;
+        buf.put_slice(&self.buffer[start..start + size]);
+        self.position += size;
+        self.unread_credit += size as u32;
+        if self.unread_credit >= STREAM_CREDIT_THRESHOLD {
+            let credit = std::mem::take(&mut self.unread_credit);
+            self.credits.send((self.stream_id, credit)).ok();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Routes incoming stream messages to their `StreamReader`.
+pub(crate) struct IncomingStreams {
+    streams: Mutex<HashMap<u32, UnboundedSender<Vec<u8>>>>,
+    opened: (Sender<StreamReader>, Receiver<StreamReader>),
+    /// Bytes read per stream, returned to the server from `step()`
+    credits: (Sender<(u32, u32)>, Receiver<(u32, u32)>),
+}
+
+impl IncomingStreams {
+    pub fn new() -> Self {
+        Self {
+            streams: Default::default(),
+            opened: flume::unbounded(),
+            credits: flume::unbounded(),
+        }
+    }
+
+    /// Consume stream messages; any other message is returned back
+    pub fn route(&self, message: ServerMessages) -> Option<ServerMessages> {
+        match message {
+            ServerMessages::StreamOpen { stream_id, label } => {
+                let (tx, rx) = unbounded_channel();
+                self.streams.lock().insert(stream_id, tx);
+                let reader = StreamReader {
+                    stream_id,
+                    label,
+                    rx,
+                    buffer: Default::default(),
+                    position: 0,
+                    unread_credit: 0,
+                    credits: self.credits.0.clone(),
+                };
+                self.opened.0.send(reader).ok();
+                None
+            }
+            ServerMessages::StreamData { stream_id, data } => {
+                let mut streams = self.streams.lock();
+                // Reader was dropped; the data is discarded, and returned as credit
+                // so the writer isn't held back
+                let size = data.len() as u32;
+                if streams.get(&stream_id).is_some_and(|tx| tx.send(data).is_err()) {
+                    streams.remove(&stream_id);
+                }
+                if !streams.contains_key(&stream_id) {
+                    self.credits.0.send((stream_id, size)).ok();
+                }
+                None
+            }
+            ServerMessages::StreamClose { stream_id } => {
+                self.streams.lock().remove(&stream_id);
+                None
+            }
+            message => Some(message),
+        }
+    }
+
+    pub fn drain(&self) -> Drain<'_, StreamReader> {
+        self.opened.1.drain()
+    }
+
+    /// `ClientMessages::StreamCredit` of the bytes read since the last call; sent by the client `step()`
+    pub fn take_credits(&self) -> Vec<ClientMessages> {
+        let mut credits: HashMap<u32, u32> = HashMap::new();
+        for (stream_id, bytes) in self.credits.1.drain() {
+            let credit = credits.entry(stream_id).or_default();
+            *credit = credit.saturating_add(bytes);
+        }
+        credits
+            .into_iter()
+            .map(|(stream_id, bytes)| ClientMessages::StreamCredit { stream_id, bytes })
+            .collect()
+    }
+}
@@ -0,0 +1,61 @@
+//! Messages of the crate itself, on a channel of their own.
+//!
+//! `SystemMessage`s go outside the game channels: on tokio as system
+//! frames, which the server writes ahead of the game frames queued, past
+//! batching, shaping and phases; on renet over a reliable channel of their
+//! own in each direction, filled first into every packet. A backlog of game
+//! messages never delays them, and new system messages grow neither
+//! `ServerMessages` nor `ClientMessages`.
+//!
+//! The server sends them with `IServerConnection::send_system` and the
+//! client reads them from `IClientNetwork::iter_system_messages`. The
+//! client only sends the ones of the crate itself, e.g. `TuningApplied`.
+//! Peers that predate the channel ignore it, and a variant unknown to the
+//! receiver is skipped. On tokio, system frames lost with a socket are not
+//! replayed when the session resumes.
+
+use serde::{Deserialize, Serialize};
+
+use crate::tuning::ChannelTuning;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum SystemMessage {
+    /// Settings for the client to apply, by name
+    SettingsPush { settings: Vec<(String, String)> },
+    /// A feature the client uses is deprecated and will be removed
+    DeprecationNotice { feature: String, message: String },
+    /// Connect to another server, e.g. another shard; `token` lets the client in there
+    Transfer { address: String, token: Option<Vec<u8>> },
+    /// Channel parameters to apply, see `crate::tuning`; handled by the crate
+    ChannelTuning { revision: u32, tuning: ChannelTuning },
+    /// The server suspended or resumed the reliable game messages, see `crate::degraded`
+    DegradedMode { active: bool },
+    /// The frames after this one are sealed with the key of `epoch`, see
+    /// `crate::security`; sent by both ends and handled by the crate
+    Rekey { epoch: u32 },
+    /// The client applied the `ChannelTuning`s up to `revision`; sent by the
+    /// client and handled by the crate
+    TuningApplied { revision: u32 },
+}
+
+pub(crate) fn encode_system(message: &SystemMessage) -> Option<Vec<u8>> {
+    match bincode::serialize(message) {
+        Ok(payload) => Some(payload),
+        Err(e) => {
+            log::warn!(target: "network", "System message encode error: {}", e);
+            None
+        }
+    }
+}
+
+/// None for a variant of a newer server
+pub(crate) fn decode_system(payload: &[u8]) -> Option<SystemMessage> {
+    match bincode::deserialize(payload) {
+        Ok(message) => Some(message),
+        Err(e) => {
+            log::warn!(target: "network", "System message skipped: {}", e);
+            None
+        }
+    }
+}
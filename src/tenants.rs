@@ -0,0 +1,142 @@
+//! Tenants: isolated namespaces sharing one server port.
+//!
+//! With `ServerConfig::with_tenants` every client names its tenant in the
+//! handshake (`ClientConfig::with_tenant`) and is refused if it is unknown.
+//! The connect and disconnect reports, per-connection events and statistics
+//! of a tenant go to its `Tenant` (see `IServerNetwork::get_tenant`), not to
+//! the server streams, which keep the server-wide events (`Overloaded`,
+//! `TickReport`, ...). Server-wide calls such as `disconnect_all` still
+//! reach every connection.
+
+use parking_lot::RwLock;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::client_id::ClientId;
+use crate::messages::{NetworkMessageType, ServerMessages};
+use crate::server::{ConnectionMessages, IServerConnection, ServerEvents};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TenantStats {
+    pub connections: usize,
+    /// Connections reported since the server started
+    pub total_connections: u64,
+    pub bytes_sent_per_sec: f64,
+    pub bytes_received_per_sec: f64,
+}
+
+pub struct Tenant<C: IServerConnection> {
+    name: String,
+    connections: RwLock<HashMap<ClientId, C>>,
+    total_connections: AtomicU64,
+    channel_connections: (
+        flume::Sender<ConnectionMessages<C>>,
+        flume::Receiver<ConnectionMessages<C>>,
+    ),
+    channel_events: (flume::Sender<ServerEvents>, flume::Receiver<ServerEvents>),
+}
+
+impl<C: IServerConnection> Tenant<C> {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            connections: Default::default(),
+            total_connections: Default::default(),
+            channel_connections: flume::unbounded(),
+            channel_events: flume::unbounded(),
+        }
+    }
+
+    pub fn get_name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn drain_connections(&self) -> impl Iterator<Item = ConnectionMessages<C>> + '_ {
+        self.channel_connections.1.drain()
+    }
+
+    /// Events of the tenant connections
+    pub fn drain_events(&self) -> impl Iterator<Item = ServerEvents> + '_ {
+        self.channel_events.1.drain()
+    }
+
+    pub fn connections_count(&self) -> usize {
+        self.connections.read().len()
+    }
+
+    pub fn connections_snapshot(&self) -> Vec<C> {
+        self.connections.read().values().cloned().collect()
+    }
+
+    pub fn broadcast_message(&self, message_type: NetworkMessageType, message: &ServerMessages) {
+        for connection in self.connections.read().values() {
+            connection.send_message(message_type, message);
+        }
+    }
+
+    pub fn get_stats(&self) -> TenantStats {
+        let connections = self.connections.read();
+        let mut stats = TenantStats {
+            connections: connections.len(),
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        for connection in connections.values() {
+            let info = connection.get_network_info();
+            stats.bytes_sent_per_sec += info.bytes_sent_per_sec;
+            stats.bytes_received_per_sec += info.bytes_received_per_sec;
+        }
+        stats
+    }
+}
+
+/// Tenants of a server, fixed by `ServerConfig::tenants`
+#[cfg_attr(not(feature = "network-tokio"), allow(dead_code))]
+pub(crate) struct Tenants<C: IServerConnection> {
+    tenants: HashMap<String, Tenant<C>>,
+}
+
+#[cfg_attr(not(feature = "network-tokio"), allow(dead_code))]
+impl<C: IServerConnection> Tenants<C> {
+    pub fn new(names: &[String]) -> Self {
+        let tenants = names
+            .iter()
+            .map(|name| (name.clone(), Tenant::new(name.clone())))
+            .collect();
+        Self { tenants }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Tenant<C>> {
+        self.tenants.get(name)
+    }
+
+    /// Events sender of the tenant; None sends to the server stream
+    pub fn get_events_sender(&self, tenant: Option<&String>) -> Option<flume::Sender<ServerEvents>> {
+        tenant
+            .and_then(|name| self.tenants.get(name))
+            .map(|tenant| tenant.channel_events.0.clone())
+    }
+
+    /// Report a connection to its tenant; returns the message if it belongs to the server stream
+    pub fn route(&self, tenant: Option<&String>, message: ConnectionMessages<C>) -> Option<ConnectionMessages<C>> {
+        let Some(tenant) = tenant.and_then(|name| self.tenants.get(name)) else {
+            return Some(message);
+        };
+        match &message {
+            ConnectionMessages::Connect { connection } => {
+                tenant.total_connections.fetch_add(1, Ordering::Relaxed);
+                tenant
+                    .connections
+                    .write()
+                    .insert(connection.get_client_id(), connection.clone());
+            }
+            ConnectionMessages::Disconnect { client_id, .. } => {
+                tenant.connections.write().remove(client_id);
+            }
+        }
+        tenant.channel_connections.0.send(message).ok();
+        None
+    }
+}
@@ -0,0 +1,254 @@
+//! Canonical wire bytes for third-party implementations (`test-vectors` feature).
+//!
+//! Every vector is built from fixed inputs with the same code the crate
+//! sends with, so other implementations (e.g. a service parsing
+//! `ClientMessages::ConnectionInfo`) can check their encoding byte for byte.
+//! Payloads are bincode 1 with its default options: little-endian fixed-size
+//! integers, u32 enum variant indices and u64 length prefixes.
+//!
+//! Handshake structures are internal; their vectors carry the `Debug`
+//! rendering of the decoded value instead. `dump` renders everything as text.
+
+use std::fmt::{self, Write};
+use std::time::Duration;
+
+use serde::Serialize;
+use strum::EnumCount;
+
+use crate::approval::RejectionReason;
+#[cfg(feature = "network-tokio")]
+use crate::compression::compress_payload;
+use crate::handshake::{psk_proof, ClientHello, HandshakeResult, ServerHello, SessionParameters, CHALLENGE_SIZE};
+use crate::messages::{ClientMessages, NetworkMessageType, ServerMessages, PROTOCOL_VERSION};
+use crate::quantization::QuantizationProfile;
+
+/// Passphrase of the handshake vectors
+pub const PASSPHRASE: &str = "brilliance";
+
+/// Compression threshold of the compressed message vectors
+pub const COMPRESSION_THRESHOLD: u32 = 64;
+
+pub struct TestVector<T> {
+    pub name: &'static str,
+    /// Bincode payload, as carried by a renet channel
+    pub payload: Vec<u8>,
+    /// Complete tokio frame: length prefix, frame type, channel byte for messages, payload
+    #[cfg(feature = "network-tokio")]
+    pub frame: Vec<u8>,
+    /// Value the payload decodes to
+    pub decoded: T,
+}
+
+impl<T: fmt::Debug> fmt::Display for TestVector<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.name)?;
+        writeln!(f, "  payload: {}", to_hex(&self.payload))?;
+        #[cfg(feature = "network-tokio")]
+        writeln!(f, "  frame:   {}", to_hex(&self.frame))?;
+        writeln!(f, "  decoded: {:?}", self.decoded)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        write!(hex, "{:02x}", byte).unwrap();
+        hex
+    })
+}
+
+#[cfg(feature = "network-tokio")]
+fn encode_frame(header: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut frame = ((header.len() + payload.len()) as u32).to_le_bytes().to_vec();
+    frame.extend(header);
+    frame.extend(payload);
+    frame
+}
+
+fn handshake_vector<T: Serialize + fmt::Debug>(name: &'static str, value: T) -> TestVector<String> {
+    let payload = bincode::serialize(&value).unwrap();
+    TestVector {
+        name,
+        #[cfg(feature = "network-tokio")]
+        frame: encode_frame(&[crate::tokio::FRAME_HANDSHAKE], &payload),
+        payload,
+        decoded: format!("{:?}", value),
+    }
+}
+
+fn message_vector<T: Serialize>(
+    name: &'static str,
+    message_type: NetworkMessageType,
+    compression_threshold: Option<u32>,
+    message: T,
+) -> TestVector<T> {
+    let payload = bincode::serialize(&message).unwrap();
+    #[cfg(feature = "network-tokio")]
+    let frame = {
+        let (channel, compressed) = compress_payload(compression_threshold, message_type.channel_id(), payload.clone());
+        encode_frame(&[crate::tokio::FRAME_MESSAGE, channel], &compressed)
+    };
+    #[cfg(not(feature = "network-tokio"))]
+    let _ = (message_type, compression_threshold);
+    TestVector {
+        name,
+        payload,
+        #[cfg(feature = "network-tokio")]
+        frame,
+        decoded: message,
+    }
+}
+
+fn challenge() -> [u8; CHALLENGE_SIZE] {
+    std::array::from_fn(|i| i as u8)
+}
+
+/// Handshake frames in the order they are exchanged
+pub fn handshake() -> Vec<TestVector<String>> {
+    let session = SessionParameters {
+        quantization: vec![(NetworkMessageType::WorldInfo.channel_id(), "centimeter".to_string())],
+        datagram_token: 0x0102_0304_0506_0708,
+        compression_threshold: Some(COMPRESSION_THRESHOLD),
+        seed: Some(42),
+        protocol_version: PROTOCOL_VERSION,
+        schema: ServerMessages::COUNT as u32,
+        ..Default::default()
+    };
+    let mut signed = session.clone();
+    signed.sign_seed(Some(&PASSPHRASE.to_string()), &challenge());
+    vec![
+        handshake_vector("server_hello", ServerHello { challenge: challenge() }),
+        handshake_vector(
+            "client_hello",
+            ClientHello {
+                proof: Some(psk_proof(PASSPHRASE, &challenge())),
+                quantization_profiles: QuantizationProfile::supported_names(),
+                compression: true,
+                max_texture_size: Some(1024),
+                connect_token: None,
+                tenant: None,
+                protocol_version: PROTOCOL_VERSION,
+                schema: ClientMessages::COUNT as u32,
+                resume: None,
+                credential: None,
+                rekey: true,
+                clear_channels: vec![NetworkMessageType::Unreliable.channel_id()],
+            },
+        ),
+        handshake_vector(
+            "client_hello_minimal",
+            ClientHello {
+                proof: None,
+                quantization_profiles: Vec::new(),
+                compression: false,
+                max_texture_size: None,
+                connect_token: None,
+                tenant: None,
+                protocol_version: PROTOCOL_VERSION,
+                schema: 0,
+                resume: None,
+                credential: None,
+                rekey: false,
+                clear_channels: Vec::new(),
+            },
+        ),
+        handshake_vector("accepted", HandshakeResult::Accepted(session)),
+        handshake_vector("accepted_signed_seed", HandshakeResult::Accepted(signed)),
+        handshake_vector(
+            "rejected",
+            HandshakeResult::Rejected {
+                reason: "Wrong server password".to_string(),
+            },
+        ),
+    ]
+}
+
+pub fn client_messages() -> Vec<TestVector<ClientMessages>> {
+    vec![
+        message_vector(
+            "connection_info",
+            NetworkMessageType::ReliableOrdered,
+            None,
+            ClientMessages::ConnectionInfo {
+                login: "player".to_string(),
+                version: "1.0.0".to_string(),
+                architecture: "x86_64".to_string(),
+                rendering_device: "Vulkan".to_string(),
+            },
+        ),
+        message_vector(
+            "console_input",
+            NetworkMessageType::ReliableOrdered,
+            None,
+            ClientMessages::ConsoleInput {
+                command: "/help".to_string(),
+            },
+        ),
+        message_vector(
+            "console_input_compressed",
+            NetworkMessageType::ReliableOrdered,
+            Some(COMPRESSION_THRESHOLD),
+            ClientMessages::ConsoleInput {
+                command: format!("/say {}", "a".repeat(256)),
+            },
+        ),
+    ]
+}
+
+pub fn server_messages() -> Vec<TestVector<ServerMessages>> {
+    vec![
+        message_vector(
+            "allow_connection",
+            NetworkMessageType::ReliableOrdered,
+            None,
+            ServerMessages::AllowConnection,
+        ),
+        message_vector(
+            "disconnect",
+            NetworkMessageType::ReliableOrdered,
+            None,
+            ServerMessages::Disconnect {
+                message: Some("Kicked".to_string()),
+            },
+        ),
+        message_vector(
+            "connection_rejected",
+            NetworkMessageType::ReliableOrdered,
+            None,
+            ServerMessages::ConnectionRejected {
+                reason: RejectionReason::UnsupportedVersion {
+                    required: "1.1.0".to_string(),
+                },
+            },
+        ),
+        message_vector(
+            "shutdown_notice",
+            NetworkMessageType::ReliableOrdered,
+            None,
+            ServerMessages::ShutdownNotice {
+                shutdown_in: Duration::from_millis(30_500),
+                message: None,
+            },
+        ),
+        message_vector(
+            "server_status",
+            NetworkMessageType::Unreliable,
+            None,
+            ServerMessages::ServerStatus { tps: 20.0 },
+        ),
+    ]
+}
+
+/// Every vector as text, e.g. to commit into another implementation's test data
+pub fn dump() -> String {
+    let mut dump = String::new();
+    for vector in handshake() {
+        write!(dump, "handshake/{}", vector).unwrap();
+    }
+    for vector in client_messages() {
+        write!(dump, "client/{}", vector).unwrap();
+    }
+    for vector in server_messages() {
+        write!(dump, "server/{}", vector).unwrap();
+    }
+    dump
+}
@@ -0,0 +1,101 @@
+//! Connection count thresholds, e.g. for autoscaling.
+//!
+//! Observers registered on `IServerNetwork::get_thresholds` are called from
+//! `step()` when the connection count crosses their threshold, in either
+//! direction, and `ServerEvents::ThresholdCrossed` is emitted alongside.
+//! Thresholds can be given as a fraction of `ServerConfig::max_connections`.
+
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ThresholdCrossing {
+    pub threshold: usize,
+    pub connections: usize,
+    /// True if the count reached the threshold, false if it fell below
+    pub rising: bool,
+}
+
+/// Called from `step()`, so it must not block for long
+pub trait ThresholdObserver: Send + Sync {
+    fn on_crossed(&self, crossing: &ThresholdCrossing);
+}
+
+impl<F> ThresholdObserver for F
+where
+    F: Fn(&ThresholdCrossing) + Send + Sync,
+{
+    fn on_crossed(&self, crossing: &ThresholdCrossing) {
+        self(crossing)
+    }
+}
+
+#[derive(Default)]
+struct ThresholdsState {
+    observers: Vec<(usize, Arc<dyn ThresholdObserver>)>,
+    last_count: usize,
+}
+
+/// Thresholds of a server, checked at the end of every `step()`
+pub struct ConnectionThresholds {
+    max_connections: Option<usize>,
+    state: Mutex<ThresholdsState>,
+}
+
+impl ConnectionThresholds {
+    pub(crate) fn new(max_connections: Option<usize>) -> Self {
+        Self {
+            max_connections,
+            state: Default::default(),
+        }
+    }
+
+    /// Call the observer when the count reaches or falls below `connections`
+    pub fn register(&self, connections: usize, observer: impl ThresholdObserver + 'static) {
+        self.state.lock().observers.push((connections, Arc::new(observer)));
+    }
+
+    /// Threshold at `ratio` of `ServerConfig::max_connections`, e.g. 0.8;
+    /// ignored with a warning if it is unset
+    pub fn register_ratio(&self, ratio: f32, observer: impl ThresholdObserver + 'static) {
+        let Some(max_connections) = self.max_connections else {
+            log::warn!(target: "network", "Threshold at {} of max connections ignored: max_connections is unset", ratio);
+            return;
+        };
+        let connections = (max_connections as f32 * ratio).ceil() as usize;
+        self.register(connections, observer);
+    }
+
+    /// Call the observers of the thresholds crossed since the last update
+    pub(crate) fn update(&self, connections: usize) -> Vec<ThresholdCrossing> {
+        let (last_count, observers) = {
+            let mut state = self.state.lock();
+            let last_count = std::mem::replace(&mut state.last_count, connections);
+            if last_count == connections {
+                return Vec::new();
+            }
+            let (low, high) = (last_count.min(connections), last_count.max(connections));
+            let observers: Vec<_> = state
+                .observers
+                .iter()
+                .filter(|(threshold, _)| *threshold > low && *threshold <= high)
+                .cloned()
+                .collect();
+            (last_count, observers)
+        };
+        let crossing = |threshold| ThresholdCrossing {
+            threshold,
+            connections,
+            rising: connections > last_count,
+        };
+
+        // Observers may register more thresholds, so they run without the lock
+        for (threshold, observer) in observers.iter() {
+            observer.on_crossed(&crossing(*threshold));
+        }
+        let mut crossed: Vec<_> = observers.into_iter().map(|(threshold, _)| threshold).collect();
+        crossed.sort_unstable();
+        crossed.dedup();
+        crossed.into_iter().map(crossing).collect()
+    }
+}
@@ -0,0 +1,60 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+/// Ticks a fixed-rate loop may run back to back to catch up
+pub const MAX_CATCH_UP_TICKS: u32 = 5;
+
+/// Deadline scheduler behind the `run_fixed` loops.
+///
+/// Deadlines are absolute, so the loop does not drift. Missed ticks are
+/// caught up (at most `MAX_CATCH_UP_TICKS` in a row); after a longer stall
+/// the schedule is reset instead.
+///
+/// With wall clock alignment every tick starts on a multiple of the
+/// interval since the Unix epoch, so servers with synchronized clocks
+/// tick in phase. Missed ticks are skipped rather than caught up.
+pub(crate) struct TickSchedule {
+    interval: Duration,
+    next_tick: Instant,
+    wall_clock: bool,
+}
+
+impl TickSchedule {
+    pub fn new(rate: u32, wall_clock: bool) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1.0 / rate as f64),
+            next_tick: Instant::now(),
+            wall_clock,
+        }
+    }
+
+    pub fn get_interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Sleep until the next tick
+    pub async fn wait(&mut self) {
+        if self.wall_clock {
+            self.next_tick = next_wall_clock_tick(self.interval);
+        } else {
+            self.next_tick += self.interval;
+            let now = Instant::now();
+            if now > self.next_tick + self.interval * MAX_CATCH_UP_TICKS {
+                log::warn!(target: "network", "Tick loop is {:.2?} behind; skipping ticks", now - self.next_tick);
+                self.next_tick = now;
+            }
+        }
+        tokio::time::sleep_until(self.next_tick).await;
+    }
+}
+
+fn next_wall_clock_tick(interval: Duration) -> Instant {
+    let now = Instant::now();
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let interval_nanos = interval.as_nanos().max(1);
+    let next = (since_epoch / interval_nanos + 1) * interval_nanos;
+    now + Duration::from_nanos((next - since_epoch) as u64)
+}
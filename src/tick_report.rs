@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Network load of one server tick, emitted as `ServerEvents::TickReport`
+/// at the end of every `step()` when `ServerConfig::tick_reports` is set.
+///
+/// Counters cover everything since the previous report, so traffic handled
+/// by the transport tasks between two steps lands in the next report.
+#[derive(Clone, Debug, Default)]
+pub struct TickReport {
+    /// Interval since the previous step; None on the first step
+    pub tick_time: Option<Duration>,
+    /// Time spent inside `step()`
+    pub step_time: Duration,
+    pub connections: usize,
+    /// Client messages decoded
+    pub messages_in: u64,
+    /// Server messages queued for sending
+    pub messages_out: u64,
+    /// Encoded size of the messages above
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Messages dropped: undecodable, over the size cap or past their deadline
+    pub dropped: u64,
+}
+
+/// Counters shared with the transport, collected into a `TickReport`
+#[derive(Debug, Default)]
+pub(crate) struct TickCounters {
+    messages_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl TickCounters {
+    pub fn add_in(&self, bytes: usize) {
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_out(&self, bytes: usize) {
+        self.messages_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Report of the counted traffic; the counters start over
+    pub fn take_report(&self, tick_time: Option<Duration>, step_time: Duration, connections: usize) -> TickReport {
+        TickReport {
+            tick_time,
+            step_time,
+            connections,
+            messages_in: self.messages_in.swap(0, Ordering::Relaxed),
+            messages_out: self.messages_out.swap(0, Ordering::Relaxed),
+            bytes_in: self.bytes_in.swap(0, Ordering::Relaxed),
+            bytes_out: self.bytes_out.swap(0, Ordering::Relaxed),
+            dropped: self.dropped.swap(0, Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Clock synchronization between the client and the server.
+//!
+//! Every `TIME_SYNC_INTERVAL` the client `step()` sends
+//! `ClientMessages::TimeSync`; the server answers with the times it received
+//! and answered it on its own clock, `IServerNetwork::server_time`, which is
+//! also the clock to stamp `ServerMessages::EntityMove` and snapshots with.
+//! From the four timestamps the client estimates the round-trip time and the
+//! clock offset the way NTP does, smoothed over the recent samples, and
+//! exposes them as `IClientNetwork::server_time` and `IClientNetwork::rtt`.
+
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::interpolation::clock_sync::ClockSync;
+use crate::messages::{ClientMessages, ServerMessages};
+
+/// Interval between two time sync requests of a client
+pub const TIME_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Offset samples the median is taken over
+const OFFSET_SAMPLES: usize = 16;
+
+/// Weight of a new round-trip sample in the smoothed round-trip time
+const RTT_SMOOTHING: f64 = 0.125;
+
+/// Requests of one connection, answered by the server `step()`
+#[derive(Debug, Default)]
+pub(crate) struct TimeSyncRequests {
+    pending: Mutex<Vec<(f64, Instant)>>,
+}
+
+impl TimeSyncRequests {
+    pub fn push(&self, client_time: f64) {
+        self.pending.lock().push((client_time, Instant::now()));
+    }
+
+    /// `ServerMessages::TimeSync` answers of the pending requests
+    pub fn take_replies(&self, server_time: f64) -> Vec<ServerMessages> {
+        let pending = std::mem::take(&mut *self.pending.lock());
+        pending
+            .into_iter()
+            .map(|(client_time, received)| ServerMessages::TimeSync {
+                client_time,
+                received: server_time - received.elapsed().as_secs_f64(),
+                sent: server_time,
+            })
+            .collect()
+    }
+}
+
+struct TimeSyncState {
+    clock: ClockSync,
+    rtt: Option<f64>,
+    last_request: Option<Instant>,
+}
+
+/// Client side estimate of the server clock
+pub(crate) struct TimeSync {
+    started: Instant,
+    state: Mutex<TimeSyncState>,
+}
+
+impl TimeSync {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            state: Mutex::new(TimeSyncState {
+                clock: ClockSync::new(OFFSET_SAMPLES),
+                rtt: None,
+                last_request: None,
+            }),
+        }
+    }
+
+    fn local_time(&self) -> f64 {
+        self.started.elapsed().as_secs_f64()
+    }
+
+    /// Request to send once `TIME_SYNC_INTERVAL` passed since the last one
+    pub fn take_request(&self) -> Option<ClientMessages> {
+        let mut state = self.state.lock();
+        if state
+            .last_request
+            .is_some_and(|last| last.elapsed() < TIME_SYNC_INTERVAL)
+        {
+            return None;
+        }
+        state.last_request = Some(Instant::now());
+        Some(ClientMessages::TimeSync {
+            client_time: self.local_time(),
+        })
+    }
+
+    /// Consume time sync answers; any other message is returned back
+    pub fn route(&self, message: ServerMessages) -> Option<ServerMessages> {
+        let ServerMessages::TimeSync {
+            client_time,
+            received,
+            sent,
+        } = message
+        else {
+            return Some(message);
+        };
+        let now = self.local_time();
+        // Time on the wire, without the time the server held the request
+        let rtt = ((now - client_time) - (sent - received)).max(0.0);
+
+        let mut state = self.state.lock();
+        state.rtt = Some(match state.rtt {
+            Some(smoothed) => smoothed * (1.0 - RTT_SMOOTHING) + rtt * RTT_SMOOTHING,
+            None => rtt,
+        });
+        // The answer is assumed to take half of the round trip
+        state.clock.record_sample(now, sent + rtt / 2.0);
+        None
+    }
+
+    /// Current server time; None until the first answer
+    pub fn server_time(&self) -> Option<f64> {
+        let state = self.state.lock();
+        state
+            .clock
+            .is_initialized()
+            .then(|| self.local_time() - state.clock.get_offset())
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.state.lock().rtt.map(Duration::from_secs_f64)
+    }
+}
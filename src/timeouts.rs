@@ -0,0 +1,85 @@
+//! Connection timeouts and keep-alive.
+//!
+//! Both sides ping every keep-alive interval, so a connection that
+//! receives nothing for the connection timeout is considered lost and
+//! closed. The timeout should span several keep-alive intervals.
+//!
+//! A side that receives nothing for `QUIET_PINGS` keep-alive intervals
+//! probes its peer with a ping every `PROBE_INTERVAL`, so that a half-open
+//! connection is noticed early: the host of a peer whose socket is gone
+//! resets the connection, and the loss is reported as `PeerLoss::Closed`
+//! (its process exited or crashed). A peer that answers nothing up to the
+//! connection timeout is `PeerLoss::Unreachable` (its host is down or the
+//! network is partitioned) and may come back. On the renet backend netcode
+//! keeps the connection alive and doesn't tell these apart; neither is reported.
+
+use parking_lot::Mutex;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Time without a frame from the peer before the connection is closed
+pub const DEFAULT_CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval of the pings sent to the peer
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(1);
+
+/// Time between `IServerConnection::disconnect` and the close of the
+/// connection, for the last messages to go out; see `ServerConfig::disconnect_grace`
+pub const DISCONNECT_DELAY: Duration = Duration::from_millis(200);
+
+/// Keep-alive intervals without a frame from the peer before it is probed
+pub const QUIET_PINGS: u32 = 2;
+
+/// Interval of the pings probing a quiet peer
+pub const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How a peer was lost without disconnecting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerLoss {
+    /// The peer end of the socket was closed or reset
+    Closed,
+    /// Nothing came back from the peer, probes included
+    Unreachable,
+}
+
+impl PeerLoss {
+    pub fn from_io(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::BrokenPipe => Self::Closed,
+            _ => Self::Unreachable,
+        }
+    }
+}
+
+/// Time of the last frame received from the peer
+#[derive(Debug)]
+pub(crate) struct Liveness {
+    last_received: Mutex<Instant>,
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Self {
+            last_received: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl Liveness {
+    pub fn touch(&self) {
+        *self.last_received.lock() = Instant::now();
+    }
+
+    pub fn get_silent(&self) -> Duration {
+        self.last_received.lock().elapsed()
+    }
+
+    /// Nothing received for `QUIET_PINGS` keep-alive intervals: the peer is to be probed
+    pub fn is_quiet(&self, keep_alive: Duration) -> bool {
+        self.get_silent() >= keep_alive * QUIET_PINGS
+    }
+}
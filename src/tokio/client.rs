@@ -1,4 +1,6 @@
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -6,183 +8,721 @@ use common::utils::debug::info::{DebugInfo, DebugValue};
 use flume::Drain;
 use parking_lot::{Mutex, RwLock, RwLockReadGuard};
 use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::TcpStream;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::time::MissedTickBehavior;
 
-use crate::client::{resolve_connect_domain, IClientNetwork};
-use crate::messages::{ClientMessages, NetworkMessageType, ServerMessages};
+use crate::client::{resolve_connect_domain, ClientConfig, IClientNetwork};
+use crate::compression::COMPRESSED_FLAG;
+use crate::conditions::{condition_channel, Conditioner, NetworkConditions, SharedConditions};
+use crate::decode_budget::DecodeQueue;
+use crate::diagnostics::ClientDiagnostics;
+use crate::echo::EchoProbe;
+use crate::errors::NetworkError;
+use crate::handshake::SessionParameters;
+use crate::journal::MessageJournal;
+use crate::keyed_state::StateReplica;
+use crate::messages::{ClientMessages, NetworkMessageType, ServerMessages, ServerMessagesDiscriminants};
+use crate::network_info::{BandwidthUsage, NetworkInfo, TrafficMeter, UsageMeter};
+use crate::protocol::{ClientProtocol, ProtocolConfig};
+use crate::proxy::socks5_connect;
+use crate::resume::{ResumeRequest, ResumeState, SessionToken, RESUME_RETRY_INTERVAL};
+use crate::retries::BoundedReceiver;
+use crate::rpc::RpcEndpoint;
+use crate::scoreboard::Scoreboard;
+use crate::snapshots::{IncomingSnapshots, Snapshot};
+use crate::streams::{IncomingStreams, StreamReader};
+use crate::system::{decode_system, SystemMessage};
+use crate::time_sync::TimeSync;
+use crate::timeouts::{Liveness, PeerLoss, PROBE_INTERVAL};
+use crate::tuning::TuningReceiver;
 
-use super::{read_frame, write_frame, FRAME_MESSAGE, FRAME_PING, FRAME_PONG};
+use super::datagram::ClientDatagrams;
+use super::encryption::encrypt_halves;
+use super::handshake::{client_handshake, HANDSHAKE_TIMEOUT};
+use super::transport::Transport;
+use super::{
+    ack_frame, parse_ack, read_frame, system_frame, write_frame, BoxedReader, BoxedWriter, FRAME_ACK, FRAME_MESSAGE,
+    FRAME_PING, FRAME_PONG, FRAME_SYSTEM, LOCAL_SOCKET_PREFIX, WEBSOCKET_PREFIX,
+};
 
 pub struct TokioClient {
+    config: ClientConfig,
+    /// Encodes the messages sent, see `crate::protocol`
+    protocol: ClientProtocol,
+    /// `ServerMessages` variants known to the server
+    server_schema: u32,
+    session_seed: Option<u64>,
+    journal: Option<MessageJournal>,
     connected: Arc<AtomicBool>,
     debug_info: Arc<RwLock<DebugInfo>>,
-    rtt_nanos: Arc<AtomicU64>,
+    traffic: Arc<TrafficMeter>,
+    usage: Arc<Mutex<UsageMeter>>,
+    streams: Arc<IncomingStreams>,
+    snapshots: Arc<IncomingSnapshots>,
+    time_sync: Arc<TimeSync>,
+    echo: Arc<EchoProbe>,
+    state: Arc<StateReplica>,
+    scoreboard: Arc<Scoreboard>,
+    diagnostics: Arc<ClientDiagnostics>,
+    rpc: Arc<RpcEndpoint>,
+    bounded: Arc<BoundedReceiver>,
+    tuning: Arc<TuningReceiver>,
+    /// Set with `ClientConfig::decode_budget`, with the protocol decoding its messages
+    decode_queue: Option<(Arc<DecodeQueue>, Mutex<ClientProtocol>)>,
+    datagrams: Option<Arc<ClientDatagrams>>,
+    /// Set with `ClientConfig::network_conditions`
+    network_conditions: Option<SharedConditions>,
 
     incoming_messages: (flume::Sender<ServerMessages>, flume::Receiver<ServerMessages>),
-    incoming_errors: (flume::Sender<String>, flume::Receiver<String>),
+    incoming_errors: (flume::Sender<NetworkError>, flume::Receiver<NetworkError>),
     outgoing_messages: (flume::Sender<Vec<u8>>, flume::Receiver<Vec<u8>>),
+    incoming_system: (flume::Sender<SystemMessage>, flume::Receiver<SystemMessage>),
 }
 
-/// Background task: reads length-prefixed frames from the socket,
-/// dispatches messages to the incoming channel, handles pong for RTT.
-async fn client_reader_task(
-    reader: OwnedReadHalf,
+/// State shared with the reader task
+struct ClientReader {
+    /// Decodes the message frames, see `crate::protocol`
+    protocol: ClientProtocol,
+    streams: Arc<IncomingStreams>,
+    snapshots: Arc<IncomingSnapshots>,
+    time_sync: Arc<TimeSync>,
+    echo: Arc<EchoProbe>,
+    state: Arc<StateReplica>,
+    scoreboard: Arc<Scoreboard>,
+    rpc: Arc<RpcEndpoint>,
+    bounded: Arc<BoundedReceiver>,
     tx: flume::Sender<ServerMessages>,
-    error_tx: flume::Sender<String>,
-    connected: Arc<AtomicBool>,
+    error_tx: flume::Sender<NetworkError>,
     last_ping_sent: Arc<Mutex<Option<Instant>>>,
-    rtt_nanos: Arc<AtomicU64>,
-) {
+    traffic: Arc<TrafficMeter>,
+    usage: Arc<Mutex<UsageMeter>>,
+    outgoing_tx: flume::Sender<Vec<u8>>,
+    decode_queue: Option<Arc<DecodeQueue>>,
+    timeout: Duration,
+    /// Set when the server offers to resume the session
+    resume: Option<Arc<ResumeState>>,
+    diagnostics: Arc<ClientDiagnostics>,
+    system_tx: flume::Sender<SystemMessage>,
+    liveness: Arc<Liveness>,
+    tuning: Arc<TuningReceiver>,
+}
+
+/// Why the reader or writer task of a socket stopped
+enum SocketEnd {
+    /// The connection was closed
+    Closed,
+    /// The socket failed
+    Failed(io::Error),
+}
+
+/// Background task: reads length-prefixed frames from the socket,
+/// dispatches messages to the incoming channel, handles ping and pong for RTT.
+async fn client_reader_task(reader: BoxedReader, ctx: &mut ClientReader) -> SocketEnd {
     let mut buf_reader = BufReader::new(reader);
     loop {
-        match read_frame(&mut buf_reader).await {
+        let frame = match tokio::time::timeout(ctx.timeout, read_frame(&mut buf_reader)).await {
+            Ok(frame) => frame,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "Connection timed out")),
+        };
+        if frame.is_ok() {
+            ctx.liveness.touch();
+        }
+        match frame {
             Ok(data) if data.is_empty() => continue,
-            Ok(data) => match data[0] {
-                FRAME_MESSAGE => match bincode::deserialize::<ServerMessages>(&data[1..]) {
-                    Ok(msg) => {
-                        if tx.send(msg).is_err() {
-                            break;
+            Ok(data) => {
+                ctx.traffic.add_received(data.len() + 4);
+                if data[0] == FRAME_MESSAGE && data.len() >= 2 {
+                    ctx.usage.lock().add_received(data[1] & !COMPRESSED_FLAG, data.len() - 2);
+                }
+                if let (FRAME_MESSAGE, Some(resume)) = (data[0], ctx.resume.as_ref()) {
+                    resume.count_received();
+                }
+                match data[0] {
+                    FRAME_MESSAGE if data.len() < 2 => {
+                        ctx.error_tx
+                            .send(NetworkError::MalformedFrame { client_id: None })
+                            .ok();
+                    }
+                    FRAME_MESSAGE => {
+                        let deferred = DecodeQueue::is_deferred(data[1] & !COMPRESSED_FLAG);
+                        if let Some(queue) = ctx.decode_queue.as_ref().filter(|_| deferred) {
+                            queue.push(data);
+                            continue;
+                        }
+                        match ctx.protocol.decode_message(&data) {
+                            Ok(None) => {}
+                            Ok(Some(msg)) => {
+                                // Routed by the inner message, see `crate::trace_context`
+                                let (msg, context) = msg.untraced();
+                                let msg = ctx.bounded.route(msg).and_then(|msg| ctx.streams.route(msg));
+                                let msg = msg.and_then(|msg| ctx.snapshots.route(msg));
+                                let msg = msg.and_then(|msg| ctx.time_sync.route(msg));
+                                let msg = msg.and_then(|msg| ctx.echo.route(msg));
+                                let msg = msg.and_then(|msg| ctx.state.route(msg, &ctx.scoreboard));
+                                let Some(msg) = msg.and_then(|msg| ctx.rpc.route_server_message(msg)) else {
+                                    continue;
+                                };
+                                ctx.scoreboard.apply(&msg);
+                                if ctx.tx.send(msg.retraced(context)).is_err() {
+                                    return SocketEnd::Closed;
+                                }
+                            }
+                            Err(e) => {
+                                ctx.error_tx
+                                    .send(NetworkError::Decode {
+                                        client_id: None,
+                                        reason: e,
+                                    })
+                                    .ok();
+                            }
                         }
                     }
-                    Err(e) => {
-                        error_tx
-                            .send(format!("Message decode error: {}", e))
-                            .ok();
+                    FRAME_PING => {
+                        ctx.outgoing_tx.send(vec![FRAME_PONG]).ok();
+                        if let Some(resume) = ctx.resume.as_ref() {
+                            ctx.outgoing_tx.send(ack_frame(resume.get_received())).ok();
+                        }
                     }
-                },
-                FRAME_PONG => {
-                    if let Some(sent_at) = last_ping_sent.lock().take() {
-                        rtt_nanos.store(sent_at.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                    FRAME_PONG => {
+                        if let Some(sent_at) = ctx.last_ping_sent.lock().take() {
+                            ctx.traffic.set_rtt(sent_at.elapsed());
+                        }
+                    }
+                    FRAME_ACK => {
+                        if let (Some(resume), Some(received)) = (ctx.resume.as_ref(), parse_ack(&data)) {
+                            resume.ack(received);
+                        }
                     }
+                    FRAME_SYSTEM => match decode_system(&data[1..]) {
+                        Some(SystemMessage::ChannelTuning { revision, tuning }) => ctx.tuning.push(revision, tuning),
+                        Some(message) => {
+                            ctx.system_tx.send(message).ok();
+                        }
+                        None => {}
+                    },
+                    _ => {}
                 }
-                _ => {}
-            },
-            Err(_) => {
-                connected.store(false, Ordering::SeqCst);
-                break;
             }
+            Err(e) => return SocketEnd::Failed(e),
         }
     }
 }
 
+/// State shared with the writer task
+struct ClientWriter {
+    connected: Arc<AtomicBool>,
+    last_ping_sent: Arc<Mutex<Option<Instant>>>,
+    traffic: Arc<TrafficMeter>,
+    keep_alive: Duration,
+    /// Set when the server offers to resume the session
+    resume: Option<Arc<ResumeState>>,
+    /// Probes a quiet server, see `crate::timeouts`
+    liveness: Arc<Liveness>,
+}
+
 /// Background task: drains outgoing channel, writes length-prefixed frames
 /// to the socket with batch-flushing. Sends periodic ping frames.
+/// An empty frame closes the connection once the frames before it are written.
+/// The `initial` frames are written first, as they are, e.g. the resends of a resumed session.
 async fn client_writer_task(
-    writer: OwnedWriteHalf,
-    rx: flume::Receiver<Vec<u8>>,
-    connected: Arc<AtomicBool>,
-    last_ping_sent: Arc<Mutex<Option<Instant>>>,
-) {
+    writer: BoxedWriter,
+    rx: &flume::Receiver<Vec<u8>>,
+    ctx: &ClientWriter,
+    initial: Vec<Vec<u8>>,
+) -> SocketEnd {
     let mut buf_writer = BufWriter::new(writer);
-    let ping_start = tokio::time::Instant::now() + Duration::from_secs(1);
-    let mut ping_interval = tokio::time::interval_at(ping_start, Duration::from_secs(1));
+    let ping_start = tokio::time::Instant::now() + ctx.keep_alive;
+    let mut ping_interval = tokio::time::interval_at(ping_start, ctx.keep_alive);
+    let mut probe_interval = tokio::time::interval(PROBE_INTERVAL);
+    probe_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    if !initial.is_empty() {
+        for data in initial.iter() {
+            if let Err(e) = write_frame(&mut buf_writer, data).await {
+                return SocketEnd::Failed(e);
+            }
+            ctx.traffic.add_sent(data.len() + 4);
+        }
+        if let Err(e) = buf_writer.flush().await {
+            return SocketEnd::Failed(e);
+        }
+    }
 
     loop {
-        if !connected.load(Ordering::SeqCst) {
-            break;
+        if !ctx.connected.load(Ordering::SeqCst) {
+            return SocketEnd::Closed;
         }
-        tokio::select! {
+        let probing = ctx.liveness.is_quiet(ctx.keep_alive);
+        let ping = tokio::select! {
             result = rx.recv_async() => {
                 match result {
                     Ok(data) => {
-                        if write_frame(&mut buf_writer, &data).await.is_err() {
-                            connected.store(false, Ordering::SeqCst);
-                            break;
-                        }
                         // Batch any additional queued messages before flushing
-                        while let Ok(data) = rx.try_recv() {
-                            if write_frame(&mut buf_writer, &data).await.is_err() {
-                                connected.store(false, Ordering::SeqCst);
-                                return;
+                        let mut closing = false;
+                        for data in std::iter::once(data).chain(rx.try_iter()) {
+                            if data.is_empty() {
+                                closing = true;
+                                break;
+                            }
+                            if let (FRAME_MESSAGE, Some(resume)) = (data[0], ctx.resume.as_ref()) {
+                                resume.push_sent(&data);
+                            }
+                            if let Err(e) = write_frame(&mut buf_writer, &data).await {
+                                return SocketEnd::Failed(e);
                             }
+                            ctx.traffic.add_sent(data.len() + 4);
                         }
-                        if buf_writer.flush().await.is_err() {
-                            connected.store(false, Ordering::SeqCst);
-                            break;
+                        if let Err(e) = buf_writer.flush().await {
+                            return SocketEnd::Failed(e);
                         }
+                        if closing {
+                            buf_writer.shutdown().await.ok();
+                            ctx.connected.store(false, Ordering::SeqCst);
+                            return SocketEnd::Closed;
+                        }
+                        false
                     }
-                    Err(_) => break,
+                    Err(_) => return SocketEnd::Closed,
                 }
             }
-            _ = ping_interval.tick() => {
-                *last_ping_sent.lock() = Some(Instant::now());
-                if write_frame(&mut buf_writer, &[FRAME_PING]).await.is_err() {
-                    connected.store(false, Ordering::SeqCst);
-                    break;
-                }
-                if buf_writer.flush().await.is_err() {
-                    connected.store(false, Ordering::SeqCst);
-                    break;
-                }
+            _ = ping_interval.tick() => true,
+            _ = probe_interval.tick(), if probing => true,
+        };
+        if ping {
+            *ctx.last_ping_sent.lock() = Some(Instant::now());
+            if let Err(e) = write_frame(&mut buf_writer, &[FRAME_PING]).await {
+                return SocketEnd::Failed(e);
+            }
+            ctx.traffic.add_sent(5);
+            if let Err(e) = buf_writer.flush().await {
+                return SocketEnd::Failed(e);
             }
         }
     }
 }
 
-impl IClientNetwork for TokioClient {
-    async fn new(ip_port: String) -> Result<Self, String> {
-        let addr = resolve_connect_domain(&ip_port, 25565).await?;
+/// Resume side of the client task, see `crate::resume`
+struct ClientResume {
+    state: Arc<ResumeState>,
+    token: SessionToken,
+    grace: Duration,
+    ip_port: String,
+    config: ClientConfig,
+}
+
+impl ClientResume {
+    /// Reconnect until the server resumes the session or the grace period ends;
+    /// returns the socket with the frames to write first
+    async fn reconnect(&self, connected: &AtomicBool) -> Option<(BoxedReader, BoxedWriter, Vec<Vec<u8>>)> {
+        let deadline = Instant::now() + self.grace;
+        while connected.load(Ordering::SeqCst) && Instant::now() < deadline {
+            let request = ResumeRequest {
+                token: self.token,
+                received: self.state.get_received(),
+            };
+            match self.resume(request).await {
+                Ok(resumed) => return resumed,
+                Err(e) => log::debug!(target: "network", "Resuming the session with {} failed: {}", self.ip_port, e),
+            }
+            tokio::time::sleep(RESUME_RETRY_INTERVAL).await;
+        }
+        None
+    }
+
+    /// Ok(None) if the session can't be resumed anymore
+    async fn resume(&self, request: ResumeRequest) -> Result<Option<(BoxedReader, BoxedWriter, Vec<Vec<u8>>)>, String> {
+        let (mut reader, writer) = match connect_transport(&self.ip_port, &self.config, Some(request)).await {
+            Ok((_session, reader, writer, _peer)) => (reader, writer),
+            Err(e) if e.starts_with("Connection rejected") => {
+                log::warn!(target: "network", "Session with {} can't be resumed: {}", self.ip_port, e);
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        // The server first tells how many message frames it received
+        let frame = match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_frame(&mut reader)).await {
+            Ok(frame) => frame.map_err(|e| e.to_string())?,
+            Err(_) => return Err("Timed out".to_string()),
+        };
+        let Some(received) = parse_ack(&frame).filter(|_| frame[0] == FRAME_ACK) else {
+            return Err("Unexpected frame".to_string());
+        };
+        let Some(resends) = self.state.resend_from(received) else {
+            log::warn!(target: "network", "Session with {} can't be resumed: unacknowledged messages were dropped", self.ip_port);
+            return Ok(None);
+        };
+        log::info!(target: "network", "Resumed the session with {}", self.ip_port);
+        Ok(Some((reader, writer, resends)))
+    }
+}
+
+/// Background task: runs the reader and writer tasks of the connection.
+/// When the server offers to resume the session, a dropped socket is
+/// replaced by reconnecting instead of ending the connection.
+async fn client_task(
+    reader: BoxedReader,
+    writer: BoxedWriter,
+    mut reader_ctx: ClientReader,
+    writer_ctx: ClientWriter,
+    rx: flume::Receiver<Vec<u8>>,
+    resume: Option<ClientResume>,
+) {
+    let mut socket = (reader, writer, Vec::new());
+    loop {
+        let (reader, writer, initial) = socket;
+        let end = tokio::select! {
+            end = client_reader_task(reader, &mut reader_ctx) => end,
+            end = client_writer_task(writer, &rx, &writer_ctx, initial) => end,
+        };
+        let SocketEnd::Failed(error) = end else {
+            reader_ctx.diagnostics.record_disconnect("connection closed");
+            break;
+        };
+        reader_ctx
+            .diagnostics
+            .record_event(&format!("Socket failed: {:?}", error.kind()));
+        let resumed = match resume.as_ref() {
+            Some(resume) => resume.reconnect(&writer_ctx.connected).await,
+            None => None,
+        };
+        let Some(resumed) = resumed else {
+            let cause = match PeerLoss::from_io(&error) {
+                PeerLoss::Closed => "connection lost: server closed the socket",
+                PeerLoss::Unreachable => "connection lost: server unreachable",
+            };
+            reader_ctx.diagnostics.record_disconnect(cause);
+            if writer_ctx.connected.swap(false, Ordering::SeqCst) {
+                let error = NetworkError::ConnectionLost {
+                    client_id: None,
+                    error: Arc::new(error),
+                };
+                reader_ctx.error_tx.send(error).ok();
+            }
+            break;
+        };
+        reader_ctx.diagnostics.record_event("Session resumed");
+        reader_ctx.liveness.touch();
+        socket = resumed;
+    }
+    reader_ctx.rpc.close();
+}
+
+/// Run the handshake over the transport and split it for the reader and writer tasks
+async fn open_transport<T: Transport>(
+    mut stream: T,
+    ip_port: &str,
+    config: &ClientConfig,
+    resume: Option<ResumeRequest>,
+) -> Result<(SessionParameters, BoxedReader, BoxedWriter), String> {
+    let handshake = client_handshake(&mut stream, config, resume);
+    let session = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(result) => result?,
+        Err(_) => return Err(format!("Handshake with {} timed out", ip_port)),
+    };
+    let (reader, writer) = stream.into_halves();
+    let (reader, writer) = match config.connect_token.as_ref() {
+        Some(token) if session.encrypted => {
+            let keys = token.get_keys();
+            encrypt_halves(reader, writer, &keys.client_to_server, &keys.server_to_client, &session)
+        }
+        _ => (reader, writer),
+    };
+    Ok((session, reader, writer))
+}
+
+/// Open the TCP connection, directly or through the configured proxy
+async fn connect_tcp(ip_port: &String, config: &ClientConfig) -> Result<TcpStream, String> {
+    let addr = resolve_connect_domain(ip_port, 25565).await?;
+
+    let socket = TcpSocket::new_v4().map_err(|e| format!("Socket create error: {e}"))?;
+    config.socket.apply(&socket)?;
+    config.bind_local(&socket)?;
 
-        let stream = TcpStream::connect(addr)
+    let stream = match config.proxy.as_ref() {
+        Some(proxy) => {
+            let mut stream = socket
+                .connect(proxy.address)
+                .await
+                .map_err(|e| format!("Connection to proxy {} failed: {}", proxy.address, e))?;
+            socks5_connect(&mut stream, proxy, addr).await?;
+            stream
+        }
+        None => socket
+            .connect(addr)
             .await
-            .map_err(|e| format!("Connection to {} failed: {}", addr, e))?;
+            .map_err(|e| format!("Connection to {} failed: {}", addr, e))?,
+    };
 
-        stream
-            .set_nodelay(true)
-            .map_err(|e| format!("Failed to set TCP_NODELAY: {}", e))?;
+    stream
+        .set_nodelay(true)
+        .map_err(|e| format!("Failed to set TCP_NODELAY: {}", e))?;
+    Ok(stream)
+}
 
-        let (reader, writer) = stream.into_split();
+/// Connect over the transport selected by the address and run the handshake;
+/// returns the peer of a direct TCP connection, the only one with datagrams
+async fn connect_transport(
+    ip_port: &String,
+    config: &ClientConfig,
+    resume: Option<ResumeRequest>,
+) -> Result<(SessionParameters, BoxedReader, BoxedWriter, Option<SocketAddr>), String> {
+    // Datagrams go straight to the server, so only a direct TCP connection has them
+    match ip_port.strip_prefix(LOCAL_SOCKET_PREFIX) {
+        #[cfg(unix)]
+        Some(path) => {
+            let stream = tokio::net::UnixStream::connect(path)
+                .await
+                .map_err(|e| format!("Connection to {} failed: {}", ip_port, e))?;
+            let (session, reader, writer) = open_transport(stream, ip_port, config, resume).await?;
+            Ok((session, reader, writer, None))
+        }
+        #[cfg(not(unix))]
+        Some(_) => Err("Local socket transport is only supported on Unix".to_string()),
+        #[cfg(feature = "websocket")]
+        None if ip_port.starts_with(WEBSOCKET_PREFIX) => {
+            let stream = super::websocket::connect_websocket(ip_port).await?;
+            let (session, reader, writer) = open_transport(stream, ip_port, config, resume).await?;
+            Ok((session, reader, writer, None))
+        }
+        #[cfg(not(feature = "websocket"))]
+        None if ip_port.starts_with(WEBSOCKET_PREFIX) => {
+            Err("WebSocket transport requires the websocket feature".to_string())
+        }
+        None => {
+            let stream = connect_tcp(ip_port, config).await?;
+            let peer = match config.proxy {
+                Some(_) => None,
+                None => stream.peer_addr().ok(),
+            };
+            let (session, reader, writer) = open_transport(stream, ip_port, config, resume).await?;
+            Ok((session, reader, writer, peer))
+        }
+    }
+}
+
+impl TokioClient {
+    /// Connect over a transport opened by the caller, e.g. a WebTransport
+    /// session; `label` names the server in logs. The session isn't resumed
+    /// since the client can't reopen the transport.
+    pub async fn new_over_transport<T: Transport>(
+        stream: T,
+        label: String,
+        config: ClientConfig,
+    ) -> Result<Self, String> {
+        let (mut session, reader, writer) = open_transport(stream, &label, &config, None).await?;
+        session.resume = None;
+        Self::start(label, config, session, reader, writer, None).await
+    }
+
+    /// Spawn the tasks of the connection that completed the handshake
+    async fn start(
+        ip_port: String,
+        config: ClientConfig,
+        session: SessionParameters,
+        reader: BoxedReader,
+        writer: BoxedWriter,
+        datagram_peer: Option<SocketAddr>,
+    ) -> Result<Self, String> {
+        let protocol_config = ProtocolConfig::negotiated(&session, session.schema, config.get_max_message_size());
+        let protocol = ClientProtocol::new(&protocol_config)?;
 
         let connected = Arc::new(AtomicBool::new(true));
-        let rtt_nanos = Arc::new(AtomicU64::new(0));
+        let traffic: Arc<TrafficMeter> = Default::default();
+        let usage: Arc<Mutex<UsageMeter>> = Default::default();
         let last_ping_sent = Arc::new(Mutex::new(None));
+        let network_conditions: Option<SharedConditions> = config.network_conditions.map(|c| Arc::new(RwLock::new(c)));
         let incoming_messages = flume::unbounded();
         let incoming_errors = flume::unbounded();
         let outgoing_messages = flume::unbounded();
+        let incoming_system = flume::unbounded();
 
-        // Spawn background reader task
-        {
-            let tx = incoming_messages.0.clone();
-            let error_tx = incoming_errors.0.clone();
-            let connected = connected.clone();
-            let last_ping_sent = last_ping_sent.clone();
-            let rtt_nanos = rtt_nanos.clone();
-            tokio::spawn(async move {
-                client_reader_task(reader, tx, error_tx, connected, last_ping_sent, rtt_nanos)
-                    .await;
-            });
-        }
+        let streams = Arc::new(IncomingStreams::new());
+        let snapshots = Arc::new(IncomingSnapshots::new());
+        let time_sync = Arc::new(TimeSync::new());
+        let echo: Arc<EchoProbe> = Default::default();
+        let state: Arc<StateReplica> = Default::default();
+        let scoreboard: Arc<Scoreboard> = Default::default();
+        let diagnostics = Arc::new(ClientDiagnostics::new("tokio"));
+        diagnostics.add_setting("protocol_version", session.protocol_version);
+        diagnostics.add_setting("schema", session.schema);
+        diagnostics.add_setting("quantization", format!("{:?}", session.quantization));
+        diagnostics.add_setting("compression_threshold", format!("{:?}", session.compression_threshold));
+        diagnostics.add_setting("encrypted", session.encrypted);
+        diagnostics.add_setting("session_resume", session.resume.is_some());
+        let rpc = Arc::new(config.create_rpc_endpoint());
+        let bounded: Arc<BoundedReceiver> = Default::default();
+        let tuning: Arc<TuningReceiver> = Default::default();
+        let decode_queue = config.create_decode_queue().map(Arc::new);
+        let liveness: Arc<Liveness> = Default::default();
+
+        // Set when the server offers to resume the session, see `crate::resume`
+        let resume_state: Option<Arc<ResumeState>> = session.resume.map(|_| Default::default());
 
-        // Spawn background writer task
+        // Spawn background reader and writer tasks
         {
-            let rx = outgoing_messages.1.clone();
-            let connected = connected.clone();
-            let last_ping_sent = last_ping_sent.clone();
+            let reader_ctx = ClientReader {
+                protocol: ClientProtocol::new(&protocol_config)?,
+                streams: streams.clone(),
+                snapshots: snapshots.clone(),
+                time_sync: time_sync.clone(),
+                echo: echo.clone(),
+                state: state.clone(),
+                scoreboard: scoreboard.clone(),
+                rpc: rpc.clone(),
+                bounded: bounded.clone(),
+                tx: incoming_messages.0.clone(),
+                error_tx: incoming_errors.0.clone(),
+                last_ping_sent: last_ping_sent.clone(),
+                traffic: traffic.clone(),
+                usage: usage.clone(),
+                outgoing_tx: outgoing_messages.0.clone(),
+                decode_queue: decode_queue.clone(),
+                timeout: config.get_connection_timeout(),
+                resume: resume_state.clone(),
+                diagnostics: diagnostics.clone(),
+                system_tx: incoming_system.0.clone(),
+                liveness: liveness.clone(),
+                tuning: tuning.clone(),
+            };
+            let rx = match network_conditions.as_ref() {
+                Some(conditions) => condition_channel(outgoing_messages.1.clone(), Conditioner::new(conditions)),
+                None => outgoing_messages.1.clone(),
+            };
+            let writer_ctx = ClientWriter {
+                connected: connected.clone(),
+                last_ping_sent: last_ping_sent.clone(),
+                traffic: traffic.clone(),
+                keep_alive: config.get_keep_alive(),
+                resume: resume_state.clone(),
+                liveness,
+            };
+            let resume = session.resume.zip(resume_state).map(|(offer, state)| ClientResume {
+                state,
+                token: offer.token,
+                grace: offer.grace,
+                ip_port: ip_port.clone(),
+                config: config.clone(),
+            });
             tokio::spawn(async move {
-                client_writer_task(writer, rx, connected, last_ping_sent).await;
+                client_task(reader, writer, reader_ctx, writer_ctx, rx, resume).await;
             });
         }
 
-        log::info!(target: "network", "Connected to {}", addr);
+        let datagrams = match datagram_peer {
+            Some(peer) => {
+                let rate = config.get_datagram_rate();
+                let conditioner = network_conditions.as_ref().map(Conditioner::new);
+                let connect =
+                    ClientDatagrams::connect(peer, session.datagram_token, rate, connected.clone(), conditioner);
+                match connect.await {
+                    Ok(datagrams) => Some(datagrams),
+                    Err(e) => {
+                        log::warn!(target: "network", "Datagrams are unavailable: {}", e);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+
+        diagnostics.add_setting("datagrams", datagrams.is_some());
+        diagnostics.record_event("Connected");
+
+        log::info!(target: "network", "Connected to {}", ip_port);
+        let decode_queue = match decode_queue {
+            Some(queue) => Some((queue, Mutex::new(ClientProtocol::new(&protocol_config)?))),
+            None => None,
+        };
 
         Ok(Self {
+            journal: config.create_message_journal(),
+            config,
+            protocol,
+            server_schema: session.schema,
+            session_seed: session.seed,
             connected,
             debug_info: Arc::new(RwLock::new(Default::default())),
-            rtt_nanos,
+            traffic,
+            usage,
+            streams,
+            snapshots,
+            time_sync,
+            echo,
+            state,
+            scoreboard,
+            diagnostics,
+            rpc,
+            bounded,
+            tuning,
+            decode_queue,
+            datagrams,
+            network_conditions,
             incoming_messages,
             incoming_errors,
             outgoing_messages,
+            incoming_system,
         })
     }
+}
+
+impl IClientNetwork for TokioClient {
+    async fn new_with_config(ip_port: String, config: ClientConfig) -> Result<Self, String> {
+        let (session, reader, writer, datagram_peer) = connect_transport(&ip_port, &config, None).await?;
+        Self::start(ip_port, config, session, reader, writer, datagram_peer).await
+    }
 
     async fn step(&self, _delta: Duration) -> bool {
         if !self.connected.load(Ordering::SeqCst) {
             return false;
         }
+        self.diagnostics.sample(|| self.get_network_info());
+        if let Some(ack) = self.snapshots.take_ack() {
+            self.send_message(NetworkMessageType::Unreliable, &ack);
+        }
+        if let Some(request) = self.time_sync.take_request() {
+            self.send_message(NetworkMessageType::Unreliable, &request);
+        }
+        if let Some(ack) = self.bounded.take_ack() {
+            self.send_message(NetworkMessageType::Unreliable, &ack);
+        }
+        for ack in self.state.take_acks() {
+            self.send_message(NetworkMessageType::Unreliable, &ack);
+        }
+        // Credit adds up, so only its delivery matters
+        for credit in self.streams.take_credits() {
+            self.send_message(NetworkMessageType::ReliableUnordered, &credit);
+        }
+        if let Some((tunings, applied)) = self.tuning.take() {
+            for tuning in tunings {
+                if let (Some(rate), Some(datagrams)) = (tuning.datagram_rate, self.datagrams.as_ref()) {
+                    datagrams.set_rate(rate);
+                }
+            }
+            if let Some(frame) = system_frame(&applied) {
+                self.outgoing_messages.0.send(frame).ok();
+            }
+        }
+        if let Some((queue, protocol)) = self.decode_queue.as_ref() {
+            let mut protocol = protocol.lock();
+            queue.run(|data| match protocol.decode_message(&data) {
+                Ok(None) => {}
+                Ok(Some(msg)) => {
+                    let (msg, context) = msg.untraced();
+                    if let Some(msg) = self.streams.route(msg).and_then(|msg| self.snapshots.route(msg)) {
+                        self.incoming_messages.0.send(msg.retraced(context)).ok();
+                    }
+                }
+                Err(e) => {
+                    let error = NetworkError::Decode {
+                        client_id: None,
+                        reason: e,
+                    };
+                    self.incoming_errors.0.send(error).ok();
+                }
+            });
+        }
 
-        let rtt_ns = self.rtt_nanos.load(Ordering::Relaxed);
-        let rtt = Duration::from_nanos(rtt_ns);
+        let rtt = self.traffic.get_rtt();
         let rtt_ms = rtt.as_secs_f64() * 1000.0;
         let ping_color = match rtt_ms {
             ms if ms < 20.0 => "&a",
@@ -205,28 +745,195 @@ impl IClientNetwork for TokioClient {
         self.incoming_messages.1.drain()
     }
 
-    fn iter_errors(&self) -> Drain<'_, String> {
+    fn iter_errors(&self) -> Drain<'_, NetworkError> {
         self.incoming_errors.1.drain()
     }
 
+    fn iter_streams(&self) -> Drain<'_, StreamReader> {
+        self.streams.drain()
+    }
+
+    fn iter_snapshots(&self) -> Drain<'_, Snapshot> {
+        self.snapshots.drain()
+    }
+
+    fn iter_system_messages(&self) -> Drain<'_, SystemMessage> {
+        self.incoming_system.1.drain()
+    }
+
     fn is_connected(&self) -> bool {
         self.connected.load(Ordering::SeqCst)
     }
 
     fn disconnect(&self) {
+        self.diagnostics.record_disconnect("by the client");
         self.connected.swap(false, Ordering::SeqCst);
     }
 
-    fn send_message(&self, _message_type: NetworkMessageType, message: &ClientMessages) {
+    fn disconnect_with_reason(&self, reason: Option<String>) {
+        self.diagnostics.record_disconnect("by the client");
+        let message = ClientMessages::Disconnect { message: reason };
+        self.send_message(NetworkMessageType::ReliableOrdered, &message);
+        // The writer task closes the connection once the frames queued before are written
+        self.outgoing_messages.0.send(Vec::new()).ok();
+    }
+
+    fn send_message(&self, message_type: NetworkMessageType, message: &ClientMessages) {
         if !self.connected.load(Ordering::SeqCst) {
             return;
         }
-        let mut frame = vec![FRAME_MESSAGE];
-        frame.extend(bincode::serialize(message).unwrap());
+        if let Err(e) = self.config.check_message(message) {
+            self.incoming_errors.0.send(e).ok();
+            return;
+        }
+        let payload = match self.protocol.encode(message_type, message) {
+            Ok(payload) => payload,
+            Err(e) => {
+                self.incoming_errors.0.send(e).ok();
+                return;
+            }
+        };
+        if let Err(e) = self.config.check_message_size(message.as_ref(), payload.len()) {
+            self.incoming_errors.0.send(e).ok();
+            return;
+        }
+        let frame = self.protocol.message_frame(message_type.channel_id(), payload);
+        let size = frame.len() - 2;
+        {
+            let mut usage = self.usage.lock();
+            if message_type.is_optional() && usage.is_over_cap(self.config.bandwidth_cap) {
+                return;
+            }
+            usage.add_sent(frame[1] & !COMPRESSED_FLAG, size);
+        }
+        if let Some(journal) = self.journal.as_ref() {
+            journal.record(message.as_ref(), message_type, size);
+        }
         self.outgoing_messages.0.send(frame).ok();
     }
 
+    fn send_datagram(&self, data: &[u8]) -> Result<(), String> {
+        match self.datagrams.as_ref() {
+            Some(datagrams) => datagrams.send(data),
+            None => Err("Datagrams are not available on this connection".to_string()),
+        }
+    }
+
+    fn iter_datagrams(&self) -> impl Iterator<Item = Vec<u8>> {
+        self.datagrams.iter().flat_map(|datagrams| datagrams.drain())
+    }
+
     fn get_debug_info(&self) -> RwLockReadGuard<'_, DebugInfo> {
         self.debug_info.read()
     }
+
+    fn get_network_info(&self) -> NetworkInfo {
+        self.traffic.get_network_info(self.outgoing_messages.0.len())
+    }
+
+    fn get_rpc(&self) -> &RpcEndpoint {
+        &self.rpc
+    }
+
+    fn is_trace_supported(&self) -> bool {
+        // `ClientMessages::Traced` was added along `ServerMessages::Traced`
+        self.server_schema > ServerMessagesDiscriminants::Traced as u32
+    }
+
+    fn server_time(&self) -> Option<f64> {
+        self.time_sync.server_time()
+    }
+
+    fn rtt(&self) -> Option<Duration> {
+        self.time_sync.rtt()
+    }
+
+    fn get_bandwidth_usage(&self) -> BandwidthUsage {
+        self.usage.lock().get_usage()
+    }
+
+    fn set_network_conditions(&self, conditions: NetworkConditions) {
+        match self.network_conditions.as_ref() {
+            Some(shared) => *shared.write() = conditions,
+            None => log::warn!(target: "network", "Network conditions can only be changed when configured at start"),
+        }
+    }
+
+    fn get_session_seed(&self) -> Option<u64> {
+        self.session_seed
+    }
+
+    fn get_message_journal(&self) -> Option<MessageJournal> {
+        self.journal.clone()
+    }
+
+    fn get_echo_probe(&self) -> &EchoProbe {
+        &self.echo
+    }
+
+    fn get_state_replica(&self) -> &StateReplica {
+        &self.state
+    }
+
+    fn get_scoreboard(&self) -> &Scoreboard {
+        &self.scoreboard
+    }
+
+    fn get_diagnostics(&self) -> &ClientDiagnostics {
+        &self.diagnostics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::TokioClient;
+    use crate::client::{ClientConfig, IClientNetwork};
+    use crate::server::ServerConfig;
+    use crate::tokio::handshake::server_handshake;
+    use crate::tokio::{random_frame, write_frame};
+
+    static PANICS: AtomicUsize = AtomicUsize::new(0);
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reader_survives_random_frames() {
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            PANICS.fetch_add(1, Ordering::Relaxed);
+            hook(info);
+        }));
+
+        for _ in 0..20 {
+            let (client_end, mut server_end) = tokio::io::duplex(64 * 1024);
+            let server = tokio::spawn(async move {
+                let config = ServerConfig::default();
+                let (audit_log, draining, sessions) = Default::default();
+                server_handshake(&mut server_end, "memory", &config, &audit_log, &draining, &sessions)
+                    .await
+                    .unwrap();
+                for _ in 0..200 {
+                    // The client may drop the connection on a bad frame
+                    if write_frame(&mut server_end, &random_frame()).await.is_err() {
+                        break;
+                    }
+                }
+                server_end
+            });
+            let client = TokioClient::new_over_transport(client_end, "memory".to_string(), ClientConfig::default())
+                .await
+                .unwrap();
+            // Kept open, so the client reads every frame
+            let _server_end = server.await.unwrap();
+            for _ in 0..10 {
+                client.step(Duration::from_millis(10)).await;
+                client.iter_server_messages().for_each(drop);
+                client.iter_system_messages().for_each(drop);
+                client.iter_errors().for_each(drop);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+        assert_eq!(PANICS.load(Ordering::Relaxed), 0);
+    }
 }
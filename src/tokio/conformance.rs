@@ -0,0 +1,307 @@
+//! Transport conformance: the API promises the same delivery whatever the
+//! transport, so the same scenario runs over each and the deliveries the app
+//! observes are compared.
+//!
+//! The client sends numbered messages on every channel, one of each per
+//! tick, and the echo server returns them on the channel they came on. Per
+//! channel, the messages delivered, duplicated and reordered and the round
+//! trip are compared against the in-memory transport, once with compression
+//! off and once on.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use super::client::TokioClient;
+use super::server::{TokioServer, TokioServerConnection};
+use crate::client::{ClientConfig, IClientNetwork};
+use crate::client_id::ClientId;
+use crate::messages::{ClientMessages, NetworkMessageType, ServerMessages};
+use crate::server::{ConnectionMessages, IServerConnection, IServerNetwork, ServerConfig};
+
+/// Channels the scenario sends on
+const CHANNELS: [NetworkMessageType; 4] = [
+    NetworkMessageType::ReliableOrdered,
+    NetworkMessageType::ReliableUnordered,
+    NetworkMessageType::Unreliable,
+    NetworkMessageType::UnreliableSequenced,
+];
+
+const MESSAGES: u32 = 50;
+
+const TICK: Duration = Duration::from_millis(10);
+
+/// Time the echoes of the last messages are waited for
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Messages over this size are compressed when compression is on
+const COMPRESSION_THRESHOLD: u32 = 64;
+
+/// Filler after the sequence number, so compressed runs really compress
+const PADDING: &str = "the quick brown fox jumps over the lazy dog; ";
+const PADDING_REPEAT: usize = 8;
+
+/// Allowed difference of the loss rate on the unreliable channels
+const LOSS_TOLERANCE: f64 = 0.05;
+
+/// Allowed difference of the median round trip
+const RTT_TOLERANCE: Duration = Duration::from_millis(50);
+
+/// Every `ConsoleInput` comes back as `ConsoleOutput` with the same text, on
+/// the channel named in it
+struct EchoServer {
+    server: TokioServer,
+    connections: HashMap<ClientId, TokioServerConnection>,
+}
+
+impl EchoServer {
+    async fn step(&mut self) {
+        self.server.step(TICK).await;
+        for message in self.server.drain_connections() {
+            match message {
+                ConnectionMessages::Connect { connection } => {
+                    self.connections.insert(connection.get_client_id(), connection);
+                }
+                ConnectionMessages::Disconnect { client_id, .. } => {
+                    self.connections.remove(&client_id);
+                }
+            }
+        }
+        for connection in self.connections.values() {
+            for message in connection.drain_client_messages() {
+                let ClientMessages::ConsoleInput { command } = message else {
+                    continue;
+                };
+                if let Some((channel, _)) = parse_command(&command) {
+                    connection.send_message(channel, &ServerMessages::ConsoleOutput { message: command });
+                }
+            }
+        }
+    }
+}
+
+/// `channel:sequence:padding`
+fn parse_command(command: &str) -> Option<(NetworkMessageType, u32)> {
+    let mut parts = command.splitn(3, ':');
+    let channel = NetworkMessageType::from_channel_id(parts.next()?.parse().ok()?)?;
+    Some((channel, parts.next()?.parse().ok()?))
+}
+
+/// What the app observed on one channel
+#[derive(Default)]
+struct ChannelLog {
+    sent: HashMap<u32, Instant>,
+    received: HashSet<u32>,
+    duplicates: u32,
+    /// Echoes received after a later message of the channel
+    reordered: u32,
+    last: Option<u32>,
+    rtts: Vec<Duration>,
+}
+
+impl ChannelLog {
+    fn receive(&mut self, sequence: u32) {
+        if !self.received.insert(sequence) {
+            self.duplicates += 1;
+            return;
+        }
+        if self.last.is_some_and(|last| sequence < last) {
+            self.reordered += 1;
+        }
+        self.last = Some(self.last.map_or(sequence, |last| last.max(sequence)));
+        if let Some(sent_at) = self.sent.get(&sequence) {
+            self.rtts.push(sent_at.elapsed());
+        }
+    }
+
+    fn get_loss(&self) -> f64 {
+        1.0 - self.received.len() as f64 / self.sent.len().max(1) as f64
+    }
+
+    fn get_rtt_median(&mut self) -> Duration {
+        self.rtts.sort();
+        self.rtts.get(self.rtts.len() / 2).copied().unwrap_or_default()
+    }
+
+    fn is_settled(&self, channel: NetworkMessageType) -> bool {
+        !is_reliable(channel) || self.received.len() == self.sent.len()
+    }
+}
+
+fn is_reliable(channel: NetworkMessageType) -> bool {
+    matches!(
+        channel,
+        NetworkMessageType::ReliableOrdered | NetworkMessageType::ReliableUnordered
+    )
+}
+
+fn is_ordered(channel: NetworkMessageType) -> bool {
+    matches!(
+        channel,
+        NetworkMessageType::ReliableOrdered | NetworkMessageType::UnreliableSequenced
+    )
+}
+
+fn receive(client: &TokioClient, logs: &mut [ChannelLog]) {
+    for message in client.iter_server_messages() {
+        let ServerMessages::ConsoleOutput { message } = message else {
+            continue;
+        };
+        let Some((channel, sequence)) = parse_command(&message) else {
+            continue;
+        };
+        if let Some(index) = CHANNELS.iter().position(|c| *c == channel) {
+            logs[index].receive(sequence);
+        }
+    }
+}
+
+/// Send the messages over `client` and log the echoes until the reliable ones are all back
+async fn run_scenario(server: &mut EchoServer, client: TokioClient) -> Vec<ChannelLog> {
+    let mut logs: Vec<ChannelLog> = CHANNELS.iter().map(|_| ChannelLog::default()).collect();
+    let padding = PADDING.repeat(PADDING_REPEAT);
+    for sequence in 0..MESSAGES {
+        for (channel, log) in CHANNELS.iter().zip(logs.iter_mut()) {
+            let command = format!("{}:{}:{}", channel.channel_id(), sequence, padding);
+            log.sent.insert(sequence, Instant::now());
+            client.send_message(*channel, &ClientMessages::ConsoleInput { command });
+        }
+        assert!(client.step(TICK).await, "Connection closed");
+        server.step().await;
+        receive(&client, &mut logs);
+        tokio::time::sleep(TICK).await;
+    }
+
+    let started = Instant::now();
+    while started.elapsed() < SETTLE_TIMEOUT {
+        assert!(client.step(TICK).await, "Connection closed");
+        server.step().await;
+        receive(&client, &mut logs);
+        if CHANNELS.iter().zip(logs.iter()).all(|(c, log)| log.is_settled(*c)) {
+            break;
+        }
+        tokio::time::sleep(TICK).await;
+    }
+    // Late unreliable echoes
+    for _ in 0..10 {
+        server.step().await;
+        tokio::time::sleep(TICK).await;
+    }
+    client.step(TICK).await;
+    receive(&client, &mut logs);
+    if let Some(error) = client.iter_errors().next() {
+        panic!("Client error: {}", error);
+    }
+    client.disconnect();
+    logs
+}
+
+/// Differences in delivery between two transports
+fn diff(a: &mut [ChannelLog], b: &mut [ChannelLog]) -> Vec<String> {
+    let mut differences = Vec::new();
+    for ((channel, left), right) in CHANNELS.iter().zip(a.iter_mut()).zip(b.iter_mut()) {
+        if is_reliable(*channel) && left.received.len() != right.received.len() {
+            differences.push(format!(
+                "{:?}: {} delivered, {}",
+                channel,
+                left.received.len(),
+                right.received.len()
+            ));
+        }
+        if !is_reliable(*channel) && (left.get_loss() - right.get_loss()).abs() > LOSS_TOLERANCE {
+            differences.push(format!(
+                "{:?}: loss {:.2}, {:.2}",
+                channel,
+                left.get_loss(),
+                right.get_loss()
+            ));
+        }
+        if is_ordered(*channel) && left.reordered != right.reordered {
+            differences.push(format!(
+                "{:?}: {} reordered, {}",
+                channel, left.reordered, right.reordered
+            ));
+        }
+        if left.duplicates != right.duplicates {
+            differences.push(format!(
+                "{:?}: {} duplicates, {}",
+                channel, left.duplicates, right.duplicates
+            ));
+        }
+        if left.get_rtt_median().abs_diff(right.get_rtt_median()) > RTT_TOLERANCE {
+            differences.push(format!(
+                "{:?}: median round trip {:?}, {:?}",
+                channel,
+                left.get_rtt_median(),
+                right.get_rtt_median()
+            ));
+        }
+    }
+    differences
+}
+
+fn free_address() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Run the scenario over every transport against one server and compare each with the in-memory one
+async fn check_transports(compressed: bool) {
+    let address = free_address();
+    let mut config = ServerConfig::default();
+    if compressed {
+        config = config.with_compression(COMPRESSION_THRESHOLD);
+    }
+    // Other transports, by the address the client connects to
+    let mut transports = vec![("socket", address.clone())];
+    #[cfg(unix)]
+    {
+        let path = std::env::temp_dir().join(format!(
+            "network-conformance-{}-{}.sock",
+            std::process::id(),
+            compressed
+        ));
+        config = config.with_local_socket(&path);
+        transports.push(("local", format!("unix:{}", path.display())));
+    }
+    #[cfg(feature = "websocket")]
+    {
+        let websocket = free_address();
+        config = config.with_websocket(websocket.parse().unwrap());
+        transports.push(("websocket", format!("ws://{}", websocket)));
+    }
+    let mut server = EchoServer {
+        server: TokioServer::new_with_config(address, config).await,
+        connections: HashMap::new(),
+    };
+
+    let (client_end, server_end) = tokio::io::duplex(64 * 1024);
+    server.server.accept_transport(server_end, "memory".to_string());
+    let client = TokioClient::new_over_transport(client_end, "memory".to_string(), ClientConfig::default())
+        .await
+        .unwrap();
+    let mut memory = run_scenario(&mut server, client).await;
+
+    for (transport, address) in transports {
+        let client = TokioClient::new_with_config(address, ClientConfig::default())
+            .await
+            .unwrap();
+        let mut logs = run_scenario(&mut server, client).await;
+        let differences = diff(&mut memory, &mut logs);
+        assert!(
+            differences.is_empty(),
+            "memory and {} deliver differently: {:?}",
+            transport,
+            differences
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transports_deliver_alike() {
+    check_transports(false).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transports_deliver_alike_compressed() {
+    check_transports(true).await;
+}
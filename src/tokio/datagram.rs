@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use parking_lot::{Mutex, RwLock};
+use tokio::net::UdpSocket;
+
+use crate::conditions::Conditioner;
+use crate::server::ServerEvents;
+use crate::socket_errors::{report_socket_error, SocketRecovery, SOCKET_RETRY_DELAY};
+use crate::datagram::{
+    check_outgoing, strip_header, write_header, DatagramRateLimiter, DATAGRAM_HEADER_SIZE, DATAGRAM_KEEPALIVE,
+    MAX_DATAGRAM_SIZE,
+};
+
+const TOKEN_SIZE: usize = 8;
+
+/// Send a datagram under simulated conditions: each arriving copy after its own delay.
+///
+/// `peer` is None on a connected socket.
+fn send_conditioned(conditioner: &Conditioner, socket: &Arc<UdpSocket>, packet: &[u8], peer: Option<SocketAddr>) {
+    for delay in conditioner.datagram_delays() {
+        let socket = socket.clone();
+        let packet = packet.to_vec();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            match peer {
+                Some(peer) => socket.send_to(&packet, peer).await.ok(),
+                None => socket.send(&packet).await.ok(),
+            };
+        });
+    }
+}
+
+/// Datagram side of one server connection.
+///
+/// Clients prefix their datagrams with the token received in the
+/// handshake; the source address of the last one is where the server
+/// sends its datagrams.
+pub(crate) struct ServerDatagrams {
+    socket: Arc<UdpSocket>,
+    token: u64,
+    peer: Mutex<Option<SocketAddr>>,
+    incoming: (flume::Sender<Vec<u8>>, flume::Receiver<Vec<u8>>),
+    send_limiter: Mutex<DatagramRateLimiter>,
+    recv_limiter: Mutex<DatagramRateLimiter>,
+    /// Set with `ServerConfig::network_conditions`
+    conditioner: Option<Conditioner>,
+}
+
+pub(crate) type DatagramRoutes = Arc<RwLock<HashMap<u64, Arc<ServerDatagrams>>>>;
+
+impl ServerDatagrams {
+    pub fn new(socket: Arc<UdpSocket>, token: u64, rate: u32, conditioner: Option<Conditioner>) -> Self {
+        Self {
+            socket,
+            token,
+            peer: Default::default(),
+            incoming: flume::unbounded(),
+            send_limiter: Mutex::new(DatagramRateLimiter::new(rate)),
+            recv_limiter: Mutex::new(DatagramRateLimiter::new(rate)),
+            conditioner,
+        }
+    }
+
+    pub fn get_token(&self) -> u64 {
+        self.token
+    }
+
+    pub fn set_rate(&self, rate: u32) {
+        self.send_limiter.lock().set_rate(rate);
+        self.recv_limiter.lock().set_rate(rate);
+    }
+
+    pub fn send(&self, data: &[u8]) -> Result<(), String> {
+        let Some(peer) = *self.peer.lock() else {
+            return Err("Client datagram address is not known yet".to_string());
+        };
+        check_outgoing(&mut self.send_limiter.lock(), data)?;
+        let mut packet = Vec::with_capacity(DATAGRAM_HEADER_SIZE + data.len());
+        write_header(&mut packet);
+        packet.extend_from_slice(data);
+        if let Some(conditioner) = self.conditioner.as_ref() {
+            send_conditioned(conditioner, &self.socket, &packet, Some(peer));
+            return Ok(());
+        }
+        self.socket
+            .try_send_to(&packet, peer)
+            .map_err(|e| format!("Datagram send error: {}", e))?;
+        Ok(())
+    }
+
+    pub fn drain(&self) -> flume::Drain<'_, Vec<u8>> {
+        self.incoming.1.drain()
+    }
+}
+
+/// Background task: routes client datagrams to their connection by token.
+///
+/// Datagrams without the header are counted in `foreign` and dropped.
+pub(crate) fn spawn_server_receiver(
+    socket: Arc<UdpSocket>,
+    routes: DatagramRoutes,
+    foreign: Arc<AtomicU64>,
+    events: flume::Sender<ServerEvents>,
+) {
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; DATAGRAM_HEADER_SIZE + TOKEN_SIZE + MAX_DATAGRAM_SIZE];
+        loop {
+            let (size, addr) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    // The connections send through this socket, so it is not rebound
+                    report_socket_error(&events, "datagram", &e, SocketRecovery::Retried);
+                    tokio::time::sleep(SOCKET_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            let Some(packet) = strip_header(&buf[..size]).filter(|p| p.len() >= TOKEN_SIZE) else {
+                foreign.fetch_add(1, Ordering::Relaxed);
+                continue;
+            };
+            let token = u64::from_le_bytes(packet[..TOKEN_SIZE].try_into().unwrap());
+            let Some(route) = routes.read().get(&token).cloned() else {
+                continue;
+            };
+            *route.peer.lock() = Some(addr);
+
+            // Empty payload is a keepalive
+            if packet.len() == TOKEN_SIZE || !route.recv_limiter.lock().try_acquire() {
+                continue;
+            }
+            route.incoming.0.send(packet[TOKEN_SIZE..].to_vec()).ok();
+        }
+    });
+}
+
+/// Datagram side of the client connection.
+pub(crate) struct ClientDatagrams {
+    socket: Arc<UdpSocket>,
+    token: u64,
+    incoming: (flume::Sender<Vec<u8>>, flume::Receiver<Vec<u8>>),
+    send_limiter: Mutex<DatagramRateLimiter>,
+    recv_limiter: Arc<Mutex<DatagramRateLimiter>>,
+    /// Set with `ClientConfig::network_conditions`
+    conditioner: Option<Conditioner>,
+}
+
+impl ClientDatagrams {
+    /// Open the datagram socket towards the server and start the
+    /// receiver and keepalive tasks
+    pub async fn connect(
+        server: SocketAddr,
+        token: u64,
+        rate: u32,
+        connected: Arc<AtomicBool>,
+        conditioner: Option<Conditioner>,
+    ) -> Result<Arc<Self>, String> {
+        let bind: SocketAddr = match server {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+            SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+        };
+        let socket = UdpSocket::bind(bind)
+            .await
+            .map_err(|e| format!("Datagram socket bind error: {}", e))?;
+        socket
+            .connect(server)
+            .await
+            .map_err(|e| format!("Datagram socket connect error: {}", e))?;
+
+        let datagrams = Arc::new(Self {
+            socket: Arc::new(socket),
+            token,
+            incoming: flume::unbounded(),
+            send_limiter: Mutex::new(DatagramRateLimiter::new(rate)),
+            recv_limiter: Arc::new(Mutex::new(DatagramRateLimiter::new(rate))),
+            conditioner,
+        });
+
+        {
+            let socket = datagrams.socket.clone();
+            let tx = datagrams.incoming.0.clone();
+            let recv_limiter = datagrams.recv_limiter.clone();
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; DATAGRAM_HEADER_SIZE + MAX_DATAGRAM_SIZE];
+                // The socket is connected, so only the server reaches it; a
+                // datagram without the header comes from an incompatible build
+                while let Ok(size) = socket.recv(&mut buf).await {
+                    let Some(data) = strip_header(&buf[..size]) else {
+                        continue;
+                    };
+                    if data.is_empty() || !recv_limiter.lock().try_acquire() {
+                        continue;
+                    }
+                    if tx.send(data.to_vec()).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        {
+            let socket = datagrams.socket.clone();
+            let mut keepalive = Vec::with_capacity(DATAGRAM_HEADER_SIZE + TOKEN_SIZE);
+            write_header(&mut keepalive);
+            keepalive.extend_from_slice(&token.to_le_bytes());
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(DATAGRAM_KEEPALIVE);
+                while connected.load(Ordering::SeqCst) {
+                    interval.tick().await;
+                    socket.send(&keepalive).await.ok();
+                }
+            });
+        }
+
+        Ok(datagrams)
+    }
+
+    pub fn set_rate(&self, rate: u32) {
+        self.send_limiter.lock().set_rate(rate);
+        self.recv_limiter.lock().set_rate(rate);
+    }
+
+    pub fn send(&self, data: &[u8]) -> Result<(), String> {
+        check_outgoing(&mut self.send_limiter.lock(), data)?;
+        let mut packet = Vec::with_capacity(DATAGRAM_HEADER_SIZE + TOKEN_SIZE + data.len());
+        write_header(&mut packet);
+        packet.extend_from_slice(&self.token.to_le_bytes());
+        packet.extend_from_slice(data);
+        if let Some(conditioner) = self.conditioner.as_ref() {
+            send_conditioned(conditioner, &self.socket, &packet, None);
+            return Ok(());
+        }
+        self.socket
+            .try_send(&packet)
+            .map_err(|e| format!("Datagram send error: {}", e))?;
+        Ok(())
+    }
+
+    pub fn drain(&self) -> flume::Drain<'_, Vec<u8>> {
+        self.incoming.1.drain()
+    }
+}
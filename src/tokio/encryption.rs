@@ -0,0 +1,140 @@
+//! Frame encryption of connections opened with a connect token, see `crate::security`.
+
+use tokio::io::{AsyncWriteExt, BufReader};
+
+use crate::compression::COMPRESSED_FLAG;
+use crate::handshake::SessionParameters;
+use crate::security::{FrameCipher, KEY_SIZE, TAG_SIZE};
+use crate::system::{decode_system, SystemMessage};
+
+use super::{read_frame, system_frame, write_frame, BoxedReader, BoxedWriter, FRAME_MESSAGE, FRAME_SYSTEM};
+
+/// Buffered bytes between the socket and the connection tasks
+const PUMP_BUFFER: usize = 64 * 1024;
+
+/// Leading byte of the frames of a session with clear channels
+const SEALED: u8 = 0x00;
+const CLEAR: u8 = 0x01;
+
+/// Encrypt the halves of a connection after the handshake.
+///
+/// Frames read from `reader` are opened with `receive_key` and frames
+/// written to the returned writer are sealed with `send_key`, so the
+/// reader and writer tasks keep working with plain frames. A frame that
+/// fails to open closes the connection.
+///
+/// The keys are rotated as negotiated in `session`, the `SystemMessage::Rekey`
+/// frames staying between the ends of the pumps, and its clear channels are
+/// only authenticated.
+pub(crate) fn encrypt_halves(
+    reader: BoxedReader,
+    mut writer: BoxedWriter,
+    send_key: &[u8; KEY_SIZE],
+    receive_key: &[u8; KEY_SIZE],
+    session: &SessionParameters,
+) -> (BoxedReader, BoxedWriter) {
+    let rekey = session.rekey;
+    let (local, remote) = tokio::io::duplex(PUMP_BUFFER);
+    let (mut plain_reader, mut plain_writer) = tokio::io::split(remote);
+
+    let clear_channels = session.clear_channels.clone();
+    let mut receive = FrameCipher::new(receive_key);
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(reader);
+        while let Ok(sealed) = read_frame(&mut reader).await {
+            let frame = match unprotect(&mut receive, &clear_channels, &sealed) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    log::warn!(target: "network", "Closing connection: {}", e);
+                    break;
+                }
+            };
+            if let Some(epoch) = rekey_epoch(&frame) {
+                if epoch != receive.get_epoch() + 1 {
+                    log::warn!(target: "network", "Closing connection: unexpected key epoch {}", epoch);
+                    break;
+                }
+                receive.rekey();
+                continue;
+            }
+            if write_frame(&mut plain_writer, &frame).await.is_err() {
+                break;
+            }
+        }
+        plain_writer.shutdown().await.ok();
+    });
+
+    let clear_channels = session.clear_channels.clone();
+    let mut send = FrameCipher::new(send_key);
+    tokio::spawn(async move {
+        while let Ok(frame) = read_frame(&mut plain_reader).await {
+            if rekey.is_some_and(|policy| send.is_rekey_due(&policy)) {
+                let notice = SystemMessage::Rekey {
+                    epoch: send.get_epoch() + 1,
+                };
+                let Some(frame) = system_frame(&notice) else {
+                    break;
+                };
+                let notice = protect(&mut send, &clear_channels, &frame);
+                if write_frame(&mut writer, &notice).await.is_err() {
+                    break;
+                }
+                send.rekey();
+            }
+            let protected = protect(&mut send, &clear_channels, &frame);
+            if write_frame(&mut writer, &protected).await.is_err() || writer.flush().await.is_err() {
+                break;
+            }
+        }
+        writer.shutdown().await.ok();
+    });
+
+    let (reader, writer) = tokio::io::split(local);
+    (Box::new(reader), Box::new(writer))
+}
+
+/// Message frame of a channel sent in the clear
+fn is_clear(clear_channels: &[u8], frame: &[u8]) -> bool {
+    matches!(frame, [FRAME_MESSAGE, channel, ..] if clear_channels.contains(&(channel & !COMPRESSED_FLAG)))
+}
+
+/// Seal the frame, or sign it on a clear channel; without clear channels
+/// the frames carry no leading byte, as before they existed
+fn protect(cipher: &mut FrameCipher, clear_channels: &[u8], frame: &[u8]) -> Vec<u8> {
+    if clear_channels.is_empty() {
+        return cipher.seal(frame);
+    }
+    match is_clear(clear_channels, frame) {
+        true => [&[CLEAR][..], frame, &cipher.sign(frame)].concat(),
+        false => [&[SEALED][..], &cipher.seal(frame)].concat(),
+    }
+}
+
+fn unprotect(cipher: &mut FrameCipher, clear_channels: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    if clear_channels.is_empty() {
+        return cipher.open(data);
+    }
+    match data.split_first() {
+        Some((&SEALED, sealed)) => cipher.open(sealed),
+        Some((&CLEAR, signed)) if signed.len() >= TAG_SIZE => {
+            let (frame, tag) = signed.split_at(signed.len() - TAG_SIZE);
+            if !is_clear(clear_channels, frame) {
+                return Err("Encrypted channel sent in the clear".to_string());
+            }
+            cipher.verify(frame, tag)?;
+            Ok(frame.to_vec())
+        }
+        _ => Err("Malformed frame".to_string()),
+    }
+}
+
+/// Epoch of a `SystemMessage::Rekey` frame
+fn rekey_epoch(frame: &[u8]) -> Option<u32> {
+    match frame.split_first() {
+        Some((&FRAME_SYSTEM, payload)) => match decode_system(payload)? {
+            SystemMessage::Rekey { epoch } => Some(epoch),
+            _ => None,
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,191 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::approval::RejectionReason;
+use crate::audit::{AuditEvent, AuditLog};
+use crate::client::ClientConfig;
+use crate::draining::Draining;
+use crate::handshake::{
+    check_protocol_version, session_mac, verify_connect_token, verify_psk, verify_session_mac, ClientHello,
+    HandshakeResult, ServerHello, SessionParameters, PROOF_SIZE,
+};
+use crate::resume::{ResumeRequest, SessionRegistry};
+use crate::server::ServerConfig;
+
+use super::{read_frame, write_frame, FRAME_HANDSHAKE};
+
+/// Maximum time a peer may take to complete the handshake
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Returns the encoded value, without the frame type
+pub(crate) async fn write_handshake<T: Serialize>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    value: &T,
+) -> Result<Vec<u8>, String> {
+    let encoded = bincode::serialize(value).map_err(|e| format!("Handshake encode error: {}", e))?;
+    let frame = [&[FRAME_HANDSHAKE][..], &encoded].concat();
+    write_frame(stream, &frame)
+        .await
+        .map_err(|e| format!("Handshake write error: {}", e))?;
+    Ok(encoded)
+}
+
+/// The value and its encoding, without the frame type
+async fn read_handshake_encoded<T: DeserializeOwned>(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> Result<(T, Vec<u8>), String> {
+    let mut data = read_frame(stream)
+        .await
+        .map_err(|e| format!("Handshake read error: {}", e))?;
+    if data.first() != Some(&FRAME_HANDSHAKE) {
+        return Err("Unexpected frame during handshake".to_string());
+    }
+    data.remove(0);
+    let value = bincode::deserialize(&data).map_err(|e| format!("Handshake decode error: {}", e))?;
+    Ok((value, data))
+}
+
+pub(crate) async fn read_handshake<T: DeserializeOwned>(stream: &mut (impl AsyncRead + Unpin)) -> Result<T, String> {
+    read_handshake_encoded(stream).await.map(|(value, _)| value)
+}
+
+/// State of the server shared by the handshakes of its listeners
+#[derive(Clone)]
+pub(crate) struct HandshakeContext {
+    pub config: Arc<ServerConfig>,
+    pub audit_log: Arc<AuditLog>,
+    pub draining: Arc<Draining>,
+    pub sessions: Arc<SessionRegistry>,
+}
+
+/// Send the rejection to the client; returns the reason
+async fn reject(stream: &mut (impl AsyncWrite + Unpin), reason: String) -> String {
+    write_handshake(stream, &HandshakeResult::Rejected { reason: reason.clone() })
+        .await
+        .ok();
+    reason
+}
+
+/// Server side of the handshake.
+///
+/// Runs before the connection is registered; a rejected client
+/// receives the reason and never reaches the application. A client resuming
+/// a session is only checked to hold a live token here; `step()` attaches
+/// it to its connection.
+pub(crate) async fn server_handshake(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    ip: &str,
+    config: &ServerConfig,
+    audit_log: &AuditLog,
+    draining: &Draining,
+    sessions: &SessionRegistry,
+) -> Result<(ClientHello, SessionParameters), String> {
+    let server_hello = ServerHello::new();
+    write_handshake(stream, &server_hello).await?;
+
+    let client_hello: ClientHello = read_handshake(stream).await?;
+
+    if draining.is_draining() {
+        return Err(reject(stream, RejectionReason::ShuttingDown.to_string()).await);
+    }
+
+    // Checked first: the rest of the hello may not mean the same to both sides
+    if let Err(reason) = check_protocol_version(client_hello.protocol_version) {
+        return Err(reject(stream, reason).await);
+    }
+
+    let proof = client_hello.proof.as_ref().map(|p| p.as_slice());
+    let authenticated = verify_psk(config.passphrase.as_ref(), &server_hello.challenge, proof)
+        .and_then(|()| verify_connect_token(config.private_key.as_ref(), client_hello.connect_token.as_ref()));
+    let keys = match authenticated {
+        Ok(keys) => keys,
+        Err(reason) => {
+            audit_log.record(AuditEvent::AuthFailed {
+                ip: ip.to_string(),
+                reason: reason.clone(),
+            });
+            return Err(reject(stream, reason).await);
+        }
+    };
+
+    let identity = match config.auth.as_ref() {
+        Some(auth) => {
+            let verified = match client_hello.credential.clone() {
+                Some(credential) => auth.verify(credential, &server_hello.challenge, ip).await,
+                None => Err("Credential required".to_string()),
+            };
+            match verified {
+                Ok(identity) => Some(identity),
+                Err(reason) => {
+                    audit_log.record(AuditEvent::AuthFailed {
+                        ip: ip.to_string(),
+                        reason: reason.clone(),
+                    });
+                    return Err(reject(stream, reason).await);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let tenant = match config.check_tenant(client_hello.tenant.as_ref()) {
+        Ok(tenant) => tenant,
+        Err(reason) => return Err(reject(stream, reason).await),
+    };
+
+    let mut session = SessionParameters::negotiate(config, &client_hello);
+    if let Some(request) = client_hello.resume.as_ref() {
+        match (sessions.get(&request.token), session.resume.as_mut()) {
+            (Some(_), Some(offer)) => offer.token = request.token,
+            _ => return Err(reject(stream, "Session expired".to_string()).await),
+        }
+    }
+    session.sign_seed(config.passphrase.as_ref(), &server_hello.challenge);
+    session.encrypted = keys.is_some();
+    session.keys = keys;
+    session.tenant = tenant;
+    session.identity = identity;
+    let accepted = write_handshake(stream, &HandshakeResult::Accepted(session.clone())).await?;
+    // The result goes in the clear: the client checks it wasn't rewritten on the way
+    if let Some(keys) = session.keys.as_ref() {
+        write_handshake(stream, &session_mac(&keys.server_to_client, &accepted)).await?;
+    }
+    Ok((client_hello, session))
+}
+
+/// Client side of the handshake.
+pub(crate) async fn client_handshake(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    config: &ClientConfig,
+    resume: Option<ResumeRequest>,
+) -> Result<SessionParameters, String> {
+    let server_hello: ServerHello = read_handshake(stream).await?;
+
+    let client_hello = ClientHello::new(&server_hello, config, resume);
+    write_handshake(stream, &client_hello).await?;
+
+    match read_handshake_encoded(stream).await? {
+        (HandshakeResult::Accepted(session), accepted) => {
+            if let Some(token) = config.connect_token.as_ref().filter(|_| session.encrypted) {
+                let tag: [u8; PROOF_SIZE] = read_handshake(stream).await?;
+                verify_session_mac(&token.get_keys().server_to_client, &accepted, &tag)?;
+            }
+            session.check_clear_channels(config)?;
+            check_protocol_version(session.protocol_version)?;
+            session.verify_seed(config.passphrase.as_ref(), &server_hello.challenge)?;
+            if config.connect_token.is_some() && !session.encrypted {
+                return Err("Server ignored the connect token; refusing an unencrypted connection".to_string());
+            }
+            if let Some(request) = resume {
+                if session.resume.map(|offer| offer.token) != Some(request.token) {
+                    return Err("Server did not resume the session".to_string());
+                }
+            }
+            Ok(session)
+        }
+        (HandshakeResult::Rejected { reason }, _) => Err(format!("Connection rejected: {}", reason)),
+    }
+}
@@ -1,17 +1,46 @@
+use bytes::{Bytes, BytesMut};
 use std::io;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::system::{encode_system, SystemMessage};
 
 pub mod client;
 pub mod server;
+pub(crate) mod handshake;
+pub(crate) mod datagram;
+pub(crate) mod encryption;
+pub mod transport;
+#[cfg(test)]
+mod conformance;
+#[cfg(feature = "websocket")]
+pub(crate) mod websocket;
 
 /// Maximum frame size: 16 MB
-const MAX_FRAME_SIZE: u32 = 16 * 1024 * 1024;
+pub(crate) const MAX_FRAME_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Receive buffer of a connection, split into frames by `read_frame_pooled`
+const RECEIVE_POOL_SIZE: usize = 64 * 1024;
 
 /// Frame type markers (first byte of payload)
 pub(crate) const FRAME_MESSAGE: u8 = 0x00;
 pub(crate) const FRAME_PING: u8 = 0x01;
 pub(crate) const FRAME_PONG: u8 = 0x02;
+pub(crate) const FRAME_HANDSHAKE: u8 = 0x03;
+/// Message frames received so far, as u64 LE; see `crate::resume`
+pub(crate) const FRAME_ACK: u8 = 0x04;
+/// `SystemMessage`, see `crate::system`
+pub(crate) const FRAME_SYSTEM: u8 = 0x05;
+
+/// Address prefix selecting the local (Unix domain) socket transport
+pub const LOCAL_SOCKET_PREFIX: &str = "unix:";
+
+/// Address prefix selecting the WebSocket transport (`websocket` feature)
+pub const WEBSOCKET_PREFIX: &str = "ws://";
+
+/// Connection halves of a `transport::Transport`
+pub type BoxedReader = Box<dyn AsyncRead + Send + Unpin>;
+pub type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
 
 /// Write a length-prefixed frame to the writer.
 ///
@@ -22,10 +51,66 @@ pub(crate) async fn write_frame(writer: &mut (impl AsyncWriteExt + Unpin), data:
     Ok(())
 }
 
+/// `FRAME_ACK` frame acknowledging `received` message frames
+pub(crate) fn ack_frame(received: u64) -> Vec<u8> {
+    let mut frame = vec![FRAME_ACK];
+    frame.extend(received.to_le_bytes());
+    frame
+}
+
+/// Count of a `FRAME_ACK` frame; None if malformed
+pub(crate) fn parse_ack(frame: &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(frame.get(1..9)?.try_into().ok()?))
+}
+
+/// `FRAME_SYSTEM` frame of `message`; None if it can't be encoded
+pub(crate) fn system_frame(message: &SystemMessage) -> Option<Vec<u8>> {
+    let mut frame = vec![FRAME_SYSTEM];
+    frame.extend(encode_system(message)?);
+    Some(frame)
+}
+
+/// Random bytes, mostly behind a frame type and a channel the readers know
+#[cfg(test)]
+pub(crate) fn random_frame() -> Vec<u8> {
+    let mut frame: Vec<u8> = (0..rand::random_range(0..64)).map(|_| rand::random()).collect();
+    let frame_types = [FRAME_MESSAGE, FRAME_PING, FRAME_PONG, FRAME_ACK, FRAME_SYSTEM];
+    if !frame.is_empty() && rand::random_bool(0.9) {
+        frame[0] = frame_types[rand::random_range(0..frame_types.len())];
+    }
+    if frame.len() > 1 && rand::random_bool(0.8) {
+        frame[1] = rand::random_range(0..5);
+    }
+    frame
+}
+
 /// Read a length-prefixed frame from the reader.
 ///
 /// Frame format: [u32 LE: payload_length][payload bytes]
 pub(crate) async fn read_frame(reader: &mut (impl AsyncReadExt + Unpin)) -> io::Result<Vec<u8>> {
+    let len = read_frame_size(reader).await?;
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// `read_frame` into a buffer reused across the frames of a connection.
+///
+/// The frame is split off `pool`; its memory is reused once the frame and
+/// the messages sliced from it are dropped, so a busy connection reads
+/// without allocating.
+pub(crate) async fn read_frame_pooled(
+    reader: &mut (impl AsyncReadExt + Unpin),
+    pool: &mut BytesMut,
+) -> io::Result<Bytes> {
+    let len = read_frame_size(reader).await?;
+    pool.reserve(len.max(RECEIVE_POOL_SIZE));
+    pool.resize(len, 0);
+    reader.read_exact(&mut pool[..]).await?;
+    Ok(pool.split().freeze())
+}
+
+async fn read_frame_size(reader: &mut (impl AsyncReadExt + Unpin)) -> io::Result<usize> {
     let len = reader.read_u32_le().await?;
     if len > MAX_FRAME_SIZE {
         return Err(io::Error::new(
@@ -33,7 +118,5 @@ pub(crate) async fn read_frame(reader: &mut (impl AsyncReadExt + Unpin)) -> io::
             format!("frame size {} exceeds maximum {}", len, MAX_FRAME_SIZE),
         ));
     }
-    let mut buf = vec![0u8; len as usize];
-    reader.read_exact(&mut buf).await?;
-    Ok(buf)
+    Ok(len as usize)
 }
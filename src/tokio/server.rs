@@ -1,189 +1,1524 @@
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use bytes::{Bytes, BytesMut};
+use common::chunks::chunk_position::ChunkPosition;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpSocket, UdpSocket};
+use tokio::time::MissedTickBehavior;
 
-use crate::messages::{ClientMessages, NetworkMessageType, ServerMessages};
-use crate::server::{ConnectionMessages, IServerConnection, IServerNetwork};
+use crate::auth::AuthIdentity;
+use crate::audit::{AuditEvent, AuditLog};
+use crate::batching::SendBatch;
+use crate::client_id::ClientId;
+use crate::coalescing::Coalescer;
+use crate::degraded::Degradation;
+use crate::discovery::start_discovery;
+use crate::echo::echo_reply;
+use crate::compression::{AdaptiveCompression, CompressionBudget, COMPRESSED_FLAG};
+use crate::draining::Draining;
+use crate::conditions::{condition_channel, Conditioner, NetworkConditions, SharedConditions};
+use crate::errors::NetworkError;
+use crate::generation::Generation;
+use crate::keyed_state::StateSyncs;
+use crate::system::{decode_system, SystemMessage};
+use crate::approval::{ApprovalGate, RejectionReason};
+use crate::area_of_interest::AreaOfInterest;
+use crate::groups::ConnectionGroups;
+use crate::handshake::SessionParameters;
+use crate::watchdog::{emit_stall_report, spawn_watchdog, StallReport};
+use crate::write_ahead::WriteAheadLog;
+use crate::health::{start_health_endpoint, ServerStats};
+use crate::interest::ChunkInterest;
+use crate::ip_limits::{parse_ip, IpLimitDecision};
+use crate::labels::ConnectionLabel;
+use crate::latency_compensation::SynchronizedBroadcasts;
+use crate::protocol::{DecodedFrame, ProtocolConfig, ServerProtocol};
+use crate::rate_limits::RateLimiter;
+use crate::raw_messages::{frame_payload, RawClientMessage};
+use crate::recording::{Direction, MessageRecorder};
+use crate::resume::{ResumeRequest, ResumeState, SessionRegistry, SessionToken};
+use crate::retries::{BoundedSender, RetryPolicy};
+use crate::routing::{PermissionGate, Permissions};
+use crate::rpc::RpcEndpoint;
+use crate::messages::{ClientMessages, ClientMessagesDiscriminants, NetworkMessageType, ServerMessages};
+use crate::network_info::{NetworkInfo, TrafficMeter};
+use crate::phases::{GamePhase, PhaseGate};
+use crate::shaping::{BurstPriority, BurstReservation, Shaper};
+use crate::socket_errors::{report_socket_error, SocketFailures, SocketRecovery, SOCKET_RETRY_DELAY};
+use crate::socket_options::SocketOptions;
+use crate::snapshots::{SnapshotBuilder, SnapshotSender};
+use crate::streams::StreamWindows;
+use crate::tenants::{Tenant, Tenants};
+use crate::thresholds::ConnectionThresholds;
+use crate::tick_report::TickCounters;
+use crate::time_sync::TimeSyncRequests;
+use crate::timeouts::{Liveness, PeerLoss, PROBE_INTERVAL};
+use crate::tuning::ConnectionTuning;
+use crate::server::{ConnectionMessages, IServerConnection, IServerNetwork, ServerConfig, ServerEvents};
 
-use super::{read_frame, write_frame, FRAME_MESSAGE, FRAME_PING, FRAME_PONG};
+use super::datagram::{spawn_server_receiver, DatagramRoutes, ServerDatagrams};
+use super::handshake::{server_handshake, HandshakeContext, HANDSHAKE_TIMEOUT};
+use super::transport::Transport;
+use super::encryption::encrypt_halves;
+use super::{
+    ack_frame, parse_ack, read_frame_pooled, system_frame, write_frame, BoxedReader, BoxedWriter, FRAME_ACK,
+    FRAME_MESSAGE, FRAME_PING, FRAME_PONG, FRAME_SYSTEM,
+};
+
+/// Frame queued for the writer task
+struct OutgoingFrame {
+    data: Vec<u8>,
+    /// Latest write time and the message variant, for `send_message_with_deadline`
+    deadline: Option<(Instant, String)>,
+    /// Topic group of `ServerConfig::traffic_shaping`
+    group: Option<usize>,
+    /// Placeholder of the frames queued by `send_latest`, taken when written
+    latest: bool,
+}
+
+impl From<Vec<u8>> for OutgoingFrame {
+    fn from(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            deadline: None,
+            group: None,
+            latest: false,
+        }
+    }
+}
+
+/// Connections accepted but not registered by `step()` yet,
+/// capped by `ServerConfig::max_pending_connections`
+pub(crate) struct PendingSlots {
+    count: AtomicUsize,
+    max: Option<usize>,
+}
+
+impl PendingSlots {
+    pub fn new(max: Option<usize>) -> Arc<Self> {
+        Arc::new(Self {
+            count: AtomicUsize::new(0),
+            max,
+        })
+    }
+
+    /// None if the cap is reached; the slot is released when dropped
+    pub fn acquire(self: &Arc<Self>, ip: &dyn std::fmt::Display) -> Option<PendingSlot> {
+        let acquired = self.count.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| match self.max {
+            Some(max) if count >= max => None,
+            _ => Some(count + 1),
+        });
+        if acquired.is_err() {
+            log::warn!(target: "network", "Connection from {} refused: too many pending connections", ip);
+            return None;
+        }
+        Some(PendingSlot(self.clone()))
+    }
+}
+
+pub(crate) struct PendingSlot(Arc<PendingSlots>);
+
+impl Drop for PendingSlot {
+    fn drop(&mut self) {
+        self.0.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Connection that completed the handshake and waits for `step()` to register it.
+pub(crate) struct PendingConnection {
+    reader: BoxedReader,
+    writer: BoxedWriter,
+    ip: String,
+    session: SessionParameters,
+    /// Session the client resumes, see `crate::resume`
+    resume: Option<ResumeRequest>,
+    _slot: PendingSlot,
+}
+
+/// Run the handshake on its own task so a slow client can't stall accepting
+pub(crate) fn spawn_handshake<T: Transport>(
+    mut stream: T,
+    ip: String,
+    slot: PendingSlot,
+    new_conn_tx: flume::Sender<PendingConnection>,
+    context: HandshakeContext,
+) {
+    tokio::spawn(async move {
+        let HandshakeContext {
+            config,
+            audit_log,
+            draining,
+            sessions,
+        } = &context;
+        let handshake = server_handshake(&mut stream, &ip, config, audit_log, draining, sessions);
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+            Ok(Ok((client_hello, session))) => {
+                let (reader, writer) = stream.into_halves();
+                let (reader, writer) = match session.keys.as_ref() {
+                    Some(keys) => {
+                        encrypt_halves(reader, writer, &keys.server_to_client, &keys.client_to_server, &session)
+                    }
+                    None => (reader, writer),
+                };
+                let pending = PendingConnection {
+                    reader,
+                    writer,
+                    ip,
+                    session,
+                    resume: client_hello.resume,
+                    _slot: slot,
+                };
+                new_conn_tx.send(pending).ok();
+            }
+            Ok(Err(e)) => {
+                log::warn!(target: "network", "Handshake with {} failed: {}", ip, e);
+            }
+            Err(_) => {
+                log::warn!(target: "network", "Handshake with {} timed out", ip);
+            }
+        }
+    });
+}
+
+/// Accept loop for the local socket (`ServerConfig::local_socket`)
+#[cfg(unix)]
+fn spawn_local_listener(
+    path: std::path::PathBuf,
+    pending: Arc<PendingSlots>,
+    new_conn_tx: flume::Sender<PendingConnection>,
+    handshake: HandshakeContext,
+    events: flume::Sender<ServerEvents>,
+) {
+    use std::os::unix::fs::FileTypeExt;
+
+    // Remove the socket file left by a previous run
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(&path).ok();
+        }
+    }
+    let listener = tokio::net::UnixListener::bind(&path).unwrap();
+    log::info!(target: "network", "Local socket listening on {}", path.display());
+
+    tokio::spawn(async move {
+        loop {
+            if new_conn_tx.is_disconnected() {
+                break;
+            }
+            match listener.accept().await {
+                Ok((stream, _addr)) => {
+                    let ip = format!("{}{}", super::LOCAL_SOCKET_PREFIX, path.display());
+                    let Some(slot) = pending.acquire(&ip) else {
+                        continue;
+                    };
+                    spawn_handshake(stream, ip, slot, new_conn_tx.clone(), handshake.clone());
+                }
+                Err(e) => {
+                    report_socket_error(&events, "local", &e, SocketRecovery::Retried);
+                    tokio::time::sleep(SOCKET_RETRY_DELAY).await;
+                }
+            }
+        }
+    });
+}
+
+/// Hands the connections of a transport opened by the caller to the handshake
+struct TransportAcceptor {
+    pending: Arc<PendingSlots>,
+    new_conn_tx: flume::Sender<PendingConnection>,
+    handshake: HandshakeContext,
+}
+
+/// Bind the TCP listener of the server, also to rebind it after errors
+fn bind_listener(addr: SocketAddr, options: &SocketOptions) -> Result<TcpListener, String> {
+    let socket = match addr.is_ipv4() {
+        true => TcpSocket::new_v4(),
+        false => TcpSocket::new_v6(),
+    }
+    .map_err(|e| e.to_string())?;
+    options.apply(&socket)?;
+    socket.set_reuseaddr(true).map_err(|e| e.to_string())?;
+    socket.bind(addr).map_err(|e| e.to_string())?;
+    socket.listen(1024).map_err(|e| e.to_string())
+}
 
 pub struct TokioServer {
-    new_connections_rx: flume::Receiver<(tokio::net::TcpStream, std::net::SocketAddr)>,
-    connections: Arc<RwLock<HashMap<u64, TokioServerConnection>>>,
+    new_connections_rx: flume::Receiver<PendingConnection>,
+    connections: Arc<RwLock<HashMap<ClientId, TokioServerConnection>>>,
 
     channel_connections: (
         flume::Sender<ConnectionMessages<TokioServerConnection>>,
         flume::Receiver<ConnectionMessages<TokioServerConnection>>,
     ),
-    channel_errors: (flume::Sender<String>, flume::Receiver<String>),
+    channel_errors: (flume::Sender<NetworkError>, flume::Receiver<NetworkError>),
+    channel_events: (flume::Sender<ServerEvents>, flume::Receiver<ServerEvents>),
     next_client_id: AtomicU64,
+    config: Arc<ServerConfig>,
+    audit_log: Arc<AuditLog>,
+    stats: Arc<ServerStats>,
+    tick_counters: Arc<TickCounters>,
+
+    /// Datagram socket bound to the same address as the listener
+    datagram_socket: Option<Arc<UdpSocket>>,
+    datagram_routes: DatagramRoutes,
+    foreign_datagrams: Arc<AtomicU64>,
+    groups: ConnectionGroups,
+    area_of_interest: Arc<AreaOfInterest>,
+    draining: Arc<Draining>,
+    /// Set with `ServerConfig::network_conditions`
+    network_conditions: Option<SharedConditions>,
+    tenants: Tenants<TokioServerConnection>,
+    thresholds: ConnectionThresholds,
+    recorder: Arc<MessageRecorder>,
+    write_ahead: Arc<WriteAheadLog>,
+    /// Tokens of the resumable sessions, see `crate::resume`
+    sessions: Arc<SessionRegistry>,
+    synchronized: SynchronizedBroadcasts,
+    compression_budget: Arc<CompressionBudget>,
+    /// See `accept_transport`
+    acceptor: TransportAcceptor,
 }
 
-/// Background task: reads length-prefixed frames from a client socket,
-/// dispatches messages to the connection's channel, responds to ping with pong.
-async fn connection_reader_task(
-    reader: OwnedReadHalf,
+/// State shared with the per-connection reader task.
+struct ConnectionReader {
+    client_id: ClientId,
+    label: ConnectionLabel,
+    ip: String,
+    config: Arc<ServerConfig>,
+    /// Decodes the message frames, see `crate::protocol`
+    protocol: Arc<ServerProtocol>,
     tx: flume::Sender<ClientMessages>,
-    error_tx: flume::Sender<String>,
+    /// Game messages left undecoded with `ServerConfig::raw_receive`
+    raw_tx: flume::Sender<RawClientMessage>,
+    error_tx: flume::Sender<NetworkError>,
+    events_tx: flume::Sender<ServerEvents>,
     connected: Arc<AtomicBool>,
-    outgoing_tx: flume::Sender<Vec<u8>>,
-) {
+    outgoing_tx: flume::Sender<OutgoingFrame>,
+    last_ping_sent: Arc<Mutex<Option<Instant>>>,
+    traffic: Arc<TrafficMeter>,
+    tick_counters: Arc<TickCounters>,
+    permissions: Arc<PermissionGate>,
+    approval: Arc<ApprovalGate>,
+    rate_limiter: Arc<RateLimiter>,
+    /// Reason of the client `ClientMessages::Disconnect` or of the timeout
+    disconnect_reason: Arc<Mutex<Option<String>>>,
+    snapshots: Arc<Mutex<SnapshotSender>>,
+    time_sync: Arc<TimeSyncRequests>,
+    rpc: Arc<RpcEndpoint>,
+    bounded: Arc<BoundedSender>,
+    /// Set with `ServerConfig::position_tracking`
+    area_of_interest: Option<Arc<AreaOfInterest>>,
+    recorder: Arc<MessageRecorder>,
+    write_ahead: Arc<WriteAheadLog>,
+    /// Set with `ServerConfig::session_resume`
+    resume: Option<Arc<ResumeState>>,
+    /// The client sent `ClientMessages::Disconnect`; its session is not resumed
+    closing: AtomicBool,
+    liveness: Arc<Liveness>,
+    state_syncs: Arc<StateSyncs>,
+    stream_windows: Arc<StreamWindows>,
+    tuning: Arc<ConnectionTuning>,
+    deferred: DeferredMessages,
+}
+
+/// Most unreliable messages waiting for `step()`; the oldest are dropped past it
+const MAX_DEFERRED_MESSAGES: usize = 1024;
+
+/// Messages of the unreliable channels left undecoded for `step()` to
+/// decode within `ServerConfig::step_budget`
+struct DeferredMessages {
+    enabled: bool,
+    frames: Mutex<VecDeque<Bytes>>,
+}
+
+impl DeferredMessages {
+    fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            frames: Default::default(),
+        }
+    }
+
+    /// True if the message frame is queued for `step()`
+    fn defer(&self, data: &Bytes, tick_counters: &TickCounters) -> bool {
+        let channel = NetworkMessageType::from_channel_id(data[1] & !COMPRESSED_FLAG);
+        if !self.enabled
+            || !matches!(
+                channel,
+                Some(NetworkMessageType::Unreliable | NetworkMessageType::UnreliableSequenced)
+            )
+        {
+            return false;
+        }
+        let mut frames = self.frames.lock();
+        if frames.len() >= MAX_DEFERRED_MESSAGES {
+            frames.pop_front();
+            tick_counters.add_dropped();
+        }
+        frames.push_back(data.clone());
+        true
+    }
+
+    fn pop(&self) -> Option<Bytes> {
+        self.frames.lock().pop_front()
+    }
+
+    fn len(&self) -> usize {
+        self.frames.lock().len()
+    }
+}
+
+/// Pass on a game message undecoded, see `crate::raw_messages`; None if it
+/// is decoded on receive, false once the connection is dropped
+fn pass_raw(ctx: &ConnectionReader, frame: Bytes) -> Option<bool> {
+    let channel = frame[0];
+    let (channel, payload) = frame_payload(channel, frame.slice(1..)).ok()?;
+    let profile = ctx.protocol.get_profiles().get(channel);
+    let raw = RawClientMessage::split(channel, payload, profile, ctx.area_of_interest.is_some())?;
+    let (variant, size) = (raw.get_variant(), raw.get_payload().len());
+    ctx.recorder.record(
+        Direction::Inbound,
+        ctx.client_id,
+        channel,
+        profile,
+        variant,
+        raw.get_payload(),
+    );
+    if !ctx.config.check_message_size(&ctx.events_tx, &ctx.label, variant, size)
+        || !ctx
+            .config
+            .check_message_route(&ctx.events_tx, &ctx.permissions, &ctx.label, variant)
+    {
+        ctx.tick_counters.add_dropped();
+        return Some(true);
+    }
+    ctx.tick_counters.add_in(size);
+    let (payload, raw_tx) = (raw.get_payload().clone(), ctx.raw_tx.clone());
+    ctx.write_ahead
+        .append(&ctx.label, channel, profile, variant, &payload, move || {
+            raw_tx.send(raw).ok();
+        });
+    Some(!ctx.raw_tx.is_disconnected())
+}
+
+/// Frame returning `ClientMessages::Echo` on its channel, see `crate::echo`
+fn echo_frame(channel: u8, id: u32, payload: Vec<u8>) -> Option<Vec<u8>> {
+    let encoded = bincode::serialize(&echo_reply(id, payload)?).ok()?;
+    let mut frame = vec![FRAME_MESSAGE, channel];
+    frame.extend(encoded);
+    Some(frame)
+}
+
+/// Decode a message frame and hand the message on; false once the connection is dropped
+fn receive_message(ctx: &ConnectionReader, data: Bytes) -> bool {
+    let DecodedFrame {
+        channel,
+        payload,
+        message,
+    } = match ctx.protocol.decode_frame(&data) {
+        Ok(Some(decoded)) => decoded,
+        // A variant of a newer client
+        Ok(None) => {
+            ctx.tick_counters.add_dropped();
+            return true;
+        }
+        Err(e) => {
+            ctx.tick_counters.add_dropped();
+            ctx.error_tx
+                .send(NetworkError::Decode {
+                    client_id: Some(ctx.client_id),
+                    reason: e,
+                })
+                .ok();
+            return true;
+        }
+    };
+    let profile = ctx.protocol.get_profiles().get(channel);
+    ctx.recorder.record(
+        Direction::Inbound,
+        ctx.client_id,
+        channel,
+        profile,
+        message.as_ref(),
+        &payload,
+    );
+    // Checked and routed by the inner message, see `crate::trace_context`
+    let (message, context) = message.untraced();
+    match message {
+        ClientMessages::Disconnect { message } => {
+            // The client closes the socket next
+            *ctx.disconnect_reason.lock() = message;
+            ctx.closing.store(true, Ordering::SeqCst);
+        }
+        ClientMessages::SnapshotAck { world_slug, sequence } => {
+            ctx.snapshots.lock().ack(&world_slug, sequence);
+        }
+        ClientMessages::TimeSync { client_time } => {
+            ctx.time_sync.push(client_time);
+        }
+        ClientMessages::BoundedAck { ids } => {
+            ctx.bounded.ack(&ids);
+        }
+        ClientMessages::StateAck { name, version } => {
+            ctx.state_syncs.ack(&name, version);
+        }
+        ClientMessages::StreamCredit { stream_id, bytes } => {
+            ctx.stream_windows.grant(stream_id, bytes);
+        }
+        ClientMessages::Echo { id, payload } => {
+            if let Some(frame) = echo_frame(channel, id, payload) {
+                ctx.outgoing_tx.send(frame.into()).ok();
+            }
+        }
+        msg => {
+            let size = payload.len();
+            if !ctx
+                .config
+                .check_message_size(&ctx.events_tx, &ctx.label, msg.as_ref(), size)
+            {
+                ctx.tick_counters.add_dropped();
+                return true;
+            }
+            if !ctx
+                .config
+                .check_message_route(&ctx.events_tx, &ctx.permissions, &ctx.label, msg.as_ref())
+            {
+                ctx.tick_counters.add_dropped();
+                return true;
+            }
+            if !ctx
+                .approval
+                .check(ctx.config.approval.as_ref(), ctx.client_id, &ctx.ip, &msg)
+            {
+                ctx.tick_counters.add_dropped();
+                return true;
+            }
+            ctx.tick_counters.add_in(size);
+            if let Some(area_of_interest) = ctx.area_of_interest.as_ref() {
+                area_of_interest.observe(ctx.client_id, &msg);
+            }
+            let Some(msg) = ctx.rpc.route_client_message(msg) else {
+                return true;
+            };
+            let msg = msg.retraced(context);
+            let (variant, tx) = (msg.as_ref().to_string(), ctx.tx.clone());
+            ctx.write_ahead
+                .append(&ctx.label, channel, profile, &variant, &payload, move || {
+                    tx.send(msg).ok();
+                });
+            if ctx.tx.is_disconnected() {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Why the reader or writer task of a socket stopped
+enum SocketEnd {
+    /// The connection was closed or removed
+    Closed,
+    /// The socket failed; carries the reason of a timeout and how the client was lost
+    Failed(Option<String>, PeerLoss),
+}
+
+/// Background task: reads length-prefixed frames from a client socket,
+/// dispatches messages to the connection's channel, handles ping and pong for RTT.
+async fn connection_reader_task(reader: BoxedReader, ctx: &ConnectionReader) -> SocketEnd {
     let mut buf_reader = BufReader::new(reader);
+    let mut pool = BytesMut::new();
+    let timeout = ctx.config.get_connection_timeout();
     loop {
-        match read_frame(&mut buf_reader).await {
+        let Ok(frame) = tokio::time::timeout(timeout, read_frame_pooled(&mut buf_reader, &mut pool)).await else {
+            log::warn!(target: "network", "Client {} timed out", ctx.label);
+            return SocketEnd::Failed(Some("Timed out".to_string()), PeerLoss::Unreachable);
+        };
+        if frame.is_ok() {
+            ctx.liveness.touch();
+        }
+        match frame {
             Ok(data) if data.is_empty() => continue,
-            Ok(data) => match data[0] {
-                FRAME_MESSAGE => match bincode::deserialize::<ClientMessages>(&data[1..]) {
-                    Ok(msg) => {
-                        if tx.send(msg).is_err() {
-                            break;
+            Ok(data) => {
+                ctx.traffic.add_received(data.len() + 4);
+                if let (FRAME_MESSAGE, Some(resume)) = (data[0], ctx.resume.as_ref()) {
+                    resume.count_received();
+                }
+                match data[0] {
+                    FRAME_MESSAGE if data.len() < 2 => {
+                        ctx.error_tx
+                            .send(NetworkError::MalformedFrame {
+                                client_id: Some(ctx.client_id),
+                            })
+                            .ok();
+                    }
+                    FRAME_MESSAGE => {
+                        // Checked before decoding, so a flood costs little CPU
+                        let channel = NetworkMessageType::from_channel_id(data[1] & !COMPRESSED_FLAG);
+                        if let Some(channel) = channel {
+                            if !ctx.rate_limiter.check(&ctx.config.rate_limits, channel, data.len() - 2) {
+                                ctx.tick_counters.add_dropped();
+                                continue;
+                            }
+                        }
+                        if ctx.config.raw_receive && ctx.approval.is_approved() {
+                            match pass_raw(ctx, data.slice(1..)) {
+                                Some(true) => continue,
+                                Some(false) => return SocketEnd::Closed,
+                                None => {}
+                            }
+                        }
+                        if ctx.deferred.defer(&data, &ctx.tick_counters) {
+                            continue;
+                        }
+                        if !receive_message(ctx, data) {
+                            return SocketEnd::Closed;
                         }
                     }
-                    Err(e) => {
-                        error_tx
-                            .send(format!("Client message decode error: {}", e))
-                            .ok();
+                    FRAME_PING => {
+                        ctx.outgoing_tx.send(vec![FRAME_PONG].into()).ok();
+                        if let Some(resume) = ctx.resume.as_ref() {
+                            ctx.outgoing_tx.send(ack_frame(resume.get_received()).into()).ok();
+                        }
                     }
-                },
-                FRAME_PING => {
-                    outgoing_tx.send(vec![FRAME_PONG]).ok();
+                    FRAME_PONG => {
+                        if let Some(sent_at) = ctx.last_ping_sent.lock().take() {
+                            ctx.traffic.set_rtt(sent_at.elapsed());
+                        }
+                    }
+                    FRAME_ACK => {
+                        if let (Some(resume), Some(received)) = (ctx.resume.as_ref(), parse_ack(&data)) {
+                            resume.ack(received);
+                        }
+                    }
+                    FRAME_SYSTEM => {
+                        if let Some(SystemMessage::TuningApplied { revision }) = decode_system(&data[1..]) {
+                            ctx.tuning.ack(revision);
+                        }
+                    }
+                    _ => {}
                 }
-                _ => {}
-            },
-            Err(_) => {
-                connected.store(false, Ordering::SeqCst);
-                break;
             }
+            Err(e) => return SocketEnd::Failed(None, PeerLoss::from_io(&e)),
         }
     }
 }
 
+/// State shared with the per-connection writer task.
+struct ConnectionWriter {
+    client_id: ClientId,
+    events_tx: flume::Sender<ServerEvents>,
+    connected: Arc<AtomicBool>,
+    last_ping_sent: Arc<Mutex<Option<Instant>>>,
+    traffic: Arc<TrafficMeter>,
+    shaper: Option<Shaper<OutgoingFrame>>,
+    tick_counters: Arc<TickCounters>,
+    keep_alive: Duration,
+    latest: Arc<Coalescer<OutgoingFrame>>,
+    /// Set with `ServerConfig::session_resume`
+    resume: Option<Arc<ResumeState>>,
+    /// Frames of `IServerConnection::send_system`, written ahead of the others
+    system: flume::Receiver<Vec<u8>>,
+    /// Probes a quiet client, see `crate::timeouts`
+    liveness: Arc<Liveness>,
+    tuning: Arc<ConnectionTuning>,
+    /// Revision of `tuning` applied to the shaper
+    tuning_revision: u32,
+}
+
 /// Background task: drains outgoing channel and writes length-prefixed frames
-/// to the client socket with batch-flushing.
+/// to the client socket with batch-flushing. Sends periodic ping frames.
+/// Frames of a shaped topic group wait in the shaper until their group has budget.
+/// The `initial` frames are written first, as they are, e.g. the resends of a resumed session.
 async fn connection_writer_task(
-    writer: OwnedWriteHalf,
-    rx: flume::Receiver<Vec<u8>>,
-    connected: Arc<AtomicBool>,
-) {
+    writer: BoxedWriter,
+    rx: &flume::Receiver<OutgoingFrame>,
+    ctx: &mut ConnectionWriter,
+    initial: Vec<Vec<u8>>,
+) -> SocketEnd {
     let mut buf_writer = BufWriter::new(writer);
+    let ping_start = tokio::time::Instant::now() + ctx.keep_alive;
+    let mut ping_interval = tokio::time::interval_at(ping_start, ctx.keep_alive);
+    let mut probe_interval = tokio::time::interval(PROBE_INTERVAL);
+    probe_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    if !initial.is_empty() {
+        for data in initial.iter() {
+            if let Err(e) = write_frame(&mut buf_writer, data).await {
+                return SocketEnd::Failed(None, PeerLoss::from_io(&e));
+            }
+            ctx.traffic.add_sent(data.len() + 4);
+        }
+        if let Err(e) = buf_writer.flush().await {
+            return SocketEnd::Failed(None, PeerLoss::from_io(&e));
+        }
+    }
+
+    // Frames past their deadline are dropped instead of written
+    let write = |frame: &OutgoingFrame| -> bool {
+        let Some((deadline, variant)) = frame.deadline.as_ref() else {
+            return true;
+        };
+        let now = Instant::now();
+        if now <= *deadline {
+            return true;
+        }
+        ctx.tick_counters.add_dropped();
+        ctx.events_tx
+            .send(ServerEvents::DeadlineMissed {
+                client_id: ctx.client_id,
+                variant: variant.clone(),
+                late: now - *deadline,
+            })
+            .ok();
+        false
+    };
+
     loop {
-        if !connected.load(Ordering::SeqCst) {
-            break;
+        if !ctx.connected.load(Ordering::SeqCst) {
+            return SocketEnd::Closed;
         }
+        let shaped_wait = ctx.shaper.as_ref().and_then(|s| s.next_ready_in());
+        let probing = ctx.liveness.is_quiet(ctx.keep_alive);
+        let mut frames = Vec::new();
+        let mut system: Vec<OutgoingFrame> = Vec::new();
         tokio::select! {
             result = rx.recv_async() => {
                 match result {
-                    Ok(data) => {
-                        if write_frame(&mut buf_writer, &data).await.is_err() {
-                            connected.store(false, Ordering::SeqCst);
-                            break;
-                        }
+                    Ok(frame) => {
                         // Batch any additional queued messages before flushing
-                        while let Ok(data) = rx.try_recv() {
-                            if write_frame(&mut buf_writer, &data).await.is_err() {
-                                connected.store(false, Ordering::SeqCst);
-                                return;
-                            }
-                        }
-                        if buf_writer.flush().await.is_err() {
-                            connected.store(false, Ordering::SeqCst);
-                            break;
-                        }
+                        frames.push(frame);
+                        frames.extend(rx.try_iter());
                     }
-                    Err(_) => break,
+                    Err(_) => return SocketEnd::Closed,
                 }
             }
-            _ = tokio::time::sleep(Duration::from_secs(1)) => {
-                // Periodic check for connected flag
-                continue;
+            result = ctx.system.recv_async() => {
+                match result {
+                    Ok(data) => system.push(data.into()),
+                    Err(_) => return SocketEnd::Closed,
+                }
+            }
+            _ = tokio::time::sleep(shaped_wait.unwrap_or_default()), if shaped_wait.is_some() => {}
+            _ = ping_interval.tick() => {
+                // Clients that predate server pings ignore them; RTT then stays zero
+                *ctx.last_ping_sent.lock() = Some(Instant::now());
+                frames.push(vec![FRAME_PING].into());
+            }
+            _ = probe_interval.tick(), if probing => {
+                *ctx.last_ping_sent.lock() = Some(Instant::now());
+                frames.push(vec![FRAME_PING].into());
             }
         }
+        // The latest frame of each key replaces its placeholder, see `crate::coalescing`
+        let mut frames: Vec<_> = frames
+            .into_iter()
+            .flat_map(|frame| match frame.latest {
+                true => ctx.latest.drain(),
+                false => vec![frame],
+            })
+            .collect();
+        if let Some(shaper) = ctx.shaper.as_mut() {
+            let revision = ctx.tuning.get_revision();
+            if revision != ctx.tuning_revision {
+                ctx.tuning_revision = revision;
+                shaper.set_shares(&ctx.tuning.get_shares());
+            }
+            frames = frames
+                .into_iter()
+                .filter_map(|frame| match frame.group {
+                    Some(group) => shaper.push(group, frame.data.len(), frame),
+                    None => Some(frame),
+                })
+                .collect();
+            frames.extend(shaper.pop_ready());
+            ctx.traffic.set_held(shaper.queued());
+        }
+        // System frames skip the game frames, see `crate::system`
+        system.extend(ctx.system.try_iter().map(OutgoingFrame::from));
+        let frames: Vec<_> = system
+            .into_iter()
+            .chain(frames.into_iter().filter(|f| write(f)))
+            .collect();
+        // Kept before writing: the batch is lost with the socket otherwise
+        if let Some(resume) = ctx.resume.as_ref() {
+            for frame in frames.iter().filter(|f| f.data.first() == Some(&FRAME_MESSAGE)) {
+                resume.push_sent(&frame.data);
+            }
+        }
+        if frames.is_empty() {
+            continue;
+        }
+        for frame in frames.iter() {
+            if let Err(e) = write_frame(&mut buf_writer, &frame.data).await {
+                return SocketEnd::Failed(None, PeerLoss::from_io(&e));
+            }
+            ctx.traffic.add_sent(frame.data.len() + 4);
+        }
+        if let Err(e) = buf_writer.flush().await {
+            return SocketEnd::Failed(None, PeerLoss::from_io(&e));
+        }
+    }
+}
+
+/// Socket of a resumed session, handed by `step()` to its connection task
+struct ResumedSocket {
+    reader: BoxedReader,
+    writer: BoxedWriter,
+    /// Message frames the client received
+    received: u64,
+}
+
+/// Resume side of a connection task, see `crate::resume`
+struct SessionResume {
+    state: Arc<ResumeState>,
+    grace: Duration,
+    sockets: flume::Receiver<ResumedSocket>,
+    disconnect_at: Arc<RwLock<Option<Instant>>>,
+}
+
+impl SessionResume {
+    /// False once either side asked to disconnect
+    fn can_resume(&self, ctx: &ConnectionReader) -> bool {
+        let closing = ctx.closing.load(Ordering::SeqCst) || self.disconnect_at.read().is_some();
+        !closing && ctx.connected.load(Ordering::SeqCst)
+    }
+
+    /// Wait for the client to resume after its socket failed;
+    /// None if it does not within the grace period or must not resume
+    async fn wait(&self, ctx: &ConnectionReader) -> Option<ResumedSocket> {
+        if !self.can_resume(ctx) {
+            return None;
+        }
+        log::info!(target: "network", "Client {} dropped; holding its session for {:?}", ctx.label, self.grace);
+        let socket = tokio::time::timeout(self.grace, self.sockets.recv_async()).await;
+        socket.ok()?.ok()
+    }
+}
+
+/// Report a client lost without disconnecting, see `crate::timeouts`
+fn report_peer_loss(ctx: &ConnectionReader, loss: PeerLoss) {
+    if ctx.closing.load(Ordering::SeqCst) || !ctx.connected.load(Ordering::SeqCst) {
+        return;
+    }
+    let client_id = ctx.client_id;
+    let event = match loss {
+        PeerLoss::Closed => {
+            log::warn!(target: "network", "Client {} closed its socket without disconnecting", ctx.label);
+            ServerEvents::PeerClosed { client_id }
+        }
+        PeerLoss::Unreachable => {
+            let silent = ctx.liveness.get_silent();
+            log::warn!(target: "network", "Client {} unreachable for {:.1?}", ctx.label, silent);
+            ServerEvents::PeerUnreachable { client_id, silent }
+        }
+    };
+    ctx.events_tx.send(event).ok();
+}
+
+/// Socket resumed while the failure of the previous one was not noticed yet
+async fn next_socket(resume: Option<&SessionResume>) -> Option<ResumedSocket> {
+    match resume {
+        Some(resume) => resume.sockets.recv_async().await.ok(),
+        None => std::future::pending().await,
+    }
+}
+
+/// Background task: runs the reader and writer tasks of a connection.
+/// With `ServerConfig::session_resume` it moves them to the socket of the
+/// client when it resumes the session, instead of ending the connection.
+async fn connection_task(
+    reader: BoxedReader,
+    writer: BoxedWriter,
+    reader_ctx: Arc<ConnectionReader>,
+    mut writer_ctx: ConnectionWriter,
+    rx: flume::Receiver<OutgoingFrame>,
+    resume: Option<SessionResume>,
+) {
+    let mut socket = (reader, writer, Vec::new());
+    loop {
+        let (reader, writer, initial) = socket;
+        let next = tokio::select! {
+            end = connection_reader_task(reader, &reader_ctx) => Err(end),
+            end = connection_writer_task(writer, &rx, &mut writer_ctx, initial) => Err(end),
+            Some(resumed) = next_socket(resume.as_ref()) => Ok(resumed),
+        };
+        let resumed = match next {
+            Ok(resumed) => Some(resumed),
+            Err(SocketEnd::Closed) => break,
+            Err(SocketEnd::Failed(reason, loss)) => {
+                let resumed = match resume.as_ref() {
+                    Some(resume) => resume.wait(&reader_ctx).await,
+                    None => None,
+                };
+                if resumed.is_none() {
+                    if let Some(reason) = reason {
+                        reader_ctx.disconnect_reason.lock().get_or_insert(reason);
+                    }
+                    report_peer_loss(&reader_ctx, loss);
+                }
+                resumed
+            }
+        };
+        let (Some(resumed), Some(session)) = (resumed, resume.as_ref()) else {
+            break;
+        };
+        if !session.can_resume(&reader_ctx) {
+            break;
+        }
+        // The client reported what it received; it is told what the server did
+        let Some(resends) = session.state.resend_from(resumed.received) else {
+            log::warn!(target: "network", "Session of client {} can't be resumed: unacknowledged messages were dropped", reader_ctx.label);
+            break;
+        };
+        log::info!(target: "network", "Client {} resumed its session", reader_ctx.label);
+        let mut initial = vec![ack_frame(session.state.get_received())];
+        initial.extend(resends);
+        writer_ctx
+            .events_tx
+            .send(ServerEvents::Reconnected {
+                client_id: reader_ctx.client_id,
+            })
+            .ok();
+        reader_ctx.liveness.touch();
+        socket = (resumed.reader, resumed.writer, initial);
+    }
+    reader_ctx.connected.store(false, Ordering::SeqCst);
+}
+
+impl TokioServer {
+    /// Serve a connection of a transport the server doesn't listen on,
+    /// e.g. WebTransport sessions accepted by the caller; `peer` is the
+    /// client address, `ServerConfig::ip_limit` applies if it is an `ip:port`
+    pub fn accept_transport<T: Transport>(&self, stream: T, peer: String) {
+        let TransportAcceptor {
+            pending,
+            new_conn_tx,
+            handshake,
+        } = &self.acceptor;
+        let Some(slot) = pending.acquire(&peer) else {
+            return;
+        };
+        spawn_handshake(stream, peer, slot, new_conn_tx.clone(), handshake.clone());
+    }
+
+    fn stall_report(&self, gap: Duration) -> StallReport {
+        let mut report = StallReport {
+            gap,
+            pending_connections: self.new_connections_rx.len(),
+            ..Default::default()
+        };
+        for connection in self.connections.read().values() {
+            report.queued_client_messages += connection.channel_client_messages.len();
+            report.queued_client_messages += connection.channel_raw_messages.len();
+            report.queued_outgoing += connection.channel_outgoing.len();
+            if !connection.connected.load(Ordering::SeqCst) {
+                report.disconnected += 1;
+            }
+        }
+        report
+    }
+
+    /// Report on the stream of the connection tenant, or the server stream
+    fn report(&self, tenant: Option<&String>, message: ConnectionMessages<TokioServerConnection>) {
+        if let Some(message) = self.tenants.route(tenant, message) {
+            self.channel_connections.0.send(message).ok();
+        }
+    }
+
+    /// Apply `ServerConfig::ip_limit` to a new connection; false if it is rejected
+    fn check_ip_limit(&self, connection: &TokioServerConnection) -> bool {
+        let (Some(limit), Some(ip)) = (self.config.ip_limit.as_ref(), parse_ip(&connection.ip)) else {
+            return true;
+        };
+        let mut existing: Vec<ClientId> = self
+            .connections
+            .read()
+            .values()
+            // Not the ones already disconnecting, rejected or replaced
+            .filter(|c| c.disconnect_at.read().is_none() && c.connected.load(Ordering::SeqCst))
+            .filter(|c| parse_ip(&c.ip) == Some(ip))
+            .map(|c| c.client_id)
+            .collect();
+        existing.sort_unstable();
+        let decision = limit.check(&ip, &existing);
+        if matches!(decision, IpLimitDecision::Accept) {
+            return true;
+        }
+        log::warn!(target: "network", "Client {} from {} is over {} connections per address", connection.label, ip, limit.get_max(&ip));
+        let event = ServerEvents::IpLimitReached {
+            client_id: connection.client_id,
+            ip,
+            connections: existing.len(),
+            policy: limit.get_policy(),
+        };
+        connection.channel_events.send(event).ok();
+        match decision {
+            IpLimitDecision::Reject => {
+                let reason = RejectionReason::TooManyConnections;
+                self.audit_log.record(AuditEvent::ConnectionRejected {
+                    client_id: connection.client_id,
+                    ip: connection.ip.clone(),
+                    reason: reason.to_string(),
+                });
+                // Never reported as connected
+                connection.approval.reject();
+                connection.send_message(
+                    NetworkMessageType::ReliableOrdered,
+                    &ServerMessages::ConnectionRejected { reason },
+                );
+                connection.disconnect();
+                false
+            }
+            IpLimitDecision::Replace(oldest) => {
+                if let Some(oldest) = self.connections.read().get(&oldest) {
+                    let reason = "Replaced by a newer connection from the same address";
+                    oldest.disconnect_with_reason(Some(reason.to_string()));
+                }
+                true
+            }
+            IpLimitDecision::Accept | IpLimitDecision::Report => true,
+        }
     }
 }
 
 impl IServerNetwork<TokioServerConnection> for TokioServer {
-    async fn new(ip_port: String) -> Self {
-        let listener = TcpListener::bind(&ip_port).await.unwrap();
+    async fn new_with_config(ip_port: String, config: ServerConfig) -> Self {
+        let addr = tokio::net::lookup_host(&ip_port)
+            .await
+            .unwrap()
+            .next()
+            .expect("no addresses to bind");
+        let mut listener = bind_listener(addr, &config.socket).unwrap();
+        let address = listener.local_addr().unwrap();
+        let game_port = address.port();
         log::info!(target: "network", "TCP server listening on {}", ip_port);
 
+        let channel_events = flume::unbounded();
+        let datagram_routes: DatagramRoutes = Default::default();
+        let foreign_datagrams: Arc<AtomicU64> = Default::default();
+        let datagram_socket = match UdpSocket::bind(address).await {
+            Ok(socket) => {
+                let socket = Arc::new(socket);
+                let (routes, foreign) = (datagram_routes.clone(), foreign_datagrams.clone());
+                spawn_server_receiver(socket.clone(), routes, foreign, channel_events.0.clone());
+                Some(socket)
+            }
+            Err(e) => {
+                log::warn!(target: "network", "Datagram socket bind error: {}; datagrams are disabled", e);
+                None
+            }
+        };
+
         let (new_conn_tx, new_conn_rx) = flume::unbounded();
 
         // Spawn background accept loop
+        let config = Arc::new(config);
+        let audit_log = Arc::new(AuditLog::new(config.audit_key.clone().unwrap_or_default()));
+        let draining: Arc<Draining> = Default::default();
+        let sessions: Arc<SessionRegistry> = Default::default();
+        let handshake = HandshakeContext {
+            config: config.clone(),
+            audit_log: audit_log.clone(),
+            draining: draining.clone(),
+            sessions: sessions.clone(),
+        };
+        let pending = PendingSlots::new(config.max_pending_connections);
+
+        if let Some(path) = config.local_socket.clone() {
+            #[cfg(unix)]
+            spawn_local_listener(
+                path,
+                pending.clone(),
+                new_conn_tx.clone(),
+                handshake.clone(),
+                channel_events.0.clone(),
+            );
+            #[cfg(not(unix))]
+            log::warn!(target: "network", "Local socket {} is only supported on Unix", path.display());
+        }
+
+        if let Some(address) = config.websocket_address {
+            #[cfg(feature = "websocket")]
+            if let Err(e) = super::websocket::spawn_websocket_listener(
+                address,
+                pending.clone(),
+                new_conn_tx.clone(),
+                handshake.clone(),
+            )
+            .await
+            {
+                log::error!(target: "network", "{}", e);
+            }
+            #[cfg(not(feature = "websocket"))]
+            log::warn!(target: "network", "WebSocket address {} requires the websocket feature", address);
+        }
+
+        let acceptor = TransportAcceptor {
+            pending: pending.clone(),
+            new_conn_tx: new_conn_tx.clone(),
+            handshake: handshake.clone(),
+        };
+
+        let events = channel_events.0.clone();
+        let socket_options = config.socket.clone();
         tokio::spawn(async move {
+            let mut failures = SocketFailures::default();
             loop {
-                match listener.accept().await {
+                if new_conn_tx.is_disconnected() {
+                    break;
+                }
+                let accepted = listener.accept().await;
+                let e = match accepted {
                     Ok((stream, addr)) => {
-                        if new_conn_tx.send((stream, addr)).is_err() {
-                            break;
+                        failures.succeeded();
+                        let Some(slot) = pending.acquire(&addr) else {
+                            continue;
+                        };
+                        spawn_handshake(stream, addr.to_string(), slot, new_conn_tx.clone(), handshake.clone());
+                        continue;
+                    }
+                    Err(e) => e,
+                };
+                if !failures.failed(&e) {
+                    report_socket_error(&events, "tcp", &e, SocketRecovery::Retried);
+                    tokio::time::sleep(SOCKET_RETRY_DELAY).await;
+                    continue;
+                }
+                // The old listener holds the address until it is dropped
+                drop(listener);
+                listener = loop {
+                    match bind_listener(address, &socket_options) {
+                        Ok(listener) => break listener,
+                        Err(bind_error) => {
+                            log::warn!(target: "network", "TCP listener rebind on {} failed: {}", address, bind_error);
+                            report_socket_error(&events, "tcp", &e, SocketRecovery::RebindFailed);
                         }
                     }
-                    Err(e) => {
-                        log::error!(target: "network", "Accept error: {}", e);
+                    if new_conn_tx.is_disconnected() {
+                        return;
                     }
-                }
+                    tokio::time::sleep(SOCKET_RETRY_DELAY).await;
+                };
+                report_socket_error(&events, "tcp", &e, SocketRecovery::Rebound);
             }
         });
 
+        let stats: Arc<ServerStats> = Default::default();
+        start_health_endpoint(config.health_address, &stats).await;
+        start_discovery(config.discovery.as_ref(), &stats, game_port, config.max_connections);
+        if let Some(threshold) = config.stall_threshold {
+            spawn_watchdog(&stats, threshold);
+        }
+
         Self {
             new_connections_rx: new_conn_rx,
             connections: Arc::new(RwLock::new(HashMap::new())),
             channel_connections: flume::unbounded(),
             channel_errors: flume::unbounded(),
+            channel_events,
             next_client_id: AtomicU64::new(1),
+            audit_log,
+            stats,
+            tick_counters: Default::default(),
+            datagram_socket,
+            datagram_routes,
+            foreign_datagrams,
+            groups: Default::default(),
+            area_of_interest: Default::default(),
+            draining,
+            network_conditions: config.network_conditions.map(|c| Arc::new(RwLock::new(c))),
+            tenants: Tenants::new(&config.tenants),
+            thresholds: ConnectionThresholds::new(config.max_connections),
+            recorder: Default::default(),
+            write_ahead: Default::default(),
+            sessions,
+            synchronized: Default::default(),
+            compression_budget: Arc::new(CompressionBudget::new(config.compression_cpu_budget)),
+            acceptor,
+            config,
         }
     }
 
     async fn step(&self, _delta: Duration) {
-        // Process new connections from the accept loop
-        for (stream, addr) in self.new_connections_rx.drain() {
-            stream.set_nodelay(true).ok();
-            let (reader, writer) = stream.into_split();
+        let step_started = Instant::now();
+        let tick_time = self.stats.record_tick(self.connections_count());
+        if self.config.is_stalled(tick_time) {
+            emit_stall_report(&self.channel_events.0, self.stall_report(tick_time.unwrap_or_default()));
+        }
+
+        for (message_type, message, client_ids) in self.synchronized.take_due(step_started) {
+            self.send_to_clients(&client_ids, message_type, &message);
+        }
+
+        // Process new connections from the accept loop.
+        // Messages are decoded by the per-connection tasks, so accepting
+        // is the only deferrable work of this backend.
+        while let Ok(PendingConnection {
+            reader,
+            writer,
+            ip,
+            session,
+            resume,
+            _slot,
+        }) = self.new_connections_rx.try_recv()
+        {
+            // A resumed session goes back to its connection task, see `crate::resume`
+            if let Some(request) = resume {
+                let connection = self
+                    .sessions
+                    .get(&request.token)
+                    .and_then(|client_id| self.connections.read().get(&client_id).cloned());
+                let resumed = ResumedSocket {
+                    reader,
+                    writer,
+                    received: request.received,
+                };
+                match connection {
+                    Some(connection) => {
+                        connection.resumed_sockets.send(resumed).ok();
+                    }
+                    None => log::warn!(target: "network", "Session of {} expired before it was resumed", ip),
+                }
+                continue;
+            }
 
             let client_id = self.next_client_id.fetch_add(1, Ordering::SeqCst);
+            let client_id = ClientId::new(client_id).expect("client ids start at 1");
+            let max_message_size = self.config.get_max_message_size();
+            let protocol_config = ProtocolConfig::negotiated(&session, session.client_schema, max_message_size);
+            let protocol = match ServerProtocol::new(client_id, &protocol_config) {
+                Ok(protocol) => Arc::new(protocol),
+                Err(reason) => {
+                    self.channel_errors
+                        .0
+                        .send(NetworkError::Negotiation { client_id, reason })
+                        .ok();
+                    continue;
+                }
+            };
+
+            // Events of a tenant connection go to the tenant, see `crate::tenants`
+            let events_tx = self
+                .tenants
+                .get_events_sender(session.tenant.as_ref())
+                .unwrap_or_else(|| self.channel_events.0.clone());
+            session.emit_fallbacks(&events_tx, client_id);
             let connected = Arc::new(AtomicBool::new(true));
+            let last_ping_sent = Arc::new(Mutex::new(None));
+            let liveness: Arc<Liveness> = Default::default();
+            let state_syncs: Arc<StateSyncs> = Default::default();
+            // Clients predating the credit never return it
+            let flow_control = session.client_schema > ClientMessagesDiscriminants::StreamCredit as u32;
+            let stream_windows = Arc::new(StreamWindows::new(flow_control));
+            let tuning = Arc::new(ConnectionTuning::new(self.config.traffic_shaping.as_ref()));
+            let traffic: Arc<TrafficMeter> = Default::default();
+            let permissions: Arc<PermissionGate> = Default::default();
+            let rate_limiter: Arc<RateLimiter> = Default::default();
+            let disconnect_reason: Arc<Mutex<Option<String>>> = Default::default();
+            let snapshots: Arc<Mutex<SnapshotSender>> = Default::default();
+            let time_sync: Arc<TimeSyncRequests> = Default::default();
+            let rpc = Arc::new(self.config.create_rpc_endpoint());
+            let label = ConnectionLabel::new(client_id);
+            let bounded: Arc<BoundedSender> = Default::default();
+            let latest: Arc<Coalescer<OutgoingFrame>> = Default::default();
+            let approval = Arc::new(ApprovalGate::new(self.config.approval.as_ref()));
+            let disconnect_at: Arc<RwLock<Option<Instant>>> = Default::default();
+            let burst: Arc<BurstReservation> = Default::default();
+            let batch: Arc<SendBatch<OutgoingFrame>> = Default::default();
             let (msg_tx, msg_rx) = flume::unbounded();
+            let (raw_tx, raw_rx) = flume::unbounded();
             let (out_tx, out_rx) = flume::unbounded();
+            let (system_tx, system_rx) = flume::unbounded();
 
-            // Spawn per-connection reader task
-            {
-                let error_tx = self.channel_errors.0.clone();
-                let connected = connected.clone();
-                let outgoing_tx = out_tx.clone();
-                tokio::spawn(async move {
-                    connection_reader_task(reader, msg_tx, error_tx, connected, outgoing_tx).await;
-                });
+            // Set with `ServerConfig::session_resume`, see `crate::resume`
+            let session_token = session.resume.map(|offer| offer.token);
+            let resume_state: Option<Arc<ResumeState>> = session.resume.map(|_| Default::default());
+            let (resumed_tx, resumed_rx) = flume::unbounded();
+            if let Some(token) = session_token {
+                self.sessions.insert(token, client_id);
             }
 
-            // Spawn per-connection writer task
-            {
-                let connected = connected.clone();
+            // Spawn per-connection reader and writer tasks
+            let reader_ctx = {
+                let reader_ctx = ConnectionReader {
+                    client_id,
+                    label: label.clone(),
+                    ip: ip.clone(),
+                    config: self.config.clone(),
+                    protocol: protocol.clone(),
+                    tx: msg_tx,
+                    raw_tx,
+                    error_tx: self.channel_errors.0.clone(),
+                    events_tx: events_tx.clone(),
+                    connected: connected.clone(),
+                    outgoing_tx: out_tx.clone(),
+                    last_ping_sent: last_ping_sent.clone(),
+                    traffic: traffic.clone(),
+                    tick_counters: self.tick_counters.clone(),
+                    permissions: permissions.clone(),
+                    rate_limiter: rate_limiter.clone(),
+                    disconnect_reason: disconnect_reason.clone(),
+                    snapshots: snapshots.clone(),
+                    time_sync: time_sync.clone(),
+                    rpc: rpc.clone(),
+                    bounded: bounded.clone(),
+                    approval: approval.clone(),
+                    area_of_interest: self
+                        .config
+                        .position_tracking
+                        .then(|| self.area_of_interest.clone()),
+                    recorder: self.recorder.clone(),
+                    write_ahead: self.write_ahead.clone(),
+                    resume: resume_state.clone(),
+                    closing: AtomicBool::new(false),
+                    liveness: liveness.clone(),
+                    state_syncs: state_syncs.clone(),
+                    stream_windows: stream_windows.clone(),
+                    tuning: tuning.clone(),
+                    deferred: DeferredMessages::new(self.config.step_budget.is_some()),
+                };
+                let out_rx = match self.network_conditions.as_ref() {
+                    Some(conditions) => condition_channel(out_rx, Conditioner::new(conditions)),
+                    None => out_rx,
+                };
+                let writer_ctx = ConnectionWriter {
+                    client_id,
+                    events_tx: events_tx.clone(),
+                    connected: connected.clone(),
+                    last_ping_sent,
+                    traffic: traffic.clone(),
+                    shaper: self
+                        .config
+                        .traffic_shaping
+                        .as_ref()
+                        .map(|shaping| Shaper::new(shaping, burst.clone())),
+                    keep_alive: self.config.get_keep_alive(),
+                    tick_counters: self.tick_counters.clone(),
+                    latest: latest.clone(),
+                    resume: resume_state.clone(),
+                    system: system_rx,
+                    liveness,
+                    tuning: tuning.clone(),
+                    tuning_revision: 0,
+                };
+                let resume = session.resume.zip(resume_state).map(|(offer, state)| SessionResume {
+                    state,
+                    grace: offer.grace,
+                    sockets: resumed_rx,
+                    disconnect_at: disconnect_at.clone(),
+                });
+                let reader_ctx = Arc::new(reader_ctx);
+                let task_ctx = reader_ctx.clone();
                 tokio::spawn(async move {
-                    connection_writer_task(writer, out_rx, connected).await;
+                    connection_task(reader, writer, task_ctx, writer_ctx, out_rx, resume).await;
                 });
-            }
+                reader_ctx
+            };
+
+            let datagrams = self.datagram_socket.as_ref().map(|socket| {
+                let datagrams = Arc::new(ServerDatagrams::new(
+                    socket.clone(),
+                    session.datagram_token,
+                    self.config.get_datagram_rate(),
+                    self.network_conditions.as_ref().map(Conditioner::new),
+                ));
+                self.datagram_routes
+                    .write()
+                    .insert(session.datagram_token, datagrams.clone());
+                datagrams
+            });
 
             let connection = TokioServerConnection {
                 client_id,
-                ip: addr.to_string(),
+                ip,
+                config: self.config.clone(),
+                protocol,
+                max_texture_size: session.max_texture_size,
+                session_seed: session.seed,
+                channel_events: events_tx,
+                channel_errors: self.channel_errors.0.clone(),
                 connected,
-                disconnect_at: Arc::new(RwLock::new(None)),
+                disconnect_at,
                 channel_client_messages: msg_rx,
+                channel_raw_messages: raw_rx,
                 channel_outgoing: out_tx,
+                chunk_interest: self.config.create_chunk_interest(),
+                datagrams,
+                traffic,
+                tick_counters: self.tick_counters.clone(),
+                permissions,
+                approval,
+                tenant: session.tenant,
+                rate_limiter,
+                disconnect_reason,
+                snapshots,
+                time_sync,
+                rpc,
+                bounded,
+                latest,
+                generation: Generation::new(),
+                recorder: self.recorder.clone(),
+                label,
+                phase: Default::default(),
+                session_token,
+                resumed_sockets: resumed_tx,
+                burst,
+                batch,
+                state_syncs,
+                compression: Default::default(),
+                compression_budget: self.compression_budget.clone(),
+                channel_system: system_tx,
+                identity: session.identity,
+                stream_windows,
+                tuning,
+                trace_supported: session.client_schema > ClientMessagesDiscriminants::Traced as u32,
+                degradation: Default::default(),
+                reader: reader_ctx,
             };
 
+            // Rejected over `ServerConfig::ip_limit`, see `crate::ip_limits`;
+            // kept until the rejection is flushed
+            let accepted = self.check_ip_limit(&connection);
             self.connections
                 .write()
                 .insert(client_id, connection.clone());
-            self.channel_connections
-                .0
-                .send(ConnectionMessages::Connect { connection })
-                .ok();
+            if !accepted {
+                continue;
+            }
+            if self.config.approval.is_some() {
+                // Reported once approved, see `crate::approval`
+                connection.send_message(NetworkMessageType::ReliableOrdered, &ServerMessages::AllowConnection);
+            } else {
+                if let Some(notice) = self.draining.notice() {
+                    connection.send_message(NetworkMessageType::ReliableOrdered, &notice);
+                }
+                let tenant = connection.tenant.clone();
+                self.report(tenant.as_ref(), ConnectionMessages::Connect { connection });
+            }
+
+            if self.config.is_over_budget(step_started) {
+                break;
+            }
+        }
+
+        // Decode the unreliable messages queued by the reader tasks while the
+        // budget lasts; the rest waits for the next tick
+        let mut deferred = self.new_connections_rx.len();
+        for conn in self.connections.read().values() {
+            while !self.config.is_over_budget(step_started) {
+                let Some(data) = conn.reader.deferred.pop() else {
+                    break;
+                };
+                receive_message(&conn.reader, data);
+            }
+            deferred += conn.reader.deferred.len();
+        }
+
+        // Kick connections over `MessageRoutes::with_kick_after`
+        for conn in self.connections.read().values() {
+            if conn.permissions.take_kick() {
+                self.audit_log.record(AuditEvent::PermissionKick {
+                    client_id: conn.client_id,
+                    ip: conn.ip.clone(),
+                });
+                conn.disconnect_with_reason(Some("Forbidden message".to_string()));
+            }
+        }
+
+        // Answer the clock requests of the clients, see `crate::time_sync`
+        let server_time = self.server_time();
+        for conn in self.connections.read().values() {
+            for reply in conn.time_sync.take_replies(server_time) {
+                conn.send_message(NetworkMessageType::Unreliable, &reply);
+            }
+        }
+
+        // Resend or give up unacknowledged messages, see `crate::retries`
+        for conn in self.connections.read().values() {
+            let (resends, dropped) = conn.bounded.take_due(&conn.label, conn.tuning.get_resend_interval());
+            for message in resends {
+                conn.send_message(NetworkMessageType::Unreliable, &message);
+            }
+            for event in dropped {
+                conn.channel_events.send(event).ok();
+            }
+        }
+
+        // Apply the tunings the client applied, see `crate::tuning`; the shaper
+        // of the writer task picks up its shares itself
+        for conn in self.connections.read().values() {
+            for (revision, tuning) in conn.tuning.take_applied() {
+                if let (Some(rate), Some(datagrams)) = (tuning.datagram_rate, conn.datagrams.as_ref()) {
+                    datagrams.set_rate(rate);
+                }
+                let event = ServerEvents::ChannelsRetuned {
+                    client_id: conn.client_id,
+                    revision,
+                    tuning,
+                };
+                conn.channel_events.send(event).ok();
+            }
+        }
+
+        // Trip or restore the unreliable-only mode, see `crate::degraded`
+        for conn in self.connections.read().values() {
+            if let Some(mode) = self.config.degraded_mode.as_ref() {
+                let info = conn.get_network_info();
+                conn.degradation.update(mode, info.rtt, info.packet_loss);
+            }
+            for (degraded, event) in conn.degradation.take_changes(conn.client_id) {
+                conn.send_system(&SystemMessage::DegradedMode { active: degraded });
+                conn.channel_events.send(event).ok();
+            }
+        }
+
+        // Report and kick connections over `ServerConfig::rate_limits`
+        for conn in self.connections.read().values() {
+            if let Some(dropped) = conn.rate_limiter.take_unreported() {
+                let total = conn.rate_limiter.get_dropped();
+                log::warn!(target: "network", "Client {} rate limited; {} messages dropped", conn.label, total);
+                let event = ServerEvents::RateLimited {
+                    client_id: conn.client_id,
+                    dropped,
+                    total,
+                };
+                conn.channel_events.send(event).ok();
+            }
+            if conn.rate_limiter.take_kick() {
+                self.audit_log.record(AuditEvent::RateLimitKick {
+                    client_id: conn.client_id,
+                    ip: conn.ip.clone(),
+                });
+                conn.disconnect_with_reason(Some("Rate limit exceeded".to_string()));
+            }
+        }
+
+        // Report or reject connections decided by `ServerConfig::approval`
+        for conn in self.connections.read().values() {
+            match conn.approval.take_decision() {
+                Some(Ok(())) => {
+                    if let Some(notice) = self.draining.notice() {
+                        conn.send_message(NetworkMessageType::ReliableOrdered, &notice);
+                    }
+                    let connection = conn.clone();
+                    self.report(conn.tenant.as_ref(), ConnectionMessages::Connect { connection });
+                }
+                Some(Err(reason)) => {
+                    let event = AuditEvent::rejection(conn.client_id, &conn.ip, &reason);
+                    self.audit_log.record(event);
+                    conn.send_message(
+                        NetworkMessageType::ReliableOrdered,
+                        &ServerMessages::ConnectionRejected { reason },
+                    );
+                    conn.disconnect();
+                }
+                None => {}
+            }
         }
 
         // Handle disconnections (remote close or graceful disconnect delay)
@@ -192,7 +1527,7 @@ impl IServerNetwork<TokioServerConnection> for TokioServer {
             let connections = self.connections.read();
             for (&id, conn) in connections.iter() {
                 let should_remove = if let Some(at) = *conn.disconnect_at.read() {
-                    Instant::now() >= at
+                    Instant::now() >= at || (self.config.disconnect_grace.is_some() && conn.is_flushed())
                 } else {
                     !conn.connected.load(Ordering::SeqCst)
                 };
@@ -208,17 +1543,76 @@ impl IServerNetwork<TokioServerConnection> for TokioServer {
             for id in to_remove {
                 if let Some(conn) = connections.remove(&id) {
                     conn.connected.store(false, Ordering::SeqCst);
-                    self.channel_connections
-                        .0
-                        .send(ConnectionMessages::Disconnect {
-                            client_id: id,
-                            reason: "Disconnected".to_string(),
-                        })
-                        .ok();
+                    conn.rpc.close();
+                    conn.stream_windows.close_all();
+                    if let Some(token) = conn.session_token.as_ref() {
+                        self.sessions.remove(token);
+                    }
+                    conn.generation.expire();
+                    self.groups.remove_client(id);
+                    self.area_of_interest.remove_client(id);
+                    if let Some(datagrams) = conn.datagrams.as_ref() {
+                        self.datagram_routes.write().remove(&datagrams.get_token());
+                    }
+                    // Connections never approved were not reported as connected
+                    if !conn.approval.is_approved() {
+                        continue;
+                    }
+                    let reason = conn.disconnect_reason.lock().take();
+                    let disconnect = ConnectionMessages::Disconnect {
+                        client_id: id,
+                        reason: reason.unwrap_or_else(|| "Disconnected".to_string()),
+                    };
+                    self.report(conn.tenant.as_ref(), disconnect);
                 }
             }
         }
 
+        if let Some(drained) = self.draining.check(self.connections_count()) {
+            self.channel_events.0.send(drained).ok();
+        }
+
+        for crossing in self.thresholds.update(self.connections_count()) {
+            let event = ServerEvents::ThresholdCrossed { crossing };
+            self.channel_events.0.send(event).ok();
+        }
+
+        if let Some(budget) = self.config.step_budget {
+            let elapsed = step_started.elapsed();
+            if elapsed > budget {
+                let overloaded = ServerEvents::Overloaded {
+                    elapsed,
+                    budget,
+                    deferred,
+                };
+                self.channel_events.0.send(overloaded).ok();
+            }
+        }
+
+        // Adapt the deflate level of each connection, see `crate::compression`
+        if self.config.compression_threshold.is_some() {
+            let over_budget = self.compression_budget.is_exhausted();
+            for conn in self.connections.read().values() {
+                let queued = conn.channel_outgoing.len() + conn.traffic.get_held();
+                conn.compression.adapt(conn.traffic.get_rtt(), queued, over_budget);
+            }
+        }
+
+        // Write the messages held during the tick, see `crate::batching`
+        if self.config.send_batching.is_some() {
+            for conn in self.connections.read().values() {
+                conn.flush();
+            }
+        }
+
+        if self.config.tick_reports {
+            let connections = self.connections_count();
+            let report = self
+                .tick_counters
+                .take_report(tick_time, step_started.elapsed(), connections);
+            self.channel_events.0.send(ServerEvents::TickReport { report }).ok();
+        }
+
         log::trace!(target: "network", "network step");
     }
 
@@ -226,10 +1620,14 @@ impl IServerNetwork<TokioServerConnection> for TokioServer {
         self.channel_connections.1.drain()
     }
 
-    fn drain_errors(&self) -> impl Iterator<Item = String> {
+    fn drain_errors(&self) -> impl Iterator<Item = NetworkError> {
         self.channel_errors.1.drain()
     }
 
+    fn drain_events(&self) -> impl Iterator<Item = ServerEvents> {
+        self.channel_events.1.drain()
+    }
+
     fn is_connected(&self, connection: &TokioServerConnection) -> bool {
         if connection.is_to_disconnect() {
             return false;
@@ -240,20 +1638,302 @@ impl IServerNetwork<TokioServerConnection> for TokioServer {
     fn connections_count(&self) -> usize {
         self.connections.read().len()
     }
+
+    fn connections_snapshot(&self) -> Vec<TokioServerConnection> {
+        self.connections
+            .read()
+            .values()
+            .filter(|c| c.approval.is_approved())
+            .cloned()
+            .collect()
+    }
+
+    fn get_audit_log(&self) -> &AuditLog {
+        &self.audit_log
+    }
+
+    fn get_config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    fn server_time(&self) -> f64 {
+        self.stats.uptime().as_secs_f64()
+    }
+
+    fn get_foreign_datagrams(&self) -> u64 {
+        self.foreign_datagrams.load(Ordering::Relaxed)
+    }
+
+    fn get_groups(&self) -> &ConnectionGroups {
+        &self.groups
+    }
+
+    fn get_area_of_interest(&self) -> &AreaOfInterest {
+        &self.area_of_interest
+    }
+
+    fn get_draining(&self) -> &Draining {
+        &self.draining
+    }
+
+    fn get_synchronized_broadcasts(&self) -> &SynchronizedBroadcasts {
+        &self.synchronized
+    }
+
+    fn get_recorder(&self) -> &MessageRecorder {
+        &self.recorder
+    }
+
+    fn get_write_ahead_log(&self) -> &WriteAheadLog {
+        &self.write_ahead
+    }
+
+    fn get_thresholds(&self) -> &ConnectionThresholds {
+        &self.thresholds
+    }
+
+    fn get_tenant(&self, name: &str) -> Option<&Tenant<TokioServerConnection>> {
+        self.tenants.get(name)
+    }
+
+    fn set_network_conditions(&self, conditions: NetworkConditions) {
+        match self.network_conditions.as_ref() {
+            Some(shared) => *shared.write() = conditions,
+            None => log::warn!(target: "network", "Network conditions can only be changed when configured at start"),
+        }
+    }
+
+    fn send_to_clients(&self, client_ids: &[ClientId], message_type: NetworkMessageType, message: &ServerMessages) {
+        // The encoding only depends on the quantization profile of the channel
+        let mut encoded: HashMap<Option<&'static str>, Option<Vec<u8>>> = HashMap::new();
+        let connections = self.connections.read();
+        for client_id in client_ids {
+            let Some(connection) = connections.get(client_id) else {
+                continue;
+            };
+            if !connection.connected.load(Ordering::SeqCst) {
+                continue;
+            }
+            // Skin variants differ per client and are encoded separately
+            if let Some(resolved) = message.resolve_skins(connection.max_texture_size) {
+                if let Some(payload) = connection.encode_message(message_type, &resolved) {
+                    connection.queue_encoded(message_type, &resolved, payload, None, None);
+                }
+                continue;
+            }
+            let profile = connection
+                .protocol
+                .get_profiles()
+                .get(message_type.channel_id())
+                .map(|p| p.name);
+            let payload = encoded
+                .entry(profile)
+                .or_insert_with(|| connection.encode_message(message_type, message))
+                .clone();
+            if let Some(payload) = payload {
+                connection.queue_encoded(message_type, message, payload, None, None);
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct TokioServerConnection {
-    client_id: u64,
+    client_id: ClientId,
     ip: String,
+    config: Arc<ServerConfig>,
+    /// Encodes the messages sent, see `crate::protocol`
+    protocol: Arc<ServerProtocol>,
+    max_texture_size: Option<u32>,
+    session_seed: Option<u64>,
+    channel_events: flume::Sender<ServerEvents>,
+    channel_errors: flume::Sender<NetworkError>,
     connected: Arc<AtomicBool>,
     disconnect_at: Arc<RwLock<Option<Instant>>>,
 
     channel_client_messages: flume::Receiver<ClientMessages>,
-    channel_outgoing: flume::Sender<Vec<u8>>,
+    /// Set with `ServerConfig::raw_receive`, see `crate::raw_messages`
+    channel_raw_messages: flume::Receiver<RawClientMessage>,
+    channel_outgoing: flume::Sender<OutgoingFrame>,
+    chunk_interest: Option<Arc<RwLock<ChunkInterest>>>,
+    datagrams: Option<Arc<ServerDatagrams>>,
+    traffic: Arc<TrafficMeter>,
+    tick_counters: Arc<TickCounters>,
+    permissions: Arc<PermissionGate>,
+    approval: Arc<ApprovalGate>,
+    tenant: Option<String>,
+    rate_limiter: Arc<RateLimiter>,
+    disconnect_reason: Arc<Mutex<Option<String>>>,
+    snapshots: Arc<Mutex<SnapshotSender>>,
+    time_sync: Arc<TimeSyncRequests>,
+    rpc: Arc<RpcEndpoint>,
+    bounded: Arc<BoundedSender>,
+    /// Frames of `send_latest` not written yet
+    latest: Arc<Coalescer<OutgoingFrame>>,
+    generation: Generation,
+    recorder: Arc<MessageRecorder>,
+    label: ConnectionLabel,
+    phase: Arc<PhaseGate>,
+    /// Set with `ServerConfig::session_resume`
+    session_token: Option<SessionToken>,
+    /// Sockets of the client resuming the session, for the connection task
+    resumed_sockets: flume::Sender<ResumedSocket>,
+    burst: Arc<BurstReservation>,
+    /// Frames held by `ServerConfig::send_batching`
+    batch: Arc<SendBatch<OutgoingFrame>>,
+    state_syncs: Arc<StateSyncs>,
+    compression: Arc<AdaptiveCompression>,
+    compression_budget: Arc<CompressionBudget>,
+    channel_system: flume::Sender<Vec<u8>>,
+    identity: Option<AuthIdentity>,
+    stream_windows: Arc<StreamWindows>,
+    tuning: Arc<ConnectionTuning>,
+    /// The client knows `ServerMessages::Traced`, added along `ClientMessages::Traced`
+    trace_supported: bool,
+    degradation: Arc<Degradation>,
+    /// Shared with the reader task, for `step()` to decode the deferred messages
+    reader: Arc<ConnectionReader>,
 }
 
 impl TokioServerConnection {
+    /// Serialize with the quantization profile of the channel
+    /// None if it can't be encoded; reported as `NetworkError::Encode`
+    fn encode_message(&self, message_type: NetworkMessageType, message: &ServerMessages) -> Option<Vec<u8>> {
+        match self.protocol.encode(message_type, message) {
+            Ok(payload) => Some(payload),
+            Err(error) => {
+                self.tick_counters.add_dropped();
+                self.channel_errors.send(error).ok();
+                None
+            }
+        }
+    }
+
+    fn queue_message(
+        &self,
+        message_type: NetworkMessageType,
+        message: &ServerMessages,
+        deadline: Option<Instant>,
+        latest: Option<u64>,
+    ) {
+        if !self.generation.check_send(&self.label, &self.channel_errors) {
+            return;
+        }
+        if !self.connected.load(Ordering::SeqCst) {
+            return;
+        }
+        let resolved = message.resolve_skins(self.max_texture_size);
+        let message = resolved.as_ref().unwrap_or(message);
+        let Some(payload) = self.encode_message(message_type, message) else {
+            return;
+        };
+        self.queue_encoded(message_type, message, payload, deadline, latest);
+    }
+
+    /// Check and queue a message serialized by `encode_message`;
+    /// with a `latest` key, it replaces the queued message of the key
+    fn queue_encoded(
+        &self,
+        message_type: NetworkMessageType,
+        message: &ServerMessages,
+        payload: Vec<u8>,
+        deadline: Option<Instant>,
+        latest: Option<u64>,
+    ) {
+        let channel = message_type.channel_id();
+        let size = payload.len();
+        if !self.phase.check(&self.config.phase_channels, message_type, message) {
+            self.tick_counters.add_dropped();
+            return;
+        }
+        if !self
+            .degradation
+            .check(self.config.degraded_mode.as_ref(), message_type, message)
+        {
+            self.tick_counters.add_dropped();
+            return;
+        }
+        if !self
+            .config
+            .check_message_size(&self.channel_events, &self.label, message.as_ref(), size)
+        {
+            self.tick_counters.add_dropped();
+            return;
+        }
+        if !self
+            .config
+            .check_max_message_size(&self.channel_errors, &self.label, message.as_ref(), size)
+        {
+            self.tick_counters.add_dropped();
+            return;
+        }
+        self.tick_counters.add_out(size);
+        let profile = self.protocol.get_profiles().get(channel);
+        self.recorder.record(
+            Direction::Outbound,
+            self.client_id,
+            channel,
+            profile,
+            message.as_ref(),
+            &payload,
+        );
+        if let Some(interest) = self.chunk_interest.as_ref() {
+            interest.write().observe(message);
+        }
+        let group = match self.burst.take(size) {
+            true => None,
+            false => self.config.traffic_shaping.as_ref().and_then(|s| s.group_of(message)),
+        };
+
+        let payloads = match self.protocol.fragment(message_type, message, payload) {
+            Ok(payloads) => payloads,
+            Err(error) => {
+                self.tick_counters.add_dropped();
+                self.channel_errors.send(error).ok();
+                return;
+            }
+        };
+        // A fragment must not miss a deadline, or the client would be left with a partial message
+        let deadline = deadline.filter(|_| payloads.len() == 1);
+        for payload in payloads {
+            let (started, level) = (Instant::now(), self.compression.get_level());
+            let data = self.protocol.message_frame(channel, payload, level);
+            self.compression_budget.record(started.elapsed());
+            let frame = OutgoingFrame {
+                data,
+                deadline: deadline.map(|d| (d, message.as_ref().to_string())),
+                group,
+                latest: false,
+            };
+            match latest {
+                // Only the first message of the key queues a placeholder
+                Some(key) => {
+                    if self.latest.put(message.as_ref(), key, frame) {
+                        let placeholder = OutgoingFrame {
+                            latest: true,
+                            ..OutgoingFrame::from(Vec::new())
+                        };
+                        self.channel_outgoing.send(placeholder).ok();
+                    }
+                }
+                None => self.queue_frame(message_type, frame),
+            }
+        }
+    }
+
+    /// Hold the frame with `ServerConfig::send_batching`, see `crate::batching`
+    fn queue_frame(&self, message_type: NetworkMessageType, frame: OutgoingFrame) {
+        let batching = self.config.send_batching.as_ref();
+        let Some(batching) = batching.filter(|b| b.is_batched(message_type)) else {
+            self.channel_outgoing.send(frame).ok();
+            return;
+        };
+        let size = frame.data.len();
+        self.batch.push(frame, size, batching.get_max_batch_size(), |frame| {
+            self.channel_outgoing.send(frame).ok();
+        });
+    }
+
     fn is_to_disconnect(&self) -> bool {
         if let Some(time) = *self.disconnect_at.read() {
             Instant::now() >= time
@@ -261,6 +1941,15 @@ impl TokioServerConnection {
             false
         }
     }
+
+    /// Nothing left to write to the socket, see `ServerConfig::disconnect_grace`
+    fn is_flushed(&self) -> bool {
+        self.channel_outgoing.is_empty()
+            && self.channel_system.is_empty()
+            && self.latest.len() == 0
+            && self.traffic.get_held() == 0
+            && self.batch.is_empty()
+    }
 }
 
 impl IServerConnection for TokioServerConnection {
@@ -268,28 +1957,227 @@ impl IServerConnection for TokioServerConnection {
         &self.ip
     }
 
-    fn get_client_id(&self) -> u64 {
+    fn get_client_id(&self) -> ClientId {
         self.client_id
     }
 
+    fn get_generation(&self) -> u64 {
+        self.generation.get_id()
+    }
+
+    fn is_stale(&self) -> bool {
+        self.generation.is_stale()
+    }
+
+    fn get_label(&self) -> &ConnectionLabel {
+        &self.label
+    }
+
+    fn get_tenant(&self) -> Option<&String> {
+        self.tenant.as_ref()
+    }
+
+    fn get_identity(&self) -> Option<&AuthIdentity> {
+        self.identity.as_ref()
+    }
+
+    fn get_rate_limited(&self) -> u64 {
+        self.rate_limiter.get_dropped()
+    }
+
+    fn get_network_info(&self) -> NetworkInfo {
+        self.traffic.get_network_info(self.channel_outgoing.len())
+    }
+
     fn drain_client_messages(&self) -> impl Iterator<Item = ClientMessages> {
         self.channel_client_messages.drain()
     }
 
-    fn send_message(&self, _message_type: NetworkMessageType, message: &ServerMessages) {
-        if !self.connected.load(Ordering::SeqCst) {
+    fn drain_raw_messages(&self) -> impl Iterator<Item = RawClientMessage> {
+        self.channel_raw_messages.drain()
+    }
+
+    fn send_message(&self, message_type: NetworkMessageType, message: &ServerMessages) {
+        self.queue_message(message_type, message, None, None);
+    }
+
+    fn send_message_with_deadline(&self, message_type: NetworkMessageType, message: &ServerMessages, deadline: Instant) {
+        self.queue_message(message_type, message, Some(deadline), None);
+    }
+
+    fn send_latest(&self, key: u64, message_type: NetworkMessageType, message: &ServerMessages) {
+        match message_type.is_optional() {
+            true => self.queue_message(message_type, message, None, Some(key)),
+            false => self.send_message(message_type, message),
+        }
+    }
+
+    fn reserve_burst(&self, bytes: usize, priority: BurstPriority) {
+        self.burst.reserve(bytes, priority);
+    }
+
+    fn flush(&self) {
+        self.batch.flush(|frame| {
+            self.channel_outgoing.send(frame).ok();
+        });
+    }
+
+    fn send_message_bounded(&self, message: &ServerMessages, policy: &RetryPolicy) {
+        let message = self.bounded.send(message, policy);
+        self.send_message(NetworkMessageType::Unreliable, &message);
+    }
+
+    fn send_snapshot(&self, snapshot: &SnapshotBuilder) {
+        let message = self.snapshots.lock().encode(snapshot);
+        self.send_message(NetworkMessageType::Unreliable, &message);
+    }
+
+    fn send_system(&self, message: &SystemMessage) {
+        if !self.generation.check_send(&self.label, &self.channel_errors) {
+            return;
+        }
+        if let Some(frame) = system_frame(message) {
+            self.channel_system.send(frame).ok();
+        }
+    }
+
+    fn get_rpc(&self) -> &RpcEndpoint {
+        &self.rpc
+    }
+
+    fn get_state_syncs(&self) -> &StateSyncs {
+        &self.state_syncs
+    }
+
+    fn get_stream_windows(&self) -> &StreamWindows {
+        &self.stream_windows
+    }
+
+    fn get_tuning(&self) -> &ConnectionTuning {
+        &self.tuning
+    }
+
+    fn is_trace_supported(&self) -> bool {
+        self.trace_supported
+    }
+
+    fn get_degradation(&self) -> &Degradation {
+        &self.degradation
+    }
+
+    fn get_compression(&self) -> &AdaptiveCompression {
+        &self.compression
+    }
+
+    fn get_permissions(&self) -> Permissions {
+        self.permissions.get()
+    }
+
+    fn set_permissions(&self, permissions: Permissions) {
+        self.permissions.set(permissions);
+    }
+
+    fn get_phase(&self) -> GamePhase {
+        self.phase.get()
+    }
+
+    fn set_phase(&self, phase: GamePhase) {
+        self.phase.set(phase);
+    }
+
+    fn get_session_seed(&self) -> Option<u64> {
+        self.session_seed
+    }
+
+    fn send_datagram(&self, data: &[u8]) -> Result<(), String> {
+        match self.datagrams.as_ref() {
+            Some(datagrams) => datagrams.send(data),
+            None => Err("Datagrams are disabled on this server".to_string()),
+        }
+    }
+
+    fn drain_datagrams(&self) -> impl Iterator<Item = Vec<u8>> {
+        self.datagrams.iter().flat_map(|datagrams| datagrams.drain())
+    }
+
+    fn set_chunk_center(&self, world_slug: &str, center: ChunkPosition) {
+        let Some(interest) = self.chunk_interest.as_ref() else {
             return;
+        };
+        let unloads = interest.write().set_center(world_slug, center);
+        for message in unloads.iter() {
+            self.send_message(NetworkMessageType::ReliableOrdered, message);
         }
-        let mut frame = vec![FRAME_MESSAGE];
-        frame.extend(bincode::serialize(message).unwrap());
-        self.channel_outgoing.send(frame).ok();
     }
 
     fn disconnect(&self) {
-        // Disconnect after 200ms delay to allow pending messages to flush
+        // Disconnect after a delay to allow pending messages to flush
         let mut disconnect_at = self.disconnect_at.write();
         if disconnect_at.is_none() {
-            *disconnect_at = Some(Instant::now() + Duration::from_millis(200));
+            *disconnect_at = Some(Instant::now() + self.config.get_disconnect_delay());
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+
+    use super::TokioServer;
+    use crate::client::ClientConfig;
+    use crate::handshake::{HandshakeResult, ServerHello, PROOF_SIZE};
+    use crate::server::{IServerNetwork, ServerConfig};
+    use crate::tokio::handshake::{client_handshake, read_handshake, write_handshake};
+    use crate::tokio::{random_frame, write_frame};
+
+    static PANICS: AtomicUsize = AtomicUsize::new(0);
+
+    fn free_address() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reader_survives_random_frames() {
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            PANICS.fetch_add(1, Ordering::Relaxed);
+            hook(info);
+        }));
+
+        let address = free_address();
+        let server = TokioServer::new_with_config(address.clone(), ServerConfig::default()).await;
+        for _ in 0..20 {
+            let mut stream = TcpStream::connect(&address).await.unwrap();
+            client_handshake(&mut stream, &ClientConfig::default(), None)
+                .await
+                .unwrap();
+            for _ in 0..200 {
+                // The server may drop the connection on a bad frame
+                if write_frame(&mut stream, &random_frame()).await.is_err() {
+                    break;
+                }
+            }
+            for _ in 0..10 {
+                server.step(Duration::from_millis(10)).await;
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+        assert_eq!(PANICS.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn client_below_the_minimum_version_is_rejected() {
+        let address = free_address();
+        let _server = TokioServer::new_with_config(address.clone(), ServerConfig::default()).await;
+        let mut stream = TcpStream::connect(&address).await.unwrap();
+        let _hello: ServerHello = read_handshake(&mut stream).await.unwrap();
+        // Hello of a 0.1.0 client, which has no protocol version
+        let old = (None::<[u8; PROOF_SIZE]>, Vec::<String>::new());
+        write_handshake(&mut stream, &old).await.unwrap();
+        let result: HandshakeResult = read_handshake(&mut stream).await.unwrap();
+        assert!(matches!(result, HandshakeResult::Rejected { .. }));
+    }
+}
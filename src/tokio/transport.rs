@@ -0,0 +1,40 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use super::{BoxedReader, BoxedWriter};
+
+/// Byte stream a connection runs over.
+///
+/// Frames, the handshake included, are the same on every transport;
+/// the server and client only see the halves returned by `into_halves`.
+/// Implement it for other streams, e.g. a WebTransport session, and pass
+/// them to `TokioServer::accept_transport` and `TokioClient::new_over_transport`.
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin + 'static {
+    /// Halves for the reader and writer tasks
+    fn into_halves(self) -> (BoxedReader, BoxedWriter);
+}
+
+impl Transport for TcpStream {
+    fn into_halves(self) -> (BoxedReader, BoxedWriter) {
+        self.set_nodelay(true).ok();
+        let (reader, writer) = self.into_split();
+        (Box::new(reader), Box::new(writer))
+    }
+}
+
+#[cfg(unix)]
+impl Transport for tokio::net::UnixStream {
+    fn into_halves(self) -> (BoxedReader, BoxedWriter) {
+        let (reader, writer) = self.into_split();
+        (Box::new(reader), Box::new(writer))
+    }
+}
+
+/// In-memory stream, e.g. the frame stream of a WebSocket connection (see
+/// `websocket::spawn_pumps`) or both ends of a test
+impl Transport for tokio::io::DuplexStream {
+    fn into_halves(self) -> (BoxedReader, BoxedWriter) {
+        let (reader, writer) = tokio::io::split(self);
+        (Box::new(reader), Box::new(writer))
+    }
+}
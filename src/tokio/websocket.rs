@@ -0,0 +1,121 @@
+//! WebSocket transport for browser clients.
+//!
+//! The connection carries the same length-prefixed frame stream as TCP,
+//! handshake included, inside binary messages. Message boundaries carry
+//! no meaning: a peer may split or join frames across messages freely.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use super::handshake::{HandshakeContext, HANDSHAKE_TIMEOUT};
+use super::server::{spawn_handshake, PendingConnection, PendingSlots};
+
+/// Buffered bytes between the WebSocket and the connection tasks
+const PUMP_BUFFER: usize = 64 * 1024;
+
+/// Bridge a WebSocket to a byte stream.
+///
+/// Incoming binary messages are written to the returned stream and bytes
+/// written to it are sent as binary messages. Closing either side closes the other.
+pub(crate) fn spawn_pumps<S>(socket: WebSocketStream<S>) -> DuplexStream
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (local, remote) = tokio::io::duplex(PUMP_BUFFER);
+    let (mut sink, mut source) = socket.split();
+    let (mut reader, mut writer) = tokio::io::split(remote);
+
+    tokio::spawn(async move {
+        while let Some(message) = source.next().await {
+            let data = match message {
+                Ok(Message::Binary(data)) => data,
+                Ok(Message::Close(_)) | Err(_) => break,
+                // Pings are answered by tungstenite itself
+                Ok(_) => continue,
+            };
+            if writer.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+        writer.shutdown().await.ok();
+    });
+
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; PUMP_BUFFER];
+        loop {
+            let size = match reader.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(size) => size,
+            };
+            if sink.send(Message::binary(buf[..size].to_vec())).await.is_err() {
+                break;
+            }
+        }
+        sink.close().await.ok();
+    });
+
+    local
+}
+
+/// Accept loop for `ServerConfig::websocket_address`
+pub(crate) async fn spawn_websocket_listener(
+    address: SocketAddr,
+    pending: Arc<PendingSlots>,
+    new_conn_tx: flume::Sender<PendingConnection>,
+    handshake: HandshakeContext,
+) -> Result<(), String> {
+    let listener = tokio::net::TcpListener::bind(address)
+        .await
+        .map_err(|e| format!("WebSocket bind error: {}", e))?;
+    log::info!(target: "network", "WebSocket server listening on {}", address);
+
+    tokio::spawn(async move {
+        loop {
+            if new_conn_tx.is_disconnected() {
+                break;
+            }
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::error!(target: "network", "WebSocket accept error: {}", e);
+                    continue;
+                }
+            };
+            let Some(slot) = pending.acquire(&addr) else {
+                continue;
+            };
+            stream.set_nodelay(true).ok();
+            let new_conn_tx = new_conn_tx.clone();
+            let handshake = handshake.clone();
+            tokio::spawn(async move {
+                let upgrade = tokio_tungstenite::accept_async(stream);
+                let socket = match tokio::time::timeout(HANDSHAKE_TIMEOUT, upgrade).await {
+                    Ok(Ok(socket)) => socket,
+                    Ok(Err(e)) => {
+                        log::warn!(target: "network", "WebSocket upgrade with {} failed: {}", addr, e);
+                        return;
+                    }
+                    Err(_) => {
+                        log::warn!(target: "network", "WebSocket upgrade with {} timed out", addr);
+                        return;
+                    }
+                };
+                spawn_handshake(spawn_pumps(socket), addr.to_string(), slot, new_conn_tx, handshake);
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Open a WebSocket connection to a `ws://` address
+pub(crate) async fn connect_websocket(url: &str) -> Result<DuplexStream, String> {
+    let (socket, _response) = tokio_tungstenite::connect_async(url)
+        .await
+        .map_err(|e| format!("Connection to {} failed: {}", url, e))?;
+    Ok(spawn_pumps(socket))
+}
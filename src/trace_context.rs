@@ -0,0 +1,230 @@
+//! W3C trace context carried by selected messages.
+//!
+//! `IClientNetwork::send_traced` and `IServerConnection::send_traced` wrap a
+//! message with a `TraceContext` (`ClientMessages::Traced`,
+//! `ServerMessages::Traced`), so a distributed trace can follow a player
+//! action from the client UI through the server game logic and back. It is
+//! meant for selected reliable messages: the context adds some 30 bytes.
+//!
+//! The receiving side unwraps the message for its own checks and routing
+//! (size limits, routes and approval check the inner variant) and hands it
+//! to the game still wrapped; `untraced` splits off the context, e.g. to
+//! start the span of the handler as its child. The messages the crate acts
+//! on itself, RPC calls included, drop the context. A peer that predates
+//! tracing is sent the message without it.
+//!
+//! The crate doesn't depend on OpenTelemetry: `to_headers` and `from_headers`
+//! fill and read the `traceparent` and `tracestate` keys of a map, which the
+//! OpenTelemetry propagators inject into and extract from.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+use crate::messages::{ClientMessages, ServerMessages};
+
+/// Longest `tracestate` kept; a longer one is dropped on receipt
+pub const MAX_TRACE_STATE_LEN: usize = 512;
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+
+const FLAG_SAMPLED: u8 = 0x01;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceContext {
+    trace_id: [u8; 16],
+    /// Span of the sender, the parent of the span of the receiver
+    span_id: [u8; 8],
+    flags: u8,
+    trace_state: Option<String>,
+}
+
+impl TraceContext {
+    /// None if an id is all zeroes, never a valid id
+    pub fn new(trace_id: [u8; 16], span_id: [u8; 8], sampled: bool) -> Option<Self> {
+        let context = Self {
+            trace_id,
+            span_id,
+            flags: if sampled { FLAG_SAMPLED } else { 0 },
+            trace_state: None,
+        };
+        context.is_valid().then_some(context)
+    }
+
+    /// Context of a new trace, with random ids
+    pub fn root(sampled: bool) -> Self {
+        loop {
+            if let Some(context) = Self::new(rand::random(), rand::random(), sampled) {
+                return context;
+            }
+        }
+    }
+
+    /// Context of a new span of the same trace, e.g. for the reply to a traced message
+    pub fn child(&self) -> Self {
+        let mut span_id = [0; 8];
+        while span_id == [0; 8] {
+            span_id = rand::random();
+        }
+        Self {
+            span_id,
+            ..self.clone()
+        }
+    }
+
+    pub fn with_trace_state(mut self, trace_state: &str) -> Self {
+        self.trace_state = Some(trace_state.to_string());
+        self
+    }
+
+    pub fn get_trace_id(&self) -> [u8; 16] {
+        self.trace_id
+    }
+
+    pub fn get_span_id(&self) -> [u8; 8] {
+        self.span_id
+    }
+
+    pub fn is_sampled(&self) -> bool {
+        self.flags & FLAG_SAMPLED != 0
+    }
+
+    pub fn get_trace_state(&self) -> Option<&String> {
+        self.trace_state.as_ref()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.trace_id != [0; 16] && self.span_id != [0; 8]
+    }
+
+    /// The `traceparent` header: version, trace id, span id and flags
+    pub fn to_traceparent(&self) -> String {
+        let mut traceparent = String::with_capacity(55);
+        traceparent.push_str("00-");
+        push_hex(&mut traceparent, &self.trace_id);
+        traceparent.push('-');
+        push_hex(&mut traceparent, &self.span_id);
+        write!(traceparent, "-{:02x}", self.flags).unwrap();
+        traceparent
+    }
+
+    /// Parse the `traceparent` and `tracestate` headers; None if `traceparent` is invalid
+    pub fn from_traceparent(traceparent: &str, trace_state: Option<&str>) -> Option<Self> {
+        let parts: Vec<&str> = traceparent.trim().split('-').collect();
+        let version = parse_hex::<1>(parts.first()?)?[0];
+        // Later versions may append fields, version 0 has none
+        if version == 0xff || (version == 0 && parts.len() != 4) || parts.len() < 4 {
+            return None;
+        }
+        let context = Self {
+            trace_id: parse_hex(parts[1])?,
+            span_id: parse_hex(parts[2])?,
+            flags: parse_hex::<1>(parts[3])?[0],
+            trace_state: trace_state
+                .filter(|state| !state.is_empty() && state.len() <= MAX_TRACE_STATE_LEN)
+                .map(str::to_string),
+        };
+        context.is_valid().then_some(context)
+    }
+
+    /// `traceparent` and `tracestate` entries, for an OpenTelemetry propagator to extract from
+    pub fn to_headers(&self) -> HashMap<String, String> {
+        let mut headers = HashMap::new();
+        headers.insert(TRACEPARENT.to_string(), self.to_traceparent());
+        if let Some(trace_state) = self.trace_state.as_ref() {
+            headers.insert(TRACESTATE.to_string(), trace_state.clone());
+        }
+        headers
+    }
+
+    /// Context from the entries an OpenTelemetry propagator injected
+    pub fn from_headers(headers: &HashMap<String, String>) -> Option<Self> {
+        let trace_state = headers.get(TRACESTATE).map(String::as_str);
+        Self::from_traceparent(headers.get(TRACEPARENT)?, trace_state)
+    }
+
+    /// The context of a received message, if valid; an oversized `tracestate` is dropped
+    pub(crate) fn checked(mut self) -> Option<Self> {
+        self.trace_state = self
+            .trace_state
+            .take()
+            .filter(|state| state.len() <= MAX_TRACE_STATE_LEN);
+        self.is_valid().then_some(self)
+    }
+}
+
+fn push_hex(out: &mut String, bytes: &[u8]) {
+    for byte in bytes {
+        write!(out, "{:02x}", byte).unwrap();
+    }
+}
+
+fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+impl ClientMessages {
+    /// The message wrapped with `context`, see `crate::trace_context`
+    pub fn traced(self, context: TraceContext) -> Self {
+        Self::Traced {
+            context,
+            message: Box::new(self),
+        }
+    }
+
+    /// The message without its trace context, and the context if it had a valid one
+    pub fn untraced(self) -> (Self, Option<TraceContext>) {
+        let Self::Traced { context, mut message } = self else {
+            return (self, None);
+        };
+        // Nested contexts would hide the variant from the checks
+        while let Self::Traced { message: inner, .. } = *message {
+            message = inner;
+        }
+        (*message, context.checked())
+    }
+
+    pub(crate) fn retraced(self, context: Option<TraceContext>) -> Self {
+        match context {
+            Some(context) => self.traced(context),
+            None => self,
+        }
+    }
+}
+
+impl ServerMessages {
+    /// The message wrapped with `context`, see `crate::trace_context`
+    pub fn traced(self, context: TraceContext) -> Self {
+        Self::Traced {
+            context,
+            message: Box::new(self),
+        }
+    }
+
+    /// The message without its trace context, and the context if it had a valid one
+    pub fn untraced(self) -> (Self, Option<TraceContext>) {
+        let Self::Traced { context, mut message } = self else {
+            return (self, None);
+        };
+        // Nested contexts would hide the variant from the checks
+        while let Self::Traced { message: inner, .. } = *message {
+            message = inner;
+        }
+        (*message, context.checked())
+    }
+
+    pub(crate) fn retraced(self, context: Option<TraceContext>) -> Self {
+        match context {
+            Some(context) => self.traced(context),
+            None => self,
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Channel parameters changed on a live server.
+//!
+//! `IServerConnection::retune` sends a `ChannelTuning` to the client as a
+//! `SystemMessage`. The client applies its part from `step()` and answers
+//! `SystemMessage::TuningApplied`; only then does the server apply its
+//! part and report `ServerEvents::ChannelsRetuned`, so both ends switch
+//! together. A client predating the tuning skips the system message and the
+//! connection keeps its parameters. Fields left unset are unchanged; to
+//! retune every client, call it on each of `IServerNetwork::connections_snapshot`.
+//!
+//! - `group_shares`: bandwidth weights of the `ServerConfig::traffic_shaping`
+//!   topic groups, on the server
+//! - `resend_interval`: resends of `send_message_bounded`, on the server,
+//!   replacing the interval of every `RetryPolicy`
+//! - `datagram_rate`: datagrams per second, on both ends (tokio only)
+//!
+//! The resend times of the renet channels are fixed by its connection config
+//! and can't be retuned.
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::shaping::TrafficShaping;
+use crate::system::SystemMessage;
+
+/// Parameters to change; unset ones are left as they are
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChannelTuning {
+    /// Share of `TrafficShaping::bytes_per_sec` per topic group name
+    pub group_shares: Vec<(String, f32)>,
+    pub resend_interval: Option<Duration>,
+    pub datagram_rate: Option<u32>,
+}
+
+impl ChannelTuning {
+    pub fn with_group_share(mut self, group: &str, share: f32) -> Self {
+        self.group_shares.push((group.to_string(), share));
+        self
+    }
+
+    pub fn with_resend_interval(mut self, interval: Duration) -> Self {
+        self.resend_interval = Some(interval);
+        self
+    }
+
+    pub fn with_datagram_rate(mut self, rate: u32) -> Self {
+        self.datagram_rate = Some(rate);
+        self
+    }
+}
+
+#[derive(Debug, Default)]
+struct TuningState {
+    next_revision: u32,
+    /// Sent to the client and not applied by it yet
+    pending: Vec<(u32, ChannelTuning)>,
+    /// Share per topic group index, as applied
+    shares: Vec<f32>,
+    resend_interval: Option<Duration>,
+    /// Revision applied by both ends
+    revision: u32,
+    /// Applied since `take_applied`, for `step()` to report
+    applied: Vec<(u32, ChannelTuning)>,
+}
+
+/// Server side tuning of one connection
+#[derive(Debug)]
+pub struct ConnectionTuning {
+    /// Topic group names, by index
+    groups: Vec<String>,
+    state: Mutex<TuningState>,
+}
+
+impl ConnectionTuning {
+    pub(crate) fn new(shaping: Option<&TrafficShaping>) -> Self {
+        let groups = shaping.map(|s| s.get_groups().clone()).unwrap_or_default();
+        let state = TuningState {
+            shares: groups.iter().map(|g| g.share).collect(),
+            ..Default::default()
+        };
+        Self {
+            groups: groups.into_iter().map(|g| g.name).collect(),
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Register a tuning to send; returns its revision.
+    ///
+    /// The shares are checked against the ones of the tunings before it.
+    pub(crate) fn propose(&self, tuning: &ChannelTuning) -> Result<u32, String> {
+        let mut state = self.state.lock();
+        let mut shares = state.shares.clone();
+        for (_, pending) in state.pending.iter() {
+            self.apply_shares(&mut shares, pending)?;
+        }
+        self.apply_shares(&mut shares, tuning)?;
+        if shares.iter().sum::<f32>() > 1.0 + f32::EPSILON {
+            return Err("Shares of the topic groups exceed 1.0".to_string());
+        }
+        if tuning.datagram_rate == Some(0) {
+            return Err("Datagram rate must be above 0".to_string());
+        }
+        state.next_revision += 1;
+        let revision = state.next_revision;
+        state.pending.push((revision, tuning.clone()));
+        Ok(revision)
+    }
+
+    fn apply_shares(&self, shares: &mut [f32], tuning: &ChannelTuning) -> Result<(), String> {
+        for (name, share) in tuning.group_shares.iter() {
+            let Some(index) = self.groups.iter().position(|g| g == name) else {
+                return Err(format!("No topic group {}", name));
+            };
+            if !(*share > 0.0 && *share <= 1.0) {
+                return Err(format!("Share of group {} must be within 0.0 - 1.0", name));
+            }
+            shares[index] = *share;
+        }
+        Ok(())
+    }
+
+    /// `SystemMessage::TuningApplied`; the client applies the tunings in order
+    pub(crate) fn ack(&self, revision: u32) {
+        let mut state = self.state.lock();
+        let applied: Vec<_> = state.pending.iter().filter(|(r, _)| *r <= revision).cloned().collect();
+        state.pending.retain(|(r, _)| *r > revision);
+        for (revision, tuning) in applied {
+            let mut shares = std::mem::take(&mut state.shares);
+            // Checked by `propose`
+            self.apply_shares(&mut shares, &tuning).ok();
+            state.shares = shares;
+            if tuning.resend_interval.is_some() {
+                state.resend_interval = tuning.resend_interval;
+            }
+            state.revision = revision;
+            state.applied.push((revision, tuning));
+        }
+    }
+
+    /// Revision applied by both ends, 0 before the first one
+    pub fn get_revision(&self) -> u32 {
+        self.state.lock().revision
+    }
+
+    /// Share per topic group index
+    pub(crate) fn get_shares(&self) -> Vec<f32> {
+        self.state.lock().shares.clone()
+    }
+
+    pub(crate) fn get_resend_interval(&self) -> Option<Duration> {
+        self.state.lock().resend_interval
+    }
+
+    /// Tunings applied since the last call; called by the server `step()`
+    pub(crate) fn take_applied(&self) -> Vec<(u32, ChannelTuning)> {
+        std::mem::take(&mut self.state.lock().applied)
+    }
+}
+
+/// Client side: tunings received, applied by `step()`
+#[derive(Debug, Default)]
+pub(crate) struct TuningReceiver {
+    received: Mutex<Vec<(u32, ChannelTuning)>>,
+}
+
+impl TuningReceiver {
+    pub fn push(&self, revision: u32, tuning: ChannelTuning) {
+        self.received.lock().push((revision, tuning));
+    }
+
+    /// Tunings to apply, with the `SystemMessage::TuningApplied` to send once they are
+    pub fn take(&self) -> Option<(Vec<ChannelTuning>, SystemMessage)> {
+        let received = std::mem::take(&mut *self.received.lock());
+        let revision = received.iter().map(|(revision, _)| *revision).max()?;
+        let tunings = received.into_iter().map(|(_, tuning)| tuning).collect();
+        Some((tunings, SystemMessage::TuningApplied { revision }))
+    }
+}
@@ -0,0 +1,130 @@
+//! Validation of the client messages before they are sent.
+//!
+//! A game-logic bug that sends a NaN position or a runaway string gets the
+//! client kicked by the server, far from the code that caused it. With
+//! `ClientConfig::validation` every message passed to `send_message` is
+//! checked first; one that fails is not sent and is reported as
+//! `NetworkError::InvalidMessage` with the variant and the broken rule.
+//!
+//! `MessageValidation::standard` checks that positions and rotations are
+//! finite and caps the length of the strings; game rules are added with
+//! `with_validator`.
+
+use std::{fmt, sync::Arc};
+
+use crate::messages::ClientMessages;
+use crate::quantization::to_lanes;
+
+/// Longest string field `MessageValidation::standard` lets through, in bytes
+pub const DEFAULT_MAX_STRING_LENGTH: usize = 64 * 1024;
+
+/// Game rule checked on every sent message; returns why the message is invalid
+pub trait MessageValidator: Send + Sync {
+    fn validate(&self, message: &ClientMessages) -> Result<(), String>;
+}
+
+impl<F> MessageValidator for F
+where
+    F: Fn(&ClientMessages) -> Result<(), String> + Send + Sync,
+{
+    fn validate(&self, message: &ClientMessages) -> Result<(), String> {
+        self(message)
+    }
+}
+
+/// Checks run by the client before sending, see `ClientConfig::with_validation`
+#[derive(Clone, Default)]
+pub struct MessageValidation {
+    finite_floats: bool,
+    max_string_length: Option<usize>,
+    validators: Vec<Arc<dyn MessageValidator>>,
+}
+
+impl fmt::Debug for MessageValidation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MessageValidation")
+            .field("finite_floats", &self.finite_floats)
+            .field("max_string_length", &self.max_string_length)
+            .field("validators", &self.validators.len())
+            .finish()
+    }
+}
+
+impl MessageValidation {
+    /// Finite positions and rotations, strings up to `DEFAULT_MAX_STRING_LENGTH`
+    pub fn standard() -> Self {
+        Self {
+            finite_floats: true,
+            max_string_length: Some(DEFAULT_MAX_STRING_LENGTH),
+            validators: Vec::new(),
+        }
+    }
+
+    pub fn with_max_string_length(mut self, length: usize) -> Self {
+        self.max_string_length = Some(length);
+        self
+    }
+
+    pub fn with_validator(mut self, validator: impl MessageValidator + 'static) -> Self {
+        self.validators.push(Arc::new(validator));
+        self
+    }
+
+    pub fn validate(&self, message: &ClientMessages) -> Result<(), String> {
+        if self.finite_floats {
+            check_finite(message)?;
+        }
+        if let Some(max) = self.max_string_length {
+            for (field, value) in string_fields(message) {
+                if value.len() > max {
+                    return Err(format!("{} is {} bytes long, over {}", field, value.len(), max));
+                }
+            }
+        }
+        self.validators.iter().try_for_each(|v| v.validate(message))
+    }
+}
+
+fn check_finite(message: &ClientMessages) -> Result<(), String> {
+    let ClientMessages::PlayerMove { position, rotation, .. } = message else {
+        return Ok(());
+    };
+    let fields = [("position", to_lanes(position)), ("rotation", to_lanes(rotation))];
+    for (field, lanes) in fields {
+        if lanes.is_ok_and(|lanes| lanes.iter().any(|lane| !lane.is_finite())) {
+            return Err(format!("{} is not finite", field));
+        }
+    }
+    Ok(())
+}
+
+fn string_fields(message: &ClientMessages) -> Vec<(&'static str, &str)> {
+    match message {
+        ClientMessages::ConnectionInfo {
+            login,
+            version,
+            architecture,
+            rendering_device,
+        } => vec![
+            ("login", login.as_str()),
+            ("version", version.as_str()),
+            ("architecture", architecture.as_str()),
+            ("rendering_device", rendering_device.as_str()),
+        ],
+        ClientMessages::ConsoleInput { command } => vec![("command", command.as_str())],
+        ClientMessages::ClientScriptEvent {
+            script_slug,
+            slug,
+            json,
+        } => {
+            vec![
+                ("script_slug", script_slug.as_str()),
+                ("slug", slug.as_str()),
+                ("json", json.as_str()),
+            ]
+        }
+        ClientMessages::SnapshotAck { world_slug, .. } => vec![("world_slug", world_slug.as_str())],
+        ClientMessages::StateAck { name, .. } => vec![("name", name.as_str())],
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,49 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::health::ServerStats;
+use crate::server::ServerEvents;
+
+/// State of the server after `step()` was not called for longer than
+/// `ServerConfig::stall_threshold`, collected on the first step after the gap.
+#[derive(Clone, Debug, Default)]
+pub struct StallReport {
+    /// Time between the two steps
+    pub gap: Duration,
+    /// Accepted connections waiting to be registered
+    pub pending_connections: usize,
+    /// Client messages received but not drained yet
+    pub queued_client_messages: usize,
+    /// Frames waiting to be written to the sockets
+    pub queued_outgoing: usize,
+    /// Connections lost while the loop was stalled
+    pub disconnected: usize,
+}
+
+/// Warn while the game loop is stalled, before the report is available.
+///
+/// The task stops once the server is dropped.
+pub(crate) fn spawn_watchdog(stats: &Arc<ServerStats>, threshold: Duration) {
+    let stats = Arc::downgrade(stats);
+    tokio::spawn(async move {
+        let mut reported = false;
+        loop {
+            tokio::time::sleep(threshold / 2).await;
+            let Some(stats) = stats.upgrade() else {
+                break;
+            };
+            let stalled = match stats.since_last_tick() {
+                Some(elapsed) => elapsed > threshold,
+                None => false,
+            };
+            if stalled && !reported {
+                log::warn!(target: "network", "step() was not called for more than {:.2?}; game loop stalled", threshold);
+            }
+            reported = stalled;
+        }
+    });
+}
+
+pub(crate) fn emit_stall_report(events: &flume::Sender<ServerEvents>, report: StallReport) {
+    log::warn!(target: "network", "Game loop stalled: {:?}", report);
+    events.send(ServerEvents::Stalled { report }).ok();
+}
@@ -0,0 +1,449 @@
+//! Write-ahead log of the reliable client messages.
+//!
+//! `IServerNetwork::enable_write_ahead_log` appends every message the server
+//! accepts on a reliable channel (block edits, transactions) to a file and
+//! syncs it to disk before the message reaches `drain_client_messages` or
+//! `drain_raw_messages`. After a crash `WalReplay` reads back what the
+//! server definitely received, so the game can apply what its own save
+//! does not cover yet.
+//!
+//! Entries are numbered. Read `WriteAheadLog::get_sequence` before draining
+//! the connections: every entry up to it has been handed to the game by the
+//! time the drain returns. Once the game has persisted the state after that
+//! tick, `compact` drops those entries, and a replay holds only messages
+//! the save lacks.
+//!
+//! Entries are written and synced by a thread of the log, not by the
+//! receiving tasks: a reliable message reaches the game once its entry is on
+//! disk, which limits the throughput of reliable messages to that of the
+//! disk without holding up the runtime.
+//!
+//! A log is `WAL_MAGIC`, `WAL_VERSION` as u32 LE, then each
+//! entry as a u32 LE length followed by the bincode `WalEntry`. An entry
+//! cut short by the crash is dropped when the log is opened again; one
+//! cut short by a failed write is truncated right away, so the entries
+//! after it still replay.
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::client_id::ClientId;
+use crate::labels::ConnectionLabel;
+use crate::messages::{ClientMessages, NetworkMessageType};
+use crate::quantization::{with_profile, QuantizationProfile};
+
+pub const WAL_MAGIC: &[u8; 4] = b"BNWL";
+pub const WAL_VERSION: u32 = 1;
+
+const HEADER_SIZE: u64 = 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalEntry {
+    pub sequence: u64,
+    /// Client id of the run that received it; ids start over after a restart
+    pub client_id: ClientId,
+    /// `IServerConnection::set_label` of the connection, e.g. the player name
+    pub label: Option<String>,
+    /// `NetworkMessageType::channel_id`
+    pub channel: u8,
+    /// Quantization profile the payload is encoded with
+    pub profile: Option<String>,
+    pub variant: String,
+    /// Encoded message, after decompression
+    pub payload: Vec<u8>,
+}
+
+impl WalEntry {
+    pub fn decode(&self) -> Result<ClientMessages, String> {
+        let profile = match self.profile.as_ref() {
+            Some(name) => match QuantizationProfile::from_name(name) {
+                Some(profile) => Some(profile),
+                None => return Err(format!("Unknown quantization profile {}", name)),
+            },
+            None => None,
+        };
+        let message: ClientMessages =
+            with_profile(profile, || bincode::deserialize(&self.payload)).map_err(|e| e.to_string())?;
+        let decoded = message.as_ref();
+        if decoded != self.variant {
+            return Err(format!("Logged as {} but decodes as {}", self.variant, decoded));
+        }
+        Ok(message)
+    }
+}
+
+struct WalFile {
+    path: PathBuf,
+    file: File,
+    /// Bytes up to the end of the last entry written whole
+    length: u64,
+}
+
+/// Entry for the writer thread, and the hand-off of its message to the game
+struct WalJob {
+    entry: WalEntry,
+    /// `ConnectionLabel` of the sender, for the logs
+    from: String,
+    deliver: Box<dyn FnOnce() + Send>,
+}
+
+#[derive(Default)]
+struct WalState {
+    file: Mutex<Option<WalFile>>,
+    /// Sequence of the last entry
+    sequence: AtomicU64,
+}
+
+impl WalState {
+    /// Write and sync the entry, then hand its message to the game
+    fn write(&self, mut job: WalJob) {
+        let mut log = self.file.lock();
+        let Some(current) = log.as_mut() else {
+            drop(log);
+            return (job.deliver)();
+        };
+        job.entry.sequence = self.sequence.load(Ordering::SeqCst) + 1;
+        let written = write_entry(&mut current.file, &job.entry).and_then(|length| {
+            current.file.sync_data()?;
+            Ok(length)
+        });
+        match written {
+            Ok(length) => current.length += length,
+            // The message still goes to the game; the log misses it
+            Err(e) => {
+                log::error!(target: "network", "Write-ahead log of {} from {} failed: {}", job.entry.variant, job.from, e);
+                // Later entries must not follow a torn one
+                if let Err(e) = current.file.set_len(current.length) {
+                    log::error!(target: "network", "Write-ahead log could not drop a torn entry: {}", e);
+                }
+                (job.deliver)();
+                return;
+            }
+        }
+        (job.deliver)();
+        self.sequence.store(job.entry.sequence, Ordering::SeqCst);
+    }
+}
+
+/// Log being written, shared by the server and its connections
+pub struct WriteAheadLog {
+    state: Arc<WalState>,
+    open: AtomicBool,
+    jobs: flume::Sender<WalJob>,
+    /// Taken by the writer thread on the first `open`
+    writer: Mutex<Option<flume::Receiver<WalJob>>>,
+}
+
+impl Default for WriteAheadLog {
+    fn default() -> Self {
+        let (jobs, writer) = flume::unbounded();
+        Self {
+            state: Default::default(),
+            open: AtomicBool::new(false),
+            jobs,
+            writer: Mutex::new(Some(writer)),
+        }
+    }
+}
+
+fn write_header(file: &mut File) -> io::Result<()> {
+    file.write_all(WAL_MAGIC)?;
+    file.write_all(&WAL_VERSION.to_le_bytes())
+}
+
+/// Returns the bytes written
+fn write_entry(file: &mut File, entry: &WalEntry) -> io::Result<u64> {
+    let encoded = bincode::serialize(entry).map_err(io::Error::other)?;
+    let mut frame = Vec::with_capacity(4 + encoded.len());
+    frame.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&encoded);
+    file.write_all(&frame)?;
+    Ok(frame.len() as u64)
+}
+
+impl WriteAheadLog {
+    /// Log to `path` from now on, appending to the entries already there
+    pub fn open(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let mut last_sequence = 0;
+        let mut length = HEADER_SIZE;
+        if file.metadata()?.len() == 0 {
+            write_header(&mut file)?;
+            file.sync_data()?;
+        } else {
+            let mut replay = WalReplay::from_file(file.try_clone()?)?;
+            for entry in replay.by_ref() {
+                last_sequence = entry?.sequence;
+            }
+            // Drop an entry cut short by a crash
+            length = replay.complete_length;
+            file.set_len(length)?;
+        }
+        file.seek(SeekFrom::End(0))?;
+        if let Some(writer) = self.writer.lock().take() {
+            let state = self.state.clone();
+            std::thread::Builder::new()
+                .name("network-wal".to_string())
+                .spawn(move || {
+                    // Ends once the log is dropped
+                    for job in writer.iter() {
+                        state.write(job);
+                    }
+                })?;
+        }
+        let mut log = self.state.file.lock();
+        self.state.sequence.store(last_sequence, Ordering::SeqCst);
+        *log = Some(WalFile { path, file, length });
+        self.open.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Entries already queued are handed to the game unlogged
+    pub fn close(&self) {
+        self.open.store(false, Ordering::SeqCst);
+        self.state.file.lock().take();
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    /// Sequence of the last entry written; every entry up to it has been
+    /// handed to the message channels of the connections
+    pub fn get_sequence(&self) -> u64 {
+        self.state.sequence.load(Ordering::SeqCst)
+    }
+
+    /// Drop the entries up to `sequence`, once the game has persisted their effect
+    pub fn compact(&self, sequence: u64) -> io::Result<()> {
+        let mut log = self.state.file.lock();
+        let Some(current) = log.as_mut() else {
+            return Ok(());
+        };
+        let kept: Vec<WalEntry> = WalReplay::open(&current.path)?
+            .filter(|entry| entry.as_ref().map_or(true, |entry| entry.sequence > sequence))
+            .collect::<io::Result<_>>()?;
+
+        let compacted = current.path.with_extension("compact");
+        let mut file = File::create(&compacted)?;
+        write_header(&mut file)?;
+        let mut length = HEADER_SIZE;
+        for entry in kept.iter() {
+            length += write_entry(&mut file, entry)?;
+        }
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&compacted, &current.path)?;
+
+        let mut file = OpenOptions::new().append(true).open(&current.path)?;
+        file.seek(SeekFrom::End(0))?;
+        current.file = file;
+        current.length = length;
+        Ok(())
+    }
+
+    /// Hand an accepted message to the game with `deliver`, once logged
+    /// if it came on a reliable channel
+    pub(crate) fn append(
+        &self,
+        label: &ConnectionLabel,
+        channel: u8,
+        profile: Option<QuantizationProfile>,
+        variant: &str,
+        payload: &[u8],
+        deliver: impl FnOnce() + Send + 'static,
+    ) {
+        let reliable = matches!(
+            NetworkMessageType::from_channel_id(channel),
+            Some(NetworkMessageType::ReliableOrdered | NetworkMessageType::ReliableUnordered)
+        );
+        if !reliable || !self.is_open() {
+            return deliver();
+        }
+        let job = WalJob {
+            entry: WalEntry {
+                sequence: 0,
+                client_id: label.get_client_id(),
+                label: label.get(),
+                channel,
+                profile: profile.map(|p| p.name.to_string()),
+                variant: variant.to_string(),
+                payload: payload.to_vec(),
+            },
+            from: label.to_string(),
+            deliver: Box::new(deliver),
+        };
+        // The writer thread runs as long as the log
+        self.jobs.send(job).ok();
+    }
+}
+
+/// Entries of a log, in the order they were written
+pub struct WalReplay {
+    reader: BufReader<File>,
+    /// Bytes up to the end of the last entry read whole
+    complete_length: u64,
+}
+
+impl WalReplay {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_file(File::open(path)?)
+    }
+
+    fn from_file(mut file: File) -> io::Result<Self> {
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(file);
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != WAL_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a write-ahead log"));
+        }
+        let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if version != WAL_VERSION {
+            let reason = format!("Unsupported write-ahead log version {}", version);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+        }
+        Ok(Self {
+            reader,
+            complete_length: header.len() as u64,
+        })
+    }
+}
+
+impl Iterator for WalReplay {
+    type Item = io::Result<WalEntry>;
+
+    /// Ends at an entry cut short or garbled by a crash
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut length = [0u8; 4];
+        match self.reader.read_exact(&mut length) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(e) => return Some(Err(e)),
+        }
+        // Not allocated up front: a garbled length may be anything
+        let expected = u32::from_le_bytes(length) as usize;
+        let mut encoded = Vec::new();
+        match (&mut self.reader).take(expected as u64).read_to_end(&mut encoded) {
+            Ok(read) if read < expected => return None,
+            Ok(_) => {}
+            Err(e) => return Some(Err(e)),
+        }
+        let entry: WalEntry = bincode::deserialize(&encoded).ok()?;
+        self.complete_length += (length.len() + encoded.len()) as u64;
+        Some(Ok(entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn log_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("network-wal-{}-{}.log", name, std::process::id()));
+        fs::remove_file(&path).ok();
+        path
+    }
+
+    /// Append a console input and wait for its hand-off
+    fn append(log: &WriteAheadLog, channel: NetworkMessageType, command: &str) {
+        let message = ClientMessages::ConsoleInput {
+            command: command.to_string(),
+        };
+        let label = ConnectionLabel::new(ClientId::new(1).unwrap());
+        let (delivered_tx, delivered) = flume::bounded(1);
+        let payload = bincode::serialize(&message).unwrap();
+        let deliver = move || {
+            delivered_tx.send(()).ok();
+        };
+        log.append(&label, channel.channel_id(), None, message.as_ref(), &payload, deliver);
+        delivered.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    fn replayed(path: &Path) -> Vec<(u64, String)> {
+        WalReplay::open(path)
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let ClientMessages::ConsoleInput { command } = entry.decode().unwrap() else {
+                    panic!("unexpected {}", entry.variant);
+                };
+                (entry.sequence, command)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn appends_reliable_messages() {
+        let path = log_path("append");
+        let log = WriteAheadLog::default();
+        // Handed over unlogged while closed
+        append(&log, NetworkMessageType::ReliableOrdered, "closed");
+        log.open(&path).unwrap();
+        append(&log, NetworkMessageType::ReliableOrdered, "first");
+        append(&log, NetworkMessageType::Unreliable, "unreliable");
+        append(&log, NetworkMessageType::ReliableUnordered, "second");
+        assert_eq!(log.get_sequence(), 2);
+        let expected = vec![(1, "first".to_string()), (2, "second".to_string())];
+        assert_eq!(replayed(&path), expected);
+
+        // Reopened, the numbering goes on
+        log.close();
+        log.open(&path).unwrap();
+        append(&log, NetworkMessageType::ReliableOrdered, "third");
+        assert_eq!(replayed(&path).last(), Some(&(3, "third".to_string())));
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn compact_drops_persisted_entries() {
+        let path = log_path("compact");
+        let log = WriteAheadLog::default();
+        log.open(&path).unwrap();
+        for command in ["first", "second", "third"] {
+            append(&log, NetworkMessageType::ReliableOrdered, command);
+        }
+        log.compact(2).unwrap();
+        assert_eq!(replayed(&path), vec![(3, "third".to_string())]);
+        append(&log, NetworkMessageType::ReliableOrdered, "fourth");
+        let expected = vec![(3, "third".to_string()), (4, "fourth".to_string())];
+        assert_eq!(replayed(&path), expected);
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn torn_tail_is_dropped() {
+        let path = log_path("torn");
+        let log = WriteAheadLog::default();
+        log.open(&path).unwrap();
+        append(&log, NetworkMessageType::ReliableOrdered, "first");
+        log.close();
+
+        // A crash in the middle of the second entry
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&100u32.to_le_bytes()).unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+        drop(file);
+        assert_eq!(replayed(&path), vec![(1, "first".to_string())]);
+
+        // Reopening truncates it, so later entries replay
+        log.open(&path).unwrap();
+        append(&log, NetworkMessageType::ReliableOrdered, "second");
+        let expected = vec![(1, "first".to_string()), (2, "second".to_string())];
+        assert_eq!(replayed(&path), expected);
+        fs::remove_file(&path).ok();
+    }
+}